@@ -1,31 +1,79 @@
-use std::io::{self, Read, Seek, Write};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    fmt, fs,
+    io::{self, Read, Seek, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process, time,
+};
 
-use anyhow::Result;
-use console::style;
-use futures_util::StreamExt;
+use anyhow::{bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use http_body_util::BodyExt;
+use tokio::io::AsyncBufReadExt;
 
-use crate::{context, services, utils::file::Empty};
+use crate::{
+    config, context, docker, presentation, services,
+    utils::{file::Empty, string::to_hex},
+};
 
 const IGNORE_FILE: &str = ".dockerignore";
 
+/// Output lines of the current build step kept to show when it fails
+const FAILED_STEP_OUTPUT_LINES: usize = 30;
+
+/// Number of exported chunks buffered while the remote daemon is busy importing
+const TRANSFER_BUFFER_CHUNKS: usize = 16;
+
 pub async fn build_app_service_image(
     context: &context::Context,
     app_service: &services::app::AppService,
     docker: &bollard::Docker,
 ) -> Result<String> {
-    let bytes = create_cwd_tar(context)?;
+    app_service.validate()?;
 
-    let mut stream = docker.build_image(
-        app_service.to_image_build_config(),
-        None,
-        Some(bytes.into()),
-    );
+    let paths = BuildPaths::resolve(context)?;
+
+    let secrets = BuildSecrets::resolve(context)?;
+    if builds_with_cli(context, &secrets) {
+        return build_with_cli(context, app_service, &paths, &secrets).await;
+    }
+
+    let compression = context
+        .app_config()
+        .build_compression(context.override_context());
+
+    let started = time::Instant::now();
+    let bytes = create_context_tar(&paths, compression)?;
+    docker::log_build_context(&compression.to_string(), bytes.len(), started.elapsed());
+
+    let mut options = app_service.to_image_build_config();
+    options.dockerfile = paths.dockerfile.to_string_lossy().replace('\\', "/");
+
+    let mut stream = docker.build_image(options, None, Some(bytes.into()));
 
     let mut image_id = None;
+    let mut step = BuildStep::default();
+    let mut log = BuildLog::open(context)?;
 
     while let Some(info) = stream.next().await {
-        // TODO: log info from build image
-        match info? {
+        let info = match info {
+            Ok(info) => info,
+            Err(bollard::errors::Error::DockerStreamError { error }) => {
+                log.write_error(&error)?;
+                return Err(step.fail(error).into());
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        match info {
+            bollard::models::BuildInfo {
+                error: Some(error), ..
+            } => {
+                log.write_error(&error)?;
+                return Err(step.fail(error).into());
+            }
             bollard::models::BuildInfo {
                 aux: Some(image_id_inner),
                 ..
@@ -36,13 +84,10 @@ pub async fn build_app_service_image(
                 stream: Some(stream),
                 ..
             } if !stream.trim().is_empty() => {
-                let formatted_stream = if stream.ends_with('\n') {
-                    stream.clone()
-                } else {
-                    format!("{}\n", stream)
-                };
+                step.record(&stream);
 
-                print!("{}", style(formatted_stream).dim());
+                log.write(&stream)?;
+                log.print(&stream);
             }
             _ => {}
         }
@@ -51,11 +96,537 @@ pub async fn build_app_service_image(
     image_id.ok_or_else(|| anyhow::anyhow!("Failed to build image"))
 }
 
-fn create_cwd_tar(context: &context::Context) -> Result<Vec<u8>> {
+/// The build endpoint of the docker API can't serve BuildKit secrets or outputs,
+/// so images with them are built by the docker CLI with the local daemon
+pub fn builds_with_cli(context: &context::Context, secrets: &BuildSecrets) -> bool {
+    !secrets.is_empty()
+        || context
+            .app_config()
+            .build_output(context.override_context())
+            .is_some()
+}
+
+async fn build_with_cli(
+    context: &context::Context,
+    app_service: &services::app::AppService,
+    paths: &BuildPaths,
+    secrets: &BuildSecrets,
+) -> Result<String> {
+    let options = app_service.to_image_build_config();
+    let image_id_dir = tempfile::tempdir()?;
+    let image_id_file = image_id_dir.path().join("image-id");
+
+    let mut command = tokio::process::Command::new("docker");
+    command
+        .env("DOCKER_BUILDKIT", "1")
+        .args(["build", "--progress", "plain", "--tag", options.t.as_str()])
+        .arg("--file")
+        .arg(paths.context_dir.join(&paths.dockerfile))
+        .arg("--iidfile")
+        .arg(&image_id_file);
+
+    if !options.platform.is_empty() {
+        command.args(["--platform", options.platform.as_str()]);
+    }
+
+    if let Some(output) = context
+        .app_config()
+        .build_output(context.override_context())
+    {
+        command.arg("--output").arg(format!("type={output}"));
+    }
+
+    // the CLI reads the files, their contents never pass through dploy
+    for (id, path) in &secrets.secrets {
+        command
+            .arg("--secret")
+            .arg(format!("id={id},src={}", path.display()));
+    }
+
+    // BuildKit writes its progress to stderr
+    let mut child = command
+        .arg(&paths.context_dir)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .context("Failed to run `docker build`. Build secrets and outputs need the docker CLI")?;
+
+    let output = child
+        .stderr
+        .take()
+        .expect("stderr of docker build is piped");
+
+    let mut step = BuildStep::default();
+    let mut log = BuildLog::open(context)?;
+
+    let mut lines = tokio::io::BufReader::new(output).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let line = format!("{line}\n");
+
+        step.record(&line);
+
+        log.write(&line)?;
+        log.print(&line);
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
+        let error = format!("docker build failed with {status}");
+        log.write_error(&error)?;
+        return Err(step.fail(error).into());
+    }
+
+    let image_id = fs::read_to_string(&image_id_file)
+        .context("docker build didn't write the id of the image")?;
+
+    Ok(image_id.trim().to_owned())
+}
+
+/// Streams an image from the local daemon to the remote one without a registry.
+/// Chunks are forwarded as they are exported so the image is never held in memory.
+/// `target` is the address of the remote docker API, see [`crate::ssh::forwarded_docker_address`]
+pub async fn transfer_image(
+    source: &bollard::Docker,
+    target: SocketAddr,
+    image_name: &str,
+    label: &str,
+) -> Result<()> {
+    let (mut sender, receiver) = futures::channel::mpsc::channel(TRANSFER_BUFFER_CHUNKS);
+
+    let source = source.clone();
+    let image_name = image_name.to_owned();
+    let progress_label = label.to_owned();
+
+    let export = tokio::spawn(async move {
+        let mut stream = source.export_image(&image_name);
+        let mut transferred = 0;
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            transferred += chunk.len();
+
+            presentation::print_image_transfer_progress(&progress_label, transferred);
+
+            // the receiver is only dropped when the import failed, which is reported below
+            if sender.send(chunk).await.is_err() {
+                break;
+            }
+        }
+
+        anyhow::Ok(transferred)
+    });
+
+    load_image_stream(target, receiver).await?;
+
+    let transferred = export
+        .await?
+        .context("Failed to export the image from the local docker daemon")?;
+
+    presentation::print_image_transferred(label, transferred);
+
+    Ok(())
+}
+
+/// bollard only loads images from a single buffer, so the request is made directly
+/// with the exported chunks as its body
+async fn load_image_stream(
+    target: SocketAddr,
+    chunks: futures::channel::mpsc::Receiver<hyper::body::Bytes>,
+) -> Result<()> {
+    let stream = tokio::net::TcpStream::connect(target)
+        .await
+        .context("Could not connect to the remote docker daemon")?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream)).await?;
+
+    let connection = tokio::spawn(connection);
+
+    let body = http_body_util::StreamBody::new(
+        chunks.map(|chunk| Ok::<_, Infallible>(hyper::body::Frame::data(chunk))),
+    );
+    let request = hyper::Request::post("/images/load?quiet=1")
+        .header(hyper::header::HOST, "docker")
+        .header(hyper::header::CONTENT_TYPE, "application/x-tar")
+        .body(body)?;
+
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    let output = response.into_body().collect().await?.to_bytes();
+
+    drop(sender);
+    let _ = connection.await;
+
+    if !status.is_success() {
+        bail!(
+            "Failed to load the image on the remote host: {}",
+            String::from_utf8_lossy(&output).trim()
+        );
+    }
+
+    // the progress is a sequence of JSON objects, an error only shows up in there
+    for info in
+        serde_json::Deserializer::from_slice(&output).into_iter::<bollard::models::BuildInfo>()
+    {
+        if let Some(error) = info?.error {
+            bail!("Failed to load the image on the remote host: {error}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy of the build output in the file passed to `--build-log`.
+/// While it's written, the terminal only shows the build steps
+struct BuildLog {
+    file: Option<fs::File>,
+}
+
+impl BuildLog {
+    fn open(context: &context::Context) -> Result<Self> {
+        let Some(path) = context.args().build_log() else {
+            return Ok(Self { file: None });
+        };
+
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        let append = context.args().append_build_log();
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .with_context(|| format!("Failed to open build log {}", path.display()))?;
+
+        Ok(Self { file: Some(file) })
+    }
+
+    fn write(&mut self, stream: &str) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.write_all(stream.as_bytes())
+                .context("Failed to write to the build log")?;
+        }
+
+        Ok(())
+    }
+
+    /// The error follows the output of the failed step, so the log shows where it failed
+    fn write_error(&mut self, error: &str) -> Result<()> {
+        self.write(&format!("ERROR: {}\n", error.trim_end()))
+    }
+
+    fn print(&self, stream: &str) {
+        if self.file.is_none() {
+            presentation::print_build_output(stream);
+            return;
+        }
+
+        for line in stream.lines().filter(|line| is_step_header(line)) {
+            presentation::print_build_output(line);
+        }
+    }
+}
+
+/// e.g. `Step 3/7 : RUN cargo build`, or `#8 [3/7] RUN cargo build` with BuildKit
+fn is_step_header(line: &str) -> bool {
+    line.starts_with("Step ") || buildkit_line(line).is_some_and(|(_, rest)| rest.starts_with('['))
+}
+
+/// Keeps track of the step being built to point at it when the build fails
+#[derive(Default)]
+struct BuildStep {
+    /// e.g. `Step 3/7 : RUN cargo build` or `[3/7] RUN cargo build` with BuildKit
+    header: Option<String>,
+    output: VecDeque<String>,
+    /// BuildKit runs steps in parallel and prefixes their lines with `#<id>`,
+    /// so the header and output are kept per step until one of them fails
+    buildkit_steps: HashMap<String, (String, VecDeque<String>)>,
+}
+
+impl BuildStep {
+    fn record(&mut self, stream: &str) {
+        for line in stream.lines().filter(|line| !line.trim().is_empty()) {
+            if line.starts_with("Step ") {
+                self.header = Some(line.trim().to_owned());
+                self.output.clear();
+                continue;
+            }
+
+            if let Some((id, rest)) = buildkit_line(line) {
+                self.record_buildkit(id, rest);
+                continue;
+            }
+
+            push_output_line(&mut self.output, line);
+        }
+    }
+
+    /// Lines of `docker build --progress plain`, e.g. `#8 [3/7] RUN cargo build`,
+    /// `#8 1.234 output` or `#8 ERROR: process ... did not complete successfully`
+    fn record_buildkit(&mut self, id: &str, rest: &str) {
+        if rest.starts_with('[') {
+            self.buildkit_steps
+                .insert(id.to_owned(), (rest.trim().to_owned(), VecDeque::new()));
+            return;
+        }
+
+        let Some((header, output)) = self.buildkit_steps.get_mut(id) else {
+            return;
+        };
+
+        if rest.starts_with("ERROR") {
+            push_output_line(output, rest);
+            self.header = Some(header.clone());
+            self.output = output.clone();
+            return;
+        }
+
+        // output lines start with the seconds since the step started
+        let text = match rest.split_once(' ') {
+            Some((seconds, text)) if seconds.parse::<f64>().is_ok() => text,
+            _ => rest,
+        };
+
+        push_output_line(output, text);
+    }
+
+    /// Prints the failed step with its output and turns it into an error
+    fn fail(&mut self, message: String) -> BuildError {
+        presentation::print_build_failed_step(
+            self.header.as_deref(),
+            self.output.make_contiguous(),
+            &message,
+        );
+
+        BuildError {
+            step: self.header.take(),
+            message,
+        }
+    }
+}
+
+/// Splits a BuildKit progress line into the step id and the rest
+fn buildkit_line(line: &str) -> Option<(&str, &str)> {
+    let (id, rest) = line.strip_prefix('#')?.split_once(' ')?;
+
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((id, rest))
+}
+
+fn push_output_line(output: &mut VecDeque<String>, line: &str) {
+    if output.len() == FAILED_STEP_OUTPUT_LINES {
+        output.pop_front();
+    }
+
+    output.push_back(line.to_owned());
+}
+
+#[derive(Debug)]
+pub struct BuildError {
+    step: Option<String>,
+    message: String,
+}
+
+impl BuildError {
+    pub fn step(&self) -> Option<&str> {
+        self.step.as_deref()
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.step {
+            Some(step) => write!(f, "Image build failed at {step}: {}", self.message.trim()),
+            None => write!(f, "Image build failed: {}", self.message.trim()),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Build context directory and Dockerfile path relative to it
+pub struct BuildPaths {
+    context_dir: PathBuf,
+    dockerfile: PathBuf,
+}
+
+impl BuildPaths {
+    pub fn resolve(context: &context::Context) -> Result<Self> {
+        let context_dir = context.build_context_dir();
+        let dockerfile = context.app_config().dockerfile(context.override_context());
+
+        let context_dir = fs::canonicalize(&context_dir).with_context(|| {
+            format!(
+                "Build context directory {} does not exist. Set `context` in dploy.toml to an \
+                 existing directory. {}",
+                absolute_path(&context_dir).display(),
+                resolved_for(context)
+            )
+        })?;
+        let dockerfile = fs::canonicalize(dockerfile).with_context(|| {
+            format!(
+                "Dockerfile {} does not exist. Set `dockerfile` in dploy.toml to its path, \
+                 relative to the directory dploy is run from. {}",
+                absolute_path(Path::new(dockerfile)).display(),
+                resolved_for(context)
+            )
+        })?;
+
+        let Ok(relative_dockerfile) = dockerfile.strip_prefix(&context_dir) else {
+            bail!(
+                "Dockerfile {} must be located inside the build context directory {}",
+                dockerfile.display(),
+                context_dir.display()
+            );
+        };
+
+        Ok(Self {
+            dockerfile: relative_dockerfile.to_path_buf(),
+            context_dir,
+        })
+    }
+
+    pub fn dockerfile(&self) -> &Path {
+        &self.dockerfile
+    }
+}
+
+/// Ids and absolute paths of the `build_secrets`
+#[derive(Debug)]
+pub struct BuildSecrets {
+    secrets: Vec<(String, PathBuf)>,
+}
+
+impl BuildSecrets {
+    pub fn resolve(context: &context::Context) -> Result<Self> {
+        let configs = context
+            .app_config()
+            .build_secrets(context.override_context());
+
+        Self::from_configs(configs, &resolved_for(context))
+    }
+
+    /// `resolved_for` explains the paths when a file is missing
+    fn from_configs(configs: &[config::BuildSecretConfig], resolved_for: &str) -> Result<Self> {
+        let mut ids = HashSet::new();
+        let mut secrets = vec![];
+
+        for config in configs {
+            let id = config.id.as_str();
+
+            if id.is_empty() || id.contains([',', '=']) {
+                bail!("Invalid build secret id `{id}`. It can't be empty or contain `,` or `=`");
+            }
+
+            if !ids.insert(id) {
+                bail!("Build secret `{id}` is defined more than once");
+            }
+
+            let path = fs::canonicalize(&config.src).with_context(|| {
+                format!(
+                    "File {} of build secret `{id}` does not exist. {resolved_for}",
+                    absolute_path(Path::new(&config.src)).display(),
+                )
+            })?;
+
+            if !path.is_file() {
+                bail!(
+                    "Build secret `{id}` must point to a file, {} is not one",
+                    path.display()
+                );
+            }
+
+            secrets.push((id.to_owned(), path));
+        }
+
+        Ok(Self { secrets })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+}
+
+fn absolute_path(path: &Path) -> PathBuf {
+    std::env::current_dir()
+        .map(|current_dir| current_dir.join(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// `[[override]]` sections often change the paths, so the error says which ones applied
+fn resolved_for(context: &context::Context) -> String {
+    let override_context = context.override_context();
+
+    format!(
+        "The path was resolved for namespace `{}` and command `{}`, including matching \
+         `[[override]]` sections",
+        override_context.namespace, override_context.command
+    )
+}
+
+/// SHA-256 of everything the app image is built from: the files of the build
+/// context, which include the Dockerfile, and the build secrets. Files are read
+/// in a stable order, so the digest only changes when one of them does
+pub fn build_inputs_digest(context: &context::Context) -> Result<String> {
+    let paths = BuildPaths::resolve(context)?;
+    let secrets = BuildSecrets::resolve(context)?;
+
+    let mut files = vec![];
+
+    for entry in create_walker(&paths.context_dir).filter_map(Result::ok) {
+        if entry.metadata()?.is_file() {
+            files.push(entry.path().strip_prefix(&paths.context_dir)?.to_path_buf());
+        }
+    }
+
+    files.extend(
+        get_always_include_files(&paths)
+            .into_iter()
+            .filter(|file_name| paths.context_dir.join(file_name).is_file()),
+    );
+    files.sort();
+    files.dedup();
+
+    let mut digest = ring::digest::Context::new(&ring::digest::SHA256);
+
+    // lengths go first, so the boundaries between names and contents are unambiguous
+    let mut update = |bytes: &[u8]| {
+        digest.update(&(bytes.len() as u64).to_le_bytes());
+        digest.update(bytes);
+    };
+
+    for file_name in files {
+        update(file_name.to_string_lossy().as_bytes());
+        update(&fs::read(paths.context_dir.join(&file_name))?);
+    }
+
+    for (id, path) in &secrets.secrets {
+        update(id.as_bytes());
+        update(&fs::read(path)?);
+    }
+
+    Ok(to_hex(digest.finish().as_ref()))
+}
+
+/// docker detects by itself whether the context is compressed
+fn create_context_tar(
+    paths: &BuildPaths,
+    compression: config::BuildCompression,
+) -> Result<Vec<u8>> {
     let mut bytes = Vec::<u8>::new();
     let mut archive = tar::Builder::new(&mut bytes);
 
-    let walker = create_walker();
+    let walker = create_walker(&paths.context_dir);
 
     for entry in walker.filter_map(Result::ok) {
         let metadata = entry.metadata()?;
@@ -64,15 +635,20 @@ fn create_cwd_tar(context: &context::Context) -> Result<Vec<u8>> {
             continue;
         }
 
-        archive.append_path(entry.path())?;
+        let name = entry.path().strip_prefix(&paths.context_dir)?;
+        archive.append_path_with_name(entry.path(), name)?;
     }
 
-    for file_name in get_always_include_files(context) {
-        let _ = archive.append_path(file_name);
+    for file_name in get_always_include_files(paths) {
+        let _ = archive.append_path_with_name(paths.context_dir.join(&file_name), &file_name);
     }
 
     archive.into_inner()?;
 
+    if compression == config::BuildCompression::None {
+        return Ok(bytes);
+    }
+
     let mut compressed_file = tempfile::tempfile()?;
     let mut encoder =
         flate2::write::GzEncoder::new(&mut compressed_file, flate2::Compression::default());
@@ -92,10 +668,10 @@ fn create_cwd_tar(context: &context::Context) -> Result<Vec<u8>> {
     Ok(compressed_bytes)
 }
 
-fn create_walker() -> ignore::Walk {
-    let mut builder = ignore::WalkBuilder::new("./");
+fn create_walker(context_dir: &Path) -> ignore::Walk {
+    let mut builder = ignore::WalkBuilder::new(context_dir);
 
-    builder.add_ignore(IGNORE_FILE);
+    builder.add_ignore(context_dir.join(IGNORE_FILE));
     builder
         .hidden(false)
         .ignore(false)
@@ -104,8 +680,144 @@ fn create_walker() -> ignore::Walk {
         .build()
 }
 
-fn get_always_include_files(context: &context::Context) -> Vec<String> {
-    let dockerfile = context.app_config().dockerfile(context.override_context());
+fn get_always_include_files(paths: &BuildPaths) -> Vec<PathBuf> {
+    vec![paths.dockerfile.clone(), PathBuf::from(IGNORE_FILE)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_tar_compression() {
+        let context_dir = tempfile::tempdir().unwrap();
+        fs::write(context_dir.path().join("Dockerfile"), "FROM scratch\n").unwrap();
+        fs::write(context_dir.path().join("app.txt"), "app").unwrap();
 
-    vec![dockerfile.to_owned(), IGNORE_FILE.to_owned()]
+        let paths = BuildPaths {
+            context_dir: context_dir.path().to_path_buf(),
+            dockerfile: PathBuf::from("Dockerfile"),
+        };
+
+        let plain = create_context_tar(&paths, config::BuildCompression::None).unwrap();
+        let mut names = tar::Archive::new(plain.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .path()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+
+        assert_eq!(names, ["Dockerfile", "app.txt"]);
+
+        let gzip = create_context_tar(&paths, config::BuildCompression::Gzip).unwrap();
+
+        assert_ne!(plain[..2], [0x1f, 0x8b]);
+        assert_eq!(gzip[..2], [0x1f, 0x8b]);
+    }
+
+    #[test]
+    fn test_build_step_buildkit_failure() {
+        let mut step = BuildStep::default();
+        step.record(concat!(
+            "#7 [2/4] COPY . .\n",
+            "#8 [3/4] RUN cargo build\n",
+            "#7 DONE 0.1s\n",
+            "#9 [builder 1/2] RUN npm ci\n",
+            "#8 0.512 error[E0425]: cannot find value `x`\n",
+            "#9 1.020 added 10 packages\n",
+            "#8 ERROR: process \"/bin/sh -c cargo build\" did not complete successfully\n",
+            "------\n",
+        ));
+
+        let error = step.fail("exit code: 101".to_owned());
+
+        assert_eq!(error.step(), Some("[3/4] RUN cargo build"));
+        assert!(step
+            .output
+            .iter()
+            .any(|line| line == "error[E0425]: cannot find value `x`"));
+        assert!(!step.output.iter().any(|line| line.contains("npm")));
+    }
+
+    #[test]
+    fn test_build_step_classic_failure() {
+        let mut step = BuildStep::default();
+        step.record("Step 1/2 : FROM rust\n ---> 1234\nStep 2/2 : RUN cargo build\n");
+
+        let error = step.fail("exit code: 101".to_owned());
+
+        assert_eq!(error.step(), Some("Step 2/2 : RUN cargo build"));
+    }
+
+    #[test]
+    fn test_build_log_step_headers() {
+        let classic = "Step 1/2 : FROM rust\n ---> 1234\nStep 2/2 : RUN cargo build\n";
+        let buildkit = concat!(
+            "#7 [2/4] COPY . .\n",
+            "#7 DONE 0.1s\n",
+            "#8 [3/4] RUN cargo build\n",
+            "#8 0.512    Compiling dploy\n",
+            "#8 ERROR: process \"/bin/sh -c cargo build\" did not complete successfully\n",
+            "------\n",
+        );
+
+        let headers = |stream: &'static str| {
+            stream
+                .lines()
+                .filter(|line| is_step_header(line))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            headers(classic),
+            ["Step 1/2 : FROM rust", "Step 2/2 : RUN cargo build"]
+        );
+        assert_eq!(
+            headers(buildkit),
+            ["#7 [2/4] COPY . .", "#8 [3/4] RUN cargo build"]
+        );
+    }
+
+    #[test]
+    fn test_build_secrets_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("token");
+        fs::write(&file, "secret").unwrap();
+
+        let secret = |id: &str, src: &Path| config::BuildSecretConfig {
+            id: id.to_owned(),
+            src: src.to_string_lossy().into_owned(),
+        };
+        let resolve = |configs: &[config::BuildSecretConfig]| {
+            BuildSecrets::from_configs(configs, "").map_err(|error| format!("{error:#}"))
+        };
+
+        let secrets = resolve(&[secret("token", &file)]).unwrap();
+        assert_eq!(
+            secrets.secrets,
+            [("token".to_owned(), file.canonicalize().unwrap())]
+        );
+
+        for id in ["", "a,b", "a=b"] {
+            let error = resolve(&[secret(id, &file)]).unwrap_err();
+            assert!(error.contains("Invalid build secret id"), "{error}");
+        }
+
+        let error = resolve(&[secret("token", &file), secret("token", &file)]).unwrap_err();
+        assert!(error.contains("defined more than once"), "{error}");
+
+        let error = resolve(&[secret("token", &dir.path().join("missing"))]).unwrap_err();
+        assert!(error.contains("does not exist"), "{error}");
+
+        let error = resolve(&[secret("token", dir.path())]).unwrap_err();
+        assert!(error.contains("must point to a file"), "{error}");
+    }
 }
@@ -1,20 +1,75 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
 use clap::{Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
 
 use crate::{config, constants, services::ServiceKind};
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 #[clap(author, version, about, long_about = None)]
 #[clap(propagate_version = true)]
 pub struct Args {
-    /// Relative path to the config file
+    /// Relative path to the config file, `-` to read it from stdin or an http(s) URL
     #[clap(short, long, default_value = "dploy.toml")]
     pub config: String,
 
-    /// Namespace (or postfix) to use
+    /// Namespace (or postfix) to use. `@branch` derives it from the current git branch.
+    /// `stop` and `status` also accept a glob, e.g. `pr-*`, to run in every matching
+    /// namespace of the app
     #[clap(short, long, default_value = constants::DEFAULT_NAMESPACE)]
     pub namespace: String,
 
+    /// Run `stop` or `status` in every namespace of the app which has containers
+    #[clap(long, default_value_t = false, conflicts_with = "namespace")]
+    pub all_namespaces: bool,
+
+    /// Override an environment variable of the application container (KEY=VALUE).
+    /// Takes precedence over the env file and dependencies variables,
+    /// but is not written to the generated env file
+    #[clap(short, long = "env", value_parser = parse_env_override, global = true)]
+    pub env_overrides: Vec<(String, String)>,
+
+    /// Load an additional env file after the configured ones. Can be repeated,
+    /// later files take precedence
+    #[clap(long = "env-file", value_name = "PATH", global = true)]
+    pub env_files: Vec<String>,
+
+    /// Only print errors, warnings and the requested output, like the connection info
+    /// and logs. Progress such as pulling and starting containers is hidden
+    #[clap(short, long, default_value_t = false, global = true)]
+    pub quiet: bool,
+
+    /// Print progress as JSON lines instead of human readable text
+    #[clap(long, default_value_t = false, global = true)]
+    pub json_events: bool,
+
+    /// Wait up to this long (e.g. `30s`, `5m`) when another deploy holds the namespace
+    /// lock, instead of failing right away
+    #[clap(long, value_parser = parse_duration, global = true)]
+    pub wait_for_lock: Option<Duration>,
+
+    /// Keep retrying to connect to docker for up to this long (e.g. `60s`),
+    /// for example when dploy starts on boot before the docker daemon
+    #[clap(long, value_parser = parse_duration, global = true)]
+    pub wait_for_docker: Option<Duration>,
+
+    /// Print the container configs sent to docker and its raw error responses to stderr.
+    /// Values of secret-looking variables are masked
+    #[clap(long, default_value_t = false, global = true)]
+    pub verbose_docker: bool,
+
+    /// Write the full image build output to this file.
+    /// The terminal then only shows the build steps, or nothing with --quiet
+    #[clap(long, value_name = "PATH", global = true)]
+    pub build_log: Option<PathBuf>,
+
+    /// Append to the build log instead of truncating it
+    #[clap(long, default_value_t = false, global = true, requires = "build_log")]
+    pub append_build_log: bool,
+
     #[clap(subcommand)]
     pub command: Command,
 }
@@ -24,23 +79,134 @@ impl Args {
         &self.config
     }
 
+    /// Copy of the arguments targeting only one of the deploy hosts
+    pub fn with_host(&self, host: &str) -> Self {
+        let mut args = self.clone();
+
+        if let Command::Deploy { hosts, .. } = &mut args.command {
+            *hosts = vec![host.to_owned()];
+        }
+
+        args
+    }
+
+    pub fn config_source(&self) -> config::ConfigSource {
+        config::ConfigSource::parse(&self.config)
+    }
+
     pub fn namespace(&self) -> &str {
         &self.namespace
     }
 
+    /// Set by `--all-namespaces` or a namespace containing `*` or `?`
+    pub fn namespace_pattern(&self) -> Option<&str> {
+        if self.all_namespaces {
+            Some("*")
+        } else if self.namespace.contains(['*', '?']) {
+            Some(&self.namespace)
+        } else {
+            None
+        }
+    }
+
+    /// Copy of the arguments targeting a single namespace
+    pub fn with_namespace(&self, namespace: &str) -> Self {
+        let mut args = self.clone();
+        args.namespace = namespace.to_owned();
+        args.all_namespaces = false;
+
+        args
+    }
+
     pub fn command(&self) -> &Command {
         &self.command
     }
+
+    pub fn env_overrides(&self) -> &[(String, String)] {
+        &self.env_overrides
+    }
+
+    pub fn env_files(&self) -> &[String] {
+        &self.env_files
+    }
+
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub fn json_events(&self) -> bool {
+        self.json_events
+    }
+
+    pub fn wait_for_lock(&self) -> Option<Duration> {
+        self.wait_for_lock
+    }
+
+    pub fn wait_for_docker(&self) -> Option<Duration> {
+        self.wait_for_docker
+    }
+
+    pub fn verbose_docker(&self) -> bool {
+        self.verbose_docker
+    }
+
+    pub fn build_log(&self) -> Option<&Path> {
+        self.build_log.as_deref()
+    }
+
+    pub fn append_build_log(&self) -> bool {
+        self.append_build_log
+    }
+}
+
+fn parse_env_override(value: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = value.split_once('=') else {
+        return Err(format!("expected KEY=VALUE, got `{value}`"));
+    };
+
+    if key.is_empty() {
+        return Err("environment variable name cannot be empty".to_owned());
+    }
+
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+fn parse_scale(value: &str) -> Result<(String, usize), String> {
+    let Some((service, count)) = value.split_once('=') else {
+        return Err(format!("expected SERVICE=COUNT, got `{value}`"));
+    };
+
+    let count = count
+        .parse::<usize>()
+        .map_err(|_| format!("`{count}` is not a number"))?;
+
+    if !(1..=constants::MAX_APP_REPLICAS).contains(&count) {
+        return Err(format!(
+            "the count must be between 1 and {}",
+            constants::MAX_APP_REPLICAS
+        ));
+    }
+
+    Ok((service.to_owned(), count))
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let duration = match value.parse::<u64>() {
+        Ok(seconds) => config::DurationConfig::Seconds(seconds),
+        Err(_) => config::DurationConfig::Text(value.to_owned()),
+    };
+
+    duration.to_duration().map_err(|error| error.to_string())
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Subcommand)]
 pub enum Command {
-    /// Deploy the application with all its dependencies to a remote server
-    #[clap(visible_alias = "D")]
+    /// Deploy the application with all its dependencies to remote servers
+    #[clap(visible_alias = "D", subcommand_precedence_over_arg = true)]
     Deploy {
-        /// Host of the remote server
-        #[clap(index = 1)]
-        host: String,
+        /// Hosts of the remote servers. The same port, username and key are used for all of them
+        #[clap(index = 1, required = true, num_args = 1..)]
+        hosts: Vec<String>,
 
         /// Port of the remote server
         #[clap(short, long, default_value_t = 22)]
@@ -50,10 +216,19 @@ pub enum Command {
         #[clap(short, long, default_value = "root")]
         username: String,
 
-        /// Path to the private key file
+        /// Path to the private key file. The passphrase of an encrypted key is read
+        /// from DPLOY_SSH_PASSPHRASE or prompted for, and the key is added to ssh-agent
         #[clap(short, long)]
         keyfile: Option<String>,
 
+        /// Fail unless ssh-agent is running, instead of falling back to the default keys
+        #[clap(long, default_value_t = false, conflicts_with = "no_agent")]
+        ssh_agent: bool,
+
+        /// Don't use the keys of ssh-agent, only the key file
+        #[clap(long, default_value_t = false)]
+        no_agent: bool,
+
         /// Subcommand
         /// Run without any subcommand to start the application
         #[clap(subcommand)]
@@ -62,6 +237,62 @@ pub enum Command {
         /// Watch for file changes and restart the application
         #[clap(short, long, default_value_t = false)]
         watch: bool,
+
+        /// In watch mode, recreate the application with a growing delay when it crashes,
+        /// instead of letting docker restart it
+        #[clap(long, default_value_t = false, requires = "watch")]
+        restart_on_crash: bool,
+
+        /// Deploy even if nothing has changed since the last deploy
+        #[clap(short, long, default_value_t = false)]
+        force: bool,
+
+        /// Build the application image locally and transfer it to the remote
+        /// docker daemon over SSH instead of building it on the server
+        #[clap(long, default_value_t = false)]
+        remote_docker: bool,
+
+        /// Deploy to all hosts at the same time instead of one by one
+        #[clap(long, default_value_t = false)]
+        parallel: bool,
+
+        /// Stop deploying to the remaining hosts after the first failure
+        #[clap(long, default_value_t = false)]
+        fail_fast: bool,
+
+        /// Deploy only this service, e.g. `app` or `postgres`. Can be repeated
+        #[clap(long = "only", value_name = "SERVICE")]
+        only: Vec<String>,
+
+        /// Deploy only the dependencies, without the application and the proxy
+        #[clap(long, default_value_t = false, conflicts_with_all = ["only", "watch"])]
+        deps_only: bool,
+
+        /// Only redeploy the application, expecting the dependencies and the proxy
+        /// to be running already
+        #[clap(long, default_value_t = false, conflicts_with_all = ["only", "deps_only", "recreate_volumes"])]
+        no_deps: bool,
+
+        /// Abort the deploy if it hasn't finished within this time, e.g. `300` or `5m`
+        #[clap(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with = "watch")]
+        deploy_timeout: Option<Duration>,
+
+        /// Run several containers of a service behind the proxy, e.g. `app=3`.
+        /// Only the app can be scaled
+        #[clap(long, value_name = "SERVICE=COUNT", value_parser = parse_scale, conflicts_with_all = ["watch", "deps_only"])]
+        scale: Vec<(String, usize)>,
+
+        /// Use the image digests recorded in dploy.lock instead of resolving the tags,
+        /// and don't update the lockfile
+        #[clap(long, default_value_t = false)]
+        locked: bool,
+
+        /// Like --locked, and also fail if the config changed since dploy.lock was written
+        #[clap(long, default_value_t = false)]
+        frozen: bool,
+
+        #[clap(flatten)]
+        recreate_volumes: RecreateVolumesArgs,
     },
 
     /// Run the application with all its dependencies locally
@@ -75,6 +306,41 @@ pub enum Command {
         /// Watch for file changes and restart the application
         #[clap(short, long, default_value_t = false)]
         watch: bool,
+
+        /// In watch mode, recreate the application with a growing delay when it crashes,
+        /// instead of letting docker restart it
+        #[clap(long, default_value_t = false, requires = "watch")]
+        restart_on_crash: bool,
+
+        /// Do not wait for services to accept connections before printing connection info
+        #[clap(long, default_value_t = false)]
+        no_wait: bool,
+
+        /// Run the application as a one-shot job: stream its logs, wait until it exits
+        /// and exit with its exit code
+        #[clap(long, default_value_t = false, conflicts_with_all = ["watch", "no_wait"])]
+        wait: bool,
+
+        /// Remove the application container after it exits
+        #[clap(long, default_value_t = false, requires = "wait")]
+        rm: bool,
+
+        /// Stay in the foreground following the logs of the application
+        /// and stop all services on Ctrl-C
+        #[clap(short, long, default_value_t = false, conflicts_with_all = ["watch", "wait"])]
+        attach: bool,
+
+        /// Show how the env file would change, without writing it or starting anything
+        #[clap(long, default_value_t = false, conflicts_with = "watch")]
+        env_diff: bool,
+
+        /// Only restart the application, expecting the dependencies to be running
+        /// already. The env file is left as it is
+        #[clap(long, default_value_t = false, conflicts_with = "recreate_volumes")]
+        no_deps: bool,
+
+        #[clap(flatten)]
+        recreate_volumes: RecreateVolumesArgs,
     },
 
     /// Run only the dependencies of the application locally
@@ -84,9 +350,88 @@ pub enum Command {
         /// Run without any subcommand to start the application
         #[clap(subcommand)]
         command: Option<DevCommand>,
+
+        /// Redeploy the dependencies when the config changes and
+        /// run `dev.on_change` when the watched paths change
+        #[clap(short, long, default_value_t = false)]
+        watch: bool,
+
+        /// Do not wait for services to accept connections before printing connection info
+        #[clap(long, default_value_t = false)]
+        no_wait: bool,
+
+        /// Exit as soon as the containers are started, without printing connection info
+        #[clap(short, long, default_value_t = false)]
+        detach: bool,
+
+        /// Show how the env file would change, without writing it or starting anything
+        #[clap(long, default_value_t = false, conflicts_with = "watch")]
+        env_diff: bool,
+
+        #[clap(flatten)]
+        recreate_volumes: RecreateVolumesArgs,
     },
 }
 
+#[derive(Debug, Clone, clap::Args)]
+pub struct RecreateVolumesArgs {
+    /// Delete the volumes of the dependencies before starting them, so they start empty
+    #[clap(long, default_value_t = false, conflicts_with = "watch")]
+    recreate_volumes: bool,
+
+    /// Recreate the volumes of this service only, e.g. `postgres`. Can be repeated
+    #[clap(
+        long = "service",
+        value_name = "SERVICE",
+        requires = "recreate_volumes"
+    )]
+    services: Vec<String>,
+
+    /// Do not ask for confirmation before deleting volumes in `run` and `deploy` modes
+    #[clap(short, long, default_value_t = false, requires = "recreate_volumes")]
+    yes: bool,
+}
+
+impl RecreateVolumesArgs {
+    /// Services selected with `--service`. Empty if all volumes are recreated
+    pub fn services(&self) -> &[String] {
+        &self.services
+    }
+
+    pub fn yes(&self) -> bool {
+        self.yes
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct InfoArgs {
+    /// Check that the containers are running and accept connections
+    #[clap(long, default_value_t = false)]
+    refresh: bool,
+
+    /// Output format
+    #[clap(short, long, value_enum, default_value_t = InfoOutput::Text)]
+    output: InfoOutput,
+
+    /// Show passwords in URLs instead of masking them
+    #[clap(long, default_value_t = false)]
+    show_secrets: bool,
+}
+
+impl InfoArgs {
+    pub fn refresh(&self) -> bool {
+        self.refresh
+    }
+
+    pub fn output(&self) -> InfoOutput {
+        self.output
+    }
+
+    pub fn show_secrets(&self) -> bool {
+        self.show_secrets
+    }
+}
+
 impl From<&Command> for config::OverrideRuleCommand {
     fn from(value: &Command) -> Self {
         match value {
@@ -97,12 +442,15 @@ impl From<&Command> for config::OverrideRuleCommand {
     }
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Subcommand)]
 pub enum DevCommand {
     /// Stop the application
     #[clap(visible_alias = "s")]
     Stop,
 
+    /// Pull the images of the dependencies without starting them
+    Pull,
+
     /// Get logs of the specified service
     #[clap(visible_alias = "l")]
     Logs {
@@ -110,9 +458,30 @@ pub enum DevCommand {
         #[clap(short, long)]
         tail: Option<u64>,
 
-        /// Service to get logs from
+        /// Show timestamps of the logs
+        #[clap(long, default_value_t = false)]
+        timestamps: bool,
+
+        /// Also write the logs to this file.
+        /// An existing file is rotated to `<file>.1` unless `--append` is set
         #[clap(short, long)]
-        service: DevLogsService,
+        output: Option<PathBuf>,
+
+        /// Append to the output file instead of rotating it
+        #[clap(long, default_value_t = false, requires = "output")]
+        append: bool,
+
+        /// Only show logs written since the last deploy of the service
+        #[clap(long, default_value_t = false)]
+        since_deploy: bool,
+
+        /// Print the available logs and exit instead of following them
+        #[clap(short = 'F', long, default_value_t = false)]
+        no_follow: bool,
+
+        /// Service to get logs from. Omit to pick one of the running services
+        #[clap(short, long)]
+        service: Option<DevLogsService>,
     },
 
     /// Execute a command in the application container
@@ -122,10 +491,72 @@ pub enum DevCommand {
         #[clap(index = 1)]
         command: String,
 
-        /// Service to get logs from
+        /// Service to execute the command in. Omit to pick one of the running services
         #[clap(short, long)]
-        service: DevLogsService,
+        service: Option<DevLogsService>,
+
+        /// Start the command and return right away without attaching to it
+        #[clap(long, default_value_t = false, conflicts_with_all = ["no_stdout", "no_stderr"])]
+        detach: bool,
+
+        /// Don't attach to the standard output of the command
+        #[clap(long, default_value_t = false)]
+        no_stdout: bool,
+
+        /// Don't attach to the standard error of the command
+        #[clap(long, default_value_t = false)]
+        no_stderr: bool,
+    },
+
+    /// Show the state of the services containers
+    #[clap(visible_alias = "ps")]
+    Status {
+        /// Output format
+        #[clap(short, long, value_enum, default_value_t = StatusFormat::Table)]
+        format: StatusFormat,
+
+        /// Columns to show in table formats, e.g. `name,state,ports`
+        #[clap(long, value_enum, value_delimiter = ',')]
+        columns: Vec<StatusColumn>,
+    },
+
+    /// Show CPU, memory and network usage of the services containers
+    Stats {
+        /// Output format. `json` prints one line per container and sample
+        #[clap(short, long, value_enum, default_value_t = StatsOutput::Table)]
+        output: StatsOutput,
+
+        /// Print a single sample instead of refreshing until Ctrl-C
+        #[clap(long, default_value_t = false)]
+        no_stream: bool,
+    },
+
+    /// Wait until the services are ready: healthy if they define a healthcheck,
+    /// otherwise running and accepting connections
+    Wait {
+        /// Service to wait for, e.g. `postgres`, or `all` for every service
+        #[clap(long, default_value = "all")]
+        service: String,
+
+        /// Fail if the services aren't ready after this long, e.g. `60` or `2m`
+        #[clap(long, value_parser = parse_duration, default_value = "60")]
+        timeout: Duration,
+    },
+
+    /// Inspect the configuration
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
     },
+
+    /// Export the services to other tools
+    Export {
+        #[clap(subcommand)]
+        command: ExportCommand,
+    },
+
+    /// Show the connection info saved by the last start, without connecting to docker
+    Info(InfoArgs),
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, ValueEnum)]
@@ -142,12 +573,15 @@ impl From<DevLogsService> for ServiceKind {
     }
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Subcommand)]
 pub enum RunCommand {
     /// Stop the application
     #[clap(visible_alias = "s")]
     Stop,
 
+    /// Pull the images of the dependencies without starting them
+    Pull,
+
     /// Get logs of application container
     #[clap(visible_alias = "l")]
     Logs {
@@ -155,6 +589,27 @@ pub enum RunCommand {
         #[clap(short, long)]
         tail: Option<u64>,
 
+        /// Show timestamps of the logs
+        #[clap(long, default_value_t = false)]
+        timestamps: bool,
+
+        /// Also write the logs to this file.
+        /// An existing file is rotated to `<file>.1` unless `--append` is set
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Append to the output file instead of rotating it
+        #[clap(long, default_value_t = false, requires = "output")]
+        append: bool,
+
+        /// Only show logs written since the last deploy of the service
+        #[clap(long, default_value_t = false)]
+        since_deploy: bool,
+
+        /// Print the available logs and exit instead of following them
+        #[clap(short = 'F', long, default_value_t = false)]
+        no_follow: bool,
+
         /// Service to get logs from
         #[clap(short, long, default_value = "app")]
         service: RunLogsService,
@@ -170,7 +625,69 @@ pub enum RunCommand {
         /// Service to get logs from
         #[clap(short, long, default_value = "app")]
         service: RunLogsService,
+
+        /// Start the command and return right away without attaching to it
+        #[clap(long, default_value_t = false, conflicts_with_all = ["no_stdout", "no_stderr"])]
+        detach: bool,
+
+        /// Don't attach to the standard output of the command
+        #[clap(long, default_value_t = false)]
+        no_stdout: bool,
+
+        /// Don't attach to the standard error of the command
+        #[clap(long, default_value_t = false)]
+        no_stderr: bool,
     },
+
+    /// Show the state of the services containers
+    #[clap(visible_alias = "ps")]
+    Status {
+        /// Output format
+        #[clap(short, long, value_enum, default_value_t = StatusFormat::Table)]
+        format: StatusFormat,
+
+        /// Columns to show in table formats, e.g. `name,state,ports`
+        #[clap(long, value_enum, value_delimiter = ',')]
+        columns: Vec<StatusColumn>,
+    },
+
+    /// Show CPU, memory and network usage of the services containers
+    Stats {
+        /// Output format. `json` prints one line per container and sample
+        #[clap(short, long, value_enum, default_value_t = StatsOutput::Table)]
+        output: StatsOutput,
+
+        /// Print a single sample instead of refreshing until Ctrl-C
+        #[clap(long, default_value_t = false)]
+        no_stream: bool,
+    },
+
+    /// Wait until the services are ready: healthy if they define a healthcheck,
+    /// otherwise running and accepting connections
+    Wait {
+        /// Service to wait for, e.g. `postgres`, or `all` for every service
+        #[clap(long, default_value = "all")]
+        service: String,
+
+        /// Fail if the services aren't ready after this long, e.g. `60` or `2m`
+        #[clap(long, value_parser = parse_duration, default_value = "60")]
+        timeout: Duration,
+    },
+
+    /// Inspect the configuration
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Export the services to other tools
+    Export {
+        #[clap(subcommand)]
+        command: ExportCommand,
+    },
+
+    /// Show the connection info saved by the last start, without connecting to docker
+    Info(InfoArgs),
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, ValueEnum)]
@@ -189,12 +706,22 @@ impl From<RunLogsService> for ServiceKind {
     }
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Subcommand)]
 pub enum DeployCommand {
+    /// Print the routes and the generated config of the proxy
+    ProxyConfig {
+        /// Print the routes as JSON
+        #[clap(long, default_value_t = false)]
+        json: bool,
+    },
+
     /// Stop the application
     #[clap(visible_alias = "s")]
     Stop,
 
+    /// Pull the images of the dependencies without starting them
+    Pull,
+
     /// Get logs of application container
     #[clap(visible_alias = "l")]
     Logs {
@@ -202,9 +729,35 @@ pub enum DeployCommand {
         #[clap(short, long)]
         tail: Option<u64>,
 
+        /// Show timestamps of the logs
+        #[clap(long, default_value_t = false)]
+        timestamps: bool,
+
+        /// Also write the logs to this file.
+        /// An existing file is rotated to `<file>.1` unless `--append` is set
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Append to the output file instead of rotating it
+        #[clap(long, default_value_t = false, requires = "output")]
+        append: bool,
+
+        /// Only show logs written since the last deploy of the service
+        #[clap(long, default_value_t = false)]
+        since_deploy: bool,
+
+        /// Print the available logs and exit instead of following them
+        #[clap(short = 'F', long, default_value_t = false)]
+        no_follow: bool,
+
         /// Service to get logs from
         #[clap(short, long, default_value = "app")]
         service: DeployLogsService,
+
+        /// Only follow this replica of the app scaled with `--scale`.
+        /// Omit to follow all replicas
+        #[clap(long, value_name = "N")]
+        replica: Option<usize>,
     },
 
     /// Execute a command in the application container
@@ -217,6 +770,65 @@ pub enum DeployCommand {
         /// Service to get logs from
         #[clap(short, long, default_value = "app")]
         service: DeployLogsService,
+
+        /// Start the command and return right away without attaching to it
+        #[clap(long, default_value_t = false, conflicts_with_all = ["no_stdout", "no_stderr"])]
+        detach: bool,
+
+        /// Don't attach to the standard output of the command
+        #[clap(long, default_value_t = false)]
+        no_stdout: bool,
+
+        /// Don't attach to the standard error of the command
+        #[clap(long, default_value_t = false)]
+        no_stderr: bool,
+    },
+
+    /// Show the state of the services containers
+    #[clap(visible_alias = "ps")]
+    Status {
+        /// Output format
+        #[clap(short, long, value_enum, default_value_t = StatusFormat::Table)]
+        format: StatusFormat,
+
+        /// Columns to show in table formats, e.g. `name,state,ports`
+        #[clap(long, value_enum, value_delimiter = ',')]
+        columns: Vec<StatusColumn>,
+    },
+
+    /// Show CPU, memory and network usage of the services containers
+    Stats {
+        /// Output format. `json` prints one line per container and sample
+        #[clap(short, long, value_enum, default_value_t = StatsOutput::Table)]
+        output: StatsOutput,
+
+        /// Print a single sample instead of refreshing until Ctrl-C
+        #[clap(long, default_value_t = false)]
+        no_stream: bool,
+    },
+
+    /// Wait until the services are ready: healthy if they define a healthcheck,
+    /// otherwise running and accepting connections
+    Wait {
+        /// Service to wait for, e.g. `postgres`, or `all` for every service
+        #[clap(long, default_value = "all")]
+        service: String,
+
+        /// Fail if the services aren't ready after this long, e.g. `60` or `2m`
+        #[clap(long, value_parser = parse_duration, default_value = "60")]
+        timeout: Duration,
+    },
+
+    /// Inspect the configuration
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Export the services to other tools
+    Export {
+        #[clap(subcommand)]
+        command: ExportCommand,
     },
 }
 
@@ -238,7 +850,133 @@ impl From<DeployLogsService> for ServiceKind {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StatusFormat {
+    /// Compact table
+    Table,
+    /// Table with image and creation time
+    Wide,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InfoOutput {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StatsOutput {
+    Table,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StatusColumn {
+    Name,
+    Service,
+    State,
+    Ports,
+    Image,
+    Created,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the configuration after applying the overrides
+    /// matching the current command and namespace
+    Show,
+
+    /// Print how the resolved configuration differs between two namespaces or commands
+    Diff {
+        /// Namespace to compare from, optionally with the command, e.g. `staging` or `staging:run`.
+        /// The command defaults to the current one
+        #[clap(long)]
+        from: String,
+
+        /// Namespace to compare to, in the same format as --from
+        #[clap(long)]
+        to: String,
+
+        /// Output format
+        #[clap(short, long, value_enum, default_value_t = ConfigDiffOutput::Text)]
+        output: ConfigDiffOutput,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConfigDiffOutput {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ExportCommand {
+    /// Print the services as a docker-compose.yml
+    Compose {
+        /// Write the compose file to this path instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
 impl Command {
+    pub fn config_command(&self) -> Option<&ConfigCommand> {
+        use Command::*;
+
+        match self {
+            Deploy {
+                command: Some(DeployCommand::Config { command }),
+                ..
+            }
+            | Run {
+                command: Some(RunCommand::Config { command }),
+                ..
+            }
+            | Dev {
+                command: Some(DevCommand::Config { command }),
+                ..
+            } => Some(command),
+            _ => None,
+        }
+    }
+
+    pub fn export_command(&self) -> Option<&ExportCommand> {
+        use Command::*;
+
+        match self {
+            Deploy {
+                command: Some(DeployCommand::Export { command }),
+                ..
+            }
+            | Run {
+                command: Some(RunCommand::Export { command }),
+                ..
+            }
+            | Dev {
+                command: Some(DevCommand::Export { command }),
+                ..
+            } => Some(command),
+            _ => None,
+        }
+    }
+
+    pub fn info_args(&self) -> Option<&InfoArgs> {
+        use Command::*;
+
+        match self {
+            Run {
+                command: Some(RunCommand::Info(args)),
+                ..
+            }
+            | Dev {
+                command: Some(DevCommand::Info(args)),
+                ..
+            } => Some(args),
+            _ => None,
+        }
+    }
+
     pub fn stop(&self) -> bool {
         use Command::*;
 
@@ -249,11 +987,218 @@ impl Command {
         }
     }
 
+    /// Format and columns of the `status` subcommand
+    pub fn status(&self) -> Option<(StatusFormat, &[StatusColumn])> {
+        use Command::*;
+
+        match self {
+            Deploy {
+                command: Some(DeployCommand::Status { format, columns }),
+                ..
+            }
+            | Run {
+                command: Some(RunCommand::Status { format, columns }),
+                ..
+            }
+            | Dev {
+                command: Some(DevCommand::Status { format, columns }),
+                ..
+            } => Some((*format, columns)),
+            _ => None,
+        }
+    }
+
+    /// Whether stdout carries a document like YAML or JSON, which no progress
+    /// may be mixed into, see [`crate::presentation::enable_document_output`]
+    pub fn prints_document(&self) -> bool {
+        use Command::*;
+
+        if self.export_command().is_some() {
+            return true;
+        }
+
+        if let Some(command) = self.config_command() {
+            return match command {
+                ConfigCommand::Show => true,
+                ConfigCommand::Diff { output, .. } => *output == ConfigDiffOutput::Json,
+            };
+        }
+
+        if let Some(args) = self.info_args() {
+            return args.output() == InfoOutput::Json;
+        }
+
+        if let Some((format, _)) = self.status() {
+            return format == StatusFormat::Json;
+        }
+
+        matches!(
+            self,
+            Deploy {
+                command: Some(
+                    DeployCommand::ProxyConfig { json: true }
+                        | DeployCommand::Stats {
+                            output: StatsOutput::Json,
+                            ..
+                        }
+                ),
+                ..
+            } | Run {
+                command: Some(RunCommand::Stats {
+                    output: StatsOutput::Json,
+                    ..
+                }),
+                ..
+            } | Dev {
+                command: Some(DevCommand::Stats {
+                    output: StatsOutput::Json,
+                    ..
+                }),
+                ..
+            }
+        )
+    }
+
+    /// Services selected with `--only`. Empty if all services are deployed
+    pub fn only(&self) -> &[String] {
+        match self {
+            Command::Deploy { only, .. } => only,
+            _ => &[],
+        }
+    }
+
+    /// Service counts passed to `--scale`
+    pub fn scale(&self) -> &[(String, usize)] {
+        match self {
+            Command::Deploy { scale, .. } => scale,
+            _ => &[],
+        }
+    }
+
+    /// Set by `--locked` and `--frozen`
+    pub fn locked(&self) -> bool {
+        matches!(self, Command::Deploy { locked: true, .. }) || self.frozen()
+    }
+
+    pub fn frozen(&self) -> bool {
+        matches!(self, Command::Deploy { frozen: true, .. })
+    }
+
+    /// Set if `--no-deps` is passed
+    pub fn no_deps(&self) -> bool {
+        use Command::*;
+
+        matches!(
+            self,
+            Deploy { no_deps: true, .. } | Run { no_deps: true, .. }
+        )
+    }
+
+    pub fn deps_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Deploy {
+                deps_only: true,
+                ..
+            }
+        )
+    }
+
+    /// Set if `--recreate-volumes` is passed
+    pub fn recreate_volumes(&self) -> Option<&RecreateVolumesArgs> {
+        use Command::*;
+
+        let (Deploy {
+            recreate_volumes, ..
+        }
+        | Run {
+            recreate_volumes, ..
+        }
+        | Dev {
+            recreate_volumes, ..
+        }) = self;
+
+        recreate_volumes
+            .recreate_volumes
+            .then_some(recreate_volumes)
+    }
+
+    pub fn deploy_timeout(&self) -> Option<Duration> {
+        match self {
+            Command::Deploy { deploy_timeout, .. } => *deploy_timeout,
+            _ => None,
+        }
+    }
+
+    /// Commands which create or remove containers
+    pub fn is_mutating(&self) -> bool {
+        use Command::*;
+
+        let is_deploy = match self {
+            Deploy { command, .. } => command.is_none(),
+            Run { command, .. } => command.is_none(),
+            Dev { command, .. } => command.is_none(),
+        };
+
+        is_deploy || self.stop()
+    }
+
     pub fn watch(&self) -> bool {
         use Command::*;
 
         match self {
-            Run { watch, .. } => *watch,
+            Run { watch, .. } | Dev { watch, .. } => *watch,
+            _ => false,
+        }
+    }
+
+    pub fn restart_on_crash(&self) -> bool {
+        use Command::*;
+
+        match self {
+            Run {
+                restart_on_crash, ..
+            }
+            | Deploy {
+                restart_on_crash, ..
+            } => *restart_on_crash,
+            _ => false,
+        }
+    }
+
+    /// Whether the app runs as a job which dploy waits for, see `run --wait`
+    pub fn wait_for_exit(&self) -> bool {
+        matches!(self, Command::Run { wait: true, .. })
+    }
+
+    pub fn remove_after_exit(&self) -> bool {
+        matches!(self, Command::Run { rm: true, .. })
+    }
+
+    /// Whether only the changes of the env file are shown, see `--env-diff`
+    pub fn env_diff(&self) -> bool {
+        use Command::*;
+
+        match self {
+            Run { env_diff, .. } | Dev { env_diff, .. } => *env_diff,
+            _ => false,
+        }
+    }
+
+    pub fn detach(&self) -> bool {
+        use Command::*;
+
+        match self {
+            Dev { detach, .. } => *detach,
+            _ => false,
+        }
+    }
+
+    pub fn no_wait(&self) -> bool {
+        use Command::*;
+
+        match self {
+            Run { no_wait, .. } | Dev { no_wait, .. } => *no_wait,
             _ => false,
         }
     }
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+
+use console::style;
+use serde::Serialize;
+
+use crate::{cli, config, context, prelude::*, utils::string::mask_env_value};
+
+/// Prints the resolved configuration as TOML, preceded by the overrides which were applied
+pub fn show(context: &context::Context) -> Result<()> {
+    let app_config = context.app_config();
+    let override_context = context.override_context();
+
+    let rules = app_config.active_override_rules(override_context);
+
+    if rules.is_empty() {
+        println!("{}", style("# No overrides applied").dim());
+    }
+
+    for rule in rules {
+        println!("{}", style(format!("# Applied override: {rule}")).dim());
+    }
+
+    let resolved = app_config.resolve(override_context);
+    let contents = toml::to_string_pretty(&resolved).context("Failed to serialize the config")?;
+
+    println!("\n{}", contents.trim_end());
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigDiff {
+    from: String,
+    to: String,
+    changes: Vec<ConfigChange>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigChange {
+    /// e.g. `container.memory` or `ports[0]`
+    path: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<serde_json::Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<serde_json::Value>,
+}
+
+/// Resolves the config for both targets and prints the values which differ.
+/// Secret-looking values are masked
+pub fn diff(
+    context: &context::Context,
+    from: &str,
+    to: &str,
+    output: cli::ConfigDiffOutput,
+) -> Result<()> {
+    let from_context = target_override_context(context, from)?;
+    let to_context = target_override_context(context, to)?;
+
+    let from_values = resolved_values(context, &from_context)?;
+    let mut to_values = resolved_values(context, &to_context)?;
+
+    let mut changes = vec![];
+
+    for (path, from_value) in from_values {
+        match to_values.remove(&path) {
+            Some(to_value) if to_value == from_value => {}
+            to_value => changes.push(ConfigChange {
+                path,
+                from: Some(from_value),
+                to: to_value,
+            }),
+        }
+    }
+
+    changes.extend(to_values.into_iter().map(|(path, to_value)| ConfigChange {
+        path,
+        from: None,
+        to: Some(to_value),
+    }));
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let diff = ConfigDiff {
+        from: format!("{} ({})", from_context.namespace, from_context.command),
+        to: format!("{} ({})", to_context.namespace, to_context.command),
+        changes,
+    };
+
+    match output {
+        cli::ConfigDiffOutput::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+        cli::ConfigDiffOutput::Text => print_diff(&diff),
+    }
+
+    Ok(())
+}
+
+/// Parses `namespace` or `namespace:command`
+fn target_override_context(
+    context: &context::Context,
+    target: &str,
+) -> Result<config::OverrideContext> {
+    let (namespace, command) = match target.split_once(':') {
+        Some((namespace, command)) => {
+            let command = match command {
+                "dev" => config::OverrideRuleCommand::Dev,
+                "run" => config::OverrideRuleCommand::Run,
+                "deploy" => config::OverrideRuleCommand::Deploy,
+                _ => {
+                    bail!("Unknown command `{command}` in `{target}`. Expected dev, run or deploy")
+                }
+            };
+
+            (namespace, command)
+        }
+        None => (target, context.override_context().command.clone()),
+    };
+
+    if namespace.is_empty() {
+        bail!("The namespace is missing in `{target}`");
+    }
+
+    Ok(config::OverrideContext {
+        namespace: namespace.to_owned(),
+        command,
+    })
+}
+
+/// Leaf values of the resolved config by their path. Unset values are left out
+fn resolved_values(
+    context: &context::Context,
+    override_context: &config::OverrideContext,
+) -> Result<BTreeMap<String, serde_json::Value>> {
+    let resolved = context.app_config().resolve(override_context);
+    let value = serde_json::to_value(&resolved).context("Failed to serialize the config")?;
+
+    let mut values = BTreeMap::new();
+    flatten_value("", "", value, &mut values);
+
+    Ok(values)
+}
+
+fn flatten_value(
+    path: &str,
+    key: &str,
+    value: serde_json::Value,
+    values: &mut BTreeMap<String, serde_json::Value>,
+) {
+    match value {
+        serde_json::Value::Null => {}
+        serde_json::Value::Object(object) => {
+            for (key, value) in object {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+
+                flatten_value(&path, &key, value, values);
+            }
+        }
+        serde_json::Value::Array(array) => {
+            for (index, value) in array.into_iter().enumerate() {
+                flatten_value(&format!("{path}[{index}]"), key, value, values);
+            }
+        }
+        serde_json::Value::String(string) => {
+            values.insert(
+                path.to_owned(),
+                serde_json::Value::String(mask_env_value(key, &string)),
+            );
+        }
+        value => {
+            values.insert(path.to_owned(), value);
+        }
+    }
+}
+
+fn print_diff(diff: &ConfigDiff) {
+    println!("{}", style(format!("# {} -> {}", diff.from, diff.to)).dim());
+
+    if diff.changes.is_empty() {
+        println!("\nNo differences");
+        return;
+    }
+
+    println!();
+
+    for change in &diff.changes {
+        match (&change.from, &change.to) {
+            (Some(from), Some(to)) => println!(
+                "{} {}: {} -> {}",
+                style("~").yellow(),
+                change.path,
+                style(from).red(),
+                style(to).green()
+            ),
+            (Some(from), None) => {
+                println!("{}", style(format!("- {}: {from}", change.path)).red())
+            }
+            (None, Some(to)) => {
+                println!("{}", style(format!("+ {}: {to}", change.path)).green())
+            }
+            (None, None) => {}
+        }
+    }
+}
@@ -1,237 +1,2317 @@
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashSet},
     fs,
-    io::Write,
-    path::Path,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    process,
     sync::Arc,
     time,
 };
 
-use futures_util::TryStreamExt;
 use notify::Watcher;
 
 use crate::{
-    build, commands, context, docker, network,
+    build, cli, commands, config, constants, context, docker, lock, lockfile, network,
     prelude::*,
     presentation,
     services::{self, ToContainerConfig},
+    ssh, state, utils,
 };
 
 const WATCH_POLL_INTERVAL: time::Duration = time::Duration::from_secs(1);
 const WATCH_COOLDOWN: time::Duration = time::Duration::from_secs(3);
+const WATCH_TICK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+const WATCH_STATUS_INTERVAL: time::Duration = time::Duration::from_secs(5);
+const CRASH_RESTART_INITIAL_DELAY: time::Duration = time::Duration::from_secs(1);
+const CRASH_RESTART_MAX_DELAY: time::Duration = time::Duration::from_secs(30);
+const CRASH_RESTART_RESET_PERIOD: time::Duration = time::Duration::from_secs(60);
+const READINESS_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+const BLUE_GREEN_READY_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+const BLUE_GREEN_DRAIN_PERIOD: time::Duration = time::Duration::from_secs(10);
+
+const SMOKE_TEST_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+const SMOKE_TEST_INTERVAL: time::Duration = time::Duration::from_secs(5);
+const SMOKE_TEST_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+const APP_START_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const APP_STARTUP_LOGS_LINES: usize = 50;
+
+const DEPLOY_HASH_FILE_EXTENSION: &str = "deploy_hash";
+const GENERATED_ENV_STATE_KEY: &str = "env_file.generated";
+
+const PORT_RETRY_ATTEMPTS: usize = 3;
 
 pub async fn deploy(
     context: &context::Context,
     docker: &bollard::Docker,
-    services: &services::Services,
+    services: &services::Services,
+) -> Result<()> {
+    check_only_services(services, context)?;
+    check_scale(services, context)?;
+    check_network_mode(services, context)?;
+    check_strategy(services, context)?;
+    services.validate(context)?;
+
+    let smoke_test = SmokeTest::from_context(context)?;
+
+    let mut lockfile = read_lockfile(context)?;
+
+    let recreated_volumes = volumes_to_recreate(services, context)?;
+    confirm_recreate_volumes(context, &recreated_volumes)?;
+
+    if context.should_generate_env_file() {
+        presentation::print_env_file_generating();
+        generate_env(services, context, !context.args().command().watch())?;
+    }
+
+    if context.should_create_network() {
+        let mut container_configs = services.to_container_configs(context)?;
+
+        if let Some(app) = services.app() {
+            container_configs.push(app.to_container_config(context)?);
+        }
+
+        let static_addresses = container_configs
+            .iter()
+            .filter_map(|container_config| network::static_address(container_config.config()))
+            .collect::<Vec<_>>();
+
+        presentation::print_network_creating();
+        network::create_dploy_network(
+            docker,
+            context.app_config().network(context.override_context()),
+            &static_addresses,
+        )
+        .await?;
+    }
+
+    let app_service = services
+        .app()
+        .filter(|_| context.should_deploy_service(&services::ServiceKind::App));
+
+    // compared before building, so an unchanged deploy doesn't build the image
+    let deploy_hash = context
+        .should_track_deploy_state()
+        .then(|| compute_deploy_hash(services, context, app_service.is_some()))
+        .transpose()?;
+
+    if let Some(deploy_hash) = deploy_hash
+        .as_ref()
+        .filter(|_| context.should_skip_unchanged_deploy())
+    {
+        let is_unchanged =
+            read_deploy_hash(context, docker).await?.as_deref() == Some(deploy_hash.as_str());
+
+        if is_unchanged && check_services_running(services, context, docker).await? {
+            presentation::print_deploy_unchanged();
+            return Ok(());
+        }
+    }
+
+    if let Some(service) = app_service {
+        context.set_deploy_phase(context::DeployPhase::BuildingImage);
+        let image_id = build_app_image(service, context, docker).await?;
+
+        if let Some(lockfile) = lockfile
+            .as_mut()
+            .filter(|_| context.should_update_lockfile())
+        {
+            lockfile.set_digest(service.image_name(), image_id);
+        }
+    }
+
+    let inline_files = services.inline_files(context);
+    if !inline_files.is_empty() {
+        let files_dir = context.files_dir();
+        docker::write_host_files(docker, &files_dir.to_string_lossy(), &inline_files).await?;
+    }
+
+    let mut started_count = if context.should_start_dependencies() {
+        context.set_deploy_phase(context::DeployPhase::StartingDependencies);
+        presentation::print_dependencies_starting();
+        deploy_dependencies(services, &recreated_volumes, &mut lockfile, context, docker).await?
+    } else {
+        check_dependencies_running(services, context, docker).await?;
+        0
+    };
+
+    let mut replaced_app_containers = None;
+
+    if let Some(service) = app_service {
+        if let Some(migrations) = context.app_config().migrations(context.override_context()) {
+            run_migrations(service, migrations, services, context, docker).await?;
+        }
+
+        context.set_deploy_phase(context::DeployPhase::StartingApp);
+        replaced_app_containers = replace_app_container(
+            service,
+            services.proxy(),
+            smoke_test.as_ref(),
+            context,
+            docker,
+        )
+        .await?;
+        started_count += 1;
+
+        prune_images_after_build(context, docker).await;
+    }
+
+    context.set_deploy_phase(context::DeployPhase::RunningPostUp);
+    presentation::print_post_up_running();
+    services.post_up(docker).await?;
+
+    // a failed smoke test isn't recorded, so the next deploy isn't skipped as unchanged
+    if let Some((smoke_test, replaced)) = smoke_test.as_ref().zip(replaced_app_containers) {
+        check_smoke_test(
+            smoke_test,
+            replaced.rollback(),
+            services.proxy(),
+            context,
+            docker,
+        )
+        .await?;
+        replaced.remove_kept(docker).await?;
+    }
+
+    if let Some(deploy_hash) = &deploy_hash {
+        write_deploy_hash(context, docker, deploy_hash).await?;
+    }
+
+    if let Some(lockfile) = lockfile
+        .as_mut()
+        .filter(|_| context.should_update_lockfile())
+    {
+        update_lockfile(lockfile, services, context)?;
+    }
+
+    // the saved info of the dependencies is kept from the run which started them
+    if context.should_expose_to_host() && context.should_start_dependencies() {
+        commands::info::save(context, &services.connection_info())?;
+    }
+
+    if context.should_print_started_summary() {
+        presentation::print_services_started(started_count);
+    }
+
+    if context.args().command().deps_only() {
+        presentation::print_services_started(started_count);
+
+        let connection_info = dependencies_connection_info(services, context, docker).await?;
+        presentation::print_connection_info(&connection_info);
+    }
+
+    if context.should_print_connection_info() {
+        let should_wait = context.should_wait_for_readiness();
+
+        if should_wait {
+            context.set_deploy_phase(context::DeployPhase::WaitingForReadiness);
+            presentation::print_readiness_waiting();
+        }
+
+        let connection_info = services
+            .connection_info()
+            .into_iter()
+            .filter(|(kind, _)| {
+                context.should_start_dependencies() || *kind == services::ServiceKind::App
+            })
+            .collect();
+
+        let connection_info =
+            check_connection_info(connection_info, should_wait, services, context, docker).await;
+        presentation::print_connection_info(&connection_info);
+    }
+
+    Ok(())
+}
+
+/// Dependencies deployed with `--deps-only` aren't exposed to the host,
+/// containers on the dploy network reach them by their container name
+async fn dependencies_connection_info(
+    services: &services::Services,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<Vec<(services::ServiceKind, String, bool)>> {
+    let mut connection_info = vec![];
+
+    for service_kind in services
+        .kinds(context)
+        .into_iter()
+        .filter(services::ServiceKind::is_local)
+    {
+        let container_name = context.container_name_of(&service_kind);
+        let is_running = docker::check_container_running(docker, &container_name).await?;
+
+        connection_info.push((
+            service_kind,
+            format!("{container_name} on the {} network", network::DPLOY_NETWORK),
+            is_running,
+        ));
+    }
+
+    Ok(connection_info)
+}
+
+/// Runs the deploy within the `--deploy-timeout` budget, if one is set.
+/// On timeout, the new container of a blue-green deploy is removed so the
+/// old one keeps serving traffic, and a pending smoke test rolls back the app.
+/// Other phases have nothing to roll back to
+pub async fn deploy_with_timeout(
+    context: &context::Context,
+    docker: &bollard::Docker,
+    services: &services::Services,
+) -> Result<()> {
+    let Some(timeout) = context.args().command().deploy_timeout() else {
+        return deploy(context, docker, services).await;
+    };
+
+    let Ok(result) = tokio::time::timeout(timeout, deploy(context, docker, services)).await else {
+        let phase = context.deploy_phase();
+
+        match &phase {
+            context::DeployPhase::StartingAppCandidate { container_name } => {
+                let removed = docker::ensure_container_absent(
+                    docker,
+                    container_name,
+                    app_stop_timeout(context)?,
+                )
+                .await?;
+
+                if removed {
+                    presentation::print_container_warning(
+                        container_name,
+                        "Removed the new container, the old one keeps running",
+                    );
+                }
+            }
+            context::DeployPhase::RunningMigrations { container_name } => {
+                docker::ensure_container_absent(docker, container_name, app_stop_timeout(context)?)
+                    .await?;
+            }
+            context::DeployPhase::RunningSmokeTest {
+                rollback: Some(rollback),
+            } => {
+                roll_back_app(rollback, services.proxy(), context, docker).await?;
+                presentation::print_container_warning(
+                    &context.container_name_of(&services::ServiceKind::App),
+                    "Rolled back to the previous app container",
+                );
+            }
+            _ => {}
+        }
+
+        bail!(
+            "Deploy timed out after {}s while {phase}",
+            timeout.as_secs_f64()
+        );
+    };
+
+    result
+}
+
+/// Volume directories of a dependency removed by `--recreate-volumes`
+struct RecreatedVolumes {
+    container_name: String,
+    stop_timeout: Option<time::Duration>,
+    volumes_dir: PathBuf,
+    dir_names: Vec<String>,
+}
+
+impl RecreatedVolumes {
+    fn paths(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.dir_names
+            .iter()
+            .map(|dir_name| self.volumes_dir.join(dir_name))
+    }
+}
+
+fn volumes_to_recreate(
+    services: &services::Services,
+    context: &context::Context,
+) -> Result<Vec<RecreatedVolumes>> {
+    let Some(args) = context.args().command().recreate_volumes() else {
+        return Ok(vec![]);
+    };
+
+    let kinds = services.kinds(context);
+
+    let available = kinds
+        .iter()
+        .filter(|kind| {
+            !matches!(
+                kind,
+                services::ServiceKind::App | services::ServiceKind::Proxy
+            )
+        })
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    for name in args.services() {
+        if !available.contains(name) {
+            bail!(
+                "Unknown service `{name}` in --service. Services with volumes: {}",
+                available.join(", ")
+            );
+        }
+    }
+
+    let container_configs = services.to_container_configs(context)?;
+    let mut recreated_volumes = vec![];
+
+    for kind in kinds {
+        if !context.should_recreate_volumes_of(&kind) || !context.should_deploy_service(&kind) {
+            continue;
+        }
+
+        let container_name = context.container_name_of(&kind);
+        let volumes_dir = context.volumes_dir_of(&kind);
+
+        let Some(config) = container_configs
+            .iter()
+            .find(|config| config.container_name() == container_name)
+        else {
+            continue;
+        };
+
+        let dir_names = config
+            .config()
+            .host_config
+            .iter()
+            .flat_map(|host_config| host_config.mounts.iter().flatten())
+            .filter_map(|mount| mount.source.as_deref())
+            .filter_map(|source| Path::new(source).strip_prefix(&volumes_dir).ok())
+            .map(|dir_name| dir_name.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        if !dir_names.is_empty() {
+            recreated_volumes.push(RecreatedVolumes {
+                container_name,
+                stop_timeout: config.stop_timeout(),
+                volumes_dir,
+                dir_names,
+            });
+        }
+    }
+
+    Ok(recreated_volumes)
+}
+
+/// Volumes outside of `dev` mode may hold data which matters, so deleting them is confirmed
+fn confirm_recreate_volumes(
+    context: &context::Context,
+    recreated_volumes: &[RecreatedVolumes],
+) -> Result<()> {
+    let is_dev = matches!(context.args().command(), cli::Command::Dev { .. });
+    let is_confirmed = context
+        .args()
+        .command()
+        .recreate_volumes()
+        .is_some_and(|args| args.yes());
+
+    if recreated_volumes.is_empty() || is_dev || is_confirmed {
+        return Ok(());
+    }
+
+    if !std::io::stdin().is_terminal() || !console::user_attended_stderr() {
+        bail!("Deleting volumes has to be confirmed, pass --yes when not running in a terminal");
+    }
+
+    for path in recreated_volumes.iter().flat_map(RecreatedVolumes::paths) {
+        presentation::print_volume_to_delete(&path.to_string_lossy());
+    }
+
+    let is_accepted = dialoguer::Confirm::with_theme(&dialoguer::theme::ColorfulTheme::default())
+        .with_prompt("Delete these volumes?")
+        .default(false)
+        .interact()
+        .context("Failed to ask for confirmation")?;
+
+    if !is_accepted {
+        bail!("Deleting volumes was not confirmed");
+    }
+
+    Ok(())
+}
+
+async fn recreate_volumes(volumes: &RecreatedVolumes, docker: &bollard::Docker) -> Result<()> {
+    // the container must not write to the volumes while they are deleted
+    docker::ensure_container_absent(docker, &volumes.container_name, volumes.stop_timeout).await?;
+
+    docker::remove_host_dirs(
+        docker,
+        &volumes.volumes_dir.to_string_lossy(),
+        &volumes.dir_names,
+    )
+    .await?;
+
+    for path in volumes.paths() {
+        presentation::print_volume_removed(&volumes.container_name, &path.to_string_lossy());
+    }
+
+    Ok(())
+}
+
+/// Fails if `--only` selects a service which isn't part of the config
+fn check_only_services(services: &services::Services, context: &context::Context) -> Result<()> {
+    let available = services
+        .kinds(context)
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    for name in context.args().command().only() {
+        if !available.contains(name) {
+            bail!(
+                "Unknown service `{name}` in --only. Available services: {}",
+                available.join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Only the app is stateless, and its replicas can't share an address
+fn check_scale(services: &services::Services, context: &context::Context) -> Result<()> {
+    for (name, _) in context.args().command().scale() {
+        match services::ServiceKind::from_name(name) {
+            services::ServiceKind::App => {}
+            services::ServiceKind::Postgres | services::ServiceKind::Keydb => {
+                bail!("{name} keeps its data in a volume, so it can't be scaled")
+            }
+            _ => bail!("Only the app service can be scaled, not `{name}`"),
+        }
+    }
+
+    if context.app_replicas() == 1 {
+        return Ok(());
+    }
+
+    if context.app_config().strategy(context.override_context())
+        == config::DeployStrategy::BlueGreen
+    {
+        bail!("--scale can't be combined with the blue-green strategy");
+    }
+
+    if let Some(options) = services.app().map(|app| app.container_options()) {
+        if options.ip.is_some() || options.mac_address.is_some() {
+            bail!("The app has a static ip or mac_address, so it can't be scaled");
+        }
+    }
+
+    Ok(())
+}
+
+/// On the host network, the app can't be reached by its container name
+/// and its ports can't be shared with a second container
+fn check_network_mode(services: &services::Services, context: &context::Context) -> Result<()> {
+    let Some(app) = services
+        .app()
+        .filter(|app| app.network_mode() == config::NetworkMode::Host)
+        .filter(|_| context.should_create_app_service())
+    else {
+        return Ok(());
+    };
+
+    // deploys over ssh always run on a Linux server
+    if !cfg!(target_os = "linux") && context.ssh_credentials().is_none() {
+        bail!(
+            "network_mode = \"host\" only works with docker on Linux. Docker Desktop runs \
+             containers in a VM, whose network isn't the one of this machine"
+        );
+    }
+
+    if context.app_replicas() > 1 {
+        bail!("The app uses the host network, so its replicas would share the ports");
+    }
+
+    if context.app_config().strategy(context.override_context())
+        == config::DeployStrategy::BlueGreen
+    {
+        bail!("The blue-green strategy can't be combined with network_mode = \"host\"");
+    }
+
+    if context.should_create_proxy_service()
+        && !context
+            .app_config()
+            .proxy(context.override_context())
+            .is_empty()
+    {
+        bail!(
+            "[[proxy]] can't be combined with network_mode = \"host\", the proxy reaches \
+             the app on the dploy network"
+        );
+    }
+
+    let options = app.container_options();
+    if options.ip.is_some() || options.mac_address.is_some() {
+        bail!("The app uses the host network, so it can't have a static ip or mac_address");
+    }
+
+    presentation::print_container_warning(
+        app.container_name(),
+        "The app uses the host network. It isn't isolated on the dploy network and reaches \
+         the dependencies through their ports published on 127.0.0.1 of the host",
+    );
+
+    Ok(())
+}
+
+/// The blue-green candidate is started while the old container still runs,
+/// so both can't hold the same address on the dploy network
+fn check_strategy(services: &services::Services, context: &context::Context) -> Result<()> {
+    check_strategy_options(
+        context.app_config().strategy(context.override_context()),
+        services.app().map(|app| app.container_options()),
+    )
+}
+
+fn check_strategy_options(
+    strategy: config::DeployStrategy,
+    options: Option<&config::ContainerOptions>,
+) -> Result<()> {
+    let has_static_address =
+        options.is_some_and(|options| options.ip.is_some() || options.mac_address.is_some());
+
+    if strategy == config::DeployStrategy::BlueGreen && has_static_address {
+        bail!("The blue-green strategy can't be combined with a static ip or mac_address");
+    }
+
+    Ok(())
+}
+
+/// Pairs every connection info entry with its readiness.
+/// Entries are considered ready when `should_wait` is false. Services with a `readiness`
+/// command are checked by running it in their container, the others by connecting to their port
+async fn check_connection_info(
+    connection_info: Vec<(services::ServiceKind, services::ConnectionInfoItem)>,
+    should_wait: bool,
+    services: &services::Services,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Vec<(services::ServiceKind, String, bool)> {
+    let checks = connection_info
+        .into_iter()
+        .map(|(service_kind, item)| async move {
+            let readiness = services.readiness_of(&service_kind);
+
+            let is_ready = match (item.address(), readiness) {
+                (_, Some(readiness)) if should_wait => {
+                    let container_name = context.container_name_of(&service_kind);
+                    let timeout = readiness
+                        .timeout
+                        .as_ref()
+                        .and_then(|timeout| timeout.to_duration().ok())
+                        .unwrap_or(READINESS_TIMEOUT);
+
+                    let (is_ready, output) = docker::wait_for_exec_success(
+                        docker,
+                        &container_name,
+                        &readiness.exec,
+                        timeout,
+                    )
+                    .await;
+
+                    if !is_ready {
+                        presentation::print_container_warning(
+                            &container_name,
+                            &format!("Readiness command failed, last attempt: {output}"),
+                        );
+                    }
+
+                    is_ready
+                }
+                (Some((host, port)), _) if should_wait => {
+                    utils::network::wait_for_tcp(host, port, READINESS_TIMEOUT).await
+                }
+                _ => true,
+            };
+
+            (service_kind, item.info().to_owned(), is_ready)
+        });
+
+    futures::future::join_all(checks).await
+}
+
+pub async fn deploy_watch(
+    context: Arc<context::Context>,
+    docker: Arc<bollard::Docker>,
+    services: &services::Services,
+    watch_paths: &[&Path],
+    lock: Option<lock::NamespaceLock>,
+) -> Result<()> {
+    if watch_paths.is_empty() {
+        bail!("Called with --watch flag but no paths were provided. Please provide at least one path to watch in the dploy.toml");
+    }
+
+    deploy(&context, &docker, services).await?;
+
+    // only the initial deploy is guarded, so `stop` works from another terminal
+    drop(lock);
+
+    let (mut debouncer, mut rx) = create_debouncer()?;
+    let watcher = debouncer.watcher();
+
+    for path in watch_paths {
+        watcher
+            .watch(path, notify::RecursiveMode::Recursive)
+            .context("Could not start watcher. Please make sure the folder exists")?;
+    }
+
+    let mut rx_abort = handle_abort()?;
+
+    // from here on, the logs task is only stopped after the loop
+    let mut handle = None;
+    follow_app_logs_if_started(&context, &docker, &mut handle).await?;
+
+    let mut last_deploy = time::Instant::now();
+    let mut tick = tokio::time::interval(WATCH_TICK_INTERVAL);
+    let mut last_status = time::Instant::now();
+    // the status line is only shown while no app logs are printed, which would garble it
+    let mut is_status_shown = false;
+
+    let app_container_name = context.container_name_of(&services::ServiceKind::App);
+    let mut app_exit = AppExitWatch::default();
+    let mut crash_restart_delay = CRASH_RESTART_INITIAL_DELAY;
+    let mut crash_restart_at = None;
+    let mut app_started_at = time::Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = rx_abort.recv() => break,
+
+            Some(result) = rx.recv() => {
+                let Ok(events) = result else {
+                    continue;
+                };
+
+                if time::Instant::now() - last_deploy < WATCH_COOLDOWN
+                    || events.is_empty()
+                    || !events.iter().any(|event| event.kind.is_modify())
+                {
+                    continue;
+                }
+
+                if is_status_shown {
+                    presentation::clear_watch_status();
+                    is_status_shown = false;
+                }
+
+                presentation::print_watch_files_changed();
+
+                // a failed build shouldn't end the watch, the next change retries
+                if let Some(service) = services.app() {
+                    let result = async {
+                        deploy_app_service(service, services.proxy(), &context, &docker).await?;
+                        follow_app_logs_if_started(&context, &docker, &mut handle).await
+                    }
+                    .await;
+
+                    if let Err(error) = result {
+                        presentation::print_redeploy_failed(&error);
+                        continue;
+                    }
+                }
+
+                last_deploy = time::Instant::now();
+                app_started_at = last_deploy;
+                crash_restart_delay = CRASH_RESTART_INITIAL_DELAY;
+                crash_restart_at = None;
+            }
+
+            // wakes the loop up periodically even if nothing happens
+            _ = tick.tick() => {
+                // a docker hiccup shouldn't end the watch, the next tick checks again
+                let exit_code = match app_exit.check(&docker, &app_container_name).await {
+                    Ok(exit_code) => exit_code,
+                    Err(error) => {
+                        if is_status_shown {
+                            presentation::clear_watch_status();
+                            is_status_shown = false;
+                        }
+
+                        presentation::print_container_warning(
+                            &app_container_name,
+                            &format!("Could not check whether the app is running: {error:#}"),
+                        );
+                        None
+                    }
+                };
+
+                if let Some(code) = exit_code {
+                    if is_status_shown {
+                        presentation::clear_watch_status();
+                        is_status_shown = false;
+                    }
+
+                    presentation::print_app_container_exited(&app_container_name, code);
+
+                    if let Err(error) =
+                        docker::print_container_logs(&docker, &app_container_name, APP_STARTUP_LOGS_LINES)
+                            .await
+                    {
+                        presentation::print_container_warning(
+                            &app_container_name,
+                            &format!("Could not read the logs: {error:#}"),
+                        );
+                    }
+
+                    // a crash long after the app was started isn't part of a crash loop
+                    if app_started_at.elapsed() >= CRASH_RESTART_RESET_PERIOD {
+                        crash_restart_delay = CRASH_RESTART_INITIAL_DELAY;
+                    }
+
+                    // without the flag, docker's restart policy brings the app back
+                    if context.args().command().restart_on_crash() && crash_restart_at.is_none() {
+                        presentation::print_app_restart_scheduled(
+                            &app_container_name,
+                            crash_restart_delay,
+                        );
+                        crash_restart_at = Some(time::Instant::now() + crash_restart_delay);
+                        crash_restart_delay = (crash_restart_delay * 2).min(CRASH_RESTART_MAX_DELAY);
+                    }
+                }
+
+                if crash_restart_at.is_some_and(|at| time::Instant::now() >= at) {
+                    crash_restart_at = None;
+
+                    if is_status_shown {
+                        presentation::clear_watch_status();
+                        is_status_shown = false;
+                    }
+
+                    // the image is unchanged, only the container is recreated
+                    if let Some(service) = services.app() {
+                        let result = async {
+                            replace_app_container(
+                                service,
+                                services.proxy(),
+                                None,
+                                &context,
+                                &docker,
+                            )
+                            .await?;
+                            follow_app_logs_if_started(&context, &docker, &mut handle).await
+                        }
+                        .await;
+
+                        match result {
+                            Ok(()) => app_started_at = time::Instant::now(),
+                            // like a docker hiccup above, the recreate is tried again later
+                            Err(error) => {
+                                presentation::print_container_warning(
+                                    &app_container_name,
+                                    &format!("Could not recreate the app: {error:#}"),
+                                );
+                                presentation::print_app_restart_scheduled(
+                                    &app_container_name,
+                                    crash_restart_delay,
+                                );
+                                crash_restart_at = Some(time::Instant::now() + crash_restart_delay);
+                                crash_restart_delay =
+                                    (crash_restart_delay * 2).min(CRASH_RESTART_MAX_DELAY);
+                            }
+                        }
+                    }
+                }
+
+                let is_following_logs = handle.as_ref().is_some_and(|handle| !handle.is_finished());
+
+                if context.should_print_watch_status()
+                    && !is_following_logs
+                    && last_status.elapsed() >= WATCH_STATUS_INTERVAL
+                {
+                    presentation::print_watch_status(
+                        watch_paths.len(),
+                        last_deploy.elapsed(),
+                    );
+                    last_status = time::Instant::now();
+                    is_status_shown = true;
+                }
+            }
+        }
+    }
+
+    if is_status_shown {
+        presentation::clear_watch_status();
+    }
+
+    if let Some(handle) = handle {
+        stop_app_logs(handle).await;
+    }
+
+    presentation::print_ctrlc_started();
+
+    Ok(())
+}
+
+/// Notices when the app container exits on its own while watching files
+#[derive(Default)]
+struct AppExitWatch {
+    container_id: Option<String>,
+    finished_at: Option<String>,
+}
+
+impl AppExitWatch {
+    /// Returns the exit code if the container has exited since the last check,
+    /// including exits after which docker has already restarted it.
+    /// A new container, e.g. after a redeploy, only resets the state
+    async fn check(
+        &mut self,
+        docker: &bollard::Docker,
+        container_name: &str,
+    ) -> Result<Option<i64>> {
+        let Some(container) = docker::inspect_container(docker, container_name).await? else {
+            return Ok(None);
+        };
+
+        let state = container.state.unwrap_or_default();
+
+        if container.id != self.container_id {
+            self.container_id = container.id;
+            self.finished_at = state.finished_at;
+            return Ok(None);
+        }
+
+        if state.finished_at == self.finished_at {
+            return Ok(None);
+        }
+
+        self.finished_at = state.finished_at;
+
+        Ok(Some(state.exit_code.unwrap_or_default()))
+    }
+}
+
+/// Keeps the app in the foreground like `docker compose up` without `-d`:
+/// follows its logs and stops all services on Ctrl-C
+pub async fn deploy_attached(
+    context: Arc<context::Context>,
+    docker: Arc<bollard::Docker>,
+    services: &services::Services,
+) -> Result<()> {
+    // set up first, so Ctrl-C during the deploy stops the services as well
+    let mut rx_abort = handle_abort()?;
+
+    deploy(&context, &docker, services).await?;
+
+    let mut handle = spawn_app_logs(&context, &docker);
+
+    let logs_result = tokio::select! {
+        _ = rx_abort.recv() => {
+            stop_app_logs(handle).await;
+            Ok(())
+        }
+        result = &mut handle => result
+            .context("Failed to follow the logs")
+            .and_then(|result| result),
+    };
+
+    presentation::print_ctrlc_started();
+    commands::stop::stop(&context, &docker, services).await?;
+
+    logs_result
+}
+
+/// Dev mode runs the app on the host, so instead of rebuilding an image
+/// the dependencies are redeployed when the config file changes,
+/// and `dev.on_change` is run when any of the watched paths change
+pub async fn dev_watch(
+    mut context: Arc<context::Context>,
+    docker: Arc<bollard::Docker>,
+    mut services: services::Services,
+    lock: Option<lock::NamespaceLock>,
+) -> Result<()> {
+    deploy(&context, &docker, &services).await?;
+
+    // only the initial deploy is guarded, so `stop` works from another terminal
+    drop(lock);
+
+    // configs from stdin or a URL can't be watched
+    let config_path = context
+        .args()
+        .config_source()
+        .file()
+        .map(fs::canonicalize)
+        .transpose()?;
+    let watch_paths = context
+        .app_config()
+        .watch(context.override_context())
+        .iter()
+        .map(|path| {
+            fs::canonicalize(path)
+                .with_context(|| format!("Could not watch {path}. Please make sure it exists"))
+        })
+        .collect::<Result<Vec<PathBuf>>>()?;
+
+    let (mut debouncer, mut rx) = create_debouncer()?;
+    let watcher = debouncer.watcher();
+
+    // editors often replace files instead of writing them,
+    // so the directory is watched instead of the file itself
+    if let Some(config_dir) = config_path.as_ref().and_then(|path| path.parent()) {
+        watcher.watch(config_dir, notify::RecursiveMode::NonRecursive)?;
+    }
+
+    for path in &watch_paths {
+        watcher.watch(path, notify::RecursiveMode::Recursive)?;
+    }
+
+    let mut rx_abort = handle_abort()?;
+
+    let mut last_change = time::Instant::now();
+    let mut tick = tokio::time::interval(WATCH_TICK_INTERVAL);
+    let mut last_status = time::Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = rx_abort.recv() => break,
+
+            Some(result) = rx.recv() => {
+                let Ok(events) = result else {
+                    continue;
+                };
+
+                let changed_paths = events
+                    .iter()
+                    .filter(|event| event.kind.is_modify() || event.kind.is_create())
+                    .flat_map(|event| event.paths.iter())
+                    .collect::<Vec<_>>();
+
+                let is_config_changed = changed_paths
+                    .iter()
+                    .any(|path| Some(*path) == config_path.as_ref());
+                let is_source_changed = changed_paths
+                    .iter()
+                    .any(|path| watch_paths.iter().any(|watch_path| path.starts_with(watch_path)));
+
+                if !is_config_changed && !is_source_changed {
+                    continue;
+                }
+
+                if context.should_print_watch_status() {
+                    presentation::clear_watch_status();
+                }
+
+                if is_config_changed {
+                    presentation::print_watch_config_changed();
+
+                    // invalid service options are rejected like an invalid config
+                    let reloaded = context.reload().and_then(|reloaded| {
+                        let reloaded_services = services::Services::from_context(&reloaded)?;
+
+                        Ok((reloaded, reloaded_services))
+                    });
+
+                    match reloaded {
+                        Ok((reloaded, reloaded_services)) => {
+                            context = Arc::new(reloaded);
+                            services = reloaded_services;
+
+                            // e.g. a port clash, the next change of the config retries
+                            if let Err(error) = deploy(&context, &docker, &services).await {
+                                presentation::print_redeploy_failed(&error);
+                            }
+                        }
+                        Err(error) => presentation::print_config_reload_failed(&error),
+                    }
+                } else if let Some(command) = context
+                    .app_config()
+                    .dev(context.override_context())
+                    .and_then(config::DevConfig::on_change)
+                {
+                    presentation::print_on_change_running(command);
+                    run_on_change_command(command.to_owned()).await?;
+                }
+
+                last_change = time::Instant::now();
+            }
+
+            _ = tick.tick() => {
+                if context.should_print_watch_status()
+                    && last_status.elapsed() >= WATCH_STATUS_INTERVAL
+                {
+                    presentation::print_watch_status(
+                        watch_paths.len() + usize::from(config_path.is_some()),
+                        last_change.elapsed(),
+                    );
+                    last_status = time::Instant::now();
+                }
+            }
+        }
+    }
+
+    if context.should_print_watch_status() {
+        presentation::clear_watch_status();
+    }
+
+    presentation::print_watch_stopped();
+
+    Ok(())
+}
+
+type WatchEvents = tokio::sync::mpsc::UnboundedReceiver<notify_debouncer_full::DebounceEventResult>;
+
+fn create_debouncer() -> Result<(
+    notify_debouncer_full::Debouncer<notify::RecommendedWatcher, notify_debouncer_full::FileIdMap>,
+    WatchEvents,
+)> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let debouncer = notify_debouncer_full::new_debouncer(
+        WATCH_POLL_INTERVAL,
+        None,
+        move |result: notify_debouncer_full::DebounceEventResult| {
+            let _ = tx.send(result);
+        },
+    )?;
+
+    Ok((debouncer, rx))
+}
+
+fn handle_abort() -> Result<tokio::sync::mpsc::UnboundedReceiver<()>> {
+    let (tx_abort, rx_abort) = tokio::sync::mpsc::unbounded_channel();
+
+    // with the `termination` feature this also handles SIGTERM and SIGHUP,
+    // so supervised processes are torn down the same way as on Ctrl-C
+    ctrlc::set_handler(move || {
+        presentation::print_ctrlc_received();
+        let _ = tx_abort.send(());
+    })?;
+
+    Ok(rx_abort)
+}
+
+/// Runs the command on the host. A failing command doesn't stop watching
+async fn run_on_change_command(command: String) -> Result<()> {
+    let status = tokio::task::spawn_blocking(move || {
+        process::Command::new("sh").arg("-c").arg(&command).status()
+    })
+    .await?
+    .context("Failed to run the on_change command")?;
+
+    if !status.success() {
+        presentation::print_on_change_failed(status.code());
+    }
+
+    Ok(())
+}
+
+/// Follows the app logs once its container is up. A follower which is still attached
+/// moves over to a redeployed container by itself, so it's kept
+async fn follow_app_logs_if_started(
+    context: &Arc<context::Context>,
+    docker: &Arc<bollard::Docker>,
+    handle: &mut Option<tokio::task::JoinHandle<Result<()>>>,
+) -> Result<()> {
+    if !check_app_started(context, docker).await? {
+        return Ok(());
+    }
+
+    if handle.as_ref().is_some_and(|handle| !handle.is_finished()) {
+        return Ok(());
+    }
+
+    *handle = Some(spawn_app_logs(context, docker));
+
+    Ok(())
+}
+
+/// A container which exits right after starting gets its exit code and last output
+/// printed, instead of a log follower which shows nothing. Returns whether it's up
+async fn check_app_started(context: &context::Context, docker: &bollard::Docker) -> Result<bool> {
+    let container_name = context.container_name_of(&services::ServiceKind::App);
+
+    if docker::wait_for_container_ready(docker, &container_name, APP_START_TIMEOUT).await? {
+        return Ok(true);
+    }
+
+    let Some(state) = docker::inspect_container(docker, &container_name)
+        .await?
+        .and_then(|container| container.state)
+    else {
+        return Ok(false);
+    };
+
+    // still waiting for its healthcheck, or unhealthy, which the logs explain better
+    if state.running.unwrap_or(false) && !state.restarting.unwrap_or(false) {
+        return Ok(true);
+    }
+
+    presentation::print_app_container_exited(&container_name, state.exit_code.unwrap_or_default());
+    docker::print_container_logs(docker, &container_name, APP_STARTUP_LOGS_LINES).await?;
+
+    Ok(false)
+}
+
+fn spawn_app_logs(
+    context: &Arc<context::Context>,
+    docker: &Arc<bollard::Docker>,
+) -> tokio::task::JoinHandle<Result<()>> {
+    tokio::spawn(commands::logs::follow_across_redeploys(
+        Arc::clone(context),
+        Arc::clone(docker),
+        services::ServiceKind::App,
+    ))
+}
+
+/// Aborts the logs task and waits until it is fully torn down
+async fn stop_app_logs(handle: tokio::task::JoinHandle<Result<()>>) {
+    handle.abort();
+
+    // the task is either cancelled or already finished, both are fine here
+    let _ = handle.await;
+}
+
+async fn deploy_app_service(
+    app_service: &services::app::AppService,
+    proxy: &services::proxy::ProxyService,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<()> {
+    build_app_image(app_service, context, docker).await?;
+    replace_app_container(app_service, proxy, None, context, docker).await?;
+    prune_images_after_build(context, docker).await;
+
+    Ok(())
+}
+
+/// Runs once the new app container has replaced the old one, so the image of
+/// the old container is no longer in use and is pruned as well. Failing to prune
+/// doesn't fail the deploy
+async fn prune_images_after_build(context: &context::Context, docker: &bollard::Docker) {
+    if !context
+        .app_config()
+        .prune_after_build(context.override_context())
+    {
+        return;
+    }
+
+    let container_name = context.container_name_of(&services::ServiceKind::App);
+
+    let result = async {
+        let (mut count, mut bytes) = docker::prune_dangling_images(docker).await?;
+
+        // images built locally leave their layers on this machine
+        if context.should_build_locally() {
+            let local_docker = docker::get_default_docker_client(context).await?;
+            let (local_count, local_bytes) = docker::prune_dangling_images(&local_docker).await?;
+
+            count += local_count;
+            bytes += local_bytes;
+        }
+
+        anyhow::Ok((count, bytes))
+    }
+    .await;
+
+    match result {
+        Ok((count, bytes)) => presentation::print_images_pruned(&container_name, count, bytes),
+        Err(error) => presentation::print_container_warning(&container_name, &format!("{error:#}")),
+    }
+}
+
+/// Returns the id of the built image
+async fn build_app_image(
+    app_service: &services::app::AppService,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<String> {
+    let container_name = context.container_name_of(&services::ServiceKind::App);
+    let dockerfile = context.app_config().dockerfile(context.override_context());
+
+    // fails before connecting to the build daemon, with the paths in the message
+    build::BuildPaths::resolve(context)?;
+    let secrets = build::BuildSecrets::resolve(context)?;
+
+    // the docker CLI builds with the local daemon
+    if build::builds_with_cli(context, &secrets)
+        && matches!(context.args().command(), cli::Command::Deploy { .. })
+        && !context.should_build_locally()
+    {
+        bail!(
+            "Images with `build_secrets` or `build_output` are built by the local docker CLI. \
+             Pass --remote-docker to build the image here and transfer it to the server"
+        );
+    }
+
+    presentation::print_image_building(&container_name, dockerfile);
+
+    if !context.should_build_locally() {
+        let image_id = build::build_app_service_image(context, app_service, docker).await?;
+        presentation::print_image_built(&container_name);
+
+        return Ok(image_id);
+    }
+
+    let local_docker = docker::get_default_docker_client(context).await?;
+    let image_id = build::build_app_service_image(context, app_service, &local_docker).await?;
+    presentation::print_image_built(&container_name);
+
+    let target = context
+        .ssh_credentials()
+        .and_then(|credentials| ssh::forwarded_docker_address(credentials.host()))
+        .context("--remote-docker needs a connection to the server over SSH")?;
+
+    build::transfer_image(
+        &local_docker,
+        target,
+        app_service.image_name(),
+        &container_name,
+    )
+    .await?;
+
+    Ok(image_id)
+}
+
+/// The smoke test of the config, with `${HOST_PORT}` and `${URL}` replaced in its command
+struct SmokeTest<'a> {
+    config: &'a config::SmokeTestConfig,
+    command: String,
+}
+
+impl<'a> SmokeTest<'a> {
+    /// Fails before anything is deployed if the command uses a placeholder the app doesn't have
+    fn from_context(context: &'a context::Context) -> Result<Option<Self>> {
+        let Some(config) = context
+            .app_config()
+            .deploy(context.override_context())
+            .and_then(config::DeployConfig::smoke_test)
+            .filter(|_| context.should_run_smoke_test())
+        else {
+            return Ok(None);
+        };
+
+        let command = interpolate_smoke_test(config.command(), &smoke_test_placeholders(context))?;
+
+        Ok(Some(Self { config, command }))
+    }
+}
+
+/// The app is reached through the first `[[proxy]]` domain, or on its own port
+/// when it uses the host network. Values are `None` when the app can't be reached that way
+fn smoke_test_placeholders(context: &context::Context) -> Vec<(&'static str, Option<String>)> {
+    let app_config = context.app_config();
+    let override_context = context.override_context();
+
+    let host_port = app_config
+        .ports(override_context)
+        .first()
+        .filter(|_| app_config.network_mode(override_context) == config::NetworkMode::Host)
+        .copied();
+
+    let host = context
+        .ssh_credentials()
+        .map(|credentials| credentials.host().to_owned())
+        .unwrap_or_else(|| "127.0.0.1".to_owned());
+
+    let url = match app_config.proxy(override_context).first() {
+        Some(proxy) => Some(format!("https://{}", proxy.domain)),
+        None => host_port.map(|port| format!("http://{host}:{port}")),
+    };
+
+    vec![
+        ("HOST_PORT", host_port.map(|port| port.to_string())),
+        ("URL", url),
+    ]
+}
+
+fn interpolate_smoke_test(
+    command: &str,
+    placeholders: &[(&str, Option<String>)],
+) -> Result<String> {
+    let mut command = command.to_owned();
+
+    for (name, value) in placeholders {
+        let placeholder = format!("${{{name}}}");
+
+        if !command.contains(&placeholder) {
+            continue;
+        }
+
+        let Some(value) = value else {
+            bail!(
+                "The smoke test uses {placeholder}, but the app can't be reached that way. \
+                 ${{URL}} needs a [[proxy]] section or network_mode = \"host\" with `ports`, \
+                 ${{HOST_PORT}} needs the latter"
+            );
+        };
+
+        command = command.replace(&placeholder, value);
+    }
+
+    Ok(command)
+}
+
+/// Runs the smoke test and brings back the replaced app containers when it fails
+async fn check_smoke_test(
+    smoke_test: &SmokeTest<'_>,
+    rollback: Option<context::AppRollback>,
+    proxy: &services::proxy::ProxyService,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<()> {
+    context.set_deploy_phase(context::DeployPhase::RunningSmokeTest {
+        rollback: rollback.clone(),
+    });
+
+    let Err(error) = run_smoke_test(smoke_test, context).await else {
+        return Ok(());
+    };
+
+    let Some(rollback) = rollback else {
+        bail!("{error:#}. There was no previous app container to roll back to");
+    };
+
+    roll_back_app(&rollback, proxy, context, docker).await?;
+
+    bail!("{error:#}. Rolled back to the previous app container");
+}
+
+/// Runs the smoke test command on this machine until an attempt passes
+async fn run_smoke_test(smoke_test: &SmokeTest<'_>, context: &context::Context) -> Result<()> {
+    let container_name = context.container_name_of(&services::ServiceKind::App);
+
+    let timeout = smoke_test
+        .config
+        .timeout()
+        .map(config::DurationConfig::to_duration)
+        .transpose()
+        .context("Invalid timeout of the smoke test")?
+        .unwrap_or(SMOKE_TEST_TIMEOUT);
+    let interval = smoke_test
+        .config
+        .interval()
+        .map(config::DurationConfig::to_duration)
+        .transpose()
+        .context("Invalid interval of the smoke test")?
+        .unwrap_or(SMOKE_TEST_INTERVAL);
+
+    presentation::print_smoke_test_running(&container_name);
+
+    let env = smoke_test_env(context);
+    let attempts = smoke_test.config.retries() + 1;
+    let mut attempt = 1;
+
+    loop {
+        let result = run_smoke_test_attempt(&smoke_test.command, &env, timeout).await;
+
+        match result {
+            Ok(()) => break,
+            Err(error) if attempt < attempts => presentation::print_container_warning(
+                &container_name,
+                &format!(
+                    "Smoke test {error} (attempt {attempt} of {attempts}), retrying in {}s",
+                    interval.as_secs()
+                ),
+            ),
+            Err(error) => bail!("Smoke test {error} (attempt {attempt} of {attempts})"),
+        }
+
+        tokio::time::sleep(interval).await;
+        attempt += 1;
+    }
+
+    presentation::print_smoke_test_success(&container_name);
+
+    Ok(())
+}
+
+fn smoke_test_env(context: &context::Context) -> Vec<(&'static str, String)> {
+    let mut env = vec![("DPLOY_NAMESPACE", context.namespace().to_owned())];
+
+    if let Some(credentials) = context.ssh_credentials() {
+        env.push(("DPLOY_HOST", credentials.host().to_owned()));
+    }
+
+    env
+}
+
+/// See `stop_grace_period`, for containers removed without their config at hand
+fn app_stop_timeout(context: &context::Context) -> Result<Option<time::Duration>> {
+    context
+        .app_config()
+        .container_options(context.override_context())
+        .stop_timeout()
+}
+
+/// Brings back the app containers replaced by a deploy whose smoke test failed
+async fn roll_back_app(
+    rollback: &context::AppRollback,
+    proxy: &services::proxy::ProxyService,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<()> {
+    let stop_timeout = app_stop_timeout(context)?;
+
+    match rollback {
+        context::AppRollback::BlueGreen { candidate_name } => {
+            let container_name = context.container_name_of(&services::ServiceKind::App);
+
+            proxy.route_to(docker, &[container_name.as_str()]).await?;
+            docker::ensure_container_absent(docker, candidate_name, stop_timeout).await?;
+        }
+        context::AppRollback::Recreate { container_names } => {
+            for container_name in context.app_container_names() {
+                docker::ensure_container_absent(docker, &container_name, stop_timeout).await?;
+            }
+
+            for container_name in container_names {
+                docker
+                    .rename_container(
+                        &previous_app_container_name(container_name),
+                        bollard::container::RenameContainerOptions {
+                            name: container_name.as_str(),
+                        },
+                    )
+                    .await?;
+                docker::start_container(docker, container_name).await?;
+            }
+
+            let upstreams = container_names
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            proxy.route_to(docker, &upstreams).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_smoke_test_attempt(
+    command: &str,
+    env: &[(&str, String)],
+    timeout: time::Duration,
+) -> Result<()> {
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().cloned())
+        .stdin(process::Stdio::null())
+        .spawn()
+        .context("Failed to run the smoke test")?;
+
+    let started_at = time::Instant::now();
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return match status.code() {
+                Some(0) => Ok(()),
+                Some(code) => Err(anyhow::anyhow!("exited with code {code}")),
+                None => Err(anyhow::anyhow!("was terminated by a signal")),
+            };
+        }
+
+        if started_at.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+
+            bail!("timed out after {}s", timeout.as_secs());
+        }
+
+        tokio::time::sleep(SMOKE_TEST_POLL_INTERVAL).await;
+    }
+}
+
+/// Runs the migrations in a one-shot container of the freshly built image,
+/// with the environment and network of the app. The app container is only
+/// replaced if they succeed
+async fn run_migrations(
+    app_service: &services::app::AppService,
+    migrations: &config::MigrationsConfig,
+    services: &services::Services,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<()> {
+    let app_container_config = app_service.to_container_config(context)?;
+    let container_name = format!("{}_migrations", app_container_config.container_name());
+
+    context.set_deploy_phase(context::DeployPhase::RunningMigrations {
+        container_name: container_name.clone(),
+    });
+
+    // migrations usually connect to the database right away
+    for config in services.to_container_configs(context)? {
+        if !docker::wait_for_container_ready(docker, config.container_name(), READINESS_TIMEOUT)
+            .await?
+        {
+            presentation::print_container_warning(
+                config.container_name(),
+                "Not ready, running the migrations anyway",
+            );
+        }
+    }
+
+    let config = migrations_container_config(app_container_config.config(), migrations.command());
+
+    docker::ensure_container_absent(docker, &container_name, app_container_config.stop_timeout())
+        .await?;
+
+    presentation::print_migrations_running(&container_name);
+    docker::create_container(
+        docker,
+        bollard::container::CreateContainerOptions {
+            name: container_name.as_str(),
+            platform: app_container_config.platform(),
+        },
+        config,
+    )
+    .await?;
+
+    docker::start_container(docker, &container_name).await?;
+
+    let state = docker::follow_until_exit(docker, &container_name).await;
+    docker::ensure_container_absent(docker, &container_name, app_container_config.stop_timeout())
+        .await?;
+
+    let exit_code = state?.exit_code.unwrap_or_default();
+
+    if exit_code != 0 {
+        bail!("Migrations failed with exit code {exit_code}. The running app was not changed");
+    }
+
+    presentation::print_migrations_success(&container_name);
+
+    Ok(())
+}
+
+/// The config of the app container running the migrations command instead.
+/// The static ip, mac address and aliases of the app are left out, they belong to the running app
+fn migrations_container_config(
+    app_config: &bollard::container::Config<String>,
+    command: &str,
+) -> bollard::container::Config<String> {
+    let mut config = app_config.clone();
+    config.entrypoint = Some(vec!["sh".to_owned(), "-c".to_owned(), command.to_owned()]);
+    config.cmd = Some(vec![]);
+    config.hostname = None;
+    config.domainname = None;
+    config.tty = Some(false);
+    config.open_stdin = Some(false);
+    config.attach_stdin = Some(false);
+
+    if let Some(host_config) = config.host_config.as_mut() {
+        host_config.port_bindings = None;
+        host_config.restart_policy = None;
+    }
+
+    if let Some(endpoint) = config
+        .networking_config
+        .as_mut()
+        .and_then(|networking| networking.endpoints_config.get_mut(network::DPLOY_NETWORK))
+    {
+        endpoint.ipam_config = None;
+        endpoint.mac_address = None;
+        endpoint.aliases = None;
+    }
+
+    config
+}
+
+/// With a smoke test, the replaced containers are returned to be removed once it passes.
+/// A blue-green deploy runs the smoke test itself, before the old container is removed
+async fn replace_app_container(
+    app_service: &services::app::AppService,
+    proxy: &services::proxy::ProxyService,
+    smoke_test: Option<&SmokeTest<'_>>,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<Option<ReplacedAppContainers>> {
+    let container_config = app_service.to_container_config(context)?;
+    let container_name = container_config.container_name();
+
+    let is_blue_green = context.app_config().strategy(context.override_context())
+        == config::DeployStrategy::BlueGreen;
+
+    // there is nothing to switch from if the old container is not running,
+    // and without the proxy there is nothing to switch
+    let should_switch = is_blue_green
+        && context.should_create_proxy_service()
+        && docker::check_container_running(docker, container_name).await?;
+
+    let mut replaced = ReplacedAppContainers {
+        should_keep: smoke_test.is_some() && !should_switch,
+        stop_timeout: container_config.stop_timeout(),
+        kept_names: vec![],
+    };
+
+    let replica_names = context.app_container_names();
+
+    // replicas left over from a deploy with a higher --scale
+    for replica_name in docker::replica_container_names(docker, container_name).await? {
+        if !replica_names.contains(&replica_name) && replaced.remove(docker, &replica_name).await? {
+            presentation::print_app_container_removed(&replica_name);
+        }
+    }
+
+    if replica_names.len() > 1 {
+        replace_app_replicas(&container_config, &replica_names, &mut replaced, docker).await?;
+
+        return Ok(smoke_test.map(|_| replaced));
+    }
+
+    if should_switch {
+        replace_app_container_blue_green(&container_config, proxy, smoke_test, context, docker)
+            .await?;
+
+        return Ok(None);
+    }
+
+    if replaced.remove(docker, container_name).await? {
+        presentation::print_app_container_removed(container_name);
+    }
+
+    presentation::print_app_container_creating(container_name);
+    docker::create_container(
+        docker,
+        bollard::container::CreateContainerOptions {
+            name: container_name,
+            platform: container_config.platform(),
+        },
+        container_config.config().clone(),
+    )
+    .await?;
+
+    presentation::print_app_container_starting(container_name);
+    docker::start_container(docker, container_name).await?;
+
+    presentation::print_app_container_success(container_name);
+
+    Ok(smoke_test.map(|_| replaced))
+}
+
+/// Old app containers replaced by the deploy. While a smoke test is pending, they are
+/// only stopped and renamed, so a failed test can bring them back
+struct ReplacedAppContainers {
+    should_keep: bool,
+    stop_timeout: Option<time::Duration>,
+    kept_names: Vec<String>,
+}
+
+impl ReplacedAppContainers {
+    /// Returns whether the container existed
+    async fn remove(&mut self, docker: &bollard::Docker, container_name: &str) -> Result<bool> {
+        if !self.should_keep {
+            return docker::ensure_container_absent(docker, container_name, self.stop_timeout)
+                .await;
+        }
+
+        let previous_name = previous_app_container_name(container_name);
+
+        // left over from a deploy which was interrupted during its smoke test
+        docker::ensure_container_absent(docker, &previous_name, self.stop_timeout).await?;
+
+        let exists = docker::stop_and_rename_container(
+            docker,
+            container_name,
+            &previous_name,
+            self.stop_timeout,
+        )
+        .await?;
+        if exists {
+            self.kept_names.push(container_name.to_owned());
+        }
+
+        Ok(exists)
+    }
+
+    fn rollback(&self) -> Option<context::AppRollback> {
+        (!self.kept_names.is_empty()).then(|| context::AppRollback::Recreate {
+            container_names: self.kept_names.clone(),
+        })
+    }
+
+    async fn remove_kept(&self, docker: &bollard::Docker) -> Result<()> {
+        for container_name in &self.kept_names {
+            docker::ensure_container_absent(
+                docker,
+                &previous_app_container_name(container_name),
+                self.stop_timeout,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Name an old app container is kept under until the smoke test passes
+fn previous_app_container_name(container_name: &str) -> String {
+    format!("{container_name}_previous")
+}
+
+/// Recreates every replica of `deploy --scale`. They share the name of the
+/// app as a network alias, the proxy balances between them in `post_up`
+async fn replace_app_replicas(
+    container_config: &services::ContainerConfig,
+    replica_names: &[String],
+    replaced: &mut ReplacedAppContainers,
+    docker: &bollard::Docker,
 ) -> Result<()> {
-    if dotenvy::from_path(context.app_config().env_file(context.override_context())).is_ok() {
-        presentation::print_env_file_loaded();
-    } else {
-        presentation::print_env_file_failed_to_load();
+    let container_name = container_config.container_name();
+
+    // the unscaled container of a previous deploy
+    if replaced.remove(docker, container_name).await? {
+        presentation::print_app_container_removed(container_name);
     }
 
-    if context.should_generate_env_file() {
-        presentation::print_env_file_generating();
-        generate_env(services, context)?;
+    for replica_name in replica_names {
+        let mut config = container_config.config().clone();
+        config.hostname = Some(replica_name.clone());
+        config.domainname = Some(replica_name.clone());
+
+        if let Some(endpoint) = config
+            .networking_config
+            .as_mut()
+            .and_then(|networking| networking.endpoints_config.get_mut(network::DPLOY_NETWORK))
+        {
+            endpoint
+                .aliases
+                .get_or_insert_with(Vec::new)
+                .push(container_name.to_owned());
+        }
+
+        if replaced.remove(docker, replica_name).await? {
+            presentation::print_app_container_removed(replica_name);
+        }
+
+        presentation::print_app_container_creating(replica_name);
+        docker::create_container(
+            docker,
+            bollard::container::CreateContainerOptions {
+                name: replica_name.as_str(),
+                platform: container_config.platform(),
+            },
+            config,
+        )
+        .await?;
+
+        presentation::print_app_container_starting(replica_name);
+        docker::start_container(docker, replica_name).await?;
+
+        presentation::print_app_container_success(replica_name);
     }
 
-    if context.should_create_network() {
-        presentation::print_network_creating();
-        network::create_dploy_network(docker).await?;
+    Ok(())
+}
+
+/// Starts the new container next to the old one, switches the proxy to it
+/// once it's ready, and removes the old container after the drain period.
+/// The smoke test runs while the old container still runs, so the proxy can be switched back
+async fn replace_app_container_blue_green(
+    container_config: &services::ContainerConfig,
+    proxy: &services::proxy::ProxyService,
+    smoke_test: Option<&SmokeTest<'_>>,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<()> {
+    let container_name = container_config.container_name();
+    let next_container_name = format!(
+        "{container_name}_{}",
+        time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)?
+            .as_secs()
+    );
+
+    context.set_deploy_phase(context::DeployPhase::StartingAppCandidate {
+        container_name: next_container_name.clone(),
+    });
+
+    let mut config = container_config.config().clone();
+
+    // the alias keeps the new container reachable under the same name
+    // after it's renamed, so the proxy never points to a missing upstream
+    if let Some(endpoint) = config
+        .networking_config
+        .as_mut()
+        .and_then(|networking| networking.endpoints_config.get_mut(network::DPLOY_NETWORK))
+    {
+        endpoint
+            .aliases
+            .get_or_insert_with(Vec::new)
+            .push(next_container_name.clone());
     }
 
-    presentation::print_dependencies_starting();
-    deploy_dependencies(services, context, docker).await?;
+    presentation::print_app_container_creating(&next_container_name);
+    docker::create_container(
+        docker,
+        bollard::container::CreateContainerOptions {
+            name: next_container_name.as_str(),
+            platform: container_config.platform(),
+        },
+        config,
+    )
+    .await?;
+
+    presentation::print_app_container_starting(&next_container_name);
+    docker::start_container(docker, &next_container_name).await?;
+
+    presentation::print_app_container_waiting_ready(&next_container_name);
+    let is_ready =
+        docker::wait_for_container_ready(docker, &next_container_name, BLUE_GREEN_READY_TIMEOUT)
+            .await?;
 
-    if let Some(service) = services.app() {
-        deploy_app_service(service, context, docker).await?;
+    if !is_ready {
+        docker::ensure_container_absent(
+            docker,
+            &next_container_name,
+            container_config.stop_timeout(),
+        )
+        .await?;
+        bail!("Container {next_container_name} did not become ready. The old container is kept running");
     }
 
-    presentation::print_post_up_running();
-    services.post_up(docker).await?;
+    presentation::print_proxy_updating(&next_container_name);
+    proxy.route_to(docker, &[&next_container_name]).await?;
 
-    if context.should_print_connection_info() {
-        let connection_info = services.connection_info();
-        presentation::print_connection_info(&connection_info);
+    if let Some(smoke_test) = smoke_test {
+        let rollback = context::AppRollback::BlueGreen {
+            candidate_name: next_container_name.clone(),
+        };
+
+        check_smoke_test(smoke_test, Some(rollback), proxy, context, docker).await?;
     }
 
+    // from now on the proxy relies on the new container
+    context.set_deploy_phase(context::DeployPhase::StartingApp);
+
+    presentation::print_app_container_draining(container_name);
+    tokio::time::sleep(BLUE_GREEN_DRAIN_PERIOD).await;
+
+    docker::ensure_container_absent(docker, container_name, container_config.stop_timeout())
+        .await?;
+    docker
+        .rename_container(
+            &next_container_name,
+            bollard::container::RenameContainerOptions {
+                name: container_name,
+            },
+        )
+        .await?;
+
+    proxy.route_to(docker, &[container_name]).await?;
+
+    presentation::print_app_container_success(container_name);
+
     Ok(())
 }
 
-pub async fn deploy_watch(
-    context: Arc<context::Context>,
-    docker: Arc<bollard::Docker>,
+/// A locked deploy needs an existing lockfile, a full deploy starts a new one if it's missing
+fn read_lockfile(context: &context::Context) -> Result<Option<lockfile::Lockfile>> {
+    let command = context.args().command();
+
+    if !command.locked() {
+        return Ok(context
+            .should_update_lockfile()
+            .then(|| lockfile::Lockfile::read(context))
+            .transpose()?
+            .map(Option::unwrap_or_default));
+    }
+
+    let path = lockfile::Lockfile::path(context);
+
+    let Some(lockfile) = lockfile::Lockfile::read(context)? else {
+        bail!(
+            "{} does not exist. Deploy without --locked first to create it",
+            path.display()
+        );
+    };
+
+    if command.frozen() && lockfile.config_hash() != lockfile::config_hash(context)? {
+        bail!(
+            "The config changed since {} was written. Deploy without --frozen to update it",
+            path.display()
+        );
+    }
+
+    Ok(Some(lockfile))
+}
+
+fn update_lockfile(
+    lockfile: &mut lockfile::Lockfile,
     services: &services::Services,
-    watch_paths: &[&Path],
+    context: &context::Context,
 ) -> Result<()> {
-    if watch_paths.is_empty() {
-        bail!("Called with --watch flag but no paths were provided. Please provide at least one path to watch in the dploy.toml");
+    let container_configs = services.to_container_configs(context)?;
+    let images = container_configs
+        .iter()
+        .map(|config| config.image_name())
+        .collect::<Vec<_>>();
+
+    lockfile.retain_images(&images);
+    lockfile.set_config_hash(lockfile::config_hash(context)?);
+    lockfile.write(context)
+}
+
+/// SHA-256 of everything that ends up in the deployed containers: their configs
+/// (including resolved env vars), the inputs of the app image and the proxy routes
+fn compute_deploy_hash(
+    services: &services::Services,
+    context: &context::Context,
+    has_app_image: bool,
+) -> Result<String> {
+    let mut container_configs = services.to_container_configs(context)?;
+
+    if let Some(app) = services.app() {
+        container_configs.push(app.to_container_config(context)?);
     }
 
-    deploy(&context, &docker, services).await?;
-    let mut handle = tokio::spawn(commands::logs::logs(
-        Arc::clone(&context),
-        Arc::clone(&docker),
-        services::ServiceKind::App,
-        None,
-    ));
+    let containers = container_configs
+        .iter()
+        .map(|config| {
+            Ok(serde_json::json!({
+                "name": config.container_name(),
+                "config": serde_json::to_value(config.config())?,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let build_inputs = has_app_image
+        .then(|| build::build_inputs_digest(context))
+        .transpose()?;
+
+    // serializing through `Value` sorts map keys, so the output is stable
+    let contents = serde_json::json!({
+        "containers": containers,
+        "build_inputs": build_inputs,
+        "config": serde_json::to_value(context.app_config().resolve(context.override_context()))?,
+        "replicas": context.app_replicas(),
+        "files": services.inline_files(context),
+        "proxy": services.proxy().service_config_contents(),
+    });
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, contents.to_string().as_bytes());
+
+    Ok(utils::string::to_hex(digest.as_ref()))
+}
 
-    let (tx, rx) = std::sync::mpsc::channel();
-    let (tx_abort, rx_abort) = std::sync::mpsc::channel();
+/// The hash of the last successful deploy is kept on the docker host,
+/// so it's shared by everyone deploying to it. The local docker host is this machine,
+/// so there it's kept in the local state without going through a container
+async fn read_deploy_hash(
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<Option<String>> {
+    if context.ssh_credentials().is_none() {
+        return Ok(state::read(context, &deploy_hash_file_name(context))
+            .map(|hash| hash.trim().to_owned()));
+    }
 
-    let mut debouncer = notify_debouncer_full::new_debouncer(WATCH_POLL_INTERVAL, None, tx)?;
+    let hash = docker::read_host_file(
+        docker,
+        &context.host_state_dir().to_string_lossy(),
+        &deploy_hash_file_name(context),
+    )
+    .await?;
 
-    let watcher = debouncer.watcher();
+    Ok(hash.map(|hash| hash.trim().to_owned()))
+}
 
-    for path in watch_paths {
-        watcher
-            .watch(path, notify::RecursiveMode::Recursive)
-            .context("Could not start watcher. Please make sure the folder exists")?;
+async fn write_deploy_hash(
+    context: &context::Context,
+    docker: &bollard::Docker,
+    deploy_hash: &str,
+) -> Result<()> {
+    if context.ssh_credentials().is_none() {
+        return state::write(context, &deploy_hash_file_name(context), deploy_hash);
     }
 
-    ctrlc::set_handler(move || {
-        presentation::print_ctrlc_received();
-        tx_abort.send(()).unwrap();
-    })?;
+    docker::write_host_files(
+        docker,
+        &context.host_state_dir().to_string_lossy(),
+        &[(deploy_hash_file_name(context), deploy_hash.to_owned())],
+    )
+    .await
+}
 
-    let mut last_deploy = time::Instant::now();
+/// One file per app and namespace, named after the app container
+fn deploy_hash_file_name(context: &context::Context) -> String {
+    format!(
+        "{}.{DEPLOY_HASH_FILE_EXTENSION}",
+        context.container_name_of(&services::ServiceKind::App)
+    )
+}
 
-    // don't care about blocking here
-    loop {
-        if rx_abort.try_recv().is_ok() {
-            break;
+/// With `--no-deps` the app expects its dependencies to be started already
+async fn check_dependencies_running(
+    services: &services::Services,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<()> {
+    for config in services.to_container_configs(context)? {
+        let container_name = config.container_name();
+
+        if !docker::check_container_running(docker, container_name).await? {
+            bail!("Dependency {container_name} is not running. Start it without --no-deps first");
         }
+    }
 
-        if let Ok(Ok(events)) = rx.try_recv() {
-            if time::Instant::now() - last_deploy < WATCH_COOLDOWN
-                || events.is_empty()
-                || !events.iter().any(|event| event.kind.is_modify())
-            {
-                continue;
-            }
+    Ok(())
+}
+
+async fn check_services_running(
+    services: &services::Services,
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<bool> {
+    let mut container_names = services
+        .to_container_configs(context)?
+        .iter()
+        .map(|config| config.container_name().to_owned())
+        .collect::<Vec<_>>();
+
+    if services.app().is_some() {
+        container_names.extend(context.app_container_names());
+    }
+
+    for container_name in container_names {
+        if !docker::check_container_running(docker, &container_name).await? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
 
-            presentation::print_watch_files_changed();
+/// Sets `DPLOY_GIT_SHA` to the current commit, for the config and the app container.
+/// A value from the environment is kept, and outside of git it stays unset
+pub fn set_git_sha_env(context: &context::Context) {
+    if std::env::var_os(constants::GIT_SHA_ENV).is_some() {
+        return;
+    }
 
-            handle.abort();
+    if let Some(git_sha) = utils::git::git_hash(&context.config_dir()) {
+        std::env::set_var(constants::GIT_SHA_ENV, git_sha);
+    }
+}
 
-            if let Some(service) = services.app() {
-                deploy_app_service(service, &context, &docker).await?;
+/// Loads `env_file`, then `env_files` from the config and `--env-file` from the command line.
+/// Later files take precedence, variables already set in the environment are never overridden
+pub fn load_env_files(context: &context::Context) -> Result<()> {
+    let app_config = context.app_config();
+
+    let mut env_files = vec![(app_config.env_file(context.override_context()), false)];
+
+    env_files.extend(
+        app_config
+            .env_files(context.override_context())
+            .iter()
+            .map(|env_file| (env_file.path(), env_file.is_required())),
+    );
+
+    // files passed explicitly are expected to exist
+    env_files.extend(
+        context
+            .args()
+            .env_files()
+            .iter()
+            .map(|path| (path.as_str(), true)),
+    );
+
+    let mut results = vec![];
+
+    // dotenvy keeps variables which are already set,
+    // so the files are loaded from the last one
+    for (path, is_required) in env_files.into_iter().rev() {
+        let result = dotenvy::from_path(path);
+
+        if let Err(error) = result {
+            if is_required {
+                return Err(error).with_context(|| format!("Failed to load env file {path}"));
             }
 
-            handle = tokio::spawn(commands::logs::logs(
-                Arc::clone(&context),
-                Arc::clone(&docker),
-                services::ServiceKind::App,
-                None,
-            ));
+            results.push((path, false));
+        } else {
+            results.push((path, true));
+        }
+    }
 
-            last_deploy = time::Instant::now();
+    for (path, is_loaded) in results.into_iter().rev() {
+        if is_loaded {
+            presentation::print_env_file_loaded(path);
+        } else {
+            presentation::print_env_file_failed_to_load(path);
         }
     }
 
-    handle.abort();
+    Ok(())
+}
 
-    presentation::print_ctrlc_started();
+/// Contents of the env file which is about to be generated
+struct PlannedEnv {
+    existing_env: Option<BTreeMap<String, String>>,
+    services_env_vars: Vec<(String, String)>,
+    own_env_vars: Vec<(String, String)>,
+}
 
-    Ok(())
+impl PlannedEnv {
+    fn from_services(services: &services::Services, context: &context::Context) -> Self {
+        let existing_env =
+            get_existing_env(context.app_config().env_file(context.override_context()));
+
+        let services_env_vars = services.env_vars(context);
+        let own_env_vars = collect_own_env_vars(
+            context.app_config().env(context.override_context()),
+            existing_env.as_ref().unwrap_or(&BTreeMap::new()),
+            &services_env_vars,
+        );
+
+        Self {
+            existing_env,
+            services_env_vars,
+            own_env_vars,
+        }
+    }
+
+    /// `(key, old value, new value)` of the variables which differ from the existing file
+    fn changes(&self) -> Vec<(&str, Option<&str>, Option<&str>)> {
+        let new_env = self
+            .services_env_vars
+            .iter()
+            .chain(&self.own_env_vars)
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut changes = vec![];
+
+        for (key, new_value) in &new_env {
+            let old_value = self
+                .existing_env
+                .as_ref()
+                .and_then(|existing_env| existing_env.get(*key))
+                .map(String::as_str);
+
+            if old_value != Some(*new_value) {
+                changes.push((*key, old_value, Some(*new_value)));
+            }
+        }
+
+        for (key, old_value) in self.existing_env.iter().flatten() {
+            if !new_env.contains_key(key.as_str()) {
+                changes.push((key.as_str(), Some(old_value.as_str()), None));
+            }
+        }
+
+        changes
+    }
 }
 
-async fn deploy_app_service(
-    app_service: &services::app::AppService,
+fn generate_env(
+    services: &services::Services,
     context: &context::Context,
-    docker: &bollard::Docker,
+    should_confirm: bool,
 ) -> Result<()> {
-    let container_config = app_service.to_container_config(context)?;
-    let container_name = container_config.container_name();
-    let dockerfile = context.app_config().dockerfile(context.override_context());
+    let planned_env = PlannedEnv::from_services(services, context);
 
-    presentation::print_image_building(container_name, dockerfile);
-    build::build_app_service_image(context, app_service, docker).await?;
-    presentation::print_image_built(container_name);
+    if should_confirm {
+        confirm_env_overwrite(context, &planned_env)?;
+    }
 
-    let existing_container = match docker.inspect_container(container_name, None).await {
-        Ok(container) => Some(container),
-        Err(bollard::errors::Error::DockerResponseServerError {
-            status_code: 404, ..
-        }) => None,
-        Err(e) => return Err(e.into()),
-    };
+    generate_env_file(&planned_env, context)?;
 
-    if existing_container.is_some() {
-        presentation::print_app_container_removing(container_name);
-        docker.stop_container(container_name, None).await?;
-        docker.remove_container(container_name, None).await?;
+    if planned_env.existing_env.is_none() {
+        presentation::print_env_file_generated();
     }
 
-    presentation::print_app_container_creating(container_name);
-    docker
-        .create_container(
-            Some(bollard::container::CreateContainerOptions {
-                name: container_name,
-                ..Default::default()
-            }),
-            container_config.config().clone(),
-        )
-        .await?;
+    Ok(())
+}
 
-    presentation::print_app_container_starting(container_name);
-    docker
-        .start_container(
-            container_name,
-            None::<bollard::container::StartContainerOptions<String>>,
-        )
-        .await?;
+/// Prints the changes `dev` or `run` would make to the env file, see `--env-diff`
+pub fn print_env_diff(services: &services::Services, context: &context::Context) {
+    let planned_env = PlannedEnv::from_services(services, context);
+    let changes = planned_env.changes();
 
-    presentation::print_app_container_success(container_name);
+    if changes.is_empty() {
+        presentation::print_env_file_unchanged();
+        return;
+    }
 
-    Ok(())
+    print_env_changes(&changes);
 }
 
-fn generate_env(services: &services::Services, context: &context::Context) -> Result<()> {
-    let existing_env = get_existing_env(context.app_config().env_file(context.override_context()));
-    let is_generated_first_time = existing_env.is_none();
-    let existing_env = existing_env.unwrap_or_default();
-
-    let services_env_vars = services.env_vars(context);
-    let mut own_env_vars_names = HashSet::new();
+fn print_env_changes(changes: &[(&str, Option<&str>, Option<&str>)]) {
+    for (key, old_value, new_value) in changes {
+        let old_value = old_value.map(|value| utils::string::mask_env_value(key, value));
+        let new_value = new_value.map(|value| utils::string::mask_env_value(key, value));
 
-    for env_name in context.app_config().env(context.override_context()) {
-        own_env_vars_names.insert(env_name.clone());
+        presentation::print_env_change(key, old_value.as_deref(), new_value.as_deref());
     }
+}
 
-    for env_name in existing_env.keys() {
-        own_env_vars_names.insert(env_name.clone());
-    }
+/// Asks before values edited by hand are overwritten. Values which are the same as
+/// in the last generated file, like the ports picked by dploy, are replaced silently
+fn confirm_env_overwrite(context: &context::Context, planned_env: &PlannedEnv) -> Result<()> {
+    let Some(existing_env) = &planned_env.existing_env else {
+        return Ok(());
+    };
 
-    for (env_name, _) in &services_env_vars {
-        own_env_vars_names.remove(env_name);
+    let is_interactive = std::io::stdin().is_terminal()
+        && console::user_attended_stderr()
+        && !presentation::is_json_events();
+
+    if !is_interactive {
+        return Ok(());
     }
 
-    let own_env_vars = {
-        let mut own_env_vars = vec![];
+    let last_generated_env = state::read(context, GENERATED_ENV_STATE_KEY)
+        .map(|contents| parse_env(&contents))
+        .unwrap_or_default();
 
-        for env_name in own_env_vars_names {
-            let env_value = &existing_env
-                .get(&env_name)
-                .map(|value| value.to_owned())
-                .unwrap_or_else(|| "".to_owned());
+    let edited_changes = planned_env
+        .changes()
+        .into_iter()
+        .filter(|(key, old_value, _)| {
+            old_value.is_some() && last_generated_env.get(*key) != existing_env.get(*key)
+        })
+        .collect::<Vec<_>>();
 
-            own_env_vars.push((env_name.clone(), env_value.clone()));
-        }
+    if edited_changes.is_empty() {
+        return Ok(());
+    }
 
-        own_env_vars
-    };
+    print_env_changes(&edited_changes);
 
-    generate_env_file(&services_env_vars, &own_env_vars, context)?;
+    let env_file = context.app_config().env_file(context.override_context());
+    let is_accepted = dialoguer::Confirm::with_theme(&dialoguer::theme::ColorfulTheme::default())
+        .with_prompt(format!("Overwrite these variables in {env_file}?"))
+        .default(false)
+        .interact()
+        .context("Failed to ask for confirmation")?;
 
-    if is_generated_first_time {
-        presentation::print_env_file_generated();
+    if !is_accepted {
+        bail!("Overwriting the env file was not confirmed");
     }
 
     Ok(())
 }
 
+/// Variables of the config and the existing env file which dploy doesn't set itself,
+/// sorted by name so the generated file doesn't change between runs
+fn collect_own_env_vars(
+    env_names: &[String],
+    existing_env: &BTreeMap<String, String>,
+    services_env_vars: &[(String, String)],
+) -> Vec<(String, String)> {
+    let mut own_env_vars_names = env_names
+        .iter()
+        .chain(existing_env.keys())
+        .collect::<BTreeSet<_>>();
+
+    for (env_name, _) in services_env_vars {
+        own_env_vars_names.remove(env_name);
+    }
+
+    own_env_vars_names
+        .into_iter()
+        .map(|env_name| {
+            let env_value = existing_env.get(env_name).cloned().unwrap_or_default();
+
+            (env_name.clone(), env_value)
+        })
+        .collect()
+}
+
 fn get_existing_env(env_file_name: &str) -> Option<BTreeMap<String, String>> {
     let mut existing_env = BTreeMap::new();
     let env_file_path = Path::new(env_file_name);
@@ -255,87 +2335,308 @@ fn get_existing_env(env_file_name: &str) -> Option<BTreeMap<String, String>> {
     Some(existing_env)
 }
 
-fn generate_env_file(
+fn generate_env_file(planned_env: &PlannedEnv, context: &context::Context) -> Result<()> {
+    let contents = format_env_file(&planned_env.services_env_vars, &planned_env.own_env_vars);
+    let mut file = fs::File::create(context.app_config().env_file(context.override_context()))?;
+
+    file.write_all(contents.as_bytes())?;
+
+    // tells values edited by hand apart from generated ones next time
+    state::write(context, GENERATED_ENV_STATE_KEY, &contents)?;
+
+    Ok(())
+}
+
+fn parse_env(contents: &str) -> BTreeMap<String, String> {
+    dotenvy::from_read_iter(contents.as_bytes())
+        .filter_map(Result::ok)
+        .collect()
+}
+
+/// Services variables keep their order, own variables come after the comment
+fn format_env_file(
     services_env_vars: &[(String, String)],
     own_env_vars: &[(String, String)],
-    context: &context::Context,
-) -> Result<()> {
-    let mut file = fs::File::create(context.app_config().env_file(context.override_context()))?;
+) -> String {
+    let mut contents = String::new();
 
     for (key, value) in services_env_vars {
-        writeln!(file, "{}={}", key, value)?;
+        contents.push_str(&format!(
+            "{}={}\n",
+            key,
+            utils::string::quote_env_value(value)
+        ));
     }
 
-    writeln!(file, "\n# Your own variables come after this line")?;
-    writeln!(file, "# Feel free to modify them as you want")?;
+    contents.push_str("\n# Your own variables come after this line\n");
+    contents.push_str("# Feel free to modify them as you want\n");
 
     for (key, value) in own_env_vars {
-        writeln!(file, "{}={}", key, value)?;
+        contents.push_str(&format!(
+            "{}={}\n",
+            key,
+            utils::string::quote_env_value(value)
+        ));
     }
 
-    Ok(())
+    contents
 }
 
+/// Returns the number of started containers
 async fn deploy_dependencies(
     services: &services::Services,
+    recreated_volumes: &[RecreatedVolumes],
+    lockfile: &mut Option<lockfile::Lockfile>,
     context: &context::Context,
     docker: &bollard::Docker,
-) -> Result<()> {
-    let container_configs = services.to_container_configs(context)?;
-
-    for config in container_configs {
-        let container_name = config.container_name();
-        let image_name = config.image_name();
-        let config = config.config();
+) -> Result<usize> {
+    let selected_container_names = services
+        .kinds(context)
+        .iter()
+        .filter(|kind| context.should_deploy_service(kind))
+        .map(|kind| context.container_name_of(kind))
+        .collect::<HashSet<_>>();
+
+    let container_configs = services
+        .to_container_configs(context)?
+        .into_iter()
+        .filter(|config| selected_container_names.contains(config.container_name()))
+        .collect::<Vec<_>>();
+    let count = container_configs.len();
+    let mut is_rebound = false;
+
+    for mut config in container_configs {
+        let container_name = config.container_name().to_owned();
+        let container_name = container_name.as_str();
+        let image_name = config.image_name().to_owned();
+
+        if let Some(lockfile) = lockfile
+            .as_ref()
+            .filter(|_| context.args().command().locked() && config.digest().is_none())
+        {
+            let Some(digest) = lockfile.digest_of(&image_name) else {
+                bail!("dploy.lock has no digest of image {image_name}. Deploy without --locked to update it");
+            };
+
+            config.pin_digest(digest)?;
+        }
 
         presentation::print_dependency_pulling(container_name);
-        docker
-            .create_image(
-                Some(bollard::image::CreateImageOptions {
-                    from_image: image_name,
-                    // TODO: allow users to set tag
-                    tag: "latest",
-                    ..Default::default()
-                }),
-                None,
-                None,
-            )
-            .try_collect::<Vec<_>>()
-            .await?;
+        docker::pull_image(docker, config.image_name(), config.platform()).await?;
 
-        // TODO: check here if container exists and version is the same
-        let existing_container = docker::inspect_container(docker, container_name).await?;
+        if let Some(digest) = config.digest() {
+            docker::verify_image_digest(docker, config.image_name(), digest).await?;
+        }
 
-        presentation::print_dependency_creating(container_name);
+        if let Some(lockfile) = lockfile
+            .as_mut()
+            .filter(|_| context.should_update_lockfile())
+        {
+            let digests = docker::image_repo_digests(docker, config.image_name()).await?;
 
-        if existing_container.is_some() {
-            if docker::check_container_running(docker, container_name).await? {
-                docker.stop_container(container_name, None).await?;
+            // images which only exist locally can't be locked
+            if let Some(digest) = digests.into_iter().next() {
+                lockfile.set_digest(&image_name, digest);
             }
+        }
 
-            docker.remove_container(container_name, None).await?;
+        presentation::print_dependency_creating(container_name);
+
+        if let Some(volumes) = recreated_volumes
+            .iter()
+            .find(|volumes| volumes.container_name == container_name)
+        {
+            recreate_volumes(volumes, docker).await?;
         }
 
-        docker
-            .create_container(
-                Some(bollard::container::CreateContainerOptions {
-                    name: container_name,
-                    ..Default::default()
-                }),
-                config.clone(),
-            )
-            .await?;
+        let mut attempt = 1;
 
-        presentation::print_dependency_starting(container_name);
-        docker
-            .start_container(
-                container_name,
-                None::<bollard::container::StartContainerOptions<String>>,
-            )
-            .await?;
+        // another process may take a free port between picking it and starting the container
+        while let Err(error) = create_and_start_dependency(&config, docker).await {
+            if !docker::is_port_taken_error(&error) || attempt >= PORT_RETRY_ATTEMPTS {
+                return Err(error);
+            }
+
+            let rebound_ports = services.rebind_ports_of(context, container_name)?;
+
+            if rebound_ports.is_empty() {
+                return Err(error);
+            }
+
+            for (old_port, new_port) in rebound_ports {
+                presentation::print_container_warning(
+                    container_name,
+                    &format!("Port {old_port} is already taken, retrying with port {new_port}"),
+                );
+            }
+
+            config = services
+                .to_container_configs(context)?
+                .into_iter()
+                .find(|config| config.container_name() == container_name)
+                .context("Dependency disappeared from the config")?;
+
+            attempt += 1;
+            is_rebound = true;
+        }
 
         presentation::print_dependency_success(container_name);
     }
 
+    // the env file still points to the ports which were taken
+    if is_rebound && context.should_generate_env_file() {
+        presentation::print_env_file_generating();
+        generate_env(services, context, false)?;
+    }
+
+    Ok(count)
+}
+
+async fn create_and_start_dependency(
+    config: &services::ContainerConfig,
+    docker: &bollard::Docker,
+) -> Result<()> {
+    let container_name = config.container_name();
+
+    // TODO: check here if container exists and version is the same
+    docker::ensure_container_absent(docker, container_name, config.stop_timeout()).await?;
+
+    docker::create_container(
+        docker,
+        bollard::container::CreateContainerOptions {
+            name: container_name,
+            platform: config.platform(),
+        },
+        config.config().clone(),
+    )
+    .await?;
+
+    presentation::print_dependency_starting(container_name);
+    docker::start_container(docker, container_name).await?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_env_file_is_stable() {
+        let services_env_vars = vec![
+            ("APP_POSTGRES_URL".to_owned(), "postgres://db".to_owned()),
+            ("APP_ELASTIC_URL".to_owned(), "http://elastic".to_owned()),
+        ];
+        let existing_env = BTreeMap::from([
+            ("ZETA".to_owned(), "1".to_owned()),
+            ("APP_POSTGRES_URL".to_owned(), "postgres://old".to_owned()),
+            ("ALPHA".to_owned(), "2".to_owned()),
+        ]);
+
+        let generate = |env_names: &[String]| {
+            let own_env_vars = collect_own_env_vars(env_names, &existing_env, &services_env_vars);
+
+            format_env_file(&services_env_vars, &own_env_vars)
+        };
+
+        let first = generate(&["MIDDLE".to_owned(), "BETA".to_owned()]);
+        let second = generate(&["BETA".to_owned(), "MIDDLE".to_owned()]);
+
+        assert_eq!(first, second);
+        assert_eq!(
+            first,
+            concat!(
+                "APP_POSTGRES_URL=postgres://db\n",
+                "APP_ELASTIC_URL=http://elastic\n",
+                "\n# Your own variables come after this line\n",
+                "# Feel free to modify them as you want\n",
+                "ALPHA=2\n",
+                "BETA=\n",
+                "MIDDLE=\n",
+                "ZETA=1\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_blue_green_rejects_static_address() {
+        let with_ip = config::ContainerOptions {
+            ip: Some("172.30.0.10".to_owned()),
+            ..Default::default()
+        };
+        let with_mac_address = config::ContainerOptions {
+            mac_address: Some("02:42:ac:11:00:02".to_owned()),
+            ..Default::default()
+        };
+
+        for options in [&with_ip, &with_mac_address] {
+            assert!(
+                check_strategy_options(config::DeployStrategy::BlueGreen, Some(options)).is_err()
+            );
+            assert!(
+                check_strategy_options(config::DeployStrategy::Recreate, Some(options)).is_ok()
+            );
+        }
+
+        assert!(check_strategy_options(
+            config::DeployStrategy::BlueGreen,
+            Some(&config::ContainerOptions::default())
+        )
+        .is_ok());
+        assert!(check_strategy_options(config::DeployStrategy::BlueGreen, None).is_ok());
+    }
+
+    #[test]
+    fn test_migrations_container_has_no_static_addresses() {
+        let app_config = bollard::container::Config {
+            networking_config: Some(bollard::container::NetworkingConfig {
+                endpoints_config: std::collections::HashMap::from([(
+                    network::DPLOY_NETWORK.to_owned(),
+                    bollard::models::EndpointSettings {
+                        ipam_config: Some(bollard::models::EndpointIpamConfig {
+                            ipv4_address: Some("172.30.0.10".to_owned()),
+                            ..Default::default()
+                        }),
+                        mac_address: Some("02:42:ac:11:00:02".to_owned()),
+                        aliases: Some(vec!["app".to_owned()]),
+                        ..Default::default()
+                    },
+                )]),
+            }),
+            ..Default::default()
+        };
+
+        let config = migrations_container_config(&app_config, "./migrate");
+        let endpoint = &config.networking_config.unwrap().endpoints_config[network::DPLOY_NETWORK];
+
+        assert_eq!(endpoint.ipam_config, None);
+        assert_eq!(endpoint.mac_address, None);
+        assert_eq!(endpoint.aliases, None);
+        assert_eq!(
+            config.entrypoint,
+            Some(vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "./migrate".to_owned()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_interpolate_smoke_test() {
+        let placeholders = [
+            ("HOST_PORT", None),
+            ("URL", Some("https://app.example.com".to_owned())),
+        ];
+
+        assert_eq!(
+            interpolate_smoke_test(r#"curl -f "${URL}/health""#, &placeholders).unwrap(),
+            r#"curl -f "https://app.example.com/health""#
+        );
+        assert_eq!(
+            interpolate_smoke_test("test $DPLOY_HOST", &placeholders).unwrap(),
+            "test $DPLOY_HOST"
+        );
+        assert!(interpolate_smoke_test("nc -z localhost ${HOST_PORT}", &placeholders).is_err());
+    }
+}
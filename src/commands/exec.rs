@@ -4,20 +4,48 @@ use crate::{context, docker, prelude::*, presentation, services};
 pub struct ExecArgs {
     service: services::ServiceKind,
     command: String,
+    detach: bool,
+    no_stdout: bool,
+    no_stderr: bool,
 }
 
 impl ExecArgs {
     pub fn new(service: services::ServiceKind, command: String) -> Self {
-        Self { service, command }
+        Self {
+            service,
+            command,
+            detach: false,
+            no_stdout: false,
+            no_stderr: false,
+        }
     }
 
-    pub fn service(&self) -> services::ServiceKind {
-        self.service
+    pub fn with_streams(mut self, detach: bool, no_stdout: bool, no_stderr: bool) -> Self {
+        self.detach = detach;
+        self.no_stdout = no_stdout;
+        self.no_stderr = no_stderr;
+        self
+    }
+
+    pub fn service(&self) -> &services::ServiceKind {
+        &self.service
     }
 
     pub fn command(&self) -> &str {
         &self.command
     }
+
+    pub fn detach(&self) -> bool {
+        self.detach
+    }
+
+    pub fn no_stdout(&self) -> bool {
+        self.no_stdout
+    }
+
+    pub fn no_stderr(&self) -> bool {
+        self.no_stderr
+    }
 }
 
 pub async fn exec(
@@ -26,15 +54,45 @@ pub async fn exec(
     args: &ExecArgs,
 ) -> Result<()> {
     let service_kind = args.service();
-    let container_name = context.container_name_of(service_kind);
+    let service_container_name = context.container_name_of(service_kind);
+
+    // the first running replica of `deploy --scale`
+    let mut container_name = None;
 
-    let is_running = docker::check_container_running(docker, &container_name).await?;
-    if !is_running {
-        bail!("{container_name} is not running");
+    for name in
+        docker::service_container_names(docker, service_kind, &service_container_name).await?
+    {
+        if docker::check_container_running(docker, &name).await? {
+            container_name = Some(name);
+            break;
+        }
     }
 
+    let Some(container_name) = container_name else {
+        bail!("{service_container_name} is not running");
+    };
+
     let command = args.command();
 
+    if args.detach() {
+        docker::exec_command_detached(docker, &container_name, command).await?;
+        presentation::print_command_detached(&container_name);
+        return Ok(());
+    }
+
+    if args.no_stdout() || args.no_stderr() {
+        // without a tty the streams can be told apart, so nothing else is printed
+        docker::exec_command_streams(
+            docker,
+            &container_name,
+            command,
+            !args.no_stdout(),
+            !args.no_stderr(),
+        )
+        .await?;
+        return Ok(());
+    }
+
     presentation::print_command_executing(&container_name);
     docker::exec_command_attached(docker, &container_name, command).await?;
 
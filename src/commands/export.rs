@@ -0,0 +1,493 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use bollard::models;
+use serde::Serialize;
+
+use crate::{
+    build, cli, config, context,
+    network::DPLOY_NETWORK,
+    prelude::*,
+    services::{self, ToContainerConfig},
+};
+
+#[derive(Debug, Serialize)]
+struct ComposeFile {
+    services: BTreeMap<String, ComposeService>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    networks: BTreeMap<String, ComposeNetwork>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ComposeService {
+    image: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build: Option<ComposeBuild>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    platform: Option<String>,
+
+    container_name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entrypoint: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    environment: Vec<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    ports: Vec<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    volumes: Vec<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tmpfs: Vec<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    devices: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cap_add: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cap_drop: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    security_opt: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    privileged: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_only: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    init: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_search: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_signal: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_grace_period: Option<String>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    ulimits: BTreeMap<String, config::UlimitConfig>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    sysctls: BTreeMap<String, String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipc: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpuset: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network_mode: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restart: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    healthcheck: Option<ComposeHealthcheck>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    networks: BTreeMap<String, ComposeServiceNetwork>,
+}
+
+#[derive(Debug, Serialize)]
+struct ComposeBuild {
+    context: String,
+    dockerfile: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ComposeHealthcheck {
+    test: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_period: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retries: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ComposeServiceNetwork {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aliases: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv4_address: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6_address: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mac_address: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ComposeNetwork {
+    name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipam: Option<ComposeIpam>,
+}
+
+#[derive(Debug, Serialize)]
+struct ComposeIpam {
+    config: Vec<ComposeIpamConfig>,
+}
+
+#[derive(Debug, Serialize)]
+struct ComposeIpamConfig {
+    subnet: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gateway: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip_range: Option<String>,
+}
+
+pub fn export(
+    context: &context::Context,
+    services: &services::Services,
+    command: &cli::ExportCommand,
+) -> Result<()> {
+    match command {
+        cli::ExportCommand::Compose { output } => compose(context, services, output.as_deref()),
+    }
+}
+
+/// The `[network]` settings, needed by compose for static addresses
+fn to_compose_ipam(context: &context::Context) -> Option<ComposeIpam> {
+    let network = context.app_config().network(context.override_context())?;
+
+    Some(ComposeIpam {
+        config: vec![ComposeIpamConfig {
+            subnet: network.subnet()?.to_owned(),
+            gateway: network.gateway().map(ToOwned::to_owned),
+            ip_range: network.ip_range().map(ToOwned::to_owned),
+        }],
+    })
+}
+
+/// Writes the services of the current command as a docker-compose.yml.
+/// Features compose can't express are listed as comments at the top
+fn compose(
+    context: &context::Context,
+    services: &services::Services,
+    output: Option<&Path>,
+) -> Result<()> {
+    let mut container_configs = services.to_container_configs(context)?;
+
+    if let Some(app) = services.app() {
+        container_configs.push(app.to_container_config(context)?);
+    }
+
+    let mut compose_file = ComposeFile {
+        services: BTreeMap::new(),
+        networks: BTreeMap::new(),
+    };
+
+    for container_config in &container_configs {
+        let service = to_compose_service(container_config);
+
+        for network in service.networks.keys() {
+            compose_file.networks.insert(
+                network.clone(),
+                ComposeNetwork {
+                    name: network.clone(),
+                    ipam: (network == DPLOY_NETWORK)
+                        .then(|| to_compose_ipam(context))
+                        .flatten(),
+                },
+            );
+        }
+
+        compose_file
+            .services
+            .insert(container_config.container_name().to_owned(), service);
+    }
+
+    if let Some(app) = services.app() {
+        let paths = build::BuildPaths::resolve(context)?;
+
+        if let Some(service) = compose_file.services.get_mut(app.container_name()) {
+            service.build = Some(ComposeBuild {
+                context: context.build_context_dir().to_string_lossy().to_string(),
+                dockerfile: paths.dockerfile().to_string_lossy().replace('\\', "/"),
+            });
+        }
+    }
+
+    let mut contents = String::from("# Generated by dploy\n");
+
+    let mut notes = unsupported_features(context);
+
+    // compose has no equivalent of `--cpuset-mems`
+    if container_configs.iter().any(|config| {
+        config
+            .config()
+            .host_config
+            .as_ref()
+            .is_some_and(|host_config| host_config.cpuset_mems.is_some())
+    }) {
+        notes.push("cpuset_mems, compose can only pin the CPUs".to_owned());
+    }
+
+    for note in notes {
+        contents.push_str(&format!("# Not exported: {note}\n"));
+    }
+
+    contents.push('\n');
+    contents.push_str(
+        &serde_yaml::to_string(&compose_file).context("Failed to serialize the compose file")?,
+    );
+
+    match output {
+        Some(path) => fs::write(path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => print!("{contents}"),
+    }
+
+    Ok(())
+}
+
+fn to_compose_service(container_config: &services::ContainerConfig) -> ComposeService {
+    let config = container_config.config();
+    let host_config = config.host_config.clone().unwrap_or_default();
+
+    let mut ports = host_config
+        .port_bindings
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|(container_port, bindings)| {
+            let container_port = container_port
+                .strip_suffix("/tcp")
+                .unwrap_or(&container_port)
+                .to_owned();
+
+            bindings
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(move |binding| {
+                    let host_port = binding.host_port?;
+
+                    Some(match binding.host_ip {
+                        Some(host_ip) => format!("{host_ip}:{host_port}:{container_port}"),
+                        None => format!("{host_port}:{container_port}"),
+                    })
+                })
+        })
+        .collect::<Vec<_>>();
+    ports.sort();
+
+    let volumes = host_config
+        .mounts
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|mount| {
+            let target = mount.target?;
+
+            Some(match (mount.source, mount.read_only) {
+                (Some(source), Some(true)) => format!("{source}:{target}:ro"),
+                (Some(source), _) => format!("{source}:{target}"),
+                (None, _) => target,
+            })
+        })
+        .collect();
+
+    let mut tmpfs = host_config
+        .tmpfs
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(path, options)| {
+            if options.is_empty() {
+                path
+            } else {
+                format!("{path}:{options}")
+            }
+        })
+        .collect::<Vec<_>>();
+    tmpfs.sort();
+
+    let ulimits = host_config
+        .ulimits
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|ulimit| {
+            Some((
+                ulimit.name?,
+                config::UlimitConfig {
+                    soft: ulimit.soft?,
+                    hard: ulimit.hard?,
+                },
+            ))
+        })
+        .collect();
+
+    let networks = config
+        .networking_config
+        .as_ref()
+        .map(|networking| {
+            networking
+                .endpoints_config
+                .iter()
+                .map(|(name, endpoint)| {
+                    (
+                        name.clone(),
+                        ComposeServiceNetwork {
+                            aliases: endpoint.aliases.clone(),
+                            ipv4_address: endpoint
+                                .ipam_config
+                                .as_ref()
+                                .and_then(|ipam| ipam.ipv4_address.clone()),
+                            ipv6_address: endpoint
+                                .ipam_config
+                                .as_ref()
+                                .and_then(|ipam| ipam.ipv6_address.clone()),
+                            mac_address: endpoint.mac_address.clone(),
+                        },
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ComposeService {
+        image: container_config.image_name().to_owned(),
+        platform: container_config.platform().map(str::to_owned),
+        container_name: container_config.container_name().to_owned(),
+        hostname: config.hostname.clone(),
+        entrypoint: config.entrypoint.clone(),
+        command: config.cmd.clone(),
+        environment: config.env.clone().unwrap_or_default(),
+        ports,
+        volumes,
+        tmpfs,
+        devices: host_config
+            .devices
+            .iter()
+            .flatten()
+            .map(|device| {
+                format!(
+                    "{}:{}:{}",
+                    device.path_on_host.as_deref().unwrap_or_default(),
+                    device.path_in_container.as_deref().unwrap_or_default(),
+                    device.cgroup_permissions.as_deref().unwrap_or_default()
+                )
+            })
+            .collect(),
+        cap_add: host_config.cap_add,
+        cap_drop: host_config.cap_drop,
+        security_opt: host_config.security_opt,
+        privileged: host_config.privileged,
+        read_only: host_config.readonly_rootfs,
+        init: host_config.init,
+        dns: host_config.dns,
+        dns_search: host_config.dns_search,
+        stop_signal: config.stop_signal.clone(),
+        stop_grace_period: config.stop_timeout.map(|seconds| format!("{seconds}s")),
+        ulimits,
+        sysctls: host_config
+            .sysctls
+            .unwrap_or_default()
+            .into_iter()
+            .collect(),
+        pid: host_config.pid_mode,
+        ipc: host_config.ipc_mode,
+        cpuset: host_config.cpuset_cpus,
+        network_mode: host_config.network_mode,
+        restart: host_config
+            .restart_policy
+            .and_then(|policy| policy.name)
+            .map(|name| name.to_string()),
+        healthcheck: config.healthcheck.as_ref().and_then(to_compose_healthcheck),
+        labels: config
+            .labels
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .collect(),
+        networks,
+        ..Default::default()
+    }
+}
+
+fn to_compose_healthcheck(healthcheck: &models::HealthConfig) -> Option<ComposeHealthcheck> {
+    let format_duration = |nanos: &i64| format!("{}ms", nanos / 1_000_000);
+
+    Some(ComposeHealthcheck {
+        test: healthcheck.test.clone()?,
+        interval: healthcheck.interval.as_ref().map(format_duration),
+        timeout: healthcheck.timeout.as_ref().map(format_duration),
+        start_period: healthcheck.start_period.as_ref().map(format_duration),
+        retries: healthcheck.retries,
+    })
+}
+
+/// dploy features which have no equivalent in the exported compose file
+fn unsupported_features(context: &context::Context) -> Vec<String> {
+    let app_config = context.app_config();
+    let override_context = context.override_context();
+
+    let mut notes = vec![];
+
+    let is_deploy = matches!(context.args().command(), cli::Command::Deploy { .. });
+
+    if is_deploy && !app_config.proxy(override_context).is_empty() {
+        notes.push("the proxy and its routes, which dploy configures on deploy".to_owned());
+    }
+
+    if app_config.strategy(override_context) == config::DeployStrategy::BlueGreen {
+        notes.push("the blue-green deploy strategy".to_owned());
+    }
+
+    if let Some(postgres) = app_config.postgres(override_context) {
+        if !postgres.init_scripts().is_empty() {
+            notes.push("postgres init scripts, which dploy runs after startup".to_owned());
+        }
+    }
+
+    if app_config
+        .dev(override_context)
+        .is_some_and(|dev| dev.on_change().is_some())
+    {
+        notes.push("the `dev.on_change` command of watch mode".to_owned());
+    }
+
+    if context.should_expose_to_host() {
+        notes.push("random host ports, dploy picks free ones on every run".to_owned());
+    }
+
+    notes
+}
@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{InfoArgs, InfoOutput},
+    context, docker,
+    prelude::*,
+    presentation,
+    services::{ConnectionInfoItem, ServiceKind},
+    state,
+    utils::{network::probe_tcp, string::mask_env_value},
+};
+
+const CONNECTION_INFO_STATE_KEY: &str = "connection_info.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedConnectionInfo {
+    service: String,
+    info: String,
+    address: Option<(String, u16)>,
+}
+
+#[derive(Debug, Serialize)]
+struct InfoRow {
+    service: String,
+    info: String,
+
+    /// Only known with `--refresh`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ready: Option<bool>,
+}
+
+/// Remembers the connection info of a successful start for `info`
+pub fn save(
+    context: &context::Context,
+    connection_info: &[(ServiceKind, ConnectionInfoItem)],
+) -> Result<()> {
+    let saved = connection_info
+        .iter()
+        .map(|(service_kind, item)| SavedConnectionInfo {
+            service: service_kind.to_string(),
+            info: item.info().to_owned(),
+            address: item.address().map(|(host, port)| (host.to_owned(), port)),
+        })
+        .collect::<Vec<_>>();
+
+    state::write(
+        context,
+        CONNECTION_INFO_STATE_KEY,
+        &serde_json::to_string_pretty(&saved)?,
+    )
+}
+
+/// Prints the connection info saved by the last start of the namespace.
+/// With `--refresh` the containers are checked, otherwise docker isn't contacted
+pub async fn info(context: &context::Context, args: &InfoArgs) -> Result<()> {
+    let Some(contents) = state::read(context, CONNECTION_INFO_STATE_KEY) else {
+        bail!(
+            "No connection info was saved for namespace {}. Start the services first",
+            context.namespace()
+        );
+    };
+
+    let saved: Vec<SavedConnectionInfo> =
+        serde_json::from_str(&contents).context("The saved connection info is corrupted")?;
+
+    let docker = if args.refresh() {
+        Some(docker::get_default_docker_client(context).await?)
+    } else {
+        None
+    };
+
+    let mut rows = vec![];
+
+    for item in saved {
+        let ready = match &docker {
+            Some(docker) => Some(check_ready(context, docker, &item).await?),
+            None => None,
+        };
+
+        let info = if args.show_secrets() {
+            item.info
+        } else {
+            // only the password of URLs is masked
+            mask_env_value("", &item.info)
+        };
+
+        rows.push(InfoRow {
+            service: item.service,
+            info,
+            ready,
+        });
+    }
+
+    match args.output() {
+        InfoOutput::Json => println!("{}", serde_json::to_string_pretty(&rows)?),
+        InfoOutput::Text => presentation::print_connection_info(
+            &rows
+                .into_iter()
+                .map(|row| {
+                    (
+                        ServiceKind::from_name(&row.service),
+                        row.info,
+                        row.ready.unwrap_or(true),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        ),
+    }
+
+    Ok(())
+}
+
+/// The container must be running and, if it's published, accept connections
+async fn check_ready(
+    context: &context::Context,
+    docker: &bollard::Docker,
+    item: &SavedConnectionInfo,
+) -> Result<bool> {
+    let container_name = context.container_name_of(&ServiceKind::from_name(&item.service));
+
+    if !docker::check_container_running(docker, &container_name).await? {
+        return Ok(false);
+    }
+
+    Ok(match &item.address {
+        Some((host, port)) => probe_tcp(host, *port).await,
+        None => true,
+    })
+}
@@ -1,53 +1,470 @@
-use std::{io::Write, sync::Arc};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time,
+};
 
-use futures_util::TryStreamExt;
+use console::{style, Color};
+use futures_util::{StreamExt, TryStreamExt};
 
-use crate::{context, docker, prelude::*, presentation, services};
+use crate::{config::LogColor, context, docker, prelude::*, presentation, services};
+
+/// Lines shown before following the logs, unless `--tail` is passed
+const DEFAULT_LOGS_COUNT: u64 = 20;
+const REATTACH_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub struct LogsArgs {
+    service: services::ServiceKind,
+    count: Option<u64>,
+    timestamps: bool,
+    output: Option<PathBuf>,
+    append: bool,
+    since_deploy: bool,
+    no_follow: bool,
+    replica: Option<usize>,
+}
+
+impl LogsArgs {
+    pub fn new(service: services::ServiceKind, count: Option<u64>, timestamps: bool) -> Self {
+        Self {
+            service,
+            count,
+            timestamps,
+            output: None,
+            append: false,
+            since_deploy: false,
+            no_follow: false,
+            replica: None,
+        }
+    }
+
+    pub fn with_output(mut self, output: Option<PathBuf>, append: bool) -> Self {
+        self.output = output;
+        self.append = append;
+        self
+    }
+
+    pub fn with_since_deploy(mut self, since_deploy: bool) -> Self {
+        self.since_deploy = since_deploy;
+        self
+    }
+
+    pub fn with_no_follow(mut self, no_follow: bool) -> Self {
+        self.no_follow = no_follow;
+        self
+    }
+
+    pub fn with_replica(mut self, replica: Option<usize>) -> Self {
+        self.replica = replica;
+        self
+    }
+
+    pub fn service(&self) -> &services::ServiceKind {
+        &self.service
+    }
+
+    pub fn count(&self) -> Option<u64> {
+        self.count
+    }
+
+    pub fn timestamps(&self) -> bool {
+        self.timestamps
+    }
+
+    pub fn output(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+
+    pub fn append(&self) -> bool {
+        self.append
+    }
+
+    pub fn since_deploy(&self) -> bool {
+        self.since_deploy
+    }
+
+    pub fn no_follow(&self) -> bool {
+        self.no_follow
+    }
+
+    pub fn replica(&self) -> Option<usize> {
+        self.replica
+    }
+}
 
 pub async fn logs(
     context: Arc<context::Context>,
     docker: Arc<bollard::Docker>,
-    service: services::ServiceKind,
-    count: Option<u64>,
+    args: LogsArgs,
 ) -> Result<()> {
-    let logs_count = count.unwrap_or(20);
-    let should_follow = count.is_none();
-    let container_name = context.container_name_of(service);
+    let logs_count = args.count().unwrap_or(DEFAULT_LOGS_COUNT);
+
+    // since the deploy all lines are shown, unless --tail limits them
+    let tail_count = match args.count() {
+        None if args.since_deploy() => None,
+        _ => Some(logs_count),
+    };
+    let tail = tail_count.map_or_else(|| "all".to_owned(), |count| count.to_string());
+    let should_follow = args.count().is_none() && !args.no_follow();
+    let container_name = context.container_name_of(args.service());
+    let container_names = containers_to_follow(&docker, &container_name, &args).await?;
+
+    let mut output_file = args
+        .output()
+        .map(|path| open_output_file(path, args.append()))
+        .transpose()?;
+
+    // with --quiet the logs only go to the output file
+    let should_print = output_file.is_none() || !context.args().quiet();
+
+    let mut streams = vec![];
+    let mut prefixes = vec![];
+    let mut file_prefixes = vec![];
+
+    for (index, name) in container_names.iter().enumerate() {
+        let since = if args.since_deploy() {
+            // every deploy creates a new container, restarts after a crash keep it
+            let Some(created_at) = docker::container_created_at(&docker, name).await? else {
+                bail!("{name} does not exist. Deploy it first.");
+            };
+
+            created_at
+        } else {
+            0
+        };
+
+        let logs = docker.logs(
+            name,
+            Some(bollard::container::LogsOptions {
+                stdout: true,
+                stderr: true,
+                follow: should_follow,
+                tail: tail.clone(),
+                timestamps: args.timestamps(),
+                since,
+                ..Default::default()
+            }),
+        );
+
+        streams.push(logs.map_ok(move |chunk| (index, chunk)).boxed());
+
+        match name.strip_prefix(&container_name) {
+            Some(suffix) if container_names.len() > 1 => {
+                prefixes.push(LinePrefix::of_replica(&context, args.service(), suffix));
+                file_prefixes.push(LinePrefix::plain(&format!("{}{suffix}", args.service())));
+            }
+            _ => {
+                prefixes.push(LinePrefix::of(&context, args.service()));
+                file_prefixes.push(LinePrefix::none());
+            }
+        }
+
+        presentation::print_logs_count(name, tail_count, should_follow);
+    }
+
+    // lines of the replicas are printed as they arrive
+    let mut logs = futures_util::stream::select_all(streams);
+    let mut stdout = std::io::stdout();
+
+    while let Some((index, chunk)) = logs.try_next().await? {
+        let prefix = &mut prefixes[index];
+        let bytes = chunk.into_bytes();
+
+        // the file keeps the plain logs, only labeled when replicas are merged
+        if let Some(file) = &mut output_file {
+            let file_prefix = &mut file_prefixes[index];
+
+            for line in bytes.split_inclusive(|byte| *byte == b'\n') {
+                file_prefix
+                    .write(file, line)
+                    .and_then(|_| file.write_all(line))
+                    .context("Failed to write logs to the output file")?;
+            }
+        }
+
+        if !should_print {
+            continue;
+        }
+
+        for line in String::from_utf8_lossy(&bytes).split_inclusive('\n') {
+            prefix
+                .write(&mut stdout, line.as_bytes())
+                .expect("Failed to write to stdout");
+
+            let (timestamp, message) = if args.timestamps() {
+                split_timestamp(line)
+            } else {
+                (None, line)
+            };
+
+            if let Some(timestamp) = timestamp {
+                write!(stdout, "{} ", style(timestamp).dim()).expect("Failed to write to stdout");
+            }
+
+            stdout
+                .write_all(message.as_bytes())
+                .expect("Failed to write to stdout");
+        }
+
+        stdout.flush().expect("Failed to flush stdout");
+    }
+
+    Ok(())
+}
+
+/// The running container of the service, or with `deploy --scale` the running replicas
+/// of the app, unless one is picked with `--replica`
+async fn containers_to_follow(
+    docker: &bollard::Docker,
+    container_name: &str,
+    args: &LogsArgs,
+) -> Result<Vec<String>> {
+    let replicas = match args.service() {
+        services::ServiceKind::App => {
+            docker::replica_container_names(docker, container_name).await?
+        }
+        _ if args.replica().is_some() => bail!("Only the app service can have replicas"),
+        _ => vec![],
+    };
 
-    let is_running = docker::check_container_running(&*docker, &container_name).await?;
-    if !is_running {
+    let container_names = match args.replica() {
+        Some(replica) => {
+            let replica_name = format!("{container_name}_{replica}");
+
+            if !replicas.contains(&replica_name) {
+                bail!(
+                    "The app has no replica {replica}. Replicas are numbered from 1 and only \
+                     exist when it was deployed with --scale"
+                );
+            }
+
+            vec![replica_name]
+        }
+        None if replicas.is_empty() => vec![container_name.to_owned()],
+        None => replicas,
+    };
+
+    let mut running = vec![];
+
+    for name in container_names {
+        if docker::check_container_running(docker, &name).await? {
+            running.push(name);
+        }
+    }
+
+    if running.is_empty() {
         bail!("Cannot show logs because the container is not running. Deploy it first.");
     }
 
-    let logs = docker.logs(
-        &container_name,
-        Some(bollard::container::LogsOptions {
-            stdout: true,
-            stderr: true,
-            follow: should_follow,
-            tail: logs_count.to_string(),
-            ..Default::default()
-        }),
-    );
+    Ok(running)
+}
+
+/// Follows the logs of the service while it's redeployed by `--watch`.
+/// When the container is replaced, the stream moves over to the new one
+/// without showing the old logs again
+pub async fn follow_across_redeploys(
+    context: Arc<context::Context>,
+    docker: Arc<bollard::Docker>,
+    service: services::ServiceKind,
+) -> Result<()> {
+    let container_name = context.container_name_of(&service);
+
+    let Some(mut container_id) = docker::running_container_id(&docker, &container_name).await?
+    else {
+        bail!("Cannot show logs because the container is not running. Deploy it first.");
+    };
+
+    let mut options = bollard::container::LogsOptions::<String> {
+        stdout: true,
+        stderr: true,
+        follow: true,
+        tail: DEFAULT_LOGS_COUNT.to_string(),
+        ..Default::default()
+    };
+
+    presentation::print_logs_count(&container_name, Some(DEFAULT_LOGS_COUNT), true);
 
-    presentation::print_logs_count(&container_name, logs_count, should_follow);
+    let mut stdout = std::io::stdout();
+    let mut prefix = LinePrefix::of(&context, &service);
 
-    logs.try_for_each(|chunk| async {
-        let mut stdout = std::io::stdout();
+    loop {
+        // the stream is bound to the id, so it ends once this container stops
+        let mut logs = docker.logs(&container_id, Some(options.clone()));
 
-        let bytes = match chunk {
-            bollard::container::LogOutput::StdIn { message } => message,
-            bollard::container::LogOutput::StdOut { message } => message,
-            bollard::container::LogOutput::StdErr { message } => message,
-            bollard::container::LogOutput::Console { message } => message,
+        // a removed container ends the stream with an error, which is expected here
+        while let Ok(Some(chunk)) = logs.try_next().await {
+            for line in String::from_utf8_lossy(&chunk.into_bytes()).split_inclusive('\n') {
+                prefix
+                    .write(&mut stdout, line.as_bytes())
+                    .expect("Failed to write to stdout");
+                stdout
+                    .write_all(line.as_bytes())
+                    .expect("Failed to write to stdout");
+            }
+
+            stdout.flush().expect("Failed to flush stdout");
+        }
+
+        let stopped_at = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)?
+            .as_secs() as i64;
+
+        let next_container_id = loop {
+            if let Some(id) = docker::running_container_id(&docker, &container_name).await? {
+                break id;
+            }
+
+            tokio::time::sleep(REATTACH_POLL_INTERVAL).await;
         };
 
-        stdout.write_all(&bytes).expect("Failed to write to stdout");
-        stdout.flush().expect("Failed to flush stdout");
+        options.tail = "all".to_owned();
+        options.since = if next_container_id == container_id {
+            // restarted by docker, only the logs after the restart are new
+            stopped_at
+        } else {
+            presentation::print_logs_redeployed(&container_name);
+            0
+        };
+
+        container_id = next_container_id;
+    }
+}
+
+/// Colored `[service]` label in front of every printed line, configured in `[logs]`.
+/// A chunk may end in the middle of a line, which then continues without a label
+struct LinePrefix {
+    label: Option<String>,
+    is_line_start: bool,
+}
+
+impl LinePrefix {
+    fn of(context: &context::Context, service: &services::ServiceKind) -> Self {
+        let config = context.app_config().logs(context.override_context());
+
+        // redirected logs stay as the service wrote them
+        let is_terminal = console::Term::stdout().features().is_attended();
+        let is_labeled = is_terminal && config.is_none_or(|config| config.prefix());
+
+        Self::labeled(context, service, &service.to_string(), is_labeled)
+    }
+
+    /// Merged replicas are always labeled, e.g. `[app_2]`, as their lines can't be told apart otherwise
+    fn of_replica(
+        context: &context::Context,
+        service: &services::ServiceKind,
+        suffix: &str,
+    ) -> Self {
+        Self::labeled(context, service, &format!("{service}{suffix}"), true)
+    }
+
+    fn labeled(
+        context: &context::Context,
+        service: &services::ServiceKind,
+        name: &str,
+        is_labeled: bool,
+    ) -> Self {
+        let config = context.app_config().logs(context.override_context());
+
+        let label = is_labeled.then(|| {
+            let color = config
+                .and_then(|config| config.color_of(&service.to_string()))
+                .unwrap_or_else(|| default_color(service));
+
+            format!(
+                "{} ",
+                style(format!("[{name}]")).fg(to_console_color(color))
+            )
+        });
+
+        Self {
+            label,
+            is_line_start: true,
+        }
+    }
+
+    /// Uncolored `[app_2]` label for the `--output` file
+    fn plain(name: &str) -> Self {
+        Self {
+            label: Some(format!("[{name}] ")),
+            is_line_start: true,
+        }
+    }
+
+    fn none() -> Self {
+        Self {
+            label: None,
+            is_line_start: true,
+        }
+    }
+
+    fn write(&mut self, out: &mut impl Write, line: &[u8]) -> std::io::Result<()> {
+        if let Some(label) = self.label.as_ref().filter(|_| self.is_line_start) {
+            write!(out, "{label}")?;
+        }
+
+        self.is_line_start = line.ends_with(b"\n");
 
         Ok(())
-    })
-    .await?;
+    }
+}
 
-    Ok(())
+fn default_color(service: &services::ServiceKind) -> LogColor {
+    use services::ServiceKind::*;
+
+    match service {
+        App => LogColor::Green,
+        Postgres => LogColor::Blue,
+        Keydb => LogColor::Red,
+        Proxy => LogColor::Magenta,
+        Custom { .. } => LogColor::Cyan,
+    }
+}
+
+fn to_console_color(color: LogColor) -> Color {
+    match color {
+        LogColor::Black => Color::Black,
+        LogColor::Red => Color::Red,
+        LogColor::Green => Color::Green,
+        LogColor::Yellow => Color::Yellow,
+        LogColor::Blue => Color::Blue,
+        LogColor::Magenta => Color::Magenta,
+        LogColor::Cyan => Color::Cyan,
+        LogColor::White => Color::White,
+    }
+}
+
+/// Opens the file for appending, or moves an existing one to `<file>.1` first
+fn open_output_file(path: &Path, append: bool) -> Result<fs::File> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+
+    if !append && path.exists() {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+
+        fs::rename(path, &rotated)
+            .with_context(|| format!("Failed to rotate log file {}", path.display()))?;
+    }
+
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file {}", path.display()))
+}
+
+/// Docker prepends an RFC3339 timestamp followed by a space to every line
+/// when timestamps are requested. Returns the timestamp and the message body
+fn split_timestamp(line: &str) -> (Option<&str>, &str) {
+    match line.split_once(' ') {
+        Some((timestamp, message)) if timestamp.ends_with('Z') => (Some(timestamp), message),
+        _ => (None, line),
+    }
 }
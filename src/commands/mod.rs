@@ -1,4 +1,14 @@
+pub mod config;
 pub mod deploy;
 pub mod exec;
+pub mod export;
+pub mod info;
 pub mod logs;
+pub mod namespaces;
+pub mod proxy;
+pub mod pull;
+pub mod select;
+pub mod stats;
+pub mod status;
 pub mod stop;
+pub mod wait;
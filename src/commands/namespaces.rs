@@ -0,0 +1,33 @@
+use std::{collections::BTreeSet, collections::HashMap};
+
+use crate::{
+    context,
+    prelude::*,
+    services::options::{LABEL_APP, LABEL_NAMESPACE},
+    utils::string::matches_glob,
+};
+
+/// Namespaces of the app matching the pattern which have containers, running or not
+pub async fn find_namespaces(
+    context: &context::Context,
+    docker: &bollard::Docker,
+    pattern: &str,
+) -> Result<Vec<String>> {
+    let app_name = context.app_config().name(context.override_context());
+
+    let containers = docker
+        .list_containers(Some(bollard::container::ListContainersOptions::<String> {
+            all: true,
+            filters: HashMap::from([("label".to_owned(), vec![format!("{LABEL_APP}={app_name}")])]),
+            ..Default::default()
+        }))
+        .await?;
+
+    let namespaces = containers
+        .into_iter()
+        .filter_map(|container| container.labels?.remove(LABEL_NAMESPACE))
+        .filter(|namespace| matches_glob(pattern, namespace))
+        .collect::<BTreeSet<_>>();
+
+    Ok(namespaces.into_iter().collect())
+}
@@ -0,0 +1,68 @@
+use console::style;
+
+use crate::{prelude::*, services::proxy};
+
+/// Prints what the proxy is configured with, without connecting to the server
+pub fn show_config(proxy: &proxy::ProxyService, json: bool) -> Result<()> {
+    let routes = proxy.routes();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&routes)?);
+        return Ok(());
+    }
+
+    if routes.is_empty() {
+        println!(
+            "{}",
+            style("No [[proxy]] sections configured, the app is not routed").yellow()
+        );
+        return Ok(());
+    }
+
+    println!("{}", style("Routes:").cyan());
+
+    for route in &routes {
+        println!(
+            "{} -> {}:{}",
+            style(&route.domain).cyan(),
+            route.upstreams.join(", "),
+            route.port
+        );
+
+        let limits = [
+            (
+                "max body size",
+                route
+                    .max_body_size_bytes
+                    .map(|size| format!("{size} bytes")),
+            ),
+            (
+                "read timeout",
+                route.read_timeout_ms.map(|ms| format!("{ms}ms")),
+            ),
+            (
+                "connect timeout",
+                route.connect_timeout_ms.map(|ms| format!("{ms}ms")),
+            ),
+            (
+                "send timeout",
+                route.send_timeout_ms.map(|ms| format!("{ms}ms")),
+            ),
+        ];
+
+        for (name, value) in limits {
+            if let Some(value) = value {
+                println!("  {} {value}", style(format!("{name}:")).dim());
+            }
+        }
+    }
+
+    println!(
+        "\n{} {}\n",
+        style("Generated config").cyan(),
+        style(proxy.service_config_path()).dim()
+    );
+    println!("{}", proxy.service_config_contents());
+
+    Ok(())
+}
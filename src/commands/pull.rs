@@ -0,0 +1,35 @@
+use crate::{
+    context, docker,
+    prelude::*,
+    presentation,
+    services::{self, ToContainerConfig},
+};
+
+/// Pulls the images of all dependencies without changing any containers
+pub async fn pull(
+    context: &context::Context,
+    docker: &bollard::Docker,
+    services: &services::Services,
+) -> Result<()> {
+    let mut container_configs = services.to_container_configs(context)?;
+
+    // the proxy is only part of the configs when deploying
+    if context.ssh_credentials().is_some() {
+        container_configs.push(services.proxy().to_container_config(context)?);
+    }
+
+    for config in &container_configs {
+        presentation::print_dependency_pulling(config.container_name());
+        docker::pull_image(docker, config.image_name(), config.platform()).await?;
+
+        if let Some(digest) = config.digest() {
+            docker::verify_image_digest(docker, config.image_name(), digest).await?;
+        }
+
+        presentation::print_dependency_pulled(config.container_name());
+    }
+
+    presentation::print_images_pulled(container_configs.len());
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use std::io::IsTerminal;
+
+use crate::{context, docker, prelude::*, services};
+
+/// Returns the given service, or lets the user pick one of the running services
+/// when the command runs in a terminal
+pub async fn service_or_select(
+    context: &context::Context,
+    docker: &bollard::Docker,
+    services: &services::Services,
+    service: Option<services::ServiceKind>,
+) -> Result<services::ServiceKind> {
+    if let Some(service) = service {
+        return Ok(service);
+    }
+
+    if !std::io::stdin().is_terminal() || !console::user_attended_stderr() {
+        bail!("--service is required when not running in a terminal");
+    }
+
+    let mut running = vec![];
+
+    for kind in services.kinds(context) {
+        if docker::check_container_running(docker, &context.container_name_of(&kind)).await? {
+            running.push(kind);
+        }
+    }
+
+    if running.is_empty() {
+        bail!("No services are running. Deploy them first.");
+    }
+
+    let items = running
+        .iter()
+        .map(|kind| format!("{kind} ({})", context.container_name_of(kind)))
+        .collect::<Vec<_>>();
+
+    let selection = dialoguer::Select::with_theme(&dialoguer::theme::ColorfulTheme::default())
+        .with_prompt("Select a service")
+        .items(&items)
+        .default(0)
+        .interact_opt()
+        .context("Failed to show the service selector")?;
+
+    let Some(index) = selection else {
+        bail!("No service selected");
+    };
+
+    Ok(running.swap_remove(index))
+}
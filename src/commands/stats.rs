@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+
+use futures_util::StreamExt;
+use serde::Serialize;
+
+use crate::{
+    cli::StatsOutput,
+    commands::status::format_table,
+    context, docker,
+    prelude::*,
+    services::options::{LABEL_APP, LABEL_NAMESPACE, LABEL_SERVICE},
+};
+
+#[derive(Debug, Clone)]
+pub struct StatsArgs {
+    output: StatsOutput,
+    no_stream: bool,
+}
+
+impl StatsArgs {
+    pub fn new(output: StatsOutput, no_stream: bool) -> Self {
+        Self { output, no_stream }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StatsRow {
+    name: String,
+    service: String,
+    cpu_percent: f64,
+    memory_usage_bytes: u64,
+    memory_limit_bytes: u64,
+    network_rx_bytes: u64,
+    network_tx_bytes: u64,
+}
+
+impl StatsRow {
+    fn cells(&self) -> Vec<String> {
+        let memory_percent = match self.memory_limit_bytes {
+            0 => 0.0,
+            limit => self.memory_usage_bytes as f64 / limit as f64 * 100.0,
+        };
+
+        vec![
+            self.name.clone(),
+            self.service.clone(),
+            format!("{:.2}%", self.cpu_percent),
+            format!(
+                "{} / {}",
+                format_bytes(self.memory_usage_bytes),
+                format_bytes(self.memory_limit_bytes)
+            ),
+            format!("{memory_percent:.2}%"),
+            format!(
+                "{} / {}",
+                format_bytes(self.network_rx_bytes),
+                format_bytes(self.network_tx_bytes)
+            ),
+        ]
+    }
+}
+
+/// Resource usage of the running containers of the app and namespace,
+/// found by the labels dploy puts on them
+pub async fn stats(
+    context: &context::Context,
+    docker: &bollard::Docker,
+    args: &StatsArgs,
+) -> Result<()> {
+    let containers = find_containers(context, docker).await?;
+
+    if containers.is_empty() {
+        bail!("No services are running. Deploy them first.");
+    }
+
+    let term = console::Term::stdout();
+    let mut printed_lines = 0;
+
+    loop {
+        let rows = tokio::select! {
+            rows = sample(docker, &containers) => rows?,
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+
+        match args.output {
+            StatsOutput::Json => {
+                for row in &rows {
+                    println!("{}", serde_json::to_string(row)?);
+                }
+            }
+            StatsOutput::Table => {
+                let header = [
+                    "NAME",
+                    "SERVICE",
+                    "CPU",
+                    "MEMORY",
+                    "MEMORY %",
+                    "NET RX / TX",
+                ]
+                .map(ToOwned::to_owned);
+                let cells = rows.iter().map(StatsRow::cells).collect::<Vec<_>>();
+
+                term.clear_last_lines(printed_lines)?;
+
+                let lines = format_table(&header, &cells);
+                printed_lines = lines.len();
+
+                for line in lines {
+                    term.write_line(&line)?;
+                }
+            }
+        }
+
+        if args.no_stream {
+            return Ok(());
+        }
+    }
+}
+
+/// Names and services of the running containers, sorted by service
+async fn find_containers(
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<Vec<(String, String)>> {
+    let app_name = context.app_config().name(context.override_context());
+
+    let containers = docker
+        .list_containers(Some(bollard::container::ListContainersOptions::<String> {
+            filters: HashMap::from([(
+                "label".to_owned(),
+                vec![
+                    format!("{LABEL_APP}={app_name}"),
+                    format!("{LABEL_NAMESPACE}={}", context.namespace()),
+                ],
+            )]),
+            ..Default::default()
+        }))
+        .await?;
+
+    let mut containers = containers
+        .into_iter()
+        .filter_map(|container| {
+            let name = container.names?.first()?.trim_start_matches('/').to_owned();
+            let service = container
+                .labels
+                .and_then(|labels| labels.get(LABEL_SERVICE).cloned())
+                .unwrap_or_default();
+
+            Some((name, service))
+        })
+        .collect::<Vec<_>>();
+
+    containers.sort_by(|(_, a), (_, b)| a.cmp(b));
+
+    Ok(containers)
+}
+
+/// Takes one sample of every container at the same time.
+/// Docker needs about a second per sample to measure the CPU usage
+async fn sample(
+    docker: &bollard::Docker,
+    containers: &[(String, String)],
+) -> Result<Vec<StatsRow>> {
+    let samples = futures::future::join_all(containers.iter().map(|(name, _)| async move {
+        let sample = docker
+            .stats(
+                name,
+                Some(bollard::container::StatsOptions {
+                    stream: false,
+                    one_shot: false,
+                }),
+            )
+            .next()
+            .await
+            .transpose();
+
+        docker::none_if_not_found(sample)
+            .map(Option::flatten)
+            .with_context(|| format!("Failed to get stats of {name}"))
+    }))
+    .await;
+
+    let mut rows = vec![];
+
+    for ((name, service), sample) in containers.iter().zip(samples) {
+        // the container stopped or was removed in the meantime
+        let Some(sample) = sample? else {
+            continue;
+        };
+
+        rows.push(to_stats_row(name, service, &sample));
+    }
+
+    Ok(rows)
+}
+
+fn to_stats_row(name: &str, service: &str, stats: &bollard::container::Stats) -> StatsRow {
+    let cpu_delta = stats.cpu_stats.cpu_usage.total_usage as f64
+        - stats.precpu_stats.cpu_usage.total_usage as f64;
+    let system_delta = stats.cpu_stats.system_cpu_usage.unwrap_or_default() as f64
+        - stats.precpu_stats.system_cpu_usage.unwrap_or_default() as f64;
+    let online_cpus = stats.cpu_stats.online_cpus.unwrap_or(1) as f64;
+
+    let cpu_percent = if cpu_delta > 0.0 && system_delta > 0.0 {
+        cpu_delta / system_delta * online_cpus * 100.0
+    } else {
+        0.0
+    };
+
+    // page cache can be reclaimed, so docker doesn't count it as used either
+    let inactive_file = match &stats.memory_stats.stats {
+        Some(bollard::container::MemoryStatsStats::V1(stats)) => stats.total_inactive_file,
+        Some(bollard::container::MemoryStatsStats::V2(stats)) => stats.inactive_file,
+        None => 0,
+    };
+
+    let memory_usage_bytes = stats
+        .memory_stats
+        .usage
+        .unwrap_or_default()
+        .saturating_sub(inactive_file);
+
+    let (network_rx_bytes, network_tx_bytes) = stats
+        .networks
+        .iter()
+        .flat_map(|networks| networks.values())
+        .fold((0, 0), |(rx, tx), network| {
+            (rx + network.rx_bytes, tx + network.tx_bytes)
+        });
+
+    StatsRow {
+        name: name.to_owned(),
+        service: service.to_owned(),
+        cpu_percent,
+        memory_usage_bytes,
+        memory_limit_bytes: stats.memory_stats.limit.unwrap_or_default(),
+        network_rx_bytes,
+        network_tx_bytes,
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{value:.1}{}", UNITS[unit])
+}
@@ -0,0 +1,209 @@
+use console::{measure_text_width, pad_str, style, truncate_str, Alignment};
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{
+    cli::{StatusColumn, StatusFormat},
+    context, docker,
+    prelude::*,
+    services,
+};
+
+const MAX_NAME_WIDTH: usize = 40;
+const COLUMN_GAP: &str = "   ";
+
+const TABLE_COLUMNS: &[StatusColumn] = &[
+    StatusColumn::Name,
+    StatusColumn::Service,
+    StatusColumn::State,
+    StatusColumn::Ports,
+];
+
+const WIDE_COLUMNS: &[StatusColumn] = &[
+    StatusColumn::Name,
+    StatusColumn::Service,
+    StatusColumn::State,
+    StatusColumn::Ports,
+    StatusColumn::Image,
+    StatusColumn::Created,
+];
+
+#[derive(Debug, Clone)]
+pub struct StatusArgs {
+    format: StatusFormat,
+    columns: Vec<StatusColumn>,
+}
+
+impl StatusArgs {
+    pub fn new(format: StatusFormat, columns: Vec<StatusColumn>) -> Self {
+        Self { format, columns }
+    }
+
+    pub fn format(&self) -> StatusFormat {
+        self.format
+    }
+
+    /// Explicitly selected columns take precedence over the format defaults
+    pub fn columns(&self) -> &[StatusColumn] {
+        if !self.columns.is_empty() {
+            return &self.columns;
+        }
+
+        match self.format {
+            StatusFormat::Wide => WIDE_COLUMNS,
+            _ => TABLE_COLUMNS,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StatusRow {
+    name: String,
+    service: String,
+    state: String,
+    ports: Vec<String>,
+    image: Option<String>,
+    created: Option<String>,
+}
+
+impl StatusRow {
+    fn cell(&self, column: StatusColumn) -> String {
+        match column {
+            StatusColumn::Name => truncate_str(&self.name, MAX_NAME_WIDTH, "…").to_string(),
+            StatusColumn::Service => self.service.clone(),
+            StatusColumn::State => self.state.clone(),
+            StatusColumn::Ports => self.ports.join(", "),
+            StatusColumn::Image => self.image.clone().unwrap_or_default(),
+            StatusColumn::Created => self.created.clone().unwrap_or_default(),
+        }
+    }
+}
+
+pub async fn status(
+    context: &context::Context,
+    docker: &bollard::Docker,
+    services: &services::Services,
+    args: &StatusArgs,
+) -> Result<()> {
+    let mut rows = vec![];
+
+    for service_kind in services.kinds(context) {
+        let container_name = context.container_name_of(&service_kind);
+
+        // every replica of `deploy --scale` gets a row
+        for name in docker::service_container_names(docker, &service_kind, &container_name).await? {
+            let container = docker::inspect_container(docker, &name).await?;
+
+            rows.push(to_status_row(name, service_kind.clone(), container));
+        }
+    }
+
+    match args.format() {
+        StatusFormat::Json => println!("{}", serde_json::to_string_pretty(&rows)?),
+        StatusFormat::Table | StatusFormat::Wide => print_table(&rows, args.columns()),
+    }
+
+    Ok(())
+}
+
+fn to_status_row(
+    name: String,
+    service_kind: services::ServiceKind,
+    container: Option<bollard::models::ContainerInspectResponse>,
+) -> StatusRow {
+    let Some(container) = container else {
+        return StatusRow {
+            name,
+            service: service_kind.to_string(),
+            state: "absent".to_owned(),
+            ports: vec![],
+            image: None,
+            created: None,
+        };
+    };
+
+    let state = container
+        .state
+        .as_ref()
+        .and_then(|state| state.status.as_ref())
+        .map(|status| status.to_string())
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    let ports = container
+        .network_settings
+        .as_ref()
+        .and_then(|settings| settings.ports.as_ref())
+        .map(|ports| {
+            ports
+                .iter()
+                .flat_map(|(inner, bindings)| {
+                    bindings.iter().flatten().map(move |binding| {
+                        format!(
+                            "{}:{}->{}",
+                            binding.host_ip.as_deref().unwrap_or_default(),
+                            binding.host_port.as_deref().unwrap_or_default(),
+                            inner
+                        )
+                    })
+                })
+                .sorted()
+                .collect()
+        })
+        .unwrap_or_default();
+
+    StatusRow {
+        name,
+        service: service_kind.to_string(),
+        state,
+        ports,
+        image: container.image,
+        created: container.created,
+    }
+}
+
+fn print_table(rows: &[StatusRow], columns: &[StatusColumn]) {
+    let header = columns
+        .iter()
+        .map(|column| format!("{column:?}").to_uppercase())
+        .collect::<Vec<_>>();
+
+    let cells = rows
+        .iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|column| row.cell(*column))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    for line in format_table(&header, &cells) {
+        println!("{line}");
+    }
+}
+
+/// Aligns the cells into columns. The first line is the bold header
+pub fn format_table(header: &[String], cells: &[Vec<String>]) -> Vec<String> {
+    let widths = (0..header.len())
+        .map(|index| {
+            std::iter::once(&header[index])
+                .chain(cells.iter().map(|row| &row[index]))
+                .map(|cell| measure_text_width(cell))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+
+    let format_line = |line: &[String]| {
+        line.iter()
+            .zip(&widths)
+            .map(|(cell, width)| pad_str(cell, *width, Alignment::Left, None).to_string())
+            .join(COLUMN_GAP)
+            .trim_end()
+            .to_owned()
+    };
+
+    std::iter::once(style(format_line(header)).bold().to_string())
+        .chain(cells.iter().map(|row| format_line(row)))
+        .collect()
+}
@@ -1,5 +1,5 @@
 use crate::{
-    context,
+    context, docker,
     prelude::*,
     presentation,
     services::{self, ToContainerConfig},
@@ -31,15 +31,20 @@ async fn stop_app_service(
     let container_config = app_service.to_container_config(context)?;
     let container_name = container_config.container_name();
 
+    stop_app_container(container_name, docker).await?;
+
+    // replicas of `deploy --scale`
+    for replica_name in docker::replica_container_names(docker, container_name).await? {
+        stop_app_container(&replica_name, docker).await?;
+    }
+
+    Ok(())
+}
+
+async fn stop_app_container(container_name: &str, docker: &bollard::Docker) -> Result<()> {
     presentation::print_app_container_removing(container_name);
 
-    let existing_container = match docker.inspect_container(container_name, None).await {
-        Ok(container) => Some(container),
-        Err(bollard::errors::Error::DockerResponseServerError {
-            status_code: 404, ..
-        }) => None,
-        Err(e) => return Err(e.into()),
-    };
+    let existing_container = docker::inspect_container(docker, container_name).await?;
 
     if should_stop_container(existing_container.as_ref()) {
         docker.stop_container(container_name, None).await?;
@@ -61,13 +66,7 @@ async fn stop_dependencies(
     for config in container_configs {
         let container_name = config.container_name();
 
-        let existing_container = match docker.inspect_container(container_name, None).await {
-            Ok(container) => Some(container),
-            Err(bollard::errors::Error::DockerResponseServerError {
-                status_code: 404, ..
-            }) => None,
-            Err(e) => return Err(e.into()),
-        };
+        let existing_container = docker::inspect_container(docker, container_name).await?;
 
         presentation::print_dependency_stopping(container_name);
         if should_stop_container(existing_container.as_ref()) {
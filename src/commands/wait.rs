@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use crate::{cli, context, docker, prelude::*, presentation, services, utils};
+
+const ALL_SERVICES: &str = "all";
+
+#[derive(Debug, Clone)]
+pub struct WaitArgs {
+    service: String,
+    timeout: Duration,
+}
+
+impl WaitArgs {
+    pub fn new(service: String, timeout: Duration) -> Self {
+        Self { service, timeout }
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Streams the logs of the app containers until they exit and returns the first
+/// non-zero exit code. With `--rm` the containers are removed afterwards
+pub async fn wait_for_app_exit(
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<i64> {
+    let service_kind = services::ServiceKind::App;
+    let app_container_name = context.container_name_of(&service_kind);
+    let mut app_exit_code = 0;
+
+    for container_name in
+        docker::service_container_names(docker, &service_kind, &app_container_name).await?
+    {
+        presentation::print_app_container_waiting_exit(&container_name);
+
+        let exit_code = docker::follow_until_exit(docker, &container_name)
+            .await?
+            .exit_code
+            .unwrap_or_default();
+
+        presentation::print_app_container_exited(&container_name, exit_code);
+
+        if context.args().command().remove_after_exit() {
+            // the container has already exited, there is nothing to stop
+            docker::ensure_container_absent(docker, &container_name, None).await?;
+            presentation::print_app_container_removed(&container_name);
+        }
+
+        if app_exit_code == 0 {
+            app_exit_code = exit_code;
+        }
+    }
+
+    Ok(app_exit_code)
+}
+
+/// Blocks until the selected services are ready, see [`docker::wait_for_container_ready`].
+/// Locally, the published ports of the services have to accept connections as well
+pub async fn wait_for_services(
+    context: &context::Context,
+    docker: &bollard::Docker,
+    services: &services::Services,
+    args: &WaitArgs,
+) -> Result<()> {
+    let available = services.kinds(context);
+
+    let kinds = if args.service() == ALL_SERVICES {
+        available
+    } else {
+        let Some(kind) = available
+            .into_iter()
+            .find(|kind| kind.to_string() == args.service())
+        else {
+            bail!(
+                "Unknown service `{}`. Available services: {}, {ALL_SERVICES}",
+                args.service(),
+                services
+                    .kinds(context)
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+
+        vec![kind]
+    };
+
+    let should_probe = !matches!(context.args().command(), cli::Command::Deploy { .. });
+    let connection_info = services.connection_info();
+    let deadline = tokio::time::Instant::now() + args.timeout();
+
+    for kind in kinds {
+        let service_container_name = context.container_name_of(&kind);
+
+        // every replica of `deploy --scale` has to be ready
+        let container_names =
+            docker::service_container_names(docker, &kind, &service_container_name).await?;
+
+        for container_name in &container_names {
+            presentation::print_service_waiting(container_name);
+
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if !docker::wait_for_container_ready(docker, container_name, remaining).await? {
+                bail!(
+                    "{container_name} is not ready after {}s",
+                    args.timeout().as_secs()
+                );
+            }
+
+            // the command runs in the container, so it works for remote hosts as well
+            if let Some(readiness) = services.readiness_of(&kind) {
+                let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+                let timeout = match &readiness.timeout {
+                    Some(timeout) => timeout.to_duration()?.min(remaining),
+                    None => remaining,
+                };
+
+                let (is_ready, output) =
+                    docker::wait_for_exec_success(docker, container_name, &readiness.exec, timeout)
+                        .await;
+
+                if !is_ready {
+                    bail!(
+                        "{container_name} is not ready after {}s, the last readiness check \
+                         failed with {output}",
+                        timeout.as_secs()
+                    );
+                }
+            }
+        }
+
+        let addresses = connection_info
+            .iter()
+            .filter(|(service_kind, _)| {
+                should_probe && *service_kind == kind && services.readiness_of(&kind).is_none()
+            })
+            .filter_map(|(_, item)| item.address());
+
+        for (host, port) in addresses {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+
+            if !utils::network::wait_for_tcp(host, port, remaining).await {
+                bail!(
+                    "{service_container_name} doesn't accept connections on {host}:{port} \
+                     after {}s",
+                    args.timeout().as_secs()
+                );
+            }
+        }
+
+        for container_name in &container_names {
+            presentation::print_service_ready(container_name);
+        }
+    }
+
+    Ok(())
+}
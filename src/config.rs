@@ -1,6 +1,115 @@
-use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    constants,
+    utils::string::{interpolate_env_except, interpolate_set_env},
+};
+
+const STDIN_CONFIG: &str = "-";
+
+/// Replaced in the smoke test command once the app is deployed, instead of when the config is read
+pub const SMOKE_TEST_PLACEHOLDERS: [&str; 2] = ["HOST_PORT", "URL"];
+
+fn interpolate_config(
+    contents: &str,
+    interpolate: fn(&str, &[&str]) -> Result<String>,
+) -> Result<String> {
+    let mut kept: &[&str] = &[];
+
+    contents
+        .split_inclusive('\n')
+        .enumerate()
+        .map(|(index, line)| {
+            let trimmed = line.trim();
+
+            if trimmed.starts_with('#') {
+                return Ok(line.to_owned());
+            }
+
+            // the smoke test command gets its placeholders once the app is deployed
+            if trimmed.starts_with('[') {
+                kept = match trimmed.trim_matches(['[', ']']).ends_with("smoke_test") {
+                    true => &SMOKE_TEST_PLACEHOLDERS,
+                    false => &[],
+                };
+            }
+
+            interpolate(line, kept).with_context(|| format!("Line {} of the config", index + 1))
+        })
+        .collect()
+}
+
+/// Where the config is read from, based on the `--config` value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    File(PathBuf),
+    Stdin,
+    Url(String),
+}
+
+impl ConfigSource {
+    pub fn parse(value: &str) -> Self {
+        if value == STDIN_CONFIG {
+            Self::Stdin
+        } else if value.starts_with("http://") || value.starts_with("https://") {
+            Self::Url(value.to_owned())
+        } else {
+            Self::File(PathBuf::from(value))
+        }
+    }
+
+    /// Only files can be read more than once
+    pub fn file(&self) -> Option<&PathBuf> {
+        match self {
+            Self::File(path) => Some(path),
+            _ => None,
+        }
+    }
 
-use crate::constants;
+    pub fn read(&self) -> Result<String> {
+        match self {
+            Self::File(path) => Ok(fs::read_to_string(path)?),
+            Self::Stdin => {
+                let mut contents = String::new();
+                io::stdin()
+                    .read_to_string(&mut contents)
+                    .context("Failed to read the config from stdin")?;
+
+                Ok(contents)
+            }
+            Self::Url(url) => {
+                let response = match ureq::get(url).call() {
+                    Ok(response) => response,
+                    Err(ureq::Error::Status(status, _)) => {
+                        bail!("Failed to fetch the config from {url}: HTTP {status}")
+                    }
+                    Err(error) => {
+                        return Err(error).context(format!("Failed to fetch the config from {url}"))
+                    }
+                };
+
+                if response.status() != 200 {
+                    bail!(
+                        "Failed to fetch the config from {url}: HTTP {}",
+                        response.status()
+                    );
+                }
+
+                response
+                    .into_string()
+                    .with_context(|| format!("Failed to read the config from {url}"))
+            }
+        }
+    }
+}
 
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
@@ -37,6 +146,19 @@ pub struct OverrideConfig {
     config: TopLevelOverrideConfig,
 }
 
+impl fmt::Display for OverrideRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let namespace = self.namespace.as_deref().unwrap_or("any");
+        let command = self
+            .command
+            .as_ref()
+            .map(|command| format!("{command:?}").to_lowercase())
+            .unwrap_or_else(|| "any".to_owned());
+
+        write!(f, "namespace = {namespace}, command = {command}")
+    }
+}
+
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum OverrideRuleCommand {
@@ -45,6 +167,79 @@ pub enum OverrideRuleCommand {
     Deploy,
 }
 
+impl fmt::Display for OverrideRuleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverrideRuleCommand::Dev => write!(f, "dev"),
+            OverrideRuleCommand::Run => write!(f, "run"),
+            OverrideRuleCommand::Deploy => write!(f, "deploy"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeployStrategy {
+    /// Stop the old container, then start the new one
+    #[default]
+    Recreate,
+
+    /// Start the new container next to the old one, switch the proxy
+    /// to it once it's ready and stop the old one after a drain period
+    BlueGreen,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NetworkMode {
+    /// The dploy network, shared with the dependencies
+    #[default]
+    Dploy,
+
+    /// The network of the docker host, without NAT or port bindings. Linux only
+    Host,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildCompression {
+    #[default]
+    Gzip,
+
+    /// Sends the build context as a plain tar, faster on fast networks
+    None,
+}
+
+impl fmt::Display for BuildCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildCompression::Gzip => write!(f, "gzip"),
+            BuildCompression::None => write!(f, "none"),
+        }
+    }
+}
+
+/// BuildKit output of the built image. Only the outputs which keep the image
+/// in the local image store are supported, the container is run from there
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildOutput {
+    /// The image store of the docker daemon
+    Docker,
+
+    /// The containerd image store, if docker is configured to use it
+    Image,
+}
+
+impl fmt::Display for BuildOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildOutput::Docker => write!(f, "docker"),
+            BuildOutput::Image => write!(f, "image"),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Default)]
 pub struct TopLevelAppConfig {
     /// Name of the user's application
@@ -54,6 +249,33 @@ pub struct TopLevelAppConfig {
     #[serde(default = "constants::get_default_dockerfile_name")]
     dockerfile: String,
 
+    /// Relative path to the build context directory.
+    /// Defaults to the directory of the config file
+    #[serde(default)]
+    context: Option<String>,
+
+    /// Name of the built application image, e.g. `myorg/app`.
+    /// Defaults to the name of the application container
+    #[serde(default)]
+    image: Option<String>,
+
+    /// Tag of the built application image. With `image`, defaults to the
+    /// short git commit hash if available, `latest` otherwise
+    #[serde(default)]
+    tag: Option<String>,
+
+    /// Files passed to the build as BuildKit secrets, which don't end up in the image
+    #[serde(default)]
+    build_secrets: Vec<BuildSecretConfig>,
+
+    /// Compression of the build context sent to docker
+    #[serde(default)]
+    build_compression: BuildCompression,
+
+    /// BuildKit output type, builds with the docker CLI when set
+    #[serde(default)]
+    build_output: Option<BuildOutput>,
+
     /// Names of environment variables of the application service
     #[serde(default)]
     env: Vec<String>,
@@ -62,10 +284,18 @@ pub struct TopLevelAppConfig {
     #[serde(default = "constants::get_default_dotenv_file_name")]
     env_file: String,
 
+    /// Additional env files loaded after `env_file`, later files take precedence
+    #[serde(default)]
+    env_files: Vec<EnvFileConfig>,
+
     /// Expose namespace to specified environment variable
     #[serde(default)]
     expose_namespace_to_env: Option<String>,
 
+    /// Expose the git commit the app was built from to specified environment variable
+    #[serde(default)]
+    expose_git_sha_to_env: Option<String>,
+
     /// Paths to persistent volumes inside the container
     /// These volumes will be automatically mounted
     #[serde(default)]
@@ -79,6 +309,26 @@ pub struct TopLevelAppConfig {
     #[serde(default)]
     ports: Vec<u16>,
 
+    /// How the application container is replaced on redeploy
+    #[serde(default)]
+    strategy: DeployStrategy,
+
+    /// Network of the application container
+    #[serde(default)]
+    network_mode: NetworkMode,
+
+    /// Allocate a pseudo-TTY for the application container
+    #[serde(default)]
+    tty: bool,
+
+    /// Keep stdin of the application container open
+    #[serde(default)]
+    stdin_open: bool,
+
+    /// Remove dangling images after the application is deployed
+    #[serde(default)]
+    prune_after_build: bool,
+
     /// Configuration for Postgres
     #[serde(default)]
     postgres: Option<PostgresConfig>,
@@ -90,6 +340,38 @@ pub struct TopLevelAppConfig {
     /// Configuration for Proxy
     #[serde(default)]
     proxy: Vec<ProxyConfig>,
+
+    /// Dependency services defined entirely in the config
+    #[serde(default, rename = "service")]
+    custom_services: Vec<CustomServiceConfig>,
+
+    /// Configuration of the dev mode
+    #[serde(default)]
+    dev: Option<DevConfig>,
+
+    /// Notifications sent after deploys
+    #[serde(default)]
+    notify: Option<NotifyConfig>,
+
+    /// Migrations run from the new app image before the app container is replaced
+    #[serde(default)]
+    migrations: Option<MigrationsConfig>,
+
+    /// Configuration of the deploy mode
+    #[serde(default)]
+    deploy: Option<DeployConfig>,
+
+    /// Addresses of the dploy network
+    #[serde(default)]
+    network: Option<NetworkConfig>,
+
+    /// How followed logs are labeled
+    #[serde(default)]
+    logs: Option<LogsConfig>,
+
+    /// Container options of the application service
+    #[serde(flatten)]
+    container: ContainerOptions,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -102,6 +384,31 @@ pub struct TopLevelOverrideConfig {
     #[serde(default)]
     dockerfile: Option<String>,
 
+    /// Relative path to the build context directory.
+    /// Defaults to the directory of the config file
+    #[serde(default)]
+    context: Option<String>,
+
+    /// Name of the built application image
+    #[serde(default)]
+    image: Option<String>,
+
+    /// Tag of the built application image
+    #[serde(default)]
+    tag: Option<String>,
+
+    /// Files passed to the build as BuildKit secrets, which don't end up in the image
+    #[serde(default)]
+    build_secrets: Option<Vec<BuildSecretConfig>>,
+
+    /// Compression of the build context sent to docker
+    #[serde(default)]
+    build_compression: Option<BuildCompression>,
+
+    /// BuildKit output type, builds with the docker CLI when set
+    #[serde(default)]
+    build_output: Option<BuildOutput>,
+
     /// Names of environment variables of the application service
     #[serde(default)]
     env: Option<Vec<String>>,
@@ -110,10 +417,18 @@ pub struct TopLevelOverrideConfig {
     #[serde(default)]
     env_file: Option<String>,
 
+    /// Additional env files loaded after `env_file`, later files take precedence
+    #[serde(default)]
+    env_files: Option<Vec<EnvFileConfig>>,
+
     /// Expose namespace to specified environment variable
     #[serde(default)]
     expose_namespace_to_env: Option<String>,
 
+    /// Expose the git commit the app was built from to specified environment variable
+    #[serde(default)]
+    expose_git_sha_to_env: Option<String>,
+
     /// Paths to persistent volumes inside the container
     /// These volumes will be automatically mounted
     #[serde(default)]
@@ -127,6 +442,26 @@ pub struct TopLevelOverrideConfig {
     #[serde(default)]
     ports: Option<Vec<u16>>,
 
+    /// How the application container is replaced on redeploy
+    #[serde(default)]
+    strategy: Option<DeployStrategy>,
+
+    /// Network of the application container
+    #[serde(default)]
+    network_mode: Option<NetworkMode>,
+
+    /// Allocate a pseudo-TTY for the application container
+    #[serde(default)]
+    tty: Option<bool>,
+
+    /// Keep stdin of the application container open
+    #[serde(default)]
+    stdin_open: Option<bool>,
+
+    /// Remove dangling images after the application is deployed
+    #[serde(default)]
+    prune_after_build: Option<bool>,
+
     /// Configuration for Postgres
     #[serde(default)]
     postgres: Option<PostgresConfig>,
@@ -138,9 +473,150 @@ pub struct TopLevelOverrideConfig {
     /// Configuration for Proxy
     #[serde(default)]
     proxy: Option<Vec<ProxyConfig>>,
+
+    /// Dependency services defined entirely in the config
+    #[serde(default, rename = "service")]
+    custom_services: Option<Vec<CustomServiceConfig>>,
+
+    /// Configuration of the dev mode
+    #[serde(default)]
+    dev: Option<DevConfig>,
+
+    /// Notifications sent after deploys
+    #[serde(default)]
+    notify: Option<NotifyConfig>,
+
+    /// Migrations run from the new app image before the app container is replaced
+    #[serde(default)]
+    migrations: Option<MigrationsConfig>,
+
+    /// Configuration of the deploy mode
+    #[serde(default)]
+    deploy: Option<DeployConfig>,
+
+    /// Addresses of the dploy network
+    #[serde(default)]
+    network: Option<NetworkConfig>,
+
+    /// How followed logs are labeled
+    #[serde(default)]
+    logs: Option<LogsConfig>,
+
+    /// Container options of the application service
+    #[serde(flatten)]
+    container: ContainerOptions,
+}
+
+/// Configuration with all active overrides applied, used for inspection.
+/// Plain values come before tables, as TOML requires
+#[derive(Debug, Serialize)]
+pub struct ResolvedAppConfig<'a> {
+    name: &'a str,
+    dockerfile: &'a str,
+    context: Option<&'a str>,
+    image: Option<&'a str>,
+    tag: Option<&'a str>,
+    build_secrets: &'a [BuildSecretConfig],
+    build_compression: BuildCompression,
+    build_output: Option<BuildOutput>,
+    env: &'a [String],
+    env_file: &'a str,
+    env_files: &'a [EnvFileConfig],
+    expose_namespace_to_env: Option<&'a str>,
+    expose_git_sha_to_env: Option<&'a str>,
+    volumes: &'a [String],
+    watch: &'a [String],
+    ports: &'a [u16],
+    strategy: DeployStrategy,
+    network_mode: NetworkMode,
+    tty: bool,
+    stdin_open: bool,
+    prune_after_build: bool,
+
+    #[serde(flatten)]
+    container: ContainerOptions,
+
+    postgres: Option<&'a PostgresConfig>,
+    keydb: Option<&'a KeydbConfig>,
+    proxy: &'a [ProxyConfig],
+    dev: Option<&'a DevConfig>,
+    notify: Option<&'a NotifyConfig>,
+    migrations: Option<&'a MigrationsConfig>,
+    deploy: Option<&'a DeployConfig>,
+    network: Option<&'a NetworkConfig>,
+    logs: Option<&'a LogsConfig>,
+
+    #[serde(rename = "service")]
+    custom_services: &'a [CustomServiceConfig],
 }
 
 impl AppConfig {
+    /// Parses the config, replacing `${NAME}` and `${NAME:-default}` outside of
+    /// comment lines with environment variables
+    pub fn parse(contents: &str) -> Result<Self> {
+        let contents = interpolate_config(contents, interpolate_env_except)?;
+
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Parses the config before the env files are loaded, only to find them.
+    /// Placeholders of variables which aren't set yet are kept. If that isn't
+    /// valid TOML, e.g. for a placeholder outside of a string, the error of
+    /// [`Self::parse`] names the missing variable
+    pub fn parse_before_env(contents: &str) -> Result<Self> {
+        let interpolated = interpolate_config(contents, |line, _| interpolate_set_env(line))?;
+
+        match toml::from_str(&interpolated) {
+            Ok(config) => Ok(config),
+            Err(_) => Self::parse(contents),
+        }
+    }
+
+    pub fn resolve(&self, context: &OverrideContext) -> ResolvedAppConfig<'_> {
+        ResolvedAppConfig {
+            name: self.name(context),
+            dockerfile: self.dockerfile(context),
+            context: self.build_context(context),
+            image: self.image(context),
+            tag: self.tag(context),
+            build_secrets: self.build_secrets(context),
+            build_compression: self.build_compression(context),
+            build_output: self.build_output(context),
+            env: self.env(context),
+            env_file: self.env_file(context),
+            env_files: self.env_files(context),
+            expose_namespace_to_env: self.expose_namespace_to_env(context),
+            expose_git_sha_to_env: self.expose_git_sha_to_env(context),
+            volumes: self.volumes(context),
+            watch: self.watch(context),
+            ports: self.ports(context),
+            strategy: self.strategy(context),
+            network_mode: self.network_mode(context),
+            tty: self.tty(context),
+            stdin_open: self.stdin_open(context),
+            prune_after_build: self.prune_after_build(context),
+            container: self.container_options(context),
+            postgres: self.postgres(context),
+            keydb: self.keydb(context),
+            proxy: self.proxy(context),
+            dev: self.dev(context),
+            notify: self.notify(context),
+            migrations: self.migrations(context),
+            deploy: self.deploy(context),
+            network: self.network(context),
+            logs: self.logs(context),
+            custom_services: self.custom_services(context),
+        }
+    }
+
+    /// Rules of the overrides matching the context, in the order they are applied
+    pub fn active_override_rules(&self, context: &OverrideContext) -> Vec<&OverrideRule> {
+        self.active_overrides(context)
+            .into_iter()
+            .map(|override_config| &override_config.rule)
+            .collect()
+    }
+
     pub fn name(&self, context: &OverrideContext) -> &str {
         self.resolve_field(
             context,
@@ -157,6 +633,30 @@ impl AppConfig {
         )
     }
 
+    pub fn build_context(&self, context: &OverrideContext) -> Option<&str> {
+        self.resolve_optional_field(
+            context,
+            |config| config.context.as_deref(),
+            |config| config.context.as_deref(),
+        )
+    }
+
+    pub fn image(&self, context: &OverrideContext) -> Option<&str> {
+        self.resolve_optional_field(
+            context,
+            |config| config.image.as_deref(),
+            |config| config.image.as_deref(),
+        )
+    }
+
+    pub fn tag(&self, context: &OverrideContext) -> Option<&str> {
+        self.resolve_optional_field(
+            context,
+            |config| config.tag.as_deref(),
+            |config| config.tag.as_deref(),
+        )
+    }
+
     pub fn env(&self, context: &OverrideContext) -> &[String] {
         self.resolve_field(context, |config| &config.env, |config| config.env.as_ref())
     }
@@ -169,6 +669,22 @@ impl AppConfig {
         )
     }
 
+    pub fn build_secrets(&self, context: &OverrideContext) -> &[BuildSecretConfig] {
+        self.resolve_field(
+            context,
+            |config| &config.build_secrets,
+            |config| config.build_secrets.as_ref(),
+        )
+    }
+
+    pub fn env_files(&self, context: &OverrideContext) -> &[EnvFileConfig] {
+        self.resolve_field(
+            context,
+            |config| &config.env_files,
+            |config| config.env_files.as_ref(),
+        )
+    }
+
     pub fn expose_namespace_to_env(&self, context: &OverrideContext) -> Option<&str> {
         self.resolve_optional_field(
             context,
@@ -177,6 +693,14 @@ impl AppConfig {
         )
     }
 
+    pub fn expose_git_sha_to_env(&self, context: &OverrideContext) -> Option<&str> {
+        self.resolve_optional_field(
+            context,
+            |config| config.expose_git_sha_to_env.as_deref(),
+            |config| config.expose_git_sha_to_env.as_deref(),
+        )
+    }
+
     pub fn volumes(&self, context: &OverrideContext) -> &[String] {
         self.resolve_field(
             context,
@@ -201,6 +725,59 @@ impl AppConfig {
         )
     }
 
+    pub fn build_compression(&self, context: &OverrideContext) -> BuildCompression {
+        *self.resolve_field(
+            context,
+            |config| &config.build_compression,
+            |config| config.build_compression.as_ref(),
+        )
+    }
+
+    pub fn build_output(&self, context: &OverrideContext) -> Option<BuildOutput> {
+        self.resolve_optional_field(
+            context,
+            |config| config.build_output.as_ref(),
+            |config| config.build_output.as_ref(),
+        )
+        .copied()
+    }
+
+    pub fn strategy(&self, context: &OverrideContext) -> DeployStrategy {
+        *self.resolve_field(
+            context,
+            |config| &config.strategy,
+            |config| config.strategy.as_ref(),
+        )
+    }
+
+    pub fn network_mode(&self, context: &OverrideContext) -> NetworkMode {
+        *self.resolve_field(
+            context,
+            |config| &config.network_mode,
+            |config| config.network_mode.as_ref(),
+        )
+    }
+
+    pub fn tty(&self, context: &OverrideContext) -> bool {
+        *self.resolve_field(context, |config| &config.tty, |config| config.tty.as_ref())
+    }
+
+    pub fn stdin_open(&self, context: &OverrideContext) -> bool {
+        *self.resolve_field(
+            context,
+            |config| &config.stdin_open,
+            |config| config.stdin_open.as_ref(),
+        )
+    }
+
+    pub fn prune_after_build(&self, context: &OverrideContext) -> bool {
+        *self.resolve_field(
+            context,
+            |config| &config.prune_after_build,
+            |config| config.prune_after_build.as_ref(),
+        )
+    }
+
     pub fn postgres(&self, context: &OverrideContext) -> Option<&PostgresConfig> {
         self.resolve_optional_field(
             context,
@@ -225,6 +802,72 @@ impl AppConfig {
         )
     }
 
+    pub fn custom_services(&self, context: &OverrideContext) -> &[CustomServiceConfig] {
+        self.resolve_field(
+            context,
+            |config| &config.custom_services,
+            |config| config.custom_services.as_ref(),
+        )
+    }
+
+    pub fn dev(&self, context: &OverrideContext) -> Option<&DevConfig> {
+        self.resolve_optional_field(
+            context,
+            |config| config.dev.as_ref(),
+            |config| config.dev.as_ref(),
+        )
+    }
+
+    pub fn notify(&self, context: &OverrideContext) -> Option<&NotifyConfig> {
+        self.resolve_optional_field(
+            context,
+            |config| config.notify.as_ref(),
+            |config| config.notify.as_ref(),
+        )
+    }
+
+    pub fn migrations(&self, context: &OverrideContext) -> Option<&MigrationsConfig> {
+        self.resolve_optional_field(
+            context,
+            |config| config.migrations.as_ref(),
+            |config| config.migrations.as_ref(),
+        )
+    }
+
+    pub fn deploy(&self, context: &OverrideContext) -> Option<&DeployConfig> {
+        self.resolve_optional_field(
+            context,
+            |config| config.deploy.as_ref(),
+            |config| config.deploy.as_ref(),
+        )
+    }
+
+    pub fn network(&self, context: &OverrideContext) -> Option<&NetworkConfig> {
+        self.resolve_optional_field(
+            context,
+            |config| config.network.as_ref(),
+            |config| config.network.as_ref(),
+        )
+    }
+
+    pub fn logs(&self, context: &OverrideContext) -> Option<&LogsConfig> {
+        self.resolve_optional_field(
+            context,
+            |config| config.logs.as_ref(),
+            |config| config.logs.as_ref(),
+        )
+    }
+
+    /// Container options are merged option by option,
+    /// so overrides only need to specify what they change
+    pub fn container_options(&self, context: &OverrideContext) -> ContainerOptions {
+        self.active_overrides(context)
+            .into_iter()
+            .fold(self.config.container.clone(), |options, override_config| {
+                options.merge(&override_config.config.container)
+            })
+    }
+
     fn active_overrides(&self, context: &OverrideContext) -> Vec<&OverrideConfig> {
         self.overrides
             .iter()
@@ -296,7 +939,7 @@ impl AppConfig {
     }
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct PostgresConfig {
     /// Name of the environment variable for the database URL
     #[serde(default)]
@@ -305,6 +948,26 @@ pub struct PostgresConfig {
     /// Name of the database
     #[serde(default)]
     database_name: Option<String>,
+
+    /// Relative paths to `.sql` or `.sh` scripts which are run once
+    /// after the database is created for the first time
+    #[serde(default)]
+    init_scripts: Vec<String>,
+
+    /// How the app service reaches the database. Defaults to the mode's usual way
+    #[serde(default)]
+    connection_mode: Option<ConnectionMode>,
+
+    /// Prepended to the names of the exposed variables, e.g. `PRIMARY`
+    #[serde(default)]
+    env_prefix: Option<String>,
+
+    /// Pinned digest of the image, e.g. `sha256:...`
+    #[serde(default)]
+    digest: Option<String>,
+
+    #[serde(flatten)]
+    container: ContainerOptions,
 }
 
 impl PostgresConfig {
@@ -315,9 +978,29 @@ impl PostgresConfig {
     pub fn database_name(&self) -> Option<&str> {
         self.database_name.as_deref()
     }
+
+    pub fn init_scripts(&self) -> &[String] {
+        &self.init_scripts
+    }
+
+    pub fn connection_mode(&self) -> Option<ConnectionMode> {
+        self.connection_mode
+    }
+
+    pub fn env_prefix(&self) -> Option<&str> {
+        self.env_prefix.as_deref()
+    }
+
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
+    pub fn container_options(&self) -> &ContainerOptions {
+        &self.container
+    }
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct KeydbConfig {
     /// Name of the environment variable for the database URL
     #[serde(default)]
@@ -330,7 +1013,7 @@ impl KeydbConfig {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ProxyConfig {
     /// Domain name of the proxy
     /// Note that SSL will be generated automatically
@@ -338,12 +1021,629 @@ pub struct ProxyConfig {
 
     /// Port inside the container
     pub port: u16,
+
+    /// Largest accepted request body, e.g. `100m`. Larger requests are rejected with 413
+    #[serde(default)]
+    pub client_max_body_size: Option<String>,
+
+    /// How long to wait for the response of the app, e.g. `5m`
+    #[serde(default)]
+    pub proxy_read_timeout: Option<DurationConfig>,
+
+    /// How long to wait for a connection to the app
+    #[serde(default)]
+    pub proxy_connect_timeout: Option<DurationConfig>,
+
+    /// How long to wait while sending the request to the app
+    #[serde(default)]
+    pub proxy_send_timeout: Option<DurationConfig>,
+}
+
+/// Address of a dependency which is exposed to the app service
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionMode {
+    /// Container name and port inside of the dploy network
+    Dns,
+
+    /// Host and port published on the docker host
+    Host,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CustomServiceConfig {
+    /// Name of the service, used in the container name
+    pub name: String,
+
+    /// Image to run, optionally with a tag, e.g. `elasticsearch:8.13.0`,
+    /// or a digest, e.g. `elasticsearch@sha256:...`
+    pub image: String,
+
+    /// Pinned digest of the image, e.g. `sha256:...`. Takes precedence over the tag
+    #[serde(default)]
+    pub digest: Option<String>,
+
+    /// Ports exposed by the service
+    #[serde(default)]
+    pub ports: Vec<u16>,
+
+    /// Environment variables of the service container
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Paths to persistent volumes inside the container
+    #[serde(default)]
+    pub volumes: Vec<String>,
+
+    /// Environment variables exposed to the app service.
+    /// `{host}` and `{port}` are replaced with the address of the first port
+    #[serde(default)]
+    pub expose_to_env: BTreeMap<String, String>,
+
+    /// How the app service reaches the service. Defaults to the mode's usual way
+    #[serde(default)]
+    pub connection_mode: Option<ConnectionMode>,
+
+    /// Prepended to the names of the exposed variables, e.g. `SEARCH`
+    #[serde(default)]
+    pub env_prefix: Option<String>,
+
+    #[serde(flatten)]
+    pub container: ContainerOptions,
+}
+
+/// Docker's native container healthcheck, see `HEALTHCHECK` in the Dockerfile reference
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HealthcheckConfig {
+    /// Command in docker's format, e.g. `["CMD", "curl", "-f", "http://localhost:9200"]`
+    pub test: Vec<String>,
+
+    /// Time between checks
+    #[serde(default)]
+    pub interval: Option<DurationConfig>,
+
+    /// Time after which a check is considered failed
+    #[serde(default)]
+    pub timeout: Option<DurationConfig>,
+
+    /// Failed checks in a row after which the container is unhealthy
+    #[serde(default)]
+    pub retries: Option<i64>,
+
+    /// Time to wait before failed checks count
+    #[serde(default)]
+    pub start_period: Option<DurationConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReadinessConfig {
+    /// Command run with docker exec until it exits with 0, e.g. `["pg_isready", "-U", "postgres"]`
+    pub exec: Vec<String>,
+
+    /// How long to keep running the command. Defaults to the timeout of the deploy or `wait`
+    #[serde(default)]
+    pub timeout: Option<DurationConfig>,
+}
+
+/// Duration as a number of seconds or a string like `1m30s` or `500ms`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum DurationConfig {
+    Seconds(u64),
+    Text(String),
+}
+
+impl DurationConfig {
+    pub fn to_duration(&self) -> Result<std::time::Duration> {
+        use std::time::Duration;
+
+        let text = match self {
+            DurationConfig::Seconds(seconds) => return Ok(Duration::from_secs(*seconds)),
+            DurationConfig::Text(text) => text.trim(),
+        };
+
+        if text.is_empty() {
+            bail!("Invalid duration ``. Expected e.g. `30s`, `1m30s` or `500ms`");
+        }
+
+        let mut total = Duration::ZERO;
+        let mut rest = text;
+
+        while !rest.is_empty() {
+            let digits_end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let unit_end = rest[digits_end..]
+                .find(|c: char| c.is_ascii_digit())
+                .map_or(rest.len(), |index| digits_end + index);
+
+            let (Ok(value), unit) = (
+                rest[..digits_end].parse::<u64>(),
+                &rest[digits_end..unit_end],
+            ) else {
+                bail!("Invalid duration `{text}`. Expected e.g. `30s`, `1m30s` or `500ms`");
+            };
+
+            total += match unit {
+                "ms" => Duration::from_millis(value),
+                "s" => Duration::from_secs(value),
+                "m" => Duration::from_secs(value * 60),
+                "h" => Duration::from_secs(value * 60 * 60),
+                _ => bail!("Invalid duration `{text}`. Expected e.g. `30s`, `1m30s` or `500ms`"),
+            };
+
+            rest = &rest[unit_end..];
+        }
+
+        Ok(total)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DevConfig {
+    /// Command run on the host when watched paths change in `dev --watch`
+    #[serde(default)]
+    on_change: Option<String>,
+}
+
+impl DevConfig {
+    pub fn on_change(&self) -> Option<&str> {
+        self.on_change.as_deref()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployConfig {
+    /// Command run on the local machine once the app is deployed
+    #[serde(default)]
+    smoke_test: Option<SmokeTestConfig>,
+}
+
+impl DeployConfig {
+    pub fn smoke_test(&self) -> Option<&SmokeTestConfig> {
+        self.smoke_test.as_ref()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SmokeTestConfig {
+    /// Shell command which fails the deploy when it exits with a non-zero code
+    command: String,
+
+    /// Time limit of one attempt. Defaults to 30 seconds
+    #[serde(default)]
+    timeout: Option<DurationConfig>,
+
+    /// Attempts after the first failed one
+    #[serde(default)]
+    retries: u32,
+
+    /// Delay between the attempts. Defaults to 5 seconds
+    #[serde(default)]
+    interval: Option<DurationConfig>,
+}
+
+impl SmokeTestConfig {
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn timeout(&self) -> Option<&DurationConfig> {
+        self.timeout.as_ref()
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn interval(&self) -> Option<&DurationConfig> {
+        self.interval.as_ref()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MigrationsConfig {
+    /// Shell command run in a one-shot container of the new app image
+    command: String,
+}
+
+impl MigrationsConfig {
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+}
+
+/// Labels printed in front of the lines of followed logs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogsConfig {
+    /// Prefix every line with the name of the service. Defaults to true
+    #[serde(default)]
+    prefix: Option<bool>,
+
+    /// Label colors by service name, e.g. `postgres = "blue"`
+    #[serde(default)]
+    colors: BTreeMap<String, LogColor>,
+}
+
+impl LogsConfig {
+    pub fn prefix(&self) -> bool {
+        self.prefix.unwrap_or(true)
+    }
+
+    pub fn color_of(&self, service: &str) -> Option<LogColor> {
+        self.colors.get(service).copied()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogColor {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+/// IPAM settings of the dploy network, used when dploy creates it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    /// Subnet in CIDR notation, e.g. `172.30.0.0/16`
+    #[serde(default)]
+    subnet: Option<String>,
+
+    /// Gateway address inside the subnet
+    #[serde(default)]
+    gateway: Option<String>,
+
+    /// Part of the subnet containers get their addresses from, in CIDR notation
+    #[serde(default)]
+    ip_range: Option<String>,
+}
+
+impl NetworkConfig {
+    pub fn subnet(&self) -> Option<&str> {
+        self.subnet.as_deref()
+    }
+
+    pub fn gateway(&self) -> Option<&str> {
+        self.gateway.as_deref()
+    }
+
+    pub fn ip_range(&self) -> Option<&str> {
+        self.ip_range.as_deref()
+    }
+}
+
+/// Path of an env file, optionally marked as required
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EnvFileConfig {
+    Path(String),
+    Detailed {
+        path: String,
+
+        /// Fail instead of warning when the file is missing
+        #[serde(default)]
+        required: bool,
+    },
+}
+
+impl EnvFileConfig {
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Path(path) | Self::Detailed { path, .. } => path,
+        }
+    }
+
+    pub fn is_required(&self) -> bool {
+        matches!(self, Self::Detailed { required: true, .. })
+    }
+}
+
+/// Mounted in the Dockerfile with `RUN --mount=type=secret,id=<id>`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BuildSecretConfig {
+    pub id: String,
+
+    /// Relative path to the file with the secret
+    pub src: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotifyConfig {
+    /// URL which receives a JSON summary of every deploy
+    webhook_url: String,
+
+    /// Additional HTTP headers, `${NAME}` is replaced with environment variables
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+}
+
+impl NotifyConfig {
+    pub fn webhook_url(&self) -> &str {
+        &self.webhook_url
+    }
+
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+}
+
+/// Container settings shared by the application and dependencies services
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ContainerOptions {
+    /// Linux capabilities to add to the container
+    #[serde(default)]
+    pub cap_add: Option<Vec<String>>,
+
+    /// Linux capabilities to drop from the container
+    #[serde(default)]
+    pub cap_drop: Option<Vec<String>>,
+
+    /// Security options, e.g. `seccomp=unconfined`
+    #[serde(default)]
+    pub security_opt: Option<Vec<String>>,
+
+    /// Mount the container's root filesystem as read only
+    #[serde(default)]
+    pub read_only: Option<bool>,
+
+    /// Prevent container processes from gaining additional privileges
+    #[serde(default)]
+    pub no_new_privileges: Option<bool>,
+
+    /// Give the container full access to the host's devices and kernel features
+    #[serde(default)]
+    pub privileged: Option<bool>,
+
+    /// Unix sockets to mount from the host, in `host_path:container_path` form
+    #[serde(default)]
+    pub sockets: Option<Vec<String>>,
+
+    /// Host devices, in `host_path[:container_path[:permissions]]` form
+    #[serde(default)]
+    pub devices: Option<Vec<String>>,
+
+    /// Container paths backed by tmpfs, in `path` or `path:options` form,
+    /// e.g. `/run:size=64m,mode=1777`
+    #[serde(default)]
+    pub tmpfs: Option<Vec<String>>,
+
+    /// Resource limits by name, e.g. `nofile = { soft = 65536, hard = 65536 }`
+    #[serde(default)]
+    pub ulimits: Option<BTreeMap<String, UlimitConfig>>,
+
+    /// DNS servers, as IP addresses. Set at the top level, applies to all containers
+    #[serde(default)]
+    pub dns: Option<Vec<String>>,
+
+    /// DNS search domains. Set at the top level, applies to all containers
+    #[serde(default)]
+    pub dns_search: Option<Vec<String>>,
+
+    /// Signal sent to the container when it is stopped, e.g. `SIGINT`
+    #[serde(default)]
+    pub stop_signal: Option<String>,
+
+    /// Time the container gets to stop before it's killed, e.g. `30s`. Defaults to docker's 10 seconds
+    #[serde(default)]
+    pub stop_grace_period: Option<DurationConfig>,
+
+    /// Run an init process as PID 1 which forwards signals and reaps zombies
+    #[serde(default)]
+    pub init: Option<bool>,
+
+    /// Platform of the image, e.g. `linux/amd64`. Defaults to the platform of the docker host.
+    /// Set at the top level, applies to all containers
+    #[serde(default)]
+    pub platform: Option<String>,
+
+    /// Docker's native healthcheck, shown by `docker ps` and used to wait for readiness
+    #[serde(default)]
+    pub healthcheck: Option<HealthcheckConfig>,
+
+    /// Command run in the container to tell when it's ready, instead of connecting to its port
+    #[serde(default)]
+    pub readiness: Option<ReadinessConfig>,
+
+    /// GPUs available to the container: `"all"`, a count or a list of device ids
+    #[serde(default)]
+    pub gpus: Option<GpusConfig>,
+
+    /// Size of `/dev/shm`, e.g. `256m`. Docker's default is 64m
+    #[serde(default)]
+    pub shm_size: Option<String>,
+
+    /// Never kill the container's processes when the host runs out of memory
+    #[serde(default)]
+    pub oom_kill_disable: Option<bool>,
+
+    /// Preference of the container's processes for the OOM killer, from -1000 to 1000
+    #[serde(default)]
+    pub oom_score_adj: Option<i64>,
+
+    /// Container labels, e.g. for Traefik or Prometheus. Values may reference `${ENV}` variables
+    #[serde(default)]
+    pub labels: Option<BTreeMap<String, String>>,
+
+    /// Namespaced kernel parameters, e.g. `net.core.somaxconn`
+    #[serde(default)]
+    pub sysctls: Option<BTreeMap<String, String>>,
+
+    /// PID namespace: `host` or `container:<service>`
+    #[serde(default)]
+    pub pid_mode: Option<String>,
+
+    /// IPC namespace: `none`, `private`, `shareable`, `host` or `container:<service>`
+    #[serde(default)]
+    pub ipc_mode: Option<String>,
+
+    /// Small files defined in the config and mounted read only into the container
+    #[serde(default)]
+    pub files: Option<Vec<InlineFileConfig>>,
+
+    /// Static address on the dploy network, inside the `[network]` subnet
+    #[serde(default)]
+    pub ip: Option<String>,
+
+    /// MAC address on the dploy network, e.g. `02:42:ac:11:00:02`
+    #[serde(default)]
+    pub mac_address: Option<String>,
+
+    /// CPUs the container may run on, e.g. `0-3` or `0,2`
+    #[serde(default)]
+    pub cpuset_cpus: Option<String>,
+
+    /// NUMA memory nodes the container may use, in the format of `cpuset_cpus`
+    #[serde(default)]
+    pub cpuset_mems: Option<String>,
+}
+
+/// File written by dploy on the docker host, e.g. a short `pg_hba.conf`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InlineFileConfig {
+    /// Absolute path of the file inside the container
+    pub target: String,
+
+    /// Contents of the file
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum GpusConfig {
+    Count(i64),
+    DeviceIds(Vec<String>),
+    /// Only `all` is valid
+    All(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct UlimitConfig {
+    pub soft: i64,
+    pub hard: i64,
+}
+
+impl ContainerOptions {
+    /// Options set in `other` take precedence
+    pub fn merge(&self, other: &ContainerOptions) -> ContainerOptions {
+        ContainerOptions {
+            cap_add: other.cap_add.clone().or_else(|| self.cap_add.clone()),
+            cap_drop: other.cap_drop.clone().or_else(|| self.cap_drop.clone()),
+            security_opt: other
+                .security_opt
+                .clone()
+                .or_else(|| self.security_opt.clone()),
+            read_only: other.read_only.or(self.read_only),
+            no_new_privileges: other.no_new_privileges.or(self.no_new_privileges),
+            privileged: other.privileged.or(self.privileged),
+            sockets: other.sockets.clone().or_else(|| self.sockets.clone()),
+            devices: other.devices.clone().or_else(|| self.devices.clone()),
+            tmpfs: other.tmpfs.clone().or_else(|| self.tmpfs.clone()),
+            ulimits: other.ulimits.clone().or_else(|| self.ulimits.clone()),
+            dns: other.dns.clone().or_else(|| self.dns.clone()),
+            dns_search: other.dns_search.clone().or_else(|| self.dns_search.clone()),
+            stop_signal: other
+                .stop_signal
+                .clone()
+                .or_else(|| self.stop_signal.clone()),
+            stop_grace_period: other
+                .stop_grace_period
+                .clone()
+                .or_else(|| self.stop_grace_period.clone()),
+            init: other.init.or(self.init),
+            platform: other.platform.clone().or_else(|| self.platform.clone()),
+            healthcheck: other
+                .healthcheck
+                .clone()
+                .or_else(|| self.healthcheck.clone()),
+            readiness: other.readiness.clone().or_else(|| self.readiness.clone()),
+            gpus: other.gpus.clone().or_else(|| self.gpus.clone()),
+            shm_size: other.shm_size.clone().or_else(|| self.shm_size.clone()),
+            oom_kill_disable: other.oom_kill_disable.or(self.oom_kill_disable),
+            oom_score_adj: other.oom_score_adj.or(self.oom_score_adj),
+            labels: other.labels.clone().or_else(|| self.labels.clone()),
+            sysctls: other.sysctls.clone().or_else(|| self.sysctls.clone()),
+            pid_mode: other.pid_mode.clone().or_else(|| self.pid_mode.clone()),
+            ipc_mode: other.ipc_mode.clone().or_else(|| self.ipc_mode.clone()),
+            files: other.files.clone().or_else(|| self.files.clone()),
+            ip: other.ip.clone().or_else(|| self.ip.clone()),
+            mac_address: other
+                .mac_address
+                .clone()
+                .or_else(|| self.mac_address.clone()),
+            cpuset_cpus: other
+                .cpuset_cpus
+                .clone()
+                .or_else(|| self.cpuset_cpus.clone()),
+            cpuset_mems: other
+                .cpuset_mems
+                .clone()
+                .or_else(|| self.cpuset_mems.clone()),
+        }
+    }
+
+    /// Options which top level settings pass down to every container
+    pub fn inherited(&self) -> ContainerOptions {
+        ContainerOptions {
+            dns: self.dns.clone(),
+            dns_search: self.dns_search.clone(),
+            platform: self.platform.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn stop_timeout(&self) -> Result<Option<std::time::Duration>> {
+        self.stop_grace_period
+            .as_ref()
+            .map(DurationConfig::to_duration)
+            .transpose()
+            .context("Invalid `stop_grace_period`")
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use super::*;
 
+    #[test]
+    fn test_config_source_parse() {
+        assert_eq!(ConfigSource::parse("-"), ConfigSource::Stdin);
+        assert_eq!(
+            ConfigSource::parse("https://example.com/dploy.toml"),
+            ConfigSource::Url("https://example.com/dploy.toml".to_owned())
+        );
+        assert_eq!(
+            ConfigSource::parse("http://localhost:8080/dploy.toml"),
+            ConfigSource::Url("http://localhost:8080/dploy.toml".to_owned())
+        );
+
+        // local paths which only look like stdin or a URL
+        for path in [
+            "dploy.toml",
+            "./-",
+            "--",
+            "http:dploy.toml",
+            "http:/dploy.toml",
+            "https/dploy.toml",
+            "configs/http://dploy.toml",
+        ] {
+            assert_eq!(
+                ConfigSource::parse(path),
+                ConfigSource::File(PathBuf::from(path)),
+                "{path}"
+            );
+        }
+
+        assert_eq!(ConfigSource::parse("-").file(), None);
+    }
+
     #[test]
     fn test_resolve_field() {
         let config = TopLevelAppConfig {
@@ -388,4 +1688,23 @@ mod tests {
         assert_eq!("Dockerfile", app_config.dockerfile(&other_context));
         assert_eq!("dploy-test", app_config.name(&other_context));
     }
+
+    #[test]
+    fn test_duration_config() {
+        let parse = |text: &str| DurationConfig::Text(text.to_owned()).to_duration().ok();
+
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            DurationConfig::Seconds(10).to_duration().ok()
+        );
+        assert_eq!(Some(Duration::from_secs(30)), parse("30s"));
+        assert_eq!(Some(Duration::from_secs(90)), parse("1m30s"));
+        assert_eq!(Some(Duration::from_millis(500)), parse("500ms"));
+        assert_eq!(Some(Duration::from_secs(7200)), parse("2h"));
+
+        assert_eq!(None, parse(""));
+        assert_eq!(None, parse("30"));
+        assert_eq!(None, parse("s"));
+        assert_eq!(None, parse("10 seconds"));
+    }
 }
@@ -1,6 +1,10 @@
 pub const DEFAULT_NAMESPACE: &str = "default";
+pub const BRANCH_NAMESPACE: &str = "@branch";
 pub const DEFAULT_DOCKERFILE_NAME: &str = "Dockerfile";
 pub const DEFAULT_DOTENV_FILE_NAME: &str = ".env";
+pub const STATE_DIR_NAME: &str = ".dploy";
+pub const MAX_APP_REPLICAS: usize = 32;
+pub const GIT_SHA_ENV: &str = "DPLOY_GIT_SHA";
 
 pub fn get_default_dockerfile_name() -> String {
     DEFAULT_DOCKERFILE_NAME.to_string()
@@ -1,8 +1,14 @@
 use std::{
     collections::HashMap,
+    fmt,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex, PoisonError,
+    },
 };
 
+use anyhow::{bail, Context as _, Result};
 use bollard::models;
 
 use crate::{
@@ -17,9 +23,62 @@ use crate::{
 pub struct Context {
     args: Args,
 
-    app_config: AppConfig,
+    app_config: Arc<AppConfig>,
 
     override_context: config::OverrideContext,
+
+    deploy_phase: Mutex<DeployPhase>,
+}
+
+/// Step of the deploy in progress, reported when the deploy times out
+#[derive(Debug, Clone, Default)]
+pub enum DeployPhase {
+    #[default]
+    Preparing,
+    BuildingImage,
+    StartingDependencies,
+    /// Migrations of the new app image run in a one-shot container
+    RunningMigrations {
+        container_name: String,
+    },
+    StartingApp,
+    /// The new container of a blue-green deploy is started next to the old one
+    StartingAppCandidate {
+        container_name: String,
+    },
+    RunningPostUp,
+    /// The replaced app containers are kept until the smoke test passes
+    RunningSmokeTest {
+        rollback: Option<AppRollback>,
+    },
+    WaitingForReadiness,
+}
+
+/// How the app containers replaced by a deploy are brought back when its smoke test fails
+#[derive(Debug, Clone)]
+pub enum AppRollback {
+    /// The proxy is switched back to the old container, which is still running
+    BlueGreen { candidate_name: String },
+    /// The old containers were stopped and renamed with a `_previous` suffix
+    Recreate { container_names: Vec<String> },
+}
+
+impl fmt::Display for DeployPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Preparing => write!(f, "preparing the deploy"),
+            Self::BuildingImage => write!(f, "building the app image"),
+            Self::StartingDependencies => write!(f, "starting dependencies"),
+            Self::RunningMigrations { .. } => write!(f, "running migrations"),
+            Self::StartingApp => write!(f, "starting the app container"),
+            Self::StartingAppCandidate { container_name } => {
+                write!(f, "starting the new app container {container_name}")
+            }
+            Self::RunningPostUp => write!(f, "running post up actions"),
+            Self::RunningSmokeTest { .. } => write!(f, "running the smoke test"),
+            Self::WaitingForReadiness => write!(f, "waiting for services to accept connections"),
+        }
+    }
 }
 
 impl Context {
@@ -30,11 +89,69 @@ impl Context {
     ) -> Self {
         Self {
             args,
-            app_config,
+            app_config: Arc::new(app_config),
             override_context,
+            deploy_phase: Mutex::default(),
+        }
+    }
+
+    /// Context with the config parsed again, e.g. once the env files are loaded
+    pub fn with_app_config(self, app_config: config::AppConfig) -> Self {
+        Self {
+            app_config: Arc::new(app_config),
+            ..self
         }
     }
 
+    /// Context deploying to only one of the hosts
+    pub fn for_host(&self, host: &str) -> Self {
+        Self {
+            args: self.args.with_host(host),
+            app_config: Arc::clone(&self.app_config),
+            override_context: self.override_context.clone(),
+            deploy_phase: Mutex::default(),
+        }
+    }
+
+    /// Context of another namespace, e.g. for `--all-namespaces`
+    pub fn for_namespace(&self, namespace: &str) -> Self {
+        Self {
+            args: self.args.with_namespace(namespace),
+            app_config: Arc::clone(&self.app_config),
+            override_context: config::OverrideContext {
+                namespace: namespace.to_owned(),
+                ..self.override_context.clone()
+            },
+            deploy_phase: Mutex::default(),
+        }
+    }
+
+    /// Reads the config file again, keeping the arguments and the override context
+    pub fn reload(&self) -> anyhow::Result<Self> {
+        let contents = self.args.config_source().read()?;
+        let app_config = config::AppConfig::parse(&contents)?;
+
+        Ok(Self::new(
+            self.args.clone(),
+            app_config,
+            self.override_context.clone(),
+        ))
+    }
+
+    pub fn deploy_phase(&self) -> DeployPhase {
+        self.deploy_phase
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn set_deploy_phase(&self, phase: DeployPhase) {
+        *self
+            .deploy_phase
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = phase;
+    }
+
     pub fn args(&self) -> &Args {
         &self.args
     }
@@ -51,7 +168,40 @@ impl Context {
         &self.app_config
     }
 
-    pub fn container_name_of(&self, service_kind: ServiceKind) -> String {
+    /// Directory containing the config file.
+    /// The current directory if the config isn't read from a file
+    pub fn config_dir(&self) -> PathBuf {
+        self.args
+            .config_source()
+            .file()
+            .and_then(|path| path.parent())
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    /// Local directory for dploy's own state.
+    /// It is kept separately for every target host and namespace
+    pub fn state_dir(&self) -> PathBuf {
+        let target = self
+            .ssh_credentials()
+            .map(|credentials| credentials.host().to_owned())
+            .unwrap_or_else(|| "local".to_owned());
+
+        self.config_dir()
+            .join(constants::STATE_DIR_NAME)
+            .join(target)
+            .join(self.namespace())
+    }
+
+    pub fn build_context_dir(&self) -> PathBuf {
+        self.app_config
+            .build_context(&self.override_context)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| self.config_dir())
+    }
+
+    pub fn container_name_of(&self, service_kind: &ServiceKind) -> String {
         let prefix = if service_kind.is_singleton() {
             "dploy-singleton"
         } else {
@@ -66,10 +216,11 @@ impl Context {
                 Keydb => "keydb",
                 Proxy => "proxy",
                 App => self.app_config.name(&self.override_context),
+                Custom { name } => name.as_str(),
             }
         };
 
-        let namespace = if service_kind == services::ServiceKind::Proxy {
+        let namespace = if *service_kind == services::ServiceKind::Proxy {
             // proxy is not namespaced because it's exposed to the host's network
             constants::DEFAULT_NAMESPACE
         } else {
@@ -79,21 +230,40 @@ impl Context {
         format!("{prefix}_{suffix}_{namespace}")
     }
 
-    pub fn volume_path_of(&self, service_kind: ServiceKind, path: impl AsRef<Path>) -> PathBuf {
-        let volume_path = self
-            .get_dploy_dir()
+    /// Directory containing all volumes of the service
+    pub fn volumes_dir_of(&self, service_kind: &ServiceKind) -> PathBuf {
+        self.get_dploy_dir()
             .join("volumes")
             .join(self.container_name_of(service_kind))
-            .join(
-                path.as_ref()
-                    .to_string_lossy()
-                    .replace('\\', "/")
-                    .replace('/', "$__$"),
-            );
+    }
+
+    pub fn volume_path_of(&self, service_kind: &ServiceKind, path: impl AsRef<Path>) -> PathBuf {
+        let volume_path = self
+            .volumes_dir_of(service_kind)
+            .join(flatten_path(path.as_ref()));
 
         volume_path
     }
 
+    /// Parent of the directories with the inline `files` of every container
+    pub fn files_dir(&self) -> PathBuf {
+        self.get_dploy_dir().join("files")
+    }
+
+    /// State kept on the docker host between deploys, like the hash of the last deploy
+    pub fn host_state_dir(&self) -> PathBuf {
+        self.get_dploy_dir().join("state")
+    }
+
+    /// Path of an inline file on the docker host, relative to [`Self::files_dir`]
+    pub fn file_name_of(&self, service_kind: &ServiceKind, target: &str) -> String {
+        format!(
+            "{}/{}",
+            self.container_name_of(service_kind),
+            flatten_path(Path::new(target))
+        )
+    }
+
     pub fn should_expose_to_host(&self) -> bool {
         use Command::*;
 
@@ -110,30 +280,146 @@ impl Context {
         use Command::*;
 
         matches!(self.args.command(), Dev { .. } | Run { .. })
+            && !self.args.command().detach()
+            && !self.args.command().wait_for_exit()
+    }
+
+    pub fn should_print_started_summary(&self) -> bool {
+        self.args.command().detach()
+    }
+
+    pub fn should_wait_for_readiness(&self) -> bool {
+        self.should_print_connection_info() && !self.args.command().no_wait()
     }
 
     pub fn should_create_app_service(&self) -> bool {
         use Command::*;
 
         matches!(self.args.command(), Deploy { .. } | Run { .. })
+            && !self.args.command().deps_only()
     }
 
     pub fn should_create_proxy_service(&self) -> bool {
         use Command::*;
 
         matches!(self.args.command(), Deploy { command: None, .. })
+            && !self.args.command().deps_only()
+    }
+
+    pub fn should_skip_unchanged_deploy(&self) -> bool {
+        use Command::*;
+
+        matches!(
+            self.args.command(),
+            Deploy {
+                command: None,
+                force: false,
+                ..
+            }
+        ) && self.args.command().recreate_volumes().is_none()
+    }
+
+    /// The status line is overwritten in place, so it only makes sense in a terminal
+    pub fn should_print_watch_status(&self) -> bool {
+        !self.args.quiet() && console::Term::stdout().features().is_attended()
+    }
+
+    pub fn should_build_locally(&self) -> bool {
+        use Command::*;
+
+        matches!(
+            self.args.command(),
+            Deploy {
+                remote_docker: true,
+                ..
+            }
+        )
+    }
+
+    /// A partial deploy doesn't describe the whole stack, so it's not tracked
+    pub fn should_track_deploy_state(&self) -> bool {
+        use Command::*;
+
+        matches!(self.args.command(), Deploy { command: None, .. })
+            && self.args.command().only().is_empty()
+            && !self.args.command().deps_only()
+            && !self.args.command().no_deps()
+    }
+
+    /// Number of app containers, see `deploy --scale`
+    pub fn app_replicas(&self) -> usize {
+        let app = ServiceKind::App.to_string();
+
+        self.args
+            .command()
+            .scale()
+            .iter()
+            .rev()
+            .find(|(service, _)| *service == app)
+            .map_or(1, |(_, count)| *count)
+    }
+
+    /// Names of the app containers. A single container keeps the plain name,
+    /// replicas are numbered from 1
+    pub fn app_container_names(&self) -> Vec<String> {
+        let container_name = self.container_name_of(&ServiceKind::App);
+
+        match self.app_replicas() {
+            1 => vec![container_name],
+            count => (1..=count)
+                .map(|replica| format!("{container_name}_{replica}"))
+                .collect(),
+        }
+    }
+
+    /// Only a full deploy rewrites dploy.lock, a locked one just reads it
+    pub fn should_update_lockfile(&self) -> bool {
+        self.should_track_deploy_state() && !self.args.command().locked()
+    }
+
+    pub fn should_deploy_service(&self, service_kind: &ServiceKind) -> bool {
+        let only = self.args.command().only();
+
+        only.is_empty() || only.iter().any(|name| *name == service_kind.to_string())
     }
 
+    /// Whether `--recreate-volumes` applies to the service. The volumes of the app
+    /// are kept, and the proxy is shared between projects
+    pub fn should_recreate_volumes_of(&self, service_kind: &ServiceKind) -> bool {
+        let Some(args) = self.args.command().recreate_volumes() else {
+            return false;
+        };
+
+        if matches!(service_kind, ServiceKind::App | ServiceKind::Proxy) {
+            return false;
+        }
+
+        args.services().is_empty() || args.services().contains(&service_kind.to_string())
+    }
+
+    /// Dependencies already running with `--no-deps` keep the host ports of the
+    /// existing env file, newly picked ones would be wrong
     pub fn should_generate_env_file(&self) -> bool {
         use Command::*;
 
-        matches!(self.args.command(), Dev { .. } | Run { .. })
+        matches!(self.args.command(), Dev { .. } | Run { .. }) && self.should_start_dependencies()
+    }
+
+    /// The smoke test checks the app from the outside, which only makes sense for a server
+    pub fn should_run_smoke_test(&self) -> bool {
+        use Command::*;
+
+        matches!(self.args.command(), Deploy { .. }) && self.should_create_app_service()
+    }
+
+    pub fn should_start_dependencies(&self) -> bool {
+        !self.args.command().no_deps()
     }
 
     pub fn should_create_network(&self) -> bool {
         use Command::*;
 
-        matches!(self.args.command(), Dev { .. } | Run { .. })
+        matches!(self.args.command(), Dev { .. } | Run { .. } | Deploy { .. })
     }
 
     pub fn manual_mount(&self, outer_path: &str, inner_path: &str) -> models::Mount {
@@ -161,7 +447,7 @@ impl Context {
         self.get_dploy_dir().join(outer_path)
     }
 
-    pub fn mount(&self, service_kind: ServiceKind, inner_path: &str) -> models::Mount {
+    pub fn mount(&self, service_kind: &ServiceKind, inner_path: &str) -> models::Mount {
         models::Mount {
             source: Some(
                 self.volume_path_of(service_kind, inner_path)
@@ -183,13 +469,42 @@ impl Context {
 
     pub fn host_port_binding_of(
         &self,
-        service_kind: ServiceKind,
+        service_kind: &ServiceKind,
         inner_port: u16,
-    ) -> HostPortBinding {
+        connection_mode: Option<config::ConnectionMode>,
+    ) -> Result<HostPortBinding> {
+        let command = self.args.command();
+
+        // the app container on the host network can't resolve container names either.
+        // It runs on the docker host, so it reaches the ports published on its loopback address
+        let is_app_on_host_network = !matches!(command, Command::Dev { .. })
+            && self.app_config().network_mode(self.override_context()) == config::NetworkMode::Host;
+
+        if is_app_on_host_network && connection_mode == Some(config::ConnectionMode::Dns) {
+            bail!(
+                "The app uses the host network, so it can't reach {service_kind} by its container \
+                 name. Remove connection_mode = \"dns\" to publish {service_kind} on the host"
+            );
+        }
+
+        // the app runs on the host in dev mode, so it can't resolve container names
+        let connection_mode = connection_mode.unwrap_or(match command {
+            Command::Dev { .. } => config::ConnectionMode::Host,
+            _ if is_app_on_host_network => config::ConnectionMode::Host,
+            _ => config::ConnectionMode::Dns,
+        });
+
+        let public_host = self
+            .ssh_credentials()
+            .filter(|_| !is_app_on_host_network)
+            .map(|credentials| credentials.host().to_owned());
+
         HostPortBinding::new(
             &self.container_name_of(service_kind),
             inner_port,
-            self.args.command(),
+            command,
+            connection_mode,
+            public_host.as_deref(),
         )
     }
 
@@ -199,22 +514,36 @@ impl Context {
         let command = self.args().command();
 
         let Deploy {
-            host,
+            hosts,
             port,
             username,
             keyfile,
+            ssh_agent,
+            no_agent,
             ..
         } = command
         else {
             return None;
         };
 
-        Some(SshCredentials::new(
-            host.clone(),
-            *port,
-            username.clone(),
-            keyfile.clone().map(PathBuf::from),
-        ))
+        // multiple hosts are deployed with a context per host, see `for_host`
+        let host = hosts.first()?;
+
+        let agent = match (*ssh_agent, *no_agent) {
+            (true, _) => SshAgent::Required,
+            (_, true) => SshAgent::Disabled,
+            _ => SshAgent::Auto,
+        };
+
+        Some(
+            SshCredentials::new(
+                host.clone(),
+                *port,
+                username.clone(),
+                keyfile.clone().map(PathBuf::from),
+            )
+            .with_agent(agent),
+        )
     }
 
     fn get_dploy_dir(&self) -> PathBuf {
@@ -222,6 +551,13 @@ impl Context {
     }
 }
 
+/// Turns a container path into a single file name on the host
+fn flatten_path(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "/")
+        .replace('/', "$__$")
+}
+
 // TODO: some really complex logic going on here
 #[derive(Clone, Debug)]
 pub struct HostPortBinding {
@@ -229,54 +565,93 @@ pub struct HostPortBinding {
     inner_port: u16,
     inner_host: String,
 
-    /// The port for communication of the host with this service
-    host_port: Option<u16>,
+    /// The port for communication of the host with this service.
+    /// Shared between clones, so a port picked again by `rebind` is seen everywhere
+    host_port: Option<Arc<AtomicU16>>,
     host_host: String,
 
     /// The port inside the container
     internal_port: u16,
+
+    /// Whether the app service reaches this service on `host_port`
+    is_inner_host_port: bool,
+
+    /// Whether `host_port` was picked by `free_port` and may be picked again
+    is_free_port: bool,
 }
 
 impl HostPortBinding {
-    pub fn new(container_name: &str, internal_port: u16, command: &Command) -> Self {
+    /// `public_host` is the address of the remote server in deploy mode
+    pub fn new(
+        container_name: &str,
+        internal_port: u16,
+        command: &Command,
+        connection_mode: config::ConnectionMode,
+        public_host: Option<&str>,
+    ) -> Result<Self> {
+        use config::ConnectionMode;
         use Command::*;
 
-        let host_port = match command {
-            Dev { .. } | Run { .. } => Some(utils::network::free_port()),
+        let host_port = match (command, connection_mode) {
+            (Dev { .. } | Run { .. }, _) => {
+                Some(utils::network::free_port().with_context(|| {
+                    format!("Could not allocate a free host port for {container_name}")
+                })?)
+            }
+            // the server is reached on the same port as inside the container
+            (_, ConnectionMode::Host) => Some(internal_port),
             _ => None,
         };
 
-        let host_host = "127.0.0.1";
-
-        let inner_host = match command {
-            Dev { .. } => host_host,
-            _ => container_name,
+        let host_host = match public_host {
+            Some(_) => "0.0.0.0",
+            None => "127.0.0.1",
         };
 
-        let inner_port = match (command, host_port) {
-            (Dev { .. }, Some(port)) => port,
-            _ => internal_port,
+        let is_inner_host_port = connection_mode == ConnectionMode::Host && host_port.is_some();
+
+        let inner_host = match is_inner_host_port {
+            true => public_host.unwrap_or(host_host),
+            false => container_name,
         };
 
-        Self {
-            inner_port,
+        Ok(Self {
+            inner_port: internal_port,
             inner_host: inner_host.to_owned(),
-            host_port,
+            host_port: host_port.map(|port| Arc::new(AtomicU16::new(port))),
             host_host: host_host.to_owned(),
             internal_port,
-        }
+            is_inner_host_port,
+            is_free_port: matches!(command, Dev { .. } | Run { .. }),
+        })
     }
 
     pub fn manual(host_port: u16, host_host: &str, inner_port: u16, inner_host: &str) -> Self {
         Self {
             inner_port,
             inner_host: inner_host.to_owned(),
-            host_port: Some(host_port),
+            host_port: Some(Arc::new(AtomicU16::new(host_port))),
             host_host: host_host.to_owned(),
             internal_port: inner_port,
+            is_inner_host_port: false,
+            is_free_port: false,
         }
     }
 
+    /// Picks another free host port, e.g. after the previous one was taken
+    /// by another process. Returns the old and the new port
+    pub fn rebind(&self) -> Result<Option<(u16, u16)>> {
+        let Some(host_port) = self.host_port.as_ref().filter(|_| self.is_free_port) else {
+            return Ok(None);
+        };
+
+        let new_port =
+            utils::network::free_port().context("Could not allocate another free host port")?;
+        let old_port = host_port.swap(new_port, Ordering::SeqCst);
+
+        Ok(Some((old_port, new_port)))
+    }
+
     pub fn to_port_bindings(
         bindings: &[&HostPortBinding],
     ) -> HashMap<String, Option<Vec<models::PortBinding>>> {
@@ -307,7 +682,10 @@ impl HostPortBinding {
     }
 
     pub fn inner_port(&self) -> u16 {
-        self.inner_port
+        match self.host_port() {
+            Some(port) if self.is_inner_host_port => port,
+            _ => self.inner_port,
+        }
     }
 
     pub fn inner_host(&self) -> &str {
@@ -316,6 +694,8 @@ impl HostPortBinding {
 
     pub fn host_port(&self) -> Option<u16> {
         self.host_port
+            .as_ref()
+            .map(|port| port.load(Ordering::SeqCst))
     }
 
     pub fn host_host(&self) -> &str {
@@ -327,12 +707,22 @@ impl HostPortBinding {
     }
 }
 
+/// How ssh-agent takes part in the authentication, see `--ssh-agent` and `--no-agent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshAgent {
+    /// Used when it's running, like ssh does
+    Auto,
+    Required,
+    Disabled,
+}
+
 #[derive(Debug, Clone)]
 pub struct SshCredentials {
     host: String,
     port: u16,
     username: String,
     keyfile: Option<PathBuf>,
+    agent: SshAgent,
 }
 
 impl SshCredentials {
@@ -342,9 +732,15 @@ impl SshCredentials {
             port,
             username,
             keyfile,
+            agent: SshAgent::Auto,
         }
     }
 
+    pub fn with_agent(mut self, agent: SshAgent) -> Self {
+        self.agent = agent;
+        self
+    }
+
     pub fn host(&self) -> &str {
         &self.host
     }
@@ -360,4 +756,8 @@ impl SshCredentials {
     pub fn keyfile(&self) -> Option<&Path> {
         self.keyfile.as_deref()
     }
+
+    pub fn agent(&self) -> SshAgent {
+        self.agent
+    }
 }
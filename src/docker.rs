@@ -1,22 +1,174 @@
 use std::{
-    io::{Read, Write},
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::atomic::{AtomicBool, Ordering},
     time::Duration,
 };
 
 use futures::StreamExt;
+use serde::Serialize;
 use termion::raw::IntoRawMode;
 use tokio::io::AsyncWriteExt;
 
-use crate::{context, prelude::*, ssh};
+use crate::{
+    constants, context, prelude::*, presentation, services, ssh, utils::string::mask_env_value,
+};
+
+const DOCKER_RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const DOCKER_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
+static VERBOSE_DOCKER: AtomicBool = AtomicBool::new(false);
+
+pub fn enable_verbose_docker() {
+    VERBOSE_DOCKER.store(true, Ordering::Relaxed);
+}
+
+fn is_verbose_docker() -> bool {
+    VERBOSE_DOCKER.load(Ordering::Relaxed)
+}
+
+/// Prints a docker request to stderr in `--verbose-docker` mode
+fn log_docker_request(operation: &str, request: &impl Serialize) {
+    if !is_verbose_docker() {
+        return;
+    }
+
+    match serde_json::to_string_pretty(request) {
+        Ok(request) => eprintln!("[docker] {operation} request:\n{request}"),
+        Err(error) => eprintln!("[docker] {operation} request could not be serialized: {error}"),
+    }
+}
+
+/// Prints the size of the build context and how long packing it took in `--verbose-docker` mode,
+/// to weigh compression against the transfer time
+pub fn log_build_context(compression: &str, bytes: usize, elapsed: Duration) {
+    if is_verbose_docker() {
+        eprintln!(
+            "[docker] build context packed in {}ms: {bytes} bytes, compression {compression}",
+            elapsed.as_millis()
+        );
+    }
+}
+
+/// Prints the raw error returned by docker in `--verbose-docker` mode
+pub fn log_docker_error(operation: &str, error: &bollard::errors::Error) {
+    if is_verbose_docker() {
+        eprintln!("[docker] {operation} failed: {error:?}");
+    }
+}
+
+/// Passes the result through, printing the error like [`log_docker_error`]
+pub fn logged<T>(
+    operation: &str,
+    result: Result<T, bollard::errors::Error>,
+) -> Result<T, bollard::errors::Error> {
+    if let Err(error) = &result {
+        log_docker_error(operation, error);
+    }
+
+    result
+}
+
+/// The config with values of secret-looking env variables masked, for logging
+fn masked_config(
+    config: &bollard::container::Config<String>,
+) -> bollard::container::Config<String> {
+    let mut config = config.clone();
 
-pub async fn get_default_docker_client() -> Result<bollard::Docker> {
+    config.env = config.env.map(|env| {
+        env.iter()
+            .map(|variable| match variable.split_once('=') {
+                Some((key, value)) => format!("{key}={}", mask_env_value(key, value)),
+                None => variable.clone(),
+            })
+            .collect()
+    });
+
+    config
+}
+
+/// `docker.create_container`, logged in `--verbose-docker` mode
+pub async fn create_container(
+    docker: &bollard::Docker,
+    options: bollard::container::CreateContainerOptions<&str>,
+    config: bollard::container::Config<String>,
+) -> Result<()> {
+    #[derive(Serialize)]
+    struct Request<'a> {
+        options: &'a bollard::container::CreateContainerOptions<&'a str>,
+        config: bollard::container::Config<String>,
+    }
+
+    log_docker_request(
+        "create_container",
+        &Request {
+            options: &options,
+            config: masked_config(&config),
+        },
+    );
+
+    logged(
+        "create_container",
+        docker.create_container(Some(options), config).await,
+    )?;
+
+    Ok(())
+}
+
+/// `docker.start_container`, logged in `--verbose-docker` mode
+pub async fn start_container(docker: &bollard::Docker, container_name: &str) -> Result<()> {
+    logged(
+        "start_container",
+        docker
+            .start_container(
+                container_name,
+                None::<bollard::container::StartContainerOptions<String>>,
+            )
+            .await,
+    )?;
+
+    Ok(())
+}
+
+pub async fn get_default_docker_client(context: &context::Context) -> Result<bollard::Docker> {
     let docker = bollard::Docker::connect_with_defaults()?;
 
-    docker.ping().await.context("Could not ping docker")?;
+    wait_for_docker(&docker, context.args().wait_for_docker()).await?;
 
     Ok(docker)
 }
 
+/// Pings the daemon. With `--wait-for-docker`, failed pings are retried
+/// with a growing delay until the timeout elapses
+pub async fn wait_for_docker(docker: &bollard::Docker, timeout: Option<Duration>) -> Result<()> {
+    let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let mut delay = DOCKER_RETRY_INITIAL_DELAY;
+    let mut is_waiting_reported = false;
+
+    loop {
+        let error = match docker.ping().await {
+            Ok(_) => return Ok(()),
+            Err(error) => error,
+        };
+
+        let Some(deadline) = deadline else {
+            return Err(error).context("Could not ping docker");
+        };
+
+        if tokio::time::Instant::now() + delay > deadline {
+            return Err(error).context("Docker did not become available in time");
+        }
+
+        if !is_waiting_reported {
+            presentation::print_docker_waiting();
+            is_waiting_reported = true;
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(DOCKER_RETRY_MAX_DELAY);
+    }
+}
+
 pub async fn get_docker_client_with_session(
     context: &context::Context,
 ) -> Result<(bollard::Docker, openssh::Session)> {
@@ -30,43 +182,91 @@ pub async fn exec_command_detached(
     container_name: &str,
     command: &str,
 ) -> Result<()> {
-    let result = docker
-        .create_exec(
-            container_name,
-            bollard::exec::CreateExecOptions::<String> {
-                cmd: Some(["sh", "-c", command].into_iter().map(Into::into).collect()),
-                ..Default::default()
-            },
-        )
-        .await?;
+    let result = logged(
+        "create_exec",
+        docker
+            .create_exec(
+                container_name,
+                bollard::exec::CreateExecOptions::<String> {
+                    cmd: Some(["sh", "-c", command].into_iter().map(Into::into).collect()),
+                    ..Default::default()
+                },
+            )
+            .await,
+    )?;
 
-    docker.start_exec(&result.id, None).await?;
+    logged("start_exec", docker.start_exec(&result.id, None).await)?;
 
     Ok(())
 }
 
+/// Runs the command to completion and returns its exit code and combined output
+pub async fn exec_command_captured(
+    docker: &bollard::Docker,
+    container_name: &str,
+    command: &[&str],
+) -> Result<(i64, String)> {
+    let exec = logged(
+        "create_exec",
+        docker
+            .create_exec(
+                container_name,
+                bollard::exec::CreateExecOptions::<String> {
+                    cmd: Some(command.iter().map(|part| part.to_string()).collect()),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await,
+    )?;
+
+    let mut output = String::new();
+
+    if let bollard::exec::StartExecResults::Attached {
+        output: mut stream, ..
+    } = logged("start_exec", docker.start_exec(&exec.id, None).await)?
+    {
+        while let Some(chunk) = stream.next().await {
+            output.push_str(&String::from_utf8_lossy(&chunk?.into_bytes()));
+        }
+    }
+
+    let exit_code = logged("inspect_exec", docker.inspect_exec(&exec.id).await)?
+        .exit_code
+        .unwrap_or_default();
+
+    Ok((exit_code, output))
+}
+
 pub async fn exec_command_attached(
     docker: &bollard::Docker,
     container_name: &str,
     command: &str,
 ) -> Result<()> {
-    let exec = docker
-        .create_exec(
-            container_name,
-            bollard::exec::CreateExecOptions::<String> {
-                cmd: Some(["sh", "-c", command].into_iter().map(Into::into).collect()),
-                attach_stdout: Some(true),
-                attach_stderr: Some(true),
-                attach_stdin: Some(true),
-                tty: Some(true),
-                ..Default::default()
-            },
-        )
-        .await?;
+    let exec = logged(
+        "create_exec",
+        docker
+            .create_exec(
+                container_name,
+                bollard::exec::CreateExecOptions::<String> {
+                    cmd: Some(["sh", "-c", command].into_iter().map(Into::into).collect()),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    attach_stdin: Some(true),
+                    tty: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await,
+    )?;
 
-    let result = docker
-        .start_exec(&exec.id, Some(bollard::exec::StartExecOptions::default()))
-        .await?;
+    let result = logged(
+        "start_exec",
+        docker
+            .start_exec(&exec.id, Some(bollard::exec::StartExecOptions::default()))
+            .await,
+    )?;
 
     match result {
         bollard::exec::StartExecResults::Attached {
@@ -75,7 +275,7 @@ pub async fn exec_command_attached(
         } => {
             // pipe stdin into the docker exec stream input
             tokio::spawn(async move {
-                let mut stdin = termion::async_stdin().bytes();
+                let mut stdin = io::BufReader::new(termion::async_stdin()).bytes();
 
                 loop {
                     if let Some(Ok(byte)) = stdin.next() {
@@ -102,6 +302,114 @@ pub async fn exec_command_attached(
     Ok(())
 }
 
+/// Runs the command without a tty so stdout and stderr stay separate,
+/// only the requested streams are attached
+pub async fn exec_command_streams(
+    docker: &bollard::Docker,
+    container_name: &str,
+    command: &str,
+    attach_stdout: bool,
+    attach_stderr: bool,
+) -> Result<()> {
+    let exec = logged(
+        "create_exec",
+        docker
+            .create_exec(
+                container_name,
+                bollard::exec::CreateExecOptions::<String> {
+                    cmd: Some(["sh", "-c", command].into_iter().map(Into::into).collect()),
+                    attach_stdout: Some(attach_stdout),
+                    attach_stderr: Some(attach_stderr),
+                    ..Default::default()
+                },
+            )
+            .await,
+    )?;
+
+    let result = logged(
+        "start_exec",
+        docker
+            .start_exec(&exec.id, Some(bollard::exec::StartExecOptions::default()))
+            .await,
+    )?;
+
+    let bollard::exec::StartExecResults::Attached { mut output, .. } = result else {
+        unreachable!()
+    };
+
+    let mut stdout = std::io::stdout();
+    let mut stderr = std::io::stderr();
+
+    while let Some(chunk) = output.next().await {
+        match chunk? {
+            bollard::container::LogOutput::StdErr { message } => {
+                stderr.write_all(&message)?;
+                stderr.flush()?;
+            }
+            chunk => {
+                stdout.write_all(&chunk.into_bytes())?;
+                stdout.flush()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Containers of a service: the replicas of an app deployed with `--scale`,
+/// otherwise the container with the plain name
+pub async fn service_container_names(
+    docker: &bollard::Docker,
+    service_kind: &services::ServiceKind,
+    container_name: &str,
+) -> Result<Vec<String>> {
+    if *service_kind != services::ServiceKind::App {
+        return Ok(vec![container_name.to_owned()]);
+    }
+
+    let replicas = replica_container_names(docker, container_name).await?;
+
+    if replicas.is_empty() {
+        return Ok(vec![container_name.to_owned()]);
+    }
+
+    Ok(replicas)
+}
+
+/// Names of the existing containers named `{container_name}_{n}`, the replicas of `deploy --scale`,
+/// ordered by `n`
+pub async fn replica_container_names(
+    docker: &bollard::Docker,
+    container_name: &str,
+) -> Result<Vec<String>> {
+    let containers = docker
+        .list_containers(Some(bollard::container::ListContainersOptions::<String> {
+            all: true,
+            filters: HashMap::from([("name".to_owned(), vec![container_name.to_owned()])]),
+            ..Default::default()
+        }))
+        .await?;
+
+    let prefix = format!("{container_name}_");
+
+    let mut replicas = containers
+        .into_iter()
+        .filter_map(|container| {
+            let name = container.names?.first()?.trim_start_matches('/').to_owned();
+            let replica = name.strip_prefix(&prefix)?.parse::<usize>().ok()?;
+
+            // blue-green candidates are suffixed with a timestamp instead
+            (1..=constants::MAX_APP_REPLICAS)
+                .contains(&replica)
+                .then_some((replica, name))
+        })
+        .collect::<Vec<_>>();
+
+    replicas.sort();
+
+    Ok(replicas.into_iter().map(|(_, name)| name).collect())
+}
+
 /// This version ignores the error if the container is not found
 pub async fn inspect_container(
     docker: &bollard::Docker,
@@ -112,10 +420,324 @@ pub async fn inspect_container(
         Err(bollard::errors::Error::DockerResponseServerError {
             status_code: 404, ..
         }) => Ok(None),
+        Err(e) => {
+            log_docker_error("inspect_container", &e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Unix timestamp of the creation of the container, as recorded by the docker host.
+/// Unlike the start time, it is kept when the restart policy restarts the container.
+/// `None` if the container doesn't exist
+pub async fn container_created_at(
+    docker: &bollard::Docker,
+    container_name: &str,
+) -> Result<Option<i64>> {
+    let Some(created) = inspect_container(docker, container_name)
+        .await?
+        .and_then(|container| container.created)
+    else {
+        return Ok(None);
+    };
+
+    let timestamp = chrono::DateTime::parse_from_rfc3339(&created)
+        .with_context(|| format!("Invalid creation time `{created}` of {container_name}"))?
+        .timestamp();
+
+    Ok(Some(timestamp))
+}
+
+/// Whether starting a container failed because a host port is used by another process
+pub fn is_port_taken_error(error: &anyhow::Error) -> bool {
+    let Some(bollard::errors::Error::DockerResponseServerError { message, .. }) =
+        error.downcast_ref::<bollard::errors::Error>()
+    else {
+        return false;
+    };
+
+    message.contains("port is already allocated") || message.contains("address already in use")
+}
+
+/// Stops (only if running) and removes the container. Returns whether
+/// a container existed before the call. The container is killed if it
+/// doesn't stop within `stop_timeout`, docker's default when not set
+pub async fn ensure_container_absent(
+    docker: &bollard::Docker,
+    container_name: &str,
+    stop_timeout: Option<std::time::Duration>,
+) -> Result<bool> {
+    let existing_container = inspect_container(docker, container_name).await?;
+
+    match absent_action(existing_container.as_ref()) {
+        AbsentAction::Nothing => return Ok(false),
+        AbsentAction::StopAndRemove => {
+            ignore_not_found(
+                docker
+                    .stop_container(container_name, stop_options(stop_timeout))
+                    .await,
+            )?;
+            ignore_not_found(docker.remove_container(container_name, None).await)?;
+        }
+        AbsentAction::Remove => {
+            ignore_not_found(docker.remove_container(container_name, None).await)?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Stops (only if running) and renames the container, e.g. to keep it until
+/// its replacement is known to work. Returns whether the container exists
+pub async fn stop_and_rename_container(
+    docker: &bollard::Docker,
+    container_name: &str,
+    new_name: &str,
+    stop_timeout: Option<std::time::Duration>,
+) -> Result<bool> {
+    let existing_container = inspect_container(docker, container_name).await?;
+
+    match absent_action(existing_container.as_ref()) {
+        AbsentAction::Nothing => return Ok(false),
+        AbsentAction::StopAndRemove => {
+            ignore_not_found(
+                docker
+                    .stop_container(container_name, stop_options(stop_timeout))
+                    .await,
+            )?;
+        }
+        AbsentAction::Remove => {}
+    }
+
+    docker
+        .rename_container(
+            container_name,
+            bollard::container::RenameContainerOptions { name: new_name },
+        )
+        .await?;
+
+    Ok(true)
+}
+
+fn stop_options(
+    stop_timeout: Option<std::time::Duration>,
+) -> Option<bollard::container::StopContainerOptions> {
+    stop_timeout.map(|timeout| bollard::container::StopContainerOptions {
+        t: timeout.as_secs() as i64,
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum AbsentAction {
+    Nothing,
+    Remove,
+    StopAndRemove,
+}
+
+fn absent_action(
+    existing_container: Option<&bollard::models::ContainerInspectResponse>,
+) -> AbsentAction {
+    let Some(existing_container) = existing_container else {
+        return AbsentAction::Nothing;
+    };
+
+    let is_running = existing_container
+        .state
+        .as_ref()
+        .and_then(|state| state.running)
+        .unwrap_or(false);
+
+    if is_running {
+        AbsentAction::StopAndRemove
+    } else {
+        AbsentAction::Remove
+    }
+}
+
+/// The container might disappear between the inspection and the action
+fn ignore_not_found<T>(result: Result<T, bollard::errors::Error>) -> Result<()> {
+    match result {
+        Ok(_)
+        | Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Like [`ignore_not_found`], but keeps the result of a container which still exists
+pub fn none_if_not_found<T>(result: Result<T, bollard::errors::Error>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => Ok(None),
         Err(e) => Err(e.into()),
     }
 }
 
+const READINESS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Containers without a healthcheck are considered ready
+/// after staying up for this long
+const READINESS_STABILIZATION: Duration = Duration::from_secs(3);
+
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Prints the output of the container as it runs and returns its state once it exited
+pub async fn follow_until_exit(
+    docker: &bollard::Docker,
+    container_name: &str,
+) -> Result<bollard::models::ContainerState> {
+    let mut logs = docker.logs(
+        container_name,
+        Some(bollard::container::LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            follow: true,
+            tail: "all".to_owned(),
+            ..Default::default()
+        }),
+    );
+
+    let mut stdout = std::io::stdout();
+
+    // the stream ends when the container stops
+    while let Some(chunk) = logs.next().await {
+        stdout.write_all(&chunk?.into_bytes())?;
+        stdout.flush()?;
+    }
+
+    loop {
+        let Some(state) = inspect_container(docker, container_name)
+            .await?
+            .and_then(|container| container.state)
+        else {
+            bail!("Container {container_name} disappeared before it exited");
+        };
+
+        if !state.running.unwrap_or(false) {
+            if state.oom_killed.unwrap_or(false) {
+                presentation::print_container_oom_killed(container_name);
+            }
+
+            return Ok(state);
+        }
+
+        tokio::time::sleep(EXIT_POLL_INTERVAL).await;
+    }
+}
+
+/// Prints the last lines of the output of the container, e.g. of one which exited
+pub async fn print_container_logs(
+    docker: &bollard::Docker,
+    container_name: &str,
+    lines: usize,
+) -> Result<()> {
+    let mut logs = docker.logs(
+        container_name,
+        Some(bollard::container::LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            tail: lines.to_string(),
+            ..Default::default()
+        }),
+    );
+
+    let mut stdout = std::io::stdout();
+
+    while let Some(chunk) = logs.next().await {
+        stdout.write_all(&chunk?.into_bytes())?;
+    }
+
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// Runs the command in the container until it exits with 0. Returns whether it did
+/// within the timeout and the output of the last attempt, to show why it's not ready
+pub async fn wait_for_exec_success(
+    docker: &bollard::Docker,
+    container_name: &str,
+    command: &[String],
+    timeout: Duration,
+) -> (bool, String) {
+    let command = command.iter().map(String::as_str).collect::<Vec<_>>();
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        let attempt = exec_command_captured(docker, container_name, &command);
+
+        // a starting container may refuse the exec, which is just another failed attempt
+        let output = match tokio::time::timeout(remaining, attempt).await {
+            Ok(Ok((0, _))) => return (true, String::new()),
+            Ok(Ok((exit_code, output))) => format!("exit code {exit_code}: {}", output.trim()),
+            Ok(Err(error)) => error.to_string(),
+            Err(_) => "the command did not finish in time".to_owned(),
+        };
+
+        if tokio::time::Instant::now() + READINESS_POLL_INTERVAL >= deadline {
+            return (false, output);
+        }
+
+        tokio::time::sleep(READINESS_POLL_INTERVAL).await;
+    }
+}
+
+/// Waits until the container is running and, if it defines a healthcheck, healthy.
+/// Returns false if the container exited, became unhealthy or the timeout elapsed
+pub async fn wait_for_container_ready(
+    docker: &bollard::Docker,
+    container_name: &str,
+    timeout: Duration,
+) -> Result<bool> {
+    use bollard::models::HealthStatusEnum;
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut running_since = None;
+    let mut is_oom_reported = false;
+
+    while tokio::time::Instant::now() < deadline {
+        let state = inspect_container(docker, container_name)
+            .await?
+            .and_then(|container| container.state);
+
+        let Some(state) = state else {
+            return Ok(false);
+        };
+
+        // the flag stays set while the container is restarted after the kill
+        if state.oom_killed.unwrap_or(false) && !is_oom_reported {
+            presentation::print_container_oom_killed(container_name);
+            is_oom_reported = true;
+        }
+
+        if !state.running.unwrap_or(false) && !state.restarting.unwrap_or(false) {
+            return Ok(false);
+        }
+
+        match state.health.and_then(|health| health.status) {
+            Some(HealthStatusEnum::HEALTHY) => return Ok(true),
+            Some(HealthStatusEnum::UNHEALTHY) => return Ok(false),
+            Some(HealthStatusEnum::STARTING) => {}
+            _ if state.restarting.unwrap_or(false) => running_since = None,
+            _ => {
+                let running_since = *running_since.get_or_insert_with(tokio::time::Instant::now);
+
+                if running_since.elapsed() >= READINESS_STABILIZATION {
+                    return Ok(true);
+                }
+            }
+        }
+
+        tokio::time::sleep(READINESS_POLL_INTERVAL).await;
+    }
+
+    Ok(false)
+}
+
 pub async fn check_container_running(
     docker: &bollard::Docker,
     container_name: &str,
@@ -124,7 +746,598 @@ pub async fn check_container_running(
         .await
         .map(|container| {
             container.is_some_and(|container| {
-                container.state.is_some_and(|state| state.running.is_some())
+                container
+                    .state
+                    .is_some_and(|state| state.running.unwrap_or(false))
             })
         })
 }
+
+/// Id of the container with this name, if it is running
+pub async fn running_container_id(
+    docker: &bollard::Docker,
+    container_name: &str,
+) -> Result<Option<String>> {
+    let container = inspect_container(docker, container_name).await?;
+
+    Ok(container
+        .filter(|container| {
+            container
+                .state
+                .as_ref()
+                .is_some_and(|state| state.running.unwrap_or(false))
+        })
+        .and_then(|container| container.id))
+}
+
+/// Removes untagged images which no container uses, e.g. the ones left behind by
+/// rebuilding the same tag. Returns the number of removed images and the freed bytes
+pub async fn prune_dangling_images(docker: &bollard::Docker) -> Result<(usize, u64)> {
+    let response = docker
+        .prune_images(Some(bollard::image::PruneImagesOptions {
+            filters: HashMap::from([("dangling", vec!["true"])]),
+        }))
+        .await
+        .context("Failed to prune images")?;
+
+    let count = response.images_deleted.map_or(0, |images| {
+        images
+            .iter()
+            .filter(|image| image.deleted.is_some())
+            .count()
+    });
+    let bytes = response.space_reclaimed.unwrap_or_default().max(0) as u64;
+
+    Ok((count, bytes))
+}
+
+/// Pulls the image, or updates it if it's already present
+pub async fn pull_image(
+    docker: &bollard::Docker,
+    image: &str,
+    platform: Option<&str>,
+) -> Result<()> {
+    // docker resolves a digest reference on its own, without a tag
+    let (image_name, tag) = match image.contains('@') {
+        true => (image, ""),
+        false => {
+            let (image_name, tag) = split_image_tag(image);
+            (image_name, tag.unwrap_or("latest"))
+        }
+    };
+
+    let mut stream = docker.create_image(
+        Some(bollard::image::CreateImageOptions {
+            from_image: image_name,
+            tag,
+            platform: platform.unwrap_or_default(),
+            ..Default::default()
+        }),
+        None,
+        None,
+    );
+
+    while let Some(info) = stream.next().await {
+        logged("create_image", info).with_context(|| format!("Failed to pull image {image}"))?;
+    }
+
+    Ok(())
+}
+
+/// Image reference pinned to the digest, from either `image@digest` or the separate
+/// `digest` setting. A digest takes precedence over the tag. Returns the reference
+/// and the digest, e.g. `postgres@sha256:...` and `sha256:...`
+pub fn pinned_image(image: &str, digest: Option<&str>) -> Result<(String, Option<String>)> {
+    let (name, image_digest) = match image.split_once('@') {
+        Some((name, image_digest)) => (name, Some(image_digest)),
+        None => (image, None),
+    };
+
+    let digest = match (image_digest, digest) {
+        (Some(image_digest), Some(digest)) if image_digest != digest => {
+            bail!("The digest of image {image} differs from the pinned digest {digest}")
+        }
+        (image_digest, digest) => image_digest.or(digest),
+    };
+
+    let Some(digest) = digest else {
+        return Ok((image.to_owned(), None));
+    };
+
+    let is_valid_digest = digest.strip_prefix("sha256:").is_some_and(|hash| {
+        hash.len() == 64
+            && hash
+                .chars()
+                .all(|char| char.is_ascii_digit() || ('a'..='f').contains(&char))
+    });
+
+    if !is_valid_digest {
+        bail!("Invalid image digest `{digest}`. Expected `sha256:` and 64 hex characters");
+    }
+
+    let (name, _) = split_image_tag(name);
+
+    Ok((format!("{name}@{digest}"), Some(digest.to_owned())))
+}
+
+/// Fails if the local image isn't the one with the pinned digest
+pub async fn verify_image_digest(
+    docker: &bollard::Docker,
+    image: &str,
+    digest: &str,
+) -> Result<()> {
+    let actual_digests = image_repo_digests(docker, image).await?;
+
+    if !actual_digests.iter().any(|actual| actual == digest) {
+        let actual = if actual_digests.is_empty() {
+            "none".to_owned()
+        } else {
+            actual_digests.join(", ")
+        };
+
+        bail!("Image {image} has digest {actual}, expected the pinned digest {digest}");
+    }
+
+    Ok(())
+}
+
+/// Registry digests of a pulled image, e.g. `sha256:...`.
+/// Images which were only built locally have none
+pub async fn image_repo_digests(docker: &bollard::Docker, image: &str) -> Result<Vec<String>> {
+    let inspect = docker
+        .inspect_image(image)
+        .await
+        .with_context(|| format!("Failed to inspect image {image}"))?;
+
+    Ok(inspect
+        .repo_digests
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|repo_digest| {
+            repo_digest
+                .split_once('@')
+                .map(|(_, digest)| digest.to_owned())
+        })
+        .collect())
+}
+
+/// Image of the throwaway containers which change files on the docker host
+const HOST_FILES_IMAGE: &str = "busybox:1.36";
+
+/// Pulls the image of the throwaway containers only if the docker host doesn't have it,
+/// so changing files on the host needs no registry once it's there
+async fn ensure_host_files_image(docker: &bollard::Docker) -> Result<()> {
+    match docker.inspect_image(HOST_FILES_IMAGE).await {
+        Ok(_) => Ok(()),
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => pull_image(docker, HOST_FILES_IMAGE, None).await,
+        Err(e) => {
+            log_docker_error("inspect_image", &e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Deletes directories inside `parent_dir` on the docker host.
+/// A throwaway container does it, because the volumes live on the docker host
+/// (or in its VM) and usually belong to the users of the containers
+pub async fn remove_host_dirs(
+    docker: &bollard::Docker,
+    parent_dir: &str,
+    dir_names: &[String],
+) -> Result<()> {
+    ensure_host_files_image(docker).await?;
+
+    let mut cmd = vec!["rm".to_owned(), "-rf".to_owned(), "--".to_owned()];
+    cmd.extend(dir_names.iter().map(|name| format!("/target/{name}")));
+
+    let container = logged(
+        "create_container",
+        docker
+            .create_container(
+                None::<bollard::container::CreateContainerOptions<String>>,
+                bollard::container::Config {
+                    image: Some(HOST_FILES_IMAGE.to_owned()),
+                    cmd: Some(cmd),
+                    host_config: Some(bollard::models::HostConfig {
+                        binds: Some(vec![format!("{parent_dir}:/target")]),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await,
+    )?;
+
+    let result = run_to_completion(docker, &container.id).await;
+
+    logged(
+        "remove_container",
+        docker
+            .remove_container(
+                &container.id,
+                Some(bollard::container::RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await,
+    )?;
+
+    result.with_context(|| format!("Failed to remove directories in {parent_dir}"))
+}
+
+/// Writes files with the given paths, relative to `parent_dir`, on the docker host.
+/// Like [`remove_host_dirs`], it goes through a throwaway container, which
+/// is only created: files can be copied into a container which isn't running
+pub async fn write_host_files(
+    docker: &bollard::Docker,
+    parent_dir: &str,
+    files: &[(String, String)],
+) -> Result<()> {
+    ensure_host_files_image(docker).await?;
+
+    let mut bytes = Vec::<u8>::new();
+    let mut archive = tar::Builder::new(&mut bytes);
+
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        archive.append_data(&mut header, path, content.as_bytes())?;
+    }
+
+    archive.into_inner()?;
+
+    let container = logged(
+        "create_container",
+        docker
+            .create_container(
+                None::<bollard::container::CreateContainerOptions<String>>,
+                bollard::container::Config {
+                    image: Some(HOST_FILES_IMAGE.to_owned()),
+                    host_config: Some(bollard::models::HostConfig {
+                        binds: Some(vec![format!("{parent_dir}:/target")]),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await,
+    )?;
+
+    let result = logged(
+        "upload_to_container",
+        docker
+            .upload_to_container(
+                &container.id,
+                Some(bollard::container::UploadToContainerOptions {
+                    path: "/target",
+                    ..Default::default()
+                }),
+                bytes.into(),
+            )
+            .await,
+    );
+
+    logged(
+        "remove_container",
+        docker
+            .remove_container(
+                &container.id,
+                Some(bollard::container::RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await,
+    )?;
+
+    result.with_context(|| format!("Failed to write files in {parent_dir}"))
+}
+
+/// Printed by the container of [`try_lock_host_file`] once it holds the lock
+const HOST_LOCK_MARKER: &str = "locked";
+
+/// Exclusive lock of a file on the docker host, see [`try_lock_host_file`]
+#[derive(Debug)]
+pub struct HostFileLock {
+    /// The lock is held while this connection to the container is open
+    attached: bollard::container::AttachContainerResults,
+}
+
+/// Locks the file with `flock` in a throwaway container, which holds the lock
+/// until its stdin is closed. `None` if another process holds the lock.
+/// Dropping the lock closes the connection to the container, and so does the exit
+/// of dploy, even after a panic or CTRL+C. The container then exits and is removed
+pub async fn try_lock_host_file(
+    docker: &bollard::Docker,
+    parent_dir: &str,
+    file_name: &str,
+) -> Result<Option<HostFileLock>> {
+    ensure_host_files_image(docker).await?;
+
+    let cmd = vec![
+        "flock".to_owned(),
+        "-n".to_owned(),
+        format!("/target/{file_name}"),
+        "sh".to_owned(),
+        "-c".to_owned(),
+        format!("echo {HOST_LOCK_MARKER} && cat"),
+    ];
+
+    let container = logged(
+        "create_container",
+        docker
+            .create_container(
+                None::<bollard::container::CreateContainerOptions<String>>,
+                bollard::container::Config {
+                    image: Some(HOST_FILES_IMAGE.to_owned()),
+                    cmd: Some(cmd),
+                    attach_stdin: Some(true),
+                    attach_stdout: Some(true),
+                    open_stdin: Some(true),
+                    stdin_once: Some(true),
+                    host_config: Some(bollard::models::HostConfig {
+                        binds: Some(vec![format!("{parent_dir}:/target")]),
+                        auto_remove: Some(true),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await,
+    )?;
+
+    let attached = logged(
+        "attach_container",
+        docker
+            .attach_container(
+                &container.id,
+                Some(bollard::container::AttachContainerOptions::<String> {
+                    stdin: Some(true),
+                    stdout: Some(true),
+                    stream: Some(true),
+                    ..Default::default()
+                }),
+            )
+            .await,
+    );
+
+    let started = match attached {
+        Ok(attached) => logged(
+            "start_container",
+            docker
+                .start_container(
+                    &container.id,
+                    None::<bollard::container::StartContainerOptions<String>>,
+                )
+                .await,
+        )
+        .map(|_| attached),
+        Err(error) => Err(error),
+    };
+
+    // containers which never started aren't removed automatically
+    let mut attached = match started {
+        Ok(attached) => attached,
+        Err(error) => {
+            let _ = docker
+                .remove_container(
+                    &container.id,
+                    Some(bollard::container::RemoveContainerOptions {
+                        force: true,
+                        ..Default::default()
+                    }),
+                )
+                .await;
+
+            return Err(error).with_context(|| format!("Failed to lock {file_name}"));
+        }
+    };
+
+    // flock exits without any output if the lock is taken
+    while let Some(output) = attached.output.next().await {
+        if output?.to_string().contains(HOST_LOCK_MARKER) {
+            return Ok(Some(HostFileLock { attached }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads a file written by [`write_host_files`], `None` if it doesn't exist
+pub async fn read_host_file(
+    docker: &bollard::Docker,
+    parent_dir: &str,
+    path: &str,
+) -> Result<Option<String>> {
+    ensure_host_files_image(docker).await?;
+
+    let container = logged(
+        "create_container",
+        docker
+            .create_container(
+                None::<bollard::container::CreateContainerOptions<String>>,
+                bollard::container::Config {
+                    image: Some(HOST_FILES_IMAGE.to_owned()),
+                    host_config: Some(bollard::models::HostConfig {
+                        binds: Some(vec![format!("{parent_dir}:/target")]),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await,
+    )?;
+
+    let mut stream = docker.download_from_container(
+        &container.id,
+        Some(bollard::container::DownloadFromContainerOptions {
+            path: format!("/target/{path}"),
+        }),
+    );
+
+    let mut bytes = Vec::new();
+    let mut result = Ok(true);
+
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) => bytes.extend_from_slice(&chunk),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                result = Ok(false);
+                break;
+            }
+            Err(error) => {
+                result = logged("download_from_container", Err(error));
+                break;
+            }
+        }
+    }
+
+    drop(stream);
+
+    logged(
+        "remove_container",
+        docker
+            .remove_container(
+                &container.id,
+                Some(bollard::container::RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await,
+    )?;
+
+    if !result.with_context(|| format!("Failed to read {path} in {parent_dir}"))? {
+        return Ok(None);
+    }
+
+    // the file comes as the only entry of a tar archive
+    let mut archive = tar::Archive::new(bytes.as_slice());
+    let mut contents = String::new();
+
+    if let Some(entry) = archive.entries()?.next() {
+        entry?.read_to_string(&mut contents)?;
+    }
+
+    Ok(Some(contents))
+}
+
+async fn run_to_completion(docker: &bollard::Docker, container_id: &str) -> Result<()> {
+    start_container(docker, container_id).await?;
+
+    let mut stream = docker.wait_container(
+        container_id,
+        None::<bollard::container::WaitContainerOptions<String>>,
+    );
+
+    while let Some(response) = stream.next().await {
+        // non-zero exit codes are reported as errors by bollard
+        logged("wait_container", response)?;
+    }
+
+    Ok(())
+}
+
+/// Splits `name:tag` of an image reference without a digest into its parts
+pub fn split_image_tag(image: &str) -> (&str, Option<&str>) {
+    match image.rsplit_once(':') {
+        // a colon before the last slash belongs to a registry port
+        Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+        _ => (image, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bollard::models::{ContainerInspectResponse, ContainerState};
+
+    use super::*;
+
+    fn container_with_running(running: Option<bool>) -> ContainerInspectResponse {
+        ContainerInspectResponse {
+            state: Some(ContainerState {
+                running,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_absent_action_not_found() {
+        assert_eq!(AbsentAction::Nothing, absent_action(None));
+    }
+
+    #[test]
+    fn test_absent_action_already_stopped() {
+        let container = container_with_running(Some(false));
+        assert_eq!(AbsentAction::Remove, absent_action(Some(&container)));
+
+        let container = container_with_running(None);
+        assert_eq!(AbsentAction::Remove, absent_action(Some(&container)));
+    }
+
+    #[test]
+    fn test_absent_action_running() {
+        let container = container_with_running(Some(true));
+        assert_eq!(AbsentAction::StopAndRemove, absent_action(Some(&container)));
+    }
+
+    #[test]
+    fn test_stop_options() {
+        assert_eq!(None, stop_options(None));
+        assert_eq!(
+            Some(bollard::container::StopContainerOptions { t: 90 }),
+            stop_options(Some(std::time::Duration::from_secs(90)))
+        );
+    }
+
+    #[test]
+    fn test_pinned_image() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+
+        assert_eq!(
+            ("postgres".to_owned(), None),
+            pinned_image("postgres", None).unwrap()
+        );
+        assert_eq!(
+            (format!("postgres@{digest}"), Some(digest.clone())),
+            pinned_image("postgres:16", Some(&digest)).unwrap()
+        );
+        assert_eq!(
+            (
+                format!("localhost:5000/search@{digest}"),
+                Some(digest.clone())
+            ),
+            pinned_image(&format!("localhost:5000/search@{digest}"), None).unwrap()
+        );
+        assert!(pinned_image("postgres", Some("sha256:abc")).is_err());
+        assert!(pinned_image(
+            &format!("postgres@{digest}"),
+            Some(&format!("sha256:{}", "b".repeat(64)))
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_split_image_tag() {
+        assert_eq!(("postgres", Some("16")), split_image_tag("postgres:16"));
+        assert_eq!(("postgres", None), split_image_tag("postgres"));
+        assert_eq!(
+            ("localhost:5000/myorg/app", Some("v1")),
+            split_image_tag("localhost:5000/myorg/app:v1")
+        );
+        assert_eq!(
+            ("localhost:5000/myorg/app", None),
+            split_image_tag("localhost:5000/myorg/app")
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::{fs, time};
+
+use crate::{context, docker, prelude::*, services};
+
+const LOCK_FILE_EXTENSION: &str = "lock";
+const LOCK_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+/// Exclusive lock of a namespace on the target host, held while services are changed.
+/// Locally it's a file in the state directory. On remote hosts it's a file in the host's
+/// data directory, so deploys from every machine are guarded.
+/// The lock is released when dploy exits, even after a panic or CTRL+C
+#[derive(Debug)]
+pub struct NamespaceLock {
+    lock: Lock,
+}
+
+/// Both are released when dropped
+#[derive(Debug)]
+enum Lock {
+    Local(fs::File),
+    Host(docker::HostFileLock),
+}
+
+impl NamespaceLock {
+    /// Fails right away if the lock is taken, unless `timeout` is set
+    pub async fn acquire(
+        context: &context::Context,
+        docker: &bollard::Docker,
+        timeout: Option<time::Duration>,
+    ) -> Result<Self> {
+        let file_name = format!(
+            "{}.{LOCK_FILE_EXTENSION}",
+            context.container_name_of(&services::ServiceKind::App)
+        );
+
+        let started_at = time::Instant::now();
+
+        loop {
+            // the local docker host is this machine, so no container is needed
+            let lock = match context.ssh_credentials() {
+                None => try_lock_local_file(&context.state_dir(), &file_name)?.map(Lock::Local),
+                Some(_) => docker::try_lock_host_file(
+                    docker,
+                    &context.host_state_dir().to_string_lossy(),
+                    &file_name,
+                )
+                .await?
+                .map(Lock::Host),
+            };
+
+            if let Some(lock) = lock {
+                return Ok(Self { lock });
+            }
+
+            match timeout {
+                Some(timeout) if started_at.elapsed() < timeout => {
+                    tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+                }
+                Some(_) => bail!(
+                    "Timed out waiting for another deploy in progress for namespace {}",
+                    context.namespace()
+                ),
+                None => bail!(
+                    "Another deploy is in progress for namespace {}. Use --wait-for-lock to wait for it",
+                    context.namespace()
+                ),
+            }
+        }
+    }
+}
+
+/// `None` if another process holds the lock. The OS releases it when the file is closed
+fn try_lock_local_file(dir: &std::path::Path, file_name: &str) -> Result<Option<fs::File>> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create the state directory {}", dir.display()))?;
+
+    let path = dir.join(file_name);
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open the lock file {}", path.display()))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Ok(None),
+        Err(fs::TryLockError::Error(error)) => {
+            Err(error).with_context(|| format!("Failed to lock {}", path.display()))
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::{fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{context, prelude::*, utils};
+
+const LOCKFILE_NAME: &str = "dploy.lock";
+const LOCKFILE_HEADER: &str =
+    "# This file is written by `dploy deploy`. Do not edit it by hand\n\n";
+
+/// Image digests resolved by the last full deploy, kept next to the config.
+/// `deploy --locked` uses them instead of resolving the tags again.
+/// The app image is built on every deploy, its id is only recorded
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Lockfile {
+    /// Hash of the resolved config, checked by `deploy --frozen`
+    config_hash: String,
+
+    #[serde(default, rename = "image")]
+    images: Vec<LockedImage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LockedImage {
+    /// Image as written in the config, e.g. `postgres:16`
+    name: String,
+    digest: String,
+}
+
+impl Lockfile {
+    pub fn path(context: &context::Context) -> PathBuf {
+        context.config_dir().join(LOCKFILE_NAME)
+    }
+
+    /// Returns `None` if there is no lockfile yet
+    pub fn read(context: &context::Context) -> Result<Option<Self>> {
+        let path = Self::path(context);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let lockfile = toml::from_str(&contents)
+            .with_context(|| format!("{} is corrupted, remove it to start over", path.display()))?;
+
+        Ok(Some(lockfile))
+    }
+
+    pub fn write(&self, context: &context::Context) -> Result<()> {
+        let path = Self::path(context);
+        let contents = toml::to_string_pretty(self).context("Failed to serialize the lockfile")?;
+
+        fs::write(&path, format!("{LOCKFILE_HEADER}{contents}"))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn config_hash(&self) -> &str {
+        &self.config_hash
+    }
+
+    pub fn set_config_hash(&mut self, config_hash: String) {
+        self.config_hash = config_hash;
+    }
+
+    pub fn digest_of(&self, image: &str) -> Option<&str> {
+        self.images
+            .iter()
+            .find(|locked| locked.name == image)
+            .map(|locked| locked.digest.as_str())
+    }
+
+    pub fn set_digest(&mut self, image: &str, digest: String) {
+        match self.images.iter_mut().find(|locked| locked.name == image) {
+            Some(locked) => locked.digest = digest,
+            None => self.images.push(LockedImage {
+                name: image.to_owned(),
+                digest,
+            }),
+        }
+
+        self.images.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Forgets the images which are no longer used by the config
+    pub fn retain_images(&mut self, images: &[&str]) {
+        self.images
+            .retain(|locked| images.contains(&locked.name.as_str()));
+    }
+}
+
+/// SHA-256 of the config with the overrides of the current mode and namespace applied.
+/// It's compared on other machines, so it can't depend on the toolchain
+pub fn config_hash(context: &context::Context) -> Result<String> {
+    let resolved = context.app_config().resolve(context.override_context());
+    let contents = toml::to_string(&resolved).context("Failed to serialize the config")?;
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, contents.as_bytes());
+
+    Ok(utils::string::to_hex(digest.as_ref()))
+}
@@ -1,8 +1,9 @@
 #![allow(dead_code)]
 
-use std::{fs, path, sync::Arc};
+use std::{path::Path, sync::Arc};
 
 use clap::Parser;
+use futures::StreamExt;
 
 use crate::prelude::*;
 
@@ -13,11 +14,15 @@ mod config;
 mod constants;
 mod context;
 mod docker;
+mod lock;
+mod lockfile;
 mod network;
+mod notify;
 mod prelude;
 mod presentation;
 mod services;
 mod ssh;
+mod state;
 mod utils;
 
 #[tokio::main]
@@ -25,17 +30,37 @@ async fn main() -> Result<()> {
     match run_cli().await {
         Ok(_) => Ok(()),
         Err(error) => {
-            eprintln!("{}", error);
+            presentation::print_error(&error);
             std::process::exit(1);
         }
     }
 }
 
 async fn run_cli() -> Result<()> {
-    let args = cli::Args::try_parse()?;
+    let mut args = cli::Args::try_parse()?;
+
+    if args.json_events() {
+        presentation::enable_json_events();
+    }
+
+    if args.quiet() {
+        presentation::enable_quiet();
+    }
+
+    if args.verbose_docker() {
+        docker::enable_verbose_docker();
+    }
+
+    if args.command().prints_document() {
+        presentation::enable_document_output();
+    }
 
     presentation::print_cli_info();
 
+    if args.namespace() == constants::BRANCH_NAMESPACE {
+        args = args.with_namespace(&branch_namespace(&args));
+    }
+
     let namespace = args.namespace();
     if namespace != constants::DEFAULT_NAMESPACE {
         presentation::print_namespace_info(namespace);
@@ -46,15 +71,21 @@ async fn run_cli() -> Result<()> {
         command: args.command().into(),
     };
 
-    let file_contents = match fs::read_to_string(&args.config) {
+    let file_contents = match args.config_source().read() {
         Ok(contents) => contents,
-        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
-            presentation::print_config_not_found_error();
-            return Err(error.into());
+        Err(error) => {
+            let is_not_found = error
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|error| error.kind() == std::io::ErrorKind::NotFound);
+
+            if is_not_found {
+                presentation::print_config_not_found_error();
+            }
+
+            return Err(error);
         }
-        Err(error) => return Err(error.into()),
     };
-    let app_config: config::AppConfig = toml::from_str(&file_contents)?;
+    let app_config = config::AppConfig::parse_before_env(&file_contents)?;
     //
     // // mutate CWD to the location where the config file is
     // std::env::set_current_dir(
@@ -64,12 +95,76 @@ async fn run_cli() -> Result<()> {
     // )
     // .expect("Invalid config path");
     //
-    let context = Arc::new(context::Context::new(args, app_config, override_context));
-    let services = services::Services::from_context(&context);
+    let context = context::Context::new(args, app_config, override_context);
+
+    // the app service reads its variables from the environment
+    commands::deploy::load_env_files(&context)?;
+    commands::deploy::set_git_sha_env(&context);
+
+    // placeholders in the config may refer to variables from the env files
+    let context = Arc::new(context.with_app_config(config::AppConfig::parse(&file_contents)?));
+
+    let services = services::Services::from_context(&context)?;
+
+    // doesn't need a docker connection
+    match context.args().command().config_command() {
+        Some(cli::ConfigCommand::Show) => {
+            commands::config::show(&context)?;
+            return Ok(());
+        }
+        Some(cli::ConfigCommand::Diff { from, to, output }) => {
+            commands::config::diff(&context, from, to, *output)?;
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if let Some(command) = context.args().command().export_command() {
+        commands::export::export(&context, &services, command)?;
+        return Ok(());
+    }
+
+    // only connects to docker with --refresh
+    if let Some(args) = context.args().command().info_args() {
+        commands::info::info(&context, args).await?;
+        return Ok(());
+    }
+
+    if let cli::Command::Deploy {
+        command: Some(cli::DeployCommand::ProxyConfig { json }),
+        ..
+    } = context.args().command()
+    {
+        commands::proxy::show_config(services.proxy(), *json)?;
+        return Ok(());
+    }
+
+    if context.args().command().env_diff() {
+        commands::deploy::print_env_diff(&services, &context);
+        return Ok(());
+    }
+
+    if let Some(pattern) = context.args().namespace_pattern() {
+        run_in_namespaces(&context, pattern).await?;
+        return Ok(());
+    }
 
     match context.args().command() {
+        // Dev with watch
+        cli::Command::Dev {
+            command: None,
+            watch: true,
+            ..
+        } => {
+            let docker = docker::get_default_docker_client(&context).await?;
+            let lock = acquire_lock(&context, &docker).await?;
+            commands::deploy::dev_watch(Arc::clone(&context), Arc::new(docker), services, lock)
+                .await?;
+        }
+
         cli::Command::Dev { command, .. } => {
-            let docker = docker::get_default_docker_client().await?;
+            let docker = docker::get_default_docker_client(&context).await?;
+            let _lock = acquire_lock(&context, &docker).await?;
 
             match command {
                 None => {
@@ -78,21 +173,68 @@ async fn run_cli() -> Result<()> {
                 Some(cli::DevCommand::Stop) => {
                     commands::stop::stop(&context, &docker, &services).await?;
                 }
-                Some(cli::DevCommand::Logs { tail, service, .. }) => {
-                    commands::logs::logs(
-                        Arc::clone(&context),
-                        Arc::new(docker),
-                        (*service).into(),
-                        *tail,
+                Some(cli::DevCommand::Pull) => {
+                    commands::pull::pull(&context, &docker, &services).await?;
+                }
+                Some(cli::DevCommand::Logs {
+                    tail,
+                    service,
+                    timestamps,
+                    output,
+                    append,
+                    since_deploy,
+                    no_follow,
+                }) => {
+                    let service = commands::select::service_or_select(
+                        &context,
+                        &docker,
+                        &services,
+                        (*service).map(Into::into),
                     )
                     .await?;
+                    let args = commands::logs::LogsArgs::new(service, *tail, *timestamps)
+                        .with_output(output.clone(), *append)
+                        .with_since_deploy(*since_deploy)
+                        .with_no_follow(*no_follow);
+                    commands::logs::logs(Arc::clone(&context), Arc::new(docker), args).await?;
                 }
                 Some(cli::DevCommand::Exec {
-                    service, command, ..
+                    service,
+                    command,
+                    detach,
+                    no_stdout,
+                    no_stderr,
                 }) => {
-                    let args = commands::exec::ExecArgs::new((*service).into(), command.clone());
+                    let service = commands::select::service_or_select(
+                        &context,
+                        &docker,
+                        &services,
+                        (*service).map(Into::into),
+                    )
+                    .await?;
+                    let args = commands::exec::ExecArgs::new(service, command.clone())
+                        .with_streams(*detach, *no_stdout, *no_stderr);
                     commands::exec::exec(&context, &docker, &args).await?;
                 }
+                Some(cli::DevCommand::Status { format, columns }) => {
+                    let args = commands::status::StatusArgs::new(*format, columns.clone());
+                    commands::status::status(&context, &docker, &services, &args).await?;
+                }
+                Some(cli::DevCommand::Stats { output, no_stream }) => {
+                    let args = commands::stats::StatsArgs::new(*output, *no_stream);
+                    commands::stats::stats(&context, &docker, &args).await?;
+                }
+                Some(cli::DevCommand::Wait { service, timeout }) => {
+                    let args = commands::wait::WaitArgs::new(service.clone(), *timeout);
+                    commands::wait::wait_for_services(&context, &docker, &services, &args).await?;
+                }
+                Some(
+                    cli::DevCommand::Config { .. }
+                    | cli::DevCommand::Export { .. }
+                    | cli::DevCommand::Info(_),
+                ) => {
+                    unreachable!("handled before connecting")
+                }
             }
         }
 
@@ -100,8 +242,10 @@ async fn run_cli() -> Result<()> {
         cli::Command::Run {
             command: None,
             watch: true,
+            ..
         } => {
-            let docker = docker::get_default_docker_client().await?;
+            let docker = docker::get_default_docker_client(&context).await?;
+            let lock = acquire_lock(&context, &docker).await?;
             commands::deploy::deploy_watch(
                 Arc::clone(&context),
                 Arc::new(docker),
@@ -112,36 +256,116 @@ async fn run_cli() -> Result<()> {
                     .iter()
                     .map(|path| path.as_ref())
                     .collect::<Vec<_>>(),
+                lock,
             )
             .await?;
         }
 
+        // Run in the foreground
+        cli::Command::Run {
+            command: None,
+            attach: true,
+            ..
+        } => {
+            let docker = docker::get_default_docker_client(&context).await?;
+            let _lock = acquire_lock(&context, &docker).await?;
+            commands::deploy::deploy_attached(Arc::clone(&context), Arc::new(docker), &services)
+                .await?;
+        }
+
         cli::Command::Run { command, .. } => {
-            let docker = docker::get_default_docker_client().await?;
+            let docker = docker::get_default_docker_client(&context).await?;
+            let _lock = acquire_lock(&context, &docker).await?;
 
             match command {
                 None => {
                     commands::deploy::deploy(&context, &docker, &services).await?;
+
+                    if context.args().command().wait_for_exit() {
+                        let exit_code =
+                            commands::wait::wait_for_app_exit(&context, &docker).await?;
+
+                        if exit_code != 0 {
+                            std::process::exit(i32::try_from(exit_code).unwrap_or(1));
+                        }
+                    }
                 }
                 Some(cli::RunCommand::Stop) => {
                     commands::stop::stop(&context, &docker, &services).await?;
                 }
-                Some(cli::RunCommand::Logs { tail, service, .. }) => {
-                    commands::logs::logs(
-                        Arc::clone(&context),
-                        Arc::new(docker),
-                        (*service).into(),
-                        *tail,
-                    )
-                    .await?;
+                Some(cli::RunCommand::Pull) => {
+                    commands::pull::pull(&context, &docker, &services).await?;
+                }
+                Some(cli::RunCommand::Logs {
+                    tail,
+                    service,
+                    timestamps,
+                    output,
+                    append,
+                    since_deploy,
+                    no_follow,
+                }) => {
+                    let args = commands::logs::LogsArgs::new((*service).into(), *tail, *timestamps)
+                        .with_output(output.clone(), *append)
+                        .with_since_deploy(*since_deploy)
+                        .with_no_follow(*no_follow);
+                    commands::logs::logs(Arc::clone(&context), Arc::new(docker), args).await?;
                 }
                 Some(cli::RunCommand::Exec {
-                    service, command, ..
+                    service,
+                    command,
+                    detach,
+                    no_stdout,
+                    no_stderr,
                 }) => {
-                    let args = commands::exec::ExecArgs::new((*service).into(), command.clone());
+                    let args = commands::exec::ExecArgs::new((*service).into(), command.clone())
+                        .with_streams(*detach, *no_stdout, *no_stderr);
                     commands::exec::exec(&context, &docker, &args).await?;
                 }
+                Some(cli::RunCommand::Status { format, columns }) => {
+                    let args = commands::status::StatusArgs::new(*format, columns.clone());
+                    commands::status::status(&context, &docker, &services, &args).await?;
+                }
+                Some(cli::RunCommand::Stats { output, no_stream }) => {
+                    let args = commands::stats::StatsArgs::new(*output, *no_stream);
+                    commands::stats::stats(&context, &docker, &args).await?;
+                }
+                Some(cli::RunCommand::Wait { service, timeout }) => {
+                    let args = commands::wait::WaitArgs::new(service.clone(), *timeout);
+                    commands::wait::wait_for_services(&context, &docker, &services, &args).await?;
+                }
+                Some(
+                    cli::RunCommand::Config { .. }
+                    | cli::RunCommand::Export { .. }
+                    | cli::RunCommand::Info(_),
+                ) => {
+                    unreachable!("handled before connecting")
+                }
+            }
+        }
+
+        cli::Command::Deploy {
+            hosts,
+            command,
+            watch,
+            parallel,
+            fail_fast,
+            ..
+        } if hosts.len() > 1 => {
+            if *watch
+                || matches!(
+                    command,
+                    Some(
+                        cli::DeployCommand::Logs { .. }
+                            | cli::DeployCommand::Exec { .. }
+                            | cli::DeployCommand::Stats { .. }
+                    )
+                )
+            {
+                bail!("--watch, logs, exec and stats can only be used with a single host");
             }
+
+            run_deploy_on_hosts(&context, hosts, command.as_ref(), *parallel, *fail_fast).await?;
         }
 
         cli::Command::Deploy {
@@ -150,6 +374,7 @@ async fn run_cli() -> Result<()> {
             ..
         } => {
             let (docker, session) = docker::get_docker_client_with_session(&context).await?;
+            let lock = acquire_lock(&context, &docker).await?;
             commands::deploy::deploy_watch(
                 Arc::clone(&context),
                 Arc::new(docker),
@@ -160,41 +385,267 @@ async fn run_cli() -> Result<()> {
                     .iter()
                     .map(|path| path.as_ref())
                     .collect::<Vec<_>>(),
+                lock,
             )
             .await?;
+
             session.close().await?;
         }
 
         cli::Command::Deploy { command, .. } => {
-            let (docker, session) = docker::get_docker_client_with_session(&context).await?;
+            run_deploy(Arc::clone(&context), &services, command.as_ref()).await?;
+        }
+    }
 
-            match command {
-                None => {
-                    commands::deploy::deploy(&context, &docker, &services).await?;
-                }
-                Some(cli::DeployCommand::Stop) => {
-                    commands::stop::stop(&context, &docker, &services).await?;
-                }
-                Some(cli::DeployCommand::Logs { tail, service, .. }) => {
-                    commands::logs::logs(
-                        Arc::clone(&context),
-                        Arc::new(docker),
-                        (*service).into(),
-                        *tail,
-                    )
-                    .await?;
-                }
-                Some(cli::DeployCommand::Exec {
-                    service, command, ..
-                }) => {
-                    let args = commands::exec::ExecArgs::new((*service).into(), command.clone());
-                    commands::exec::exec(&context, &docker, &args).await?;
+    Ok(())
+}
+
+async fn run_deploy(
+    context: Arc<context::Context>,
+    services: &services::Services,
+    command: Option<&cli::DeployCommand>,
+) -> Result<()> {
+    let (docker, session) = docker::get_docker_client_with_session(&context).await?;
+    let lock = acquire_lock(&context, &docker).await?;
+
+    match command {
+        None => {
+            let started_at = std::time::Instant::now();
+            let result = commands::deploy::deploy_with_timeout(&context, &docker, services).await;
+            notify::notify_deploy_finished(&context, services, &result, started_at.elapsed()).await;
+            result?;
+        }
+        Some(cli::DeployCommand::Stop) => {
+            commands::stop::stop(&context, &docker, services).await?;
+        }
+        Some(cli::DeployCommand::Pull) => {
+            commands::pull::pull(&context, &docker, services).await?;
+        }
+        Some(cli::DeployCommand::Logs {
+            tail,
+            service,
+            timestamps,
+            output,
+            append,
+            since_deploy,
+            no_follow,
+            replica,
+        }) => {
+            let args = commands::logs::LogsArgs::new((*service).into(), *tail, *timestamps)
+                .with_output(output.clone(), *append)
+                .with_since_deploy(*since_deploy)
+                .with_no_follow(*no_follow)
+                .with_replica(*replica);
+            commands::logs::logs(Arc::clone(&context), Arc::new(docker), args).await?;
+        }
+        Some(cli::DeployCommand::Exec {
+            service,
+            command,
+            detach,
+            no_stdout,
+            no_stderr,
+        }) => {
+            let args = commands::exec::ExecArgs::new((*service).into(), command.clone())
+                .with_streams(*detach, *no_stdout, *no_stderr);
+            commands::exec::exec(&context, &docker, &args).await?;
+        }
+        Some(cli::DeployCommand::Status { format, columns }) => {
+            let args = commands::status::StatusArgs::new(*format, columns.clone());
+            commands::status::status(&context, &docker, services, &args).await?;
+        }
+        Some(cli::DeployCommand::Stats { output, no_stream }) => {
+            let args = commands::stats::StatsArgs::new(*output, *no_stream);
+            commands::stats::stats(&context, &docker, &args).await?;
+        }
+        Some(cli::DeployCommand::Wait { service, timeout }) => {
+            let args = commands::wait::WaitArgs::new(service.clone(), *timeout);
+            commands::wait::wait_for_services(&context, &docker, services, &args).await?;
+        }
+        Some(
+            cli::DeployCommand::Config { .. }
+            | cli::DeployCommand::Export { .. }
+            | cli::DeployCommand::ProxyConfig { .. },
+        ) => {
+            unreachable!("handled before connecting")
+        }
+    }
+
+    drop(lock);
+    session.close().await?;
+
+    Ok(())
+}
+
+/// Deploys to every host with its own context and connection.
+/// A failing host doesn't stop the others unless `fail_fast` is set
+async fn run_deploy_on_hosts(
+    context: &context::Context,
+    hosts: &[String],
+    command: Option<&cli::DeployCommand>,
+    parallel: bool,
+    fail_fast: bool,
+) -> Result<()> {
+    let run = |host: &String| {
+        let host = host.clone();
+        let context = Arc::new(context.for_host(&host));
+
+        async move {
+            presentation::print_host_starting(&host);
+
+            let result = match services::Services::from_context(&context) {
+                Ok(services) => run_deploy(context, &services, command).await,
+                Err(error) => Err(error),
+            };
+
+            if let Err(error) = &result {
+                presentation::print_host_failed(&host, error);
+            }
+
+            (host, result)
+        }
+    };
+
+    let mut results = vec![];
+
+    if parallel {
+        // all hosts are started at once, so even with --fail-fast the running
+        // deploys are finished. Cancelling them would leave the hosts half deployed
+        results = hosts
+            .iter()
+            .map(run)
+            .collect::<futures::stream::FuturesUnordered<_>>()
+            .collect()
+            .await;
+    } else {
+        for host in hosts {
+            let (host, result) = run(host).await;
+            let is_failed = result.is_err();
+            results.push((host, result));
+
+            if is_failed && fail_fast {
+                break;
+            }
+        }
+    }
+
+    presentation::print_hosts_summary(hosts, &results);
+
+    let failed_count = results.iter().filter(|(_, result)| result.is_err()).count();
+    let skipped_count = hosts.len() - results.len();
+
+    if failed_count > 0 || skipped_count > 0 {
+        bail!(
+            "Failed on {failed_count} of {} host(s), {skipped_count} skipped",
+            hosts.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs `stop` or `status` in every namespace of the app matching the pattern, one by one.
+/// A failing namespace doesn't stop the others
+async fn run_in_namespaces(context: &context::Context, pattern: &str) -> Result<()> {
+    let command = context.args().command();
+
+    if !command.stop() && command.status().is_none() {
+        bail!("--all-namespaces and namespace globs can only be used with stop and status");
+    }
+
+    let (docker, session) = match command {
+        cli::Command::Deploy { hosts, .. } if hosts.len() > 1 => {
+            bail!("--all-namespaces and namespace globs can only be used with a single host");
+        }
+        cli::Command::Deploy { .. } => {
+            let (docker, session) = docker::get_docker_client_with_session(context).await?;
+            (docker, Some(session))
+        }
+        _ => (docker::get_default_docker_client(context).await?, None),
+    };
+
+    let namespaces = commands::namespaces::find_namespaces(context, &docker, pattern).await?;
+
+    if namespaces.is_empty() {
+        presentation::print_namespaces_not_found(pattern);
+    }
+
+    let mut results = vec![];
+
+    for namespace in &namespaces {
+        let context = context.for_namespace(namespace);
+        presentation::print_namespace_starting(namespace);
+
+        let result = async {
+            let services = services::Services::from_context(&context)?;
+            let _lock = acquire_lock(&context, &docker).await?;
+
+            match context.args().command().status() {
+                Some((format, columns)) => {
+                    let args = commands::status::StatusArgs::new(format, columns.to_vec());
+                    commands::status::status(&context, &docker, &services, &args).await
                 }
+                None => commands::stop::stop(&context, &docker, &services).await,
             }
+        }
+        .await;
 
-            session.close().await?;
+        if let Err(error) = &result {
+            presentation::print_namespace_failed(namespace, error);
         }
+
+        results.push((namespace.clone(), result));
+    }
+
+    if let Some(session) = session {
+        session.close().await?;
+    }
+
+    if command.stop() && !namespaces.is_empty() {
+        presentation::print_namespaces_summary(&namespaces, &results);
+    }
+
+    let failed_count = results.iter().filter(|(_, result)| result.is_err()).count();
+
+    if failed_count > 0 {
+        bail!(
+            "Failed in {failed_count} of {} namespace(s)",
+            namespaces.len()
+        );
     }
 
     Ok(())
 }
+
+/// Namespace of `--namespace @branch`, derived from the branch of the config's repository
+fn branch_namespace(args: &cli::Args) -> String {
+    let config_source = args.config_source();
+    let dir = config_source
+        .file()
+        .and_then(|path| path.parent())
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    let namespace = utils::git::current_branch(dir)
+        .map(|branch| utils::string::sanitize_namespace(&branch))
+        .filter(|namespace| !namespace.is_empty());
+
+    namespace.unwrap_or_else(|| {
+        presentation::print_branch_namespace_fallback();
+        constants::DEFAULT_NAMESPACE.to_owned()
+    })
+}
+
+/// Prevents concurrent changes of the same namespace, see [`lock::NamespaceLock`]
+async fn acquire_lock(
+    context: &context::Context,
+    docker: &bollard::Docker,
+) -> Result<Option<lock::NamespaceLock>> {
+    if !context.args().command().is_mutating() {
+        return Ok(None);
+    }
+
+    let lock =
+        lock::NamespaceLock::acquire(context, docker, context.args().wait_for_lock()).await?;
+
+    Ok(Some(lock))
+}
@@ -1,14 +1,30 @@
-use anyhow::Result;
-use bollard::Docker;
+use std::net::IpAddr;
+
+use anyhow::{bail, Context, Result};
+use bollard::{models, Docker};
+
+use crate::{config::NetworkConfig, docker, presentation, utils::network::Cidr};
 
 const CONFLICT_STATUS_CODE: u16 = 409;
 
 pub const DPLOY_NETWORK: &str = "dploy_default";
 
-pub async fn create_dploy_network(docker: &Docker) -> Result<()> {
+/// Creates the network with the configured subnet. `static_addresses` are the `ip`s
+/// of the services, checked against the subnet of the network if it already exists
+pub async fn create_dploy_network(
+    docker: &Docker,
+    config: Option<&NetworkConfig>,
+    static_addresses: &[IpAddr],
+) -> Result<()> {
+    let ipam_config = config.map(to_ipam_config).transpose()?.flatten();
+
     let result = docker
         .create_network(bollard::network::CreateNetworkOptions {
             name: DPLOY_NETWORK,
+            ipam: models::Ipam {
+                config: ipam_config.clone().map(|config| vec![config]),
+                ..Default::default()
+            },
             ..Default::default()
         })
         .await;
@@ -18,7 +34,153 @@ pub async fn create_dploy_network(docker: &Docker) -> Result<()> {
         Err(bollard::errors::Error::DockerResponseServerError {
             status_code: CONFLICT_STATUS_CODE,
             ..
-        }) => Ok(()),
-        Err(error) => Err(error.into()),
+        }) => {
+            if let Some(subnet) = ipam_config.and_then(|config| config.subnet) {
+                check_existing_subnet(docker, &subnet, static_addresses).await?;
+            }
+
+            Ok(())
+        }
+        Err(error) => {
+            docker::log_docker_error("create_network", &error);
+            Err(error.into())
+        }
+    }
+}
+
+/// The network is shared by all projects on the host and can't be changed
+/// while containers are connected, so a different subnet is only reported.
+/// Static addresses outside of the existing subnet would be rejected by docker
+async fn check_existing_subnet(
+    docker: &Docker,
+    subnet: &str,
+    static_addresses: &[IpAddr],
+) -> Result<()> {
+    let network = docker::logged(
+        "inspect_network",
+        docker.inspect_network::<String>(DPLOY_NETWORK, None).await,
+    )
+    .context("Could not inspect the dploy network")?;
+
+    let existing_subnets = network
+        .ipam
+        .and_then(|ipam| ipam.config)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|config| config.subnet)
+        .collect::<Vec<_>>();
+
+    if existing_subnets.iter().any(|existing| existing == subnet) {
+        return Ok(());
+    }
+
+    let remove_hint = format!(
+        "Remove it with `docker network rm {DPLOY_NETWORK}` once no containers use it \
+         to apply the configured subnet"
+    );
+
+    for address in static_addresses {
+        let is_in_existing_subnet = existing_subnets
+            .iter()
+            .any(|existing| Cidr::parse(existing).is_ok_and(|existing| existing.contains(address)));
+
+        if !is_in_existing_subnet {
+            bail!(
+                "The ip {address} is outside of the subnet {} of the existing dploy network. \
+                 {remove_hint}",
+                existing_subnets.join(", ")
+            );
+        }
+    }
+
+    presentation::print_container_warning(
+        DPLOY_NETWORK,
+        &format!(
+            "The network already exists with subnet {}, not {subnet}. {remove_hint}",
+            existing_subnets.join(", ")
+        ),
+    );
+
+    Ok(())
+}
+
+/// Static address of the container on the dploy network, set by its `ip` option
+pub fn static_address(config: &bollard::container::Config<String>) -> Option<IpAddr> {
+    let ipam_config = config
+        .networking_config
+        .as_ref()?
+        .endpoints_config
+        .get(DPLOY_NETWORK)?
+        .ipam_config
+        .as_ref()?;
+
+    ipam_config
+        .ipv4_address
+        .as_ref()
+        .or(ipam_config.ipv6_address.as_ref())?
+        .parse()
+        .ok()
+}
+
+/// Without a subnet docker picks the addresses, so there is nothing to configure
+fn to_ipam_config(config: &NetworkConfig) -> Result<Option<models::IpamConfig>> {
+    let Some(subnet) = config.subnet() else {
+        if config.gateway().is_some() || config.ip_range().is_some() {
+            bail!("[network] gateway and ip_range require a subnet");
+        }
+
+        return Ok(None);
+    };
+
+    let subnet = Cidr::parse(subnet).context("Invalid [network] subnet")?;
+
+    if let Some(gateway) = config.gateway() {
+        let address = gateway
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid [network] gateway `{gateway}`"))?;
+
+        if !subnet.contains(&address) {
+            bail!("The [network] gateway {gateway} is outside of the subnet {subnet}");
+        }
+    }
+
+    if let Some(ip_range) = config.ip_range() {
+        let ip_range = Cidr::parse(ip_range).context("Invalid [network] ip_range")?;
+
+        if !subnet.contains_cidr(&ip_range) {
+            bail!("The [network] ip_range {ip_range} is outside of the subnet {subnet}");
+        }
+    }
+
+    Ok(Some(models::IpamConfig {
+        subnet: Some(subnet.to_string()),
+        gateway: config.gateway().map(ToOwned::to_owned),
+        ip_range: config.ip_range().map(ToOwned::to_owned),
+        ..Default::default()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_ipam_config() {
+        let parse = |value: &str| toml::from_str::<NetworkConfig>(value).unwrap();
+
+        assert_eq!(to_ipam_config(&parse("")).unwrap(), None);
+        assert!(to_ipam_config(&parse("gateway = \"172.30.0.1\"")).is_err());
+
+        let ipam_config = to_ipam_config(&parse(
+            "subnet = \"172.30.0.0/16\"\ngateway = \"172.30.0.1\"",
+        ))
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(ipam_config.subnet.as_deref(), Some("172.30.0.0/16"));
+        assert_eq!(ipam_config.gateway.as_deref(), Some("172.30.0.1"));
+        assert!(
+            to_ipam_config(&parse("subnet = \"172.30.0.0/16\"\ngateway = \"10.0.0.1\"")).is_err()
+        );
     }
 }
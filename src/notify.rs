@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{context, presentation, services, utils};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Summary of a deploy sent to the `[notify]` webhook
+#[derive(Debug, Serialize)]
+struct DeployNotification {
+    app: String,
+    namespace: String,
+    host: Option<String>,
+    services: Vec<String>,
+    success: bool,
+    error: Option<String>,
+    duration_secs: f64,
+    git_sha: Option<String>,
+}
+
+/// Posts the result of a deploy to the configured webhook.
+/// Best effort: a failed notification is only reported as a warning
+pub async fn notify_deploy_finished(
+    context: &context::Context,
+    services: &services::Services,
+    result: &anyhow::Result<()>,
+    duration: Duration,
+) {
+    let Some(notify_config) = context.app_config().notify(context.override_context()) else {
+        return;
+    };
+
+    let notification = DeployNotification {
+        app: context
+            .app_config()
+            .name(context.override_context())
+            .to_owned(),
+        namespace: context.namespace().to_owned(),
+        host: context
+            .ssh_credentials()
+            .map(|credentials| credentials.host().to_owned()),
+        services: services
+            .kinds(context)
+            .iter()
+            .filter(|kind| context.should_deploy_service(kind))
+            .map(ToString::to_string)
+            .collect(),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|error| format!("{error:#}")),
+        duration_secs: duration.as_secs_f64(),
+        git_sha: utils::git::git_short_hash(&context.config_dir()),
+    };
+
+    let body = match serde_json::to_string(&notification) {
+        Ok(body) => body,
+        Err(error) => {
+            presentation::print_notify_failed(&error.into());
+            return;
+        }
+    };
+
+    let url = notify_config.webhook_url().to_owned();
+    let headers = notify_config
+        .headers()
+        .iter()
+        .map(|(name, value)| Ok((name.clone(), utils::string::interpolate_env(value)?)))
+        .collect::<anyhow::Result<Vec<_>>>();
+
+    let headers = match headers {
+        Ok(headers) => headers,
+        Err(error) => {
+            presentation::print_notify_failed(&error);
+            return;
+        }
+    };
+
+    // ureq is blocking, keep it off the async runtime
+    let response = tokio::task::spawn_blocking(move || {
+        let mut request = ureq::post(&url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .set("Content-Type", "application/json");
+
+        for (name, value) in &headers {
+            request = request.set(name, value);
+        }
+
+        request
+            .send_string(&body)
+            .map(|_| ())
+            .map_err(|error| match error {
+                ureq::Error::Status(status, _) => {
+                    anyhow::anyhow!("{url} responded with HTTP {status}")
+                }
+                error => anyhow::Error::new(error).context(format!("Failed to reach {url}")),
+            })
+    })
+    .await;
+
+    match response {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => presentation::print_notify_failed(&error),
+        Err(error) => presentation::print_notify_failed(&error.into()),
+    }
+}
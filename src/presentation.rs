@@ -1,10 +1,359 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use console::style;
+use serde::Serialize;
 
 use crate::services::ServiceKind;
 
+static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+static DOCUMENT_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Progress events printed as JSON lines in `--json-events` mode,
+/// one for every print function of this module
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    CliInfo {
+        version: &'static str,
+    },
+    ConfigNotFound,
+    Error {
+        message: String,
+    },
+    ConnectionInfo {
+        services: Vec<ConnectionInfoEvent<'a>>,
+    },
+    LogsStarted {
+        service: &'a str,
+        /// `None` if all logs are shown
+        count: Option<u64>,
+        follow: bool,
+    },
+    Namespace {
+        namespace: &'a str,
+    },
+    ImageBuilding {
+        service: &'a str,
+        dockerfile: &'a str,
+    },
+    BuildOutput {
+        line: &'a str,
+    },
+    BuildFailed {
+        step: Option<&'a str>,
+        output: &'a [String],
+        error: &'a str,
+    },
+    WatchStatus {
+        paths: usize,
+        seconds_since_deploy: u64,
+    },
+    ImageTransferProgress {
+        service: &'a str,
+        bytes: usize,
+    },
+    ImageTransferred {
+        service: &'a str,
+        bytes: usize,
+    },
+    ConfigReloadFailed {
+        error: String,
+    },
+    RedeployFailed {
+        error: String,
+    },
+    OnChangeRunning {
+        command: &'a str,
+    },
+    OnChangeFailed {
+        code: Option<i32>,
+    },
+    HostStarting {
+        host: &'a str,
+    },
+    HostFailed {
+        host: &'a str,
+        error: String,
+    },
+    NotifyFailed {
+        error: String,
+    },
+    VolumeToDelete {
+        path: &'a str,
+    },
+    VolumeRemoved {
+        service: &'a str,
+        path: &'a str,
+    },
+    HostsSummary {
+        hosts: Vec<HostSummaryEvent<'a>>,
+    },
+    NamespaceStarting {
+        namespace: &'a str,
+    },
+    NamespaceFailed {
+        namespace: &'a str,
+        error: String,
+    },
+    NamespacesNotFound {
+        pattern: &'a str,
+    },
+    BranchNamespaceFallback,
+    NamespacesSummary {
+        namespaces: Vec<NamespaceSummaryEvent<'a>>,
+    },
+    ServicesStarted {
+        count: usize,
+    },
+    ImagesPulled {
+        count: usize,
+    },
+    ContainerWarning {
+        service: &'a str,
+        message: &'a str,
+    },
+    ContainerOomKilled {
+        service: &'a str,
+    },
+    AppContainerWaitingExit {
+        service: &'a str,
+    },
+    MigrationsRunning {
+        service: &'a str,
+    },
+    MigrationsSuccess {
+        service: &'a str,
+    },
+    SmokeTestRunning {
+        service: &'a str,
+    },
+    SmokeTestSuccess {
+        service: &'a str,
+    },
+    AppContainerExited {
+        service: &'a str,
+        code: i64,
+    },
+    AppRestartScheduled {
+        service: &'a str,
+        delay_secs: u64,
+    },
+
+    DependenciesStarting,
+    DependenciesStopping,
+    EnvFileGenerating,
+    EnvFileLoaded {
+        path: &'a str,
+    },
+    EnvFileFailedToLoad {
+        path: &'a str,
+    },
+    EnvFileGenerated,
+    ServiceWaiting {
+        service: &'a str,
+    },
+    ServiceReady {
+        service: &'a str,
+    },
+    ImagesPruned {
+        service: &'a str,
+        count: usize,
+        bytes: u64,
+    },
+    LogsRedeployed {
+        service: &'a str,
+    },
+    EnvFileUnchanged,
+    EnvChanged {
+        change: &'a str,
+        key: &'a str,
+        old_value: Option<&'a str>,
+        new_value: Option<&'a str>,
+    },
+    NetworkCreating,
+    CtrlcReceived,
+    CtrlcStarted,
+    WatchStopped,
+    WatchFilesChanged,
+    WatchConfigChanged,
+    PostUpRunning,
+    PostDownRunning,
+    DockerWaiting,
+    ReadinessWaiting,
+    DeployUnchanged,
+
+    DependencyStopping {
+        service: &'a str,
+    },
+    DependencyStopped {
+        service: &'a str,
+    },
+    DependencyAlreadyStopped {
+        service: &'a str,
+    },
+    DependencySuccess {
+        service: &'a str,
+    },
+    DependencyStarting {
+        service: &'a str,
+    },
+    DependencyCreating {
+        service: &'a str,
+    },
+    DependencyPulling {
+        service: &'a str,
+    },
+    DependencyPulled {
+        service: &'a str,
+    },
+    DependencyInitializing {
+        service: &'a str,
+    },
+    DependencyInitSkipped {
+        service: &'a str,
+    },
+    ImageBuilt {
+        service: &'a str,
+    },
+    AppContainerCreating {
+        service: &'a str,
+    },
+    AppContainerRemoving {
+        service: &'a str,
+    },
+    AppContainerRemoved {
+        service: &'a str,
+    },
+    AppContainerStarting {
+        service: &'a str,
+    },
+    AppContainerSuccess {
+        service: &'a str,
+    },
+    AppContainerWaitingReady {
+        service: &'a str,
+    },
+    AppContainerDraining {
+        service: &'a str,
+    },
+    AppContainerAlreadyStopped {
+        service: &'a str,
+    },
+    AppContainerStopped {
+        service: &'a str,
+    },
+    RemoteHostConnecting {
+        service: &'a str,
+    },
+    RemoteHostSuccess {
+        service: &'a str,
+    },
+    ProxyUpdating {
+        service: &'a str,
+    },
+    ProxySuccess {
+        service: &'a str,
+    },
+    CommandExecuting {
+        service: &'a str,
+    },
+    CommandDetached {
+        service: &'a str,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfoEvent<'a> {
+    service: String,
+    info: &'a str,
+    ready: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostSummaryEvent<'a> {
+    host: &'a str,
+    status: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamespaceSummaryEvent<'a> {
+    namespace: &'a str,
+    status: &'static str,
+}
+
+pub fn enable_json_events() {
+    JSON_EVENTS.store(true, Ordering::Relaxed);
+}
+
+pub fn is_json_events() -> bool {
+    JSON_EVENTS.load(Ordering::Relaxed)
+}
+
+/// With `--quiet` only errors, warnings and requested output, like the
+/// connection info, are printed. JSON events are not affected
+pub fn enable_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Set when stdout carries a document, like `export compose` or `status --format json`.
+/// Progress is hidden then, and JSON events are printed to stderr
+pub fn enable_document_output() {
+    DOCUMENT_OUTPUT.store(true, Ordering::Relaxed);
+}
+
+fn is_document_output() -> bool {
+    DOCUMENT_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Prints the event as a JSON line in `--json-events` mode.
+/// Returns false when human readable output should be printed instead
+fn emit(event: Event) -> bool {
+    if !is_json_events() {
+        return false;
+    }
+
+    if let Ok(line) = serde_json::to_string(&event) {
+        if is_document_output() {
+            eprintln!("{line}");
+        } else {
+            println!("{line}");
+        }
+    }
+
+    true
+}
+
+/// Like [`emit`], and also returns true when progress is hidden
+/// by `--quiet` or because stdout carries a document
+fn emit_progress(event: Event) -> bool {
+    emit(event) || is_quiet() || is_document_output()
+}
+
+#[inline]
+pub fn print_error(error: &anyhow::Error) {
+    if emit(Event::Error {
+        message: format!("{error:#}"),
+    }) {
+        return;
+    }
+
+    eprintln!("{}", error);
+}
+
 #[inline]
 pub fn print_cli_info() {
-    println!(
+    if emit_progress(Event::CliInfo {
+        version: env!("CARGO_PKG_VERSION"),
+    }) {
+        return;
+    }
+
+    eprintln!(
         "\n\nRunning {} v{}\n\n",
         style(env!("CARGO_PKG_NAME")).cyan(),
         env!("CARGO_PKG_VERSION")
@@ -13,6 +362,10 @@ pub fn print_cli_info() {
 
 #[inline]
 pub fn print_config_not_found_error() {
+    if emit(Event::ConfigNotFound) {
+        return;
+    }
+
     eprintln!("It seems that the config file does not exist.");
     eprintln!(
         "Please make sure the file exists and is named {}",
@@ -22,25 +375,61 @@ pub fn print_config_not_found_error() {
 }
 
 #[inline]
-pub fn print_connection_info(connection_info: &[(ServiceKind, String)]) {
+pub fn print_connection_info(connection_info: &[(ServiceKind, String, bool)]) {
+    if emit(Event::ConnectionInfo {
+        services: connection_info
+            .iter()
+            .map(|(service_kind, info, is_ready)| ConnectionInfoEvent {
+                service: service_kind.to_string(),
+                info,
+                ready: *is_ready,
+            })
+            .collect(),
+    }) {
+        return;
+    }
+
     if connection_info.is_empty() {
         return;
     }
 
     println!("{}", style("\nConnection info:\n").cyan());
 
-    for (service_kind, connection) in connection_info {
-        println!("{}: {}", service_kind, style(connection).cyan());
+    for (service_kind, connection, is_ready) in connection_info {
+        if *is_ready {
+            println!("{}: {}", service_kind, style(connection).cyan());
+        } else {
+            println!(
+                "{}: {} {}",
+                service_kind,
+                style(connection).cyan(),
+                style("(not ready)").yellow()
+            );
+        }
     }
 }
 
 #[inline]
-pub fn print_logs_count(service_name: &str, count: u64, is_follow: bool) {
-    println!(
-        "\nShowing {} last logs of {}",
-        style(count).cyan(),
-        style(service_name).cyan()
-    );
+pub fn print_logs_count(service_name: &str, count: Option<u64>, is_follow: bool) {
+    if emit_progress(Event::LogsStarted {
+        service: service_name,
+        count,
+        follow: is_follow,
+    }) {
+        return;
+    }
+
+    match count {
+        Some(count) => println!(
+            "\nShowing {} last logs of {}",
+            style(count).cyan(),
+            style(service_name).cyan()
+        ),
+        None => println!(
+            "\nShowing all logs of {} since it was started",
+            style(service_name).cyan()
+        ),
+    }
 
     if is_follow {
         println!(
@@ -54,7 +443,11 @@ pub fn print_logs_count(service_name: &str, count: u64, is_follow: bool) {
 
 #[inline]
 pub fn print_namespace_info(namespace: &str) {
-    println!(
+    if emit_progress(Event::Namespace { namespace }) {
+        return;
+    }
+
+    eprintln!(
         "{} Using namespace {}\n",
         style("Notice:").yellow(),
         style(namespace).cyan().bold()
@@ -63,6 +456,13 @@ pub fn print_namespace_info(namespace: &str) {
 
 #[inline]
 pub fn print_image_building(label: &str, dockerfile: &str) {
+    if emit_progress(Event::ImageBuilding {
+        service: label,
+        dockerfile,
+    }) {
+        return;
+    }
+
     println!(
         "[{}] Building image from {}",
         style(label).cyan(),
@@ -70,11 +470,532 @@ pub fn print_image_building(label: &str, dockerfile: &str) {
     );
 }
 
+/// Overwrites the current line, removed with [`clear_watch_status`]
+#[inline]
+pub fn print_watch_status(paths_count: usize, since_last_deploy: std::time::Duration) {
+    if emit_progress(Event::WatchStatus {
+        paths: paths_count,
+        seconds_since_deploy: since_last_deploy.as_secs(),
+    }) {
+        return;
+    }
+
+    let term = console::Term::stdout();
+
+    let _ = term.clear_line();
+    let _ = term.write_str(&format!(
+        "{}",
+        style(format!(
+            "Watching {paths_count} path(s), last deploy {}s ago",
+            since_last_deploy.as_secs()
+        ))
+        .dim()
+    ));
+}
+
+#[inline]
+pub fn clear_watch_status() {
+    if is_json_events() {
+        return;
+    }
+
+    let _ = console::Term::stdout().clear_line();
+}
+
+/// Prints a chunk of the image build output
+#[inline]
+pub fn print_build_output(stream: &str) {
+    if emit_progress(Event::BuildOutput {
+        line: stream.trim_end(),
+    }) {
+        return;
+    }
+
+    if stream.ends_with('\n') {
+        print!("{}", style(stream).dim());
+    } else {
+        println!("{}", style(stream).dim());
+    }
+}
+
+/// Overwrites the current line, finished by [`print_image_transferred`]
+#[inline]
+pub fn print_image_transfer_progress(label: &str, bytes: usize) {
+    if emit_progress(Event::ImageTransferProgress {
+        service: label,
+        bytes,
+    }) {
+        return;
+    }
+
+    print!(
+        "\r[{}] Transferring image {}",
+        style(label).cyan(),
+        style(format_bytes(bytes)).cyan()
+    );
+
+    let _ = std::io::Write::flush(&mut std::io::stdout());
+}
+
+#[inline]
+pub fn print_image_transferred(label: &str, bytes: usize) {
+    if emit_progress(Event::ImageTransferred {
+        service: label,
+        bytes,
+    }) {
+        return;
+    }
+
+    let _ = console::Term::stdout().clear_line();
+
+    println!(
+        "[{}] {} {}",
+        style(label).cyan(),
+        style("Image transferred").green(),
+        format_bytes(bytes)
+    );
+}
+
+#[inline]
+pub fn print_images_pruned(label: &str, count: usize, bytes: u64) {
+    if emit_progress(Event::ImagesPruned {
+        service: label,
+        count,
+        bytes,
+    }) {
+        return;
+    }
+
+    println!(
+        "[{}] {} {}, reclaimed {}",
+        style(label).cyan(),
+        style("Pruned dangling images:").green(),
+        count,
+        format_bytes(bytes as usize)
+    );
+}
+
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[inline]
+pub fn print_build_failed_step(step: Option<&str>, output: &[String], error: &str) {
+    if emit(Event::BuildFailed {
+        step,
+        output,
+        error,
+    }) {
+        return;
+    }
+
+    println!(
+        "\n{} {}",
+        style("Build failed at").red().bold(),
+        style(step.unwrap_or("unknown step")).red().bold()
+    );
+
+    for line in output {
+        println!("  {}", style(line).red());
+    }
+
+    println!("  {}\n", style(error.trim()).red().bold());
+}
+
+#[inline]
+pub fn print_config_reload_failed(error: &anyhow::Error) {
+    if emit(Event::ConfigReloadFailed {
+        error: error.to_string(),
+    }) {
+        return;
+    }
+
+    println!(
+        "{} {}",
+        style("Failed to reload the config, keeping the previous one:").yellow(),
+        error
+    );
+}
+
+#[inline]
+pub fn print_redeploy_failed(error: &anyhow::Error) {
+    if emit(Event::RedeployFailed {
+        error: format!("{error:#}"),
+    }) {
+        return;
+    }
+
+    println!(
+        "{} {:#}",
+        style("Failed to redeploy, waiting for the next change:").yellow(),
+        error
+    );
+}
+
+#[inline]
+pub fn print_on_change_running(command: &str) {
+    if emit_progress(Event::OnChangeRunning { command }) {
+        return;
+    }
+
+    println!(
+        "{} {}",
+        style("\nFiles changed. Running").cyan(),
+        style(command).cyan().bold()
+    );
+}
+
+#[inline]
+pub fn print_on_change_failed(code: Option<i32>) {
+    if emit(Event::OnChangeFailed { code }) {
+        return;
+    }
+
+    match code {
+        Some(code) => println!(
+            "{}",
+            style(format!("Command exited with code {code}")).yellow()
+        ),
+        None => println!("{}", style("Command was terminated by a signal").yellow()),
+    }
+}
+
+#[inline]
+pub fn print_host_starting(host: &str) {
+    if emit_progress(Event::HostStarting { host }) {
+        return;
+    }
+
+    println!(
+        "\n{} {}\n",
+        style("Deploying to").cyan(),
+        style(host).cyan().bold()
+    );
+}
+
+#[inline]
+pub fn print_host_failed(host: &str, error: &anyhow::Error) {
+    if emit(Event::HostFailed {
+        host,
+        error: error.to_string(),
+    }) {
+        return;
+    }
+
+    println!(
+        "[{}] {} {}",
+        style(host).cyan(),
+        style("Failed:").red(),
+        error
+    );
+}
+
+/// Hosts without a result were skipped because of `--fail-fast`
+#[inline]
+pub fn print_hosts_summary(hosts: &[String], results: &[(String, anyhow::Result<()>)]) {
+    if emit(Event::HostsSummary {
+        hosts: hosts
+            .iter()
+            .map(|host| HostSummaryEvent {
+                host,
+                status: host_status(host, results),
+            })
+            .collect(),
+    }) {
+        return;
+    }
+
+    println!("{}", style("\nSummary:\n").cyan());
+
+    for host in hosts {
+        match host_status(host, results) {
+            "success" => println!("{}: {}", host, style("success").green()),
+            "failed" => println!("{}: {}", host, style("failed").red()),
+            status => println!("{}: {}", host, style(status).yellow()),
+        }
+    }
+}
+
+/// Namespaces are printed as a group so the output of `status` can be told apart
+#[inline]
+pub fn print_namespace_starting(namespace: &str) {
+    if emit(Event::NamespaceStarting { namespace }) {
+        return;
+    }
+
+    println!(
+        "\n{} {}\n",
+        style("Namespace").cyan(),
+        style(namespace).cyan().bold()
+    );
+}
+
+#[inline]
+pub fn print_namespace_failed(namespace: &str, error: &anyhow::Error) {
+    if emit(Event::NamespaceFailed {
+        namespace,
+        error: error.to_string(),
+    }) {
+        return;
+    }
+
+    println!(
+        "[{}] {} {}",
+        style(namespace).cyan(),
+        style("Failed:").red(),
+        error
+    );
+}
+
+#[inline]
+pub fn print_branch_namespace_fallback() {
+    if emit(Event::BranchNamespaceFallback) {
+        return;
+    }
+
+    eprintln!(
+        "{} Not on a git branch, using the default namespace",
+        style("Warning:").yellow()
+    );
+}
+
+#[inline]
+pub fn print_namespaces_not_found(pattern: &str) {
+    if emit(Event::NamespacesNotFound { pattern }) {
+        return;
+    }
+
+    println!(
+        "{} No namespace with containers matches {}",
+        style("Notice:").yellow(),
+        style(pattern).cyan().bold()
+    );
+}
+
+#[inline]
+pub fn print_namespaces_summary(namespaces: &[String], results: &[(String, anyhow::Result<()>)]) {
+    if emit(Event::NamespacesSummary {
+        namespaces: namespaces
+            .iter()
+            .map(|namespace| NamespaceSummaryEvent {
+                namespace,
+                status: host_status(namespace, results),
+            })
+            .collect(),
+    }) {
+        return;
+    }
+
+    println!("{}", style("\nSummary:\n").cyan());
+
+    for namespace in namespaces {
+        match host_status(namespace, results) {
+            "success" => println!("{}: {}", namespace, style("success").green()),
+            status => println!("{}: {}", namespace, style(status).red()),
+        }
+    }
+}
+
+/// Also used for namespaces, which are never skipped
+fn host_status(host: &str, results: &[(String, anyhow::Result<()>)]) -> &'static str {
+    match results.iter().find(|(result_host, _)| result_host == host) {
+        Some((_, Ok(()))) => "success",
+        Some((_, Err(_))) => "failed",
+        None => "skipped",
+    }
+}
+
+#[inline]
+pub fn print_services_started(count: usize) {
+    if emit_progress(Event::ServicesStarted { count }) {
+        return;
+    }
+
+    println!("{}", style(format!("Started {count} service(s)")).green());
+}
+
+#[inline]
+pub fn print_app_container_exited(label: &str, code: i64) {
+    if emit(Event::AppContainerExited {
+        service: label,
+        code,
+    }) {
+        return;
+    }
+
+    let message = format!("Container exited with code {code}");
+    let message = if code == 0 {
+        style(message).green()
+    } else {
+        style(message).red()
+    };
+
+    println!("\n[{}] {}", style(label).cyan(), message);
+}
+
+#[inline]
+pub fn print_app_restart_scheduled(label: &str, delay: std::time::Duration) {
+    if emit(Event::AppRestartScheduled {
+        service: label,
+        delay_secs: delay.as_secs(),
+    }) {
+        return;
+    }
+
+    println!(
+        "[{}] {}",
+        style(label).cyan(),
+        style(format!("Recreating the container in {}s", delay.as_secs())).yellow()
+    );
+}
+
+#[inline]
+pub fn print_images_pulled(count: usize) {
+    if emit_progress(Event::ImagesPulled { count }) {
+        return;
+    }
+
+    println!("{}", style(format!("\nPulled {count} image(s)")).green());
+}
+
+#[inline]
+pub fn print_env_file_loaded(path: &str) {
+    if emit_progress(Event::EnvFileLoaded { path }) {
+        return;
+    }
+
+    eprintln!("{} {}", style("Loaded env file").green(), path);
+}
+
+#[inline]
+pub fn print_env_file_failed_to_load(path: &str) {
+    if emit(Event::EnvFileFailedToLoad { path }) {
+        return;
+    }
+
+    eprintln!("{} {}", style("Failed to load env file").yellow(), path);
+}
+
+/// Values are expected to be masked already
+#[inline]
+pub fn print_env_change(key: &str, old_value: Option<&str>, new_value: Option<&str>) {
+    let change = match (old_value, new_value) {
+        (None, _) => "added",
+        (_, None) => "removed",
+        _ => "changed",
+    };
+
+    if emit(Event::EnvChanged {
+        change,
+        key,
+        old_value,
+        new_value,
+    }) {
+        return;
+    }
+
+    match (old_value, new_value) {
+        (None, Some(value)) => println!("  {} {key}={value}", style("+").green()),
+        (Some(value), None) => println!("  {} {key}={value}", style("-").red()),
+        (Some(old_value), Some(new_value)) => println!(
+            "  {} {key}: {old_value} {} {new_value}",
+            style("~").yellow(),
+            style("->").dim()
+        ),
+        (None, None) => {}
+    }
+}
+
+/// Listed before asking to confirm `--recreate-volumes`
+#[inline]
+pub fn print_volume_to_delete(path: &str) {
+    if emit(Event::VolumeToDelete { path }) {
+        return;
+    }
+
+    println!("  {path}");
+}
+
+#[inline]
+pub fn print_volume_removed(label: &str, path: &str) {
+    if emit_progress(Event::VolumeRemoved {
+        service: label,
+        path,
+    }) {
+        return;
+    }
+
+    println!(
+        "[{}] {} {}",
+        style(label).cyan(),
+        style("Removed volume").yellow(),
+        path
+    );
+}
+
+#[inline]
+pub fn print_notify_failed(error: &anyhow::Error) {
+    if emit(Event::NotifyFailed {
+        error: format!("{error:#}"),
+    }) {
+        return;
+    }
+
+    println!(
+        "{} {error:#}",
+        style("Warning: failed to send the deploy notification:").yellow(),
+    );
+}
+
+#[inline]
+pub fn print_container_oom_killed(label: &str) {
+    if emit(Event::ContainerOomKilled { service: label }) {
+        return;
+    }
+
+    println!(
+        "[{}] {}",
+        style(label).cyan(),
+        style("Container was killed due to out-of-memory, consider raising its memory limit").red()
+    );
+}
+
+#[inline]
+pub fn print_container_warning(label: &str, message: &str) {
+    if emit(Event::ContainerWarning {
+        service: label,
+        message,
+    }) {
+        return;
+    }
+
+    println!(
+        "[{}] {} {}",
+        style(label).cyan(),
+        style("Warning:").yellow(),
+        message
+    );
+}
+
 macro_rules! generate_println {
-    ($($fn_name:ident($message:expr)),+ $(,)? ) => {
+    ($($fn_name:ident($event:ident, $message:expr)),+ $(,)? ) => {
         $(
             #[inline]
             pub fn $fn_name() {
+                if emit_progress(Event::$event) {
+                    return;
+                }
+
                 println!("{}", $message);
             }
         )+
@@ -82,10 +1003,14 @@ macro_rules! generate_println {
 }
 
 macro_rules! generate_println_with_label {
-    ($($fn_name:ident($message:expr)),+ $(,)? ) => {
+    ($($fn_name:ident($event:ident, $message:expr)),+ $(,)? ) => {
         $(
             #[inline]
             pub fn $fn_name(label: &str) {
+                if emit_progress(Event::$event { service: label }) {
+                    return;
+                }
+
                 println!("[{}] {}", style(label).cyan(), $message);
             }
         )+
@@ -93,41 +1018,60 @@ macro_rules! generate_println_with_label {
 }
 
 generate_println! {
-    print_dependencies_starting(style("Starting dependencies").cyan()),
-    print_dependencies_stopping(style("Stopping dependencies").cyan()),
-    print_env_file_generating(style("Generating env file").cyan()),
-    print_env_file_loaded(style("Loaded env file").green()),
-    print_env_file_failed_to_load(style("Failed to load env file").yellow()),
-    print_env_file_generated(style(concat!(
+    print_dependencies_starting(DependenciesStarting, style("Starting dependencies").cyan()),
+    print_dependencies_stopping(DependenciesStopping, style("Stopping dependencies").cyan()),
+    print_env_file_generating(EnvFileGenerating, style("Generating env file").cyan()),
+    print_env_file_generated(EnvFileGenerated, style(concat!(
         ".env file was generated. Please make sure to ",
         "fill in your custom environment variables.",
     )).yellow()),
-    print_network_creating(style("Creating network").cyan()),
-    print_ctrlc_received(style("\n\nReceived escape sequence. Please wait until current tasks are finished\n").red()),
-    print_ctrlc_started(style("\nStopping services because of escape sequence...\n").red()),
-    print_watch_files_changed(style("\nFiles changed. Restarting...").cyan()),
-    print_post_up_running(style("Post up actions running").cyan()),
-    print_post_down_running(style("Post down actions running").cyan()),
+    print_env_file_unchanged(EnvFileUnchanged, style("The env file is up to date").green()),
+    print_network_creating(NetworkCreating, style("Creating network").cyan()),
+    print_ctrlc_received(CtrlcReceived, style("\n\nReceived escape sequence. Please wait until current tasks are finished\n").red()),
+    print_ctrlc_started(CtrlcStarted, style("\nStopping services because of escape sequence...\n").red()),
+    print_watch_stopped(WatchStopped, style("\nStopped watching. The dependencies keep running, stop them with `dploy dev stop`\n").red()),
+    print_watch_files_changed(WatchFilesChanged, style("\nFiles changed. Restarting...").cyan()),
+    print_watch_config_changed(WatchConfigChanged, style("\nConfig changed. Redeploying dependencies...").cyan()),
+    print_post_up_running(PostUpRunning, style("Post up actions running").cyan()),
+    print_post_down_running(PostDownRunning, style("Post down actions running").cyan()),
+    print_docker_waiting(DockerWaiting, style("Docker is not available yet, retrying").yellow()),
+    print_readiness_waiting(ReadinessWaiting, style("Waiting for services to accept connections").cyan()),
+    print_deploy_unchanged(DeployUnchanged, style("Nothing changed since the last deploy. Use --force to deploy anyway").green()),
 }
 
 generate_println_with_label! {
-    print_dependency_stopping(style("Stopping").cyan()),
-    print_dependency_stopped(style("Stopped").green()),
-    print_dependency_already_stopped(style("Already stopped").green()),
-    print_dependency_success(style("Success").green()),
-    print_dependency_starting(style("Starting").cyan()),
-    print_dependency_creating(style("Creating").cyan()),
-    print_dependency_pulling(style("Pulling").cyan()),
-    print_image_built(style("Image built").green()),
-    print_app_container_creating(style("Creating container").cyan()),
-    print_app_container_removing(style("Removing container").cyan()),
-    print_app_container_starting(style("Starting container").cyan()),
-    print_app_container_success(style("Success").green()),
-    print_app_container_already_stopped(style("Already stopped").green()),
-    print_app_container_stopped(style("Stopped").green()),
-    print_remote_host_connecting(style("Connecting").cyan()),
-    print_remote_host_success(style("Success").green()),
-    print_proxy_updating(style("Updating").cyan()),
-    print_proxy_success(style("Success").green()),
-    print_command_executing(style("Executing command\n").cyan()),
+    print_logs_redeployed(LogsRedeployed, style("--- redeployed ---").dim()),
+    print_service_waiting(ServiceWaiting, style("Waiting until ready").cyan()),
+    print_service_ready(ServiceReady, style("Ready").green()),
+    print_dependency_stopping(DependencyStopping, style("Stopping").cyan()),
+    print_dependency_stopped(DependencyStopped, style("Stopped").green()),
+    print_dependency_already_stopped(DependencyAlreadyStopped, style("Already stopped").green()),
+    print_dependency_success(DependencySuccess, style("Success").green()),
+    print_dependency_starting(DependencyStarting, style("Starting").cyan()),
+    print_dependency_creating(DependencyCreating, style("Creating").cyan()),
+    print_dependency_pulling(DependencyPulling, style("Pulling").cyan()),
+    print_dependency_pulled(DependencyPulled, style("Pulled").green()),
+    print_dependency_initializing(DependencyInitializing, style("Running init scripts").cyan()),
+    print_dependency_init_skipped(DependencyInitSkipped, style("Database already has data, skipping init scripts").yellow()),
+    print_image_built(ImageBuilt, style("Image built").green()),
+    print_app_container_creating(AppContainerCreating, style("Creating container").cyan()),
+    print_app_container_removing(AppContainerRemoving, style("Removing container").cyan()),
+    print_app_container_removed(AppContainerRemoved, style("Removed previous container").cyan()),
+    print_app_container_starting(AppContainerStarting, style("Starting container").cyan()),
+    print_app_container_success(AppContainerSuccess, style("Success").green()),
+    print_app_container_waiting_ready(AppContainerWaitingReady, style("Waiting for the container to become ready").cyan()),
+    print_app_container_draining(AppContainerDraining, style("Draining connections").cyan()),
+    print_app_container_already_stopped(AppContainerAlreadyStopped, style("Already stopped").green()),
+    print_app_container_stopped(AppContainerStopped, style("Stopped").green()),
+    print_remote_host_connecting(RemoteHostConnecting, style("Connecting").cyan()),
+    print_remote_host_success(RemoteHostSuccess, style("Success").green()),
+    print_proxy_updating(ProxyUpdating, style("Updating").cyan()),
+    print_proxy_success(ProxySuccess, style("Success").green()),
+    print_migrations_running(MigrationsRunning, style("Running migrations\n").cyan()),
+    print_migrations_success(MigrationsSuccess, style("Migrations finished").green()),
+    print_smoke_test_running(SmokeTestRunning, style("Running the smoke test").cyan()),
+    print_smoke_test_success(SmokeTestSuccess, style("Smoke test passed").green()),
+    print_app_container_waiting_exit(AppContainerWaitingExit, style("Waiting for the container to exit").cyan()),
+    print_command_executing(CommandExecuting, style("Executing command\n").cyan()),
+    print_command_detached(CommandDetached, style("Command started in the background").cyan()),
 }
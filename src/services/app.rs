@@ -1,18 +1,25 @@
 use std::{collections::HashMap, env};
 
-use anyhow::Result;
+use anyhow::{bail, Context as _, Result};
 use bollard::{container, image, models};
 
 use crate::{
+    config::{ContainerOptions, NetworkMode},
     context::Context,
+    docker,
     network::DPLOY_NETWORK,
-    utils::{network::free_port, string::escape_sh},
+    utils::{git::git_short_hash, network::free_port, string::escape_sh},
 };
 
-use super::{ConnectionInfo, ContainerConfig, EnvVars, ServiceKind, ToContainerConfig};
+use super::{
+    options, ConnectionInfo, ConnectionInfoItem, ContainerConfig, EnvVars, ServiceKind,
+    ToContainerConfig,
+};
 
 const SERVICE_KIND: ServiceKind = ServiceKind::App;
 
+const DEFAULT_TAG: &str = "latest";
+
 #[derive(Debug)]
 pub struct AppService {
     app_name: String,
@@ -22,10 +29,18 @@ pub struct AppService {
     ports_mapping: Vec<(u16, u16)>,
     volumes: Vec<String>,
     dockerfile: String,
+    tty: bool,
+    stdin_open: bool,
+    network_mode: NetworkMode,
+    container_options: ContainerOptions,
 }
 
 impl AppService {
-    pub fn from_context(context: &Context, env_vars: Vec<(String, String)>) -> Self {
+    pub fn from_context(context: &Context, env_vars: Vec<(String, String)>) -> Result<Self> {
+        let network_mode = context
+            .app_config()
+            .network_mode(context.override_context());
+
         let ports_mapping = context
             .should_expose_app_service_to_host()
             .then(|| {
@@ -33,9 +48,23 @@ impl AppService {
                     .app_config()
                     .ports(context.override_context())
                     .iter()
-                    .map(|port| (free_port(), *port))
-                    .collect()
+                    .map(|port| {
+                        // the app listens on the host's ports itself
+                        if network_mode == NetworkMode::Host {
+                            return Ok((*port, *port));
+                        }
+
+                        let host_port = free_port().with_context(|| {
+                            format!(
+                                "Could not allocate a free host port for port {port} of the app"
+                            )
+                        })?;
+
+                        Ok((host_port, *port))
+                    })
+                    .collect::<Result<Vec<_>>>()
             })
+            .transpose()?
             .unwrap_or_else(std::vec::Vec::new);
 
         let mut env_vars = env_vars;
@@ -46,13 +75,19 @@ impl AppService {
             env_vars.push((env_name.to_owned(), env::var(env_name).unwrap_or_default()));
         }
 
-        Self {
+        // overrides from the command line have the highest precedence
+        for (key, value) in context.args().env_overrides() {
+            env_vars.retain(|(existing_key, _)| existing_key != key);
+            env_vars.push((key.clone(), value.clone()));
+        }
+
+        Ok(Self {
             app_name: context
                 .app_config()
                 .name(context.override_context())
                 .to_owned(),
-            image_name: context.container_name_of(SERVICE_KIND),
-            container_name: context.container_name_of(SERVICE_KIND),
+            image_name: Self::image_name_from_context(context),
+            container_name: context.container_name_of(&SERVICE_KIND),
             env_vars,
             ports_mapping,
             volumes: context
@@ -65,17 +100,112 @@ impl AppService {
                 .app_config()
                 .dockerfile(context.override_context())
                 .to_owned(),
+            tty: context.app_config().tty(context.override_context()),
+            stdin_open: context.app_config().stdin_open(context.override_context()),
+            network_mode,
+            container_options: context
+                .app_config()
+                .container_options(context.override_context()),
+        })
+    }
+
+    /// `image:tag` from the config. Without an image, the container name is used,
+    /// tagged only if a tag is configured
+    fn image_name_from_context(context: &Context) -> String {
+        let app_config = context.app_config();
+
+        let Some(image) = app_config.image(context.override_context()) else {
+            let container_name = context.container_name_of(&SERVICE_KIND);
+
+            return match app_config.tag(context.override_context()) {
+                Some(tag) => format!("{container_name}:{tag}"),
+                None => container_name,
+            };
+        };
+
+        let tag = app_config
+            .tag(context.override_context())
+            .map(ToOwned::to_owned)
+            .or_else(|| git_short_hash(&context.config_dir()))
+            .unwrap_or_else(|| DEFAULT_TAG.to_owned());
+
+        format!("{image}:{tag}")
+    }
+
+    /// Checks the image reference, e.g. `registry.example.com:5000/myorg/app:v1`
+    pub fn validate(&self) -> Result<()> {
+        let (name, tag) = docker::split_image_tag(&self.image_name);
+
+        if let Some(tag) = tag {
+            let is_valid_tag = tag.len() <= 128
+                && tag.starts_with(|char: char| char.is_ascii_alphanumeric() || char == '_')
+                && tag
+                    .chars()
+                    .all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '.' | '-'));
+
+            if !is_valid_tag {
+                bail!(
+                    "Invalid image tag `{tag}`. Tags may contain letters, digits, `_`, `.` and `-`, \
+                     and can't start with `.` or `-`"
+                );
+            }
+        }
+
+        let mut components = name.split('/').collect::<Vec<_>>();
+
+        // the registry host is only recognized by a dot, a port or being localhost
+        if components.len() > 1
+            && (components[0].contains(['.', ':']) || components[0] == "localhost")
+        {
+            components.remove(0);
+        }
+
+        let is_valid_name = components.iter().all(|component| {
+            !component.is_empty()
+                && component.starts_with(|char: char| char.is_ascii_alphanumeric())
+                && component.ends_with(|char: char| char.is_ascii_alphanumeric())
+                && component.chars().all(|char| {
+                    char.is_ascii_lowercase()
+                        || char.is_ascii_digit()
+                        || matches!(char, '.' | '_' | '-')
+                })
+        });
+
+        if !is_valid_name {
+            bail!(
+                "Invalid image name `{name}`. Names must be lowercase and may contain digits, \
+                 `.`, `_` and `-` separated by `/`"
+            );
         }
+
+        Ok(())
     }
 
     pub fn ports_mapping(&self) -> &[(u16, u16)] {
         &self.ports_mapping
     }
 
+    pub fn image_name(&self) -> &str {
+        &self.image_name
+    }
+
+    pub fn container_name(&self) -> &str {
+        &self.container_name
+    }
+
+    pub fn container_options(&self) -> &ContainerOptions {
+        &self.container_options
+    }
+
+    pub fn network_mode(&self) -> NetworkMode {
+        self.network_mode
+    }
+
     pub fn to_image_build_config(&self) -> image::BuildImageOptions<String> {
         image::BuildImageOptions {
             t: self.image_name.clone(),
             dockerfile: self.dockerfile.clone(),
+            platform: self.container_options.platform.clone().unwrap_or_default(),
             ..Default::default()
         }
     }
@@ -83,16 +213,26 @@ impl AppService {
 
 impl ToContainerConfig for AppService {
     fn to_container_config(&self, context: &Context) -> Result<ContainerConfig> {
-        let mut host_config = models::HostConfig::default();
+        self.validate()?;
 
-        host_config.mounts = Some(
-            self.volumes
-                .iter()
-                .map(|volume| context.mount(SERVICE_KIND, volume))
-                .collect(),
-        );
+        let mut host_config = models::HostConfig {
+            mounts: Some(
+                self.volumes
+                    .iter()
+                    .map(|volume| context.mount(&SERVICE_KIND, volume))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
 
-        host_config.port_bindings = Some(
+        let is_host_network = self.network_mode == NetworkMode::Host;
+
+        if is_host_network {
+            host_config.network_mode = Some("host".to_owned());
+        }
+
+        // ports of the host network can't be bound, they are the host's already
+        host_config.port_bindings = (!is_host_network).then(|| {
             self.ports_mapping
                 .iter()
                 .map(|(host_port, container_port)| {
@@ -105,18 +245,33 @@ impl ToContainerConfig for AppService {
                         }]),
                     )
                 })
-                .collect(),
-        );
+                .collect()
+        });
+
+        // a job must not be restarted once it exits, and with --restart-on-crash
+        // dploy restarts the app instead of docker, so they don't race
+        let restart_policy = if context.args().command().wait_for_exit()
+            || context.args().command().restart_on_crash()
+        {
+            models::RestartPolicyNameEnum::NO
+        } else {
+            models::RestartPolicyNameEnum::ALWAYS
+        };
 
         host_config.restart_policy = Some(models::RestartPolicy {
-            name: Some(models::RestartPolicyNameEnum::ALWAYS),
+            name: Some(restart_policy),
             ..Default::default()
         });
 
-        let config = container::Config {
+        let mut config = container::Config {
             image: Some(self.image_name.clone()),
-            hostname: Some(self.container_name.clone()),
-            domainname: Some(self.container_name.clone()),
+            // docker rejects a hostname for containers on the host network
+            hostname: (!is_host_network).then(|| self.container_name.clone()),
+            domainname: (!is_host_network).then(|| self.container_name.clone()),
+
+            tty: Some(self.tty),
+            open_stdin: Some(self.stdin_open),
+            attach_stdin: Some(self.stdin_open),
 
             env: Some(
                 self.env_vars
@@ -128,7 +283,7 @@ impl ToContainerConfig for AppService {
 
             host_config: Some(host_config),
 
-            networking_config: Some(container::NetworkingConfig {
+            networking_config: (!is_host_network).then(|| container::NetworkingConfig {
                 endpoints_config: HashMap::from([(
                     DPLOY_NETWORK.to_owned(),
                     models::EndpointSettings::default(),
@@ -138,11 +293,17 @@ impl ToContainerConfig for AppService {
             ..Default::default()
         };
 
-        Ok(ContainerConfig::new(
-            self.container_name.clone(),
-            self.image_name.clone(),
-            config,
-        ))
+        options::apply_container_options(
+            context,
+            &SERVICE_KIND,
+            &self.container_options,
+            &mut config,
+        )?;
+
+        Ok(
+            ContainerConfig::new(self.container_name.clone(), self.image_name.clone(), config)
+                .with_platform(self.container_options.platform.clone()),
+        )
     }
 }
 
@@ -153,10 +314,17 @@ impl EnvVars for AppService {
 }
 
 impl ConnectionInfo for AppService {
-    fn connection_info(&self) -> Vec<String> {
+    fn connection_info(&self) -> Vec<ConnectionInfoItem> {
         self.ports_mapping
             .iter()
-            .map(|(host_port, container_port)| format!("127.0.0.1:{host_port} >> {container_port}"))
+            .map(|(host_port, container_port)| {
+                let info = match self.network_mode {
+                    NetworkMode::Host => format!("127.0.0.1:{host_port}"),
+                    NetworkMode::Dploy => format!("127.0.0.1:{host_port} >> {container_port}"),
+                };
+
+                ConnectionInfoItem::new(info, Some(("127.0.0.1".to_owned(), *host_port)))
+            })
             .collect()
     }
 }
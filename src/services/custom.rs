@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context as _, Result};
+use bollard::{container, models};
+
+use crate::{
+    config::{ContainerOptions, CustomServiceConfig},
+    context::{Context, HostPortBinding},
+    docker,
+    network::DPLOY_NETWORK,
+};
+
+use super::{
+    options, prefixed_env_name, ConnectionInfo, ConnectionInfoItem, ContainerConfig, EnvVars,
+    ServiceKind, ToContainerConfig,
+};
+
+const RESERVED_NAMES: &[&str] = &["app", "postgres", "keydb", "proxy"];
+
+/// Dependency service defined in a `[[service]]` config section
+pub struct CustomService {
+    kind: ServiceKind,
+
+    image: String,
+    digest: Option<String>,
+    env: Vec<(String, String)>,
+    volumes: Vec<String>,
+    expose_to_env: Vec<(String, String)>,
+    env_prefix: Option<String>,
+
+    bindings: Vec<HostPortBinding>,
+
+    container_options: ContainerOptions,
+}
+
+impl CustomService {
+    pub fn from_context(context: &Context) -> Result<Vec<Self>> {
+        let inherited = context
+            .app_config()
+            .container_options(context.override_context())
+            .inherited();
+
+        context
+            .app_config()
+            .custom_services(context.override_context())
+            .iter()
+            .map(|config| Self::from_config(context, config, &inherited))
+            .collect()
+    }
+
+    fn from_config(
+        context: &Context,
+        config: &CustomServiceConfig,
+        inherited: &ContainerOptions,
+    ) -> Result<Self> {
+        let kind = ServiceKind::Custom {
+            name: config.name.clone(),
+        };
+
+        let bindings = config
+            .ports
+            .iter()
+            .map(|port| context.host_port_binding_of(&kind, *port, config.connection_mode))
+            .collect::<Result<_>>()?;
+
+        let (image, digest) = docker::pinned_image(&config.image, config.digest.as_deref())
+            .with_context(|| format!("Invalid image of service {}", config.name))?;
+
+        Ok(Self {
+            kind,
+            image,
+            digest,
+            env: config.env.clone().into_iter().collect(),
+            volumes: config.volumes.clone(),
+            expose_to_env: config.expose_to_env.clone().into_iter().collect(),
+            env_prefix: config.env_prefix.clone(),
+            bindings,
+            container_options: inherited.merge(&config.container),
+        })
+    }
+
+    pub fn bindings(&self) -> &[HostPortBinding] {
+        &self.bindings
+    }
+
+    pub fn kind(&self) -> &ServiceKind {
+        &self.kind
+    }
+
+    pub fn container_options(&self) -> &ContainerOptions {
+        &self.container_options
+    }
+
+    pub fn env_prefix(&self) -> Option<&str> {
+        self.env_prefix.as_deref()
+    }
+
+    /// Checks the names of all custom services, so containers don't clash
+    pub fn validate_all(services: &[Self]) -> Result<()> {
+        let mut names = vec![];
+
+        for service in services {
+            let name = service.kind.to_string();
+
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+            {
+                bail!("Invalid service name `{name}`. Use letters, digits, `-` and `_`");
+            }
+
+            if RESERVED_NAMES.contains(&name.as_str()) {
+                bail!("Service name `{name}` is reserved for a built-in service");
+            }
+
+            if names.contains(&name) {
+                bail!("Service `{name}` is defined more than once");
+            }
+
+            names.push(name);
+        }
+
+        Ok(())
+    }
+}
+
+impl EnvVars for CustomService {
+    fn env_vars(&self) -> Vec<(String, String)> {
+        let binding = self.bindings.first();
+
+        self.expose_to_env
+            .iter()
+            .map(|(key, value)| {
+                let value = match binding {
+                    Some(binding) => value
+                        .replace("{host}", binding.inner_host())
+                        .replace("{port}", &binding.inner_port().to_string()),
+                    None => value.clone(),
+                };
+
+                (prefixed_env_name(self.env_prefix.as_deref(), key), value)
+            })
+            .collect()
+    }
+}
+
+impl ConnectionInfo for CustomService {
+    fn connection_info(&self) -> Vec<ConnectionInfoItem> {
+        self.bindings
+            .iter()
+            .filter_map(|binding| {
+                let host = binding.host_host();
+
+                binding.host_port().map(|port| {
+                    let info = match &self.env_prefix {
+                        Some(prefix) => format!("{host}:{port} ({prefix})"),
+                        None => format!("{host}:{port}"),
+                    };
+
+                    ConnectionInfoItem::new(info, Some((host.to_owned(), port)))
+                })
+            })
+            .collect()
+    }
+}
+
+impl ToContainerConfig for CustomService {
+    fn to_container_config(&self, context: &Context) -> Result<ContainerConfig> {
+        let name = context.container_name_of(&self.kind);
+
+        let mut config = container::Config {
+            image: Some(self.image.clone()),
+            hostname: Some(name.clone()),
+            domainname: Some(name.clone()),
+
+            env: Some(
+                self.env
+                    .iter()
+                    .map(|(key, value)| format!("{key}={value}"))
+                    .collect(),
+            ),
+
+            networking_config: Some(container::NetworkingConfig {
+                endpoints_config: HashMap::from([(
+                    DPLOY_NETWORK.to_owned(),
+                    models::EndpointSettings::default(),
+                )]),
+            }),
+
+            ..Default::default()
+        };
+
+        config.host_config = Some(models::HostConfig {
+            mounts: Some(
+                self.volumes
+                    .iter()
+                    .map(|volume| context.mount(&self.kind, volume))
+                    .collect(),
+            ),
+            port_bindings: Some(HostPortBinding::to_port_bindings(
+                &self.bindings.iter().collect::<Vec<_>>(),
+            )),
+            restart_policy: Some(models::RestartPolicy {
+                name: Some(models::RestartPolicyNameEnum::ALWAYS),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        options::apply_container_options(
+            context,
+            &self.kind,
+            &self.container_options,
+            &mut config,
+        )?;
+
+        Ok(ContainerConfig::new(name, self.image.clone(), config)
+            .with_platform(self.container_options.platform.clone())
+            .with_digest(self.digest.clone()))
+    }
+}
@@ -1,15 +1,22 @@
-use std::fmt;
+use std::{collections::HashSet, fmt};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bollard::container;
 
-use crate::context::Context;
+use crate::{
+    config::{ContainerOptions, ReadinessConfig},
+    constants,
+    context::Context,
+    docker,
+};
 
 pub mod app;
+pub mod custom;
+pub mod options;
 pub mod postgres;
 pub mod proxy;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ServiceKind {
     /// Service being developed with dploy
     App,
@@ -19,6 +26,11 @@ pub enum ServiceKind {
 
     /// Reverse proxy service (Caddy)
     Proxy,
+
+    /// Dependency service defined in the `[[service]]` config section
+    Custom {
+        name: String,
+    },
 }
 
 impl ServiceKind {
@@ -31,6 +43,19 @@ impl ServiceKind {
     pub fn is_local(&self) -> bool {
         !self.is_singleton()
     }
+
+    /// Kind of the service with this name in the config, the opposite of `to_string`
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "app" => ServiceKind::App,
+            "postgres" => ServiceKind::Postgres,
+            "keydb" => ServiceKind::Keydb,
+            "proxy" => ServiceKind::Proxy,
+            _ => ServiceKind::Custom {
+                name: name.to_owned(),
+            },
+        }
+    }
 }
 
 impl fmt::Display for ServiceKind {
@@ -40,10 +65,37 @@ impl fmt::Display for ServiceKind {
             ServiceKind::Postgres => write!(f, "postgres"),
             ServiceKind::Keydb => write!(f, "keydb"),
             ServiceKind::Proxy => write!(f, "proxy"),
+            ServiceKind::Custom { name } => write!(f, "{name}"),
         }
     }
 }
 
+/// Name of an exposed variable with the service's `env_prefix`, e.g. `PRIMARY_DATABASE_URL`
+pub fn prefixed_env_name(prefix: Option<&str>, name: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("{prefix}_{name}"),
+        None => name.to_owned(),
+    }
+}
+
+/// Prefixes are joined with `_`, so they must not end with one
+fn validate_env_prefix(prefix: &str) -> Result<()> {
+    let is_valid = prefix.starts_with(|char: char| char.is_ascii_uppercase())
+        && !prefix.ends_with('_')
+        && prefix
+            .chars()
+            .all(|char| char.is_ascii_uppercase() || char.is_ascii_digit() || char == '_');
+
+    if !is_valid {
+        bail!(
+            "Invalid env_prefix `{prefix}`. Use uppercase letters, digits and `_`, \
+             starting with a letter"
+        );
+    }
+
+    Ok(())
+}
+
 /// Env vars to expose to app service
 pub trait EnvVars {
     fn env_vars(&self) -> Vec<(String, String)>;
@@ -52,6 +104,8 @@ pub trait EnvVars {
 pub struct ContainerConfig {
     container_name: String,
     image_name: String,
+    platform: Option<String>,
+    digest: Option<String>,
     config: container::Config<String>,
 }
 
@@ -64,10 +118,34 @@ impl ContainerConfig {
         Self {
             container_name,
             image_name,
+            platform: None,
+            digest: None,
             config,
         }
     }
 
+    pub fn with_platform(mut self, platform: Option<String>) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// The pulled image is checked against the digest, see [`crate::docker::verify_image_digest`]
+    pub fn with_digest(mut self, digest: Option<String>) -> Self {
+        self.digest = digest;
+        self
+    }
+
+    /// Switches to the image with this digest instead of resolving the tag, see `deploy --locked`
+    pub fn pin_digest(&mut self, digest: &str) -> Result<()> {
+        let (image_name, digest) = docker::pinned_image(&self.image_name, Some(digest))?;
+
+        self.config.image = Some(image_name.clone());
+        self.image_name = image_name;
+        self.digest = digest;
+
+        Ok(())
+    }
+
     pub fn container_name(&self) -> &str {
         &self.container_name
     }
@@ -76,9 +154,24 @@ impl ContainerConfig {
         &self.image_name
     }
 
+    pub fn platform(&self) -> Option<&str> {
+        self.platform.as_deref()
+    }
+
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
     pub fn config(&self) -> &container::Config<String> {
         &self.config
     }
+
+    /// See `stop_grace_period` in the container options
+    pub fn stop_timeout(&self) -> Option<std::time::Duration> {
+        self.config
+            .stop_timeout
+            .map(|seconds| std::time::Duration::from_secs(seconds as u64))
+    }
 }
 
 pub trait ToContainerConfig {
@@ -86,42 +179,160 @@ pub trait ToContainerConfig {
 }
 
 pub trait ConnectionInfo {
-    fn connection_info(&self) -> Vec<String>;
+    fn connection_info(&self) -> Vec<ConnectionInfoItem>;
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionInfoItem {
+    info: String,
+
+    /// Host address which accepts connections once the service is ready
+    address: Option<(String, u16)>,
+}
+
+impl ConnectionInfoItem {
+    pub fn new(info: String, address: Option<(String, u16)>) -> Self {
+        Self { info, address }
+    }
+
+    pub fn info(&self) -> &str {
+        &self.info
+    }
+
+    pub fn address(&self) -> Option<(&str, u16)> {
+        self.address
+            .as_ref()
+            .map(|(host, port)| (host.as_str(), *port))
+    }
 }
 
 pub struct Services {
     app: Option<app::AppService>,
     postgres: Option<postgres::PostgresService>,
+    custom: Vec<custom::CustomService>,
     proxy: proxy::ProxyService,
 }
 
 impl Services {
-    pub fn from_context(context: &Context) -> Self {
+    pub fn from_context(context: &Context) -> Result<Self> {
+        Self::from_context_with_git_sha(context, current_git_sha().as_deref())
+    }
+
+    /// The commit is passed in, so tests don't have to change the process env
+    fn from_context_with_git_sha(context: &Context, git_sha: Option<&str>) -> Result<Self> {
         let mut app_service_env_vars = vec![];
 
-        let postgres = postgres::PostgresService::from_context(context);
+        let postgres = postgres::PostgresService::from_context(context)?;
 
         if let Some(postgres) = &postgres {
             app_service_env_vars.extend(postgres.env_vars());
         }
 
+        let custom = custom::CustomService::from_context(context)?;
+
+        for service in &custom {
+            app_service_env_vars.extend(service.env_vars());
+        }
+
+        app_service_env_vars.extend(git_sha_env_var(context, git_sha));
+
         let app = context
             .should_create_app_service()
-            .then(|| app::AppService::from_context(context, app_service_env_vars));
+            .then(|| app::AppService::from_context(context, app_service_env_vars))
+            .transpose()?;
 
-        let proxy = proxy::ProxyService::from_context(context);
+        let proxy = proxy::ProxyService::from_context(context)?;
 
-        Self {
+        Ok(Self {
             app,
             postgres,
+            custom,
             proxy,
-        }
+        })
     }
 
     pub fn app(&self) -> Option<&app::AppService> {
         self.app.as_ref()
     }
 
+    pub fn proxy(&self) -> &proxy::ProxyService {
+        &self.proxy
+    }
+
+    /// Kinds of the services managed for the current command
+    pub fn kinds(&self, context: &Context) -> Vec<ServiceKind> {
+        let mut kinds = vec![];
+
+        if self.postgres.is_some() {
+            kinds.push(ServiceKind::Postgres);
+        }
+
+        kinds.extend(self.custom.iter().map(|service| service.kind().clone()));
+
+        if self.app.is_some() {
+            kinds.push(ServiceKind::App);
+        }
+
+        if context.ssh_credentials().is_some() {
+            kinds.push(ServiceKind::Proxy);
+        }
+
+        kinds
+    }
+
+    /// Checks the parts of the config which depend on more than one service
+    pub fn validate(&self, context: &Context) -> Result<()> {
+        custom::CustomService::validate_all(&self.custom)?;
+
+        let prefixes = self
+            .postgres
+            .iter()
+            .filter_map(|postgres| postgres.env_prefix())
+            .chain(
+                self.custom
+                    .iter()
+                    .filter_map(|service| service.env_prefix()),
+            );
+
+        for prefix in prefixes {
+            validate_env_prefix(prefix)?;
+        }
+
+        let mut ips = HashSet::new();
+        let mut mac_addresses = HashSet::new();
+
+        for (kind, options) in self.container_options() {
+            options::warn_about_options(&context.container_name_of(&kind), options);
+
+            if let Some(ip) = &options.ip {
+                if !ips.insert(ip) {
+                    bail!("The ip {ip} of {kind} is assigned to more than one service");
+                }
+            }
+
+            if let Some(mac_address) = &options.mac_address {
+                if !mac_addresses.insert(mac_address.to_lowercase()) {
+                    bail!(
+                        "The mac_address {mac_address} of {kind} is assigned to more than one service"
+                    );
+                }
+            }
+        }
+
+        let mut env_names = HashSet::new();
+
+        for (env_name, _) in self.env_vars(context) {
+            if !env_names.insert(env_name.clone()) {
+                bail!(
+                    "Environment variable `{env_name}` is exposed by more than one service. \
+                     Set `env_prefix` to tell them apart"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn to_container_configs(&self, context: &Context) -> Result<Vec<ContainerConfig>> {
         let mut configs = vec![];
 
@@ -129,6 +340,12 @@ impl Services {
             configs.push(postgres.to_container_config(context)?);
         }
 
+        custom::CustomService::validate_all(&self.custom)?;
+
+        for service in &self.custom {
+            configs.push(service.to_container_config(context)?);
+        }
+
         if context.should_create_proxy_service() {
             configs.push(self.proxy.to_container_config(context)?);
         }
@@ -143,11 +360,88 @@ impl Services {
             configs.push(postgres.to_container_config(context)?);
         }
 
+        for service in &self.custom {
+            configs.push(service.to_container_config(context)?);
+        }
+
         Ok(configs)
     }
 
+    /// The `readiness` command of the service, which replaces connecting to its port
+    pub fn readiness_of(&self, kind: &ServiceKind) -> Option<&ReadinessConfig> {
+        self.container_options()
+            .into_iter()
+            .find(|(service_kind, _)| service_kind == kind)
+            .and_then(|(_, options)| options.readiness.as_ref())
+    }
+
+    fn container_options(&self) -> Vec<(ServiceKind, &ContainerOptions)> {
+        let mut options = vec![];
+
+        if let Some(app) = &self.app {
+            options.push((ServiceKind::App, app.container_options()));
+        }
+
+        if let Some(postgres) = &self.postgres {
+            options.push((ServiceKind::Postgres, postgres.container_options()));
+        }
+
+        for service in &self.custom {
+            options.push((service.kind().clone(), service.container_options()));
+        }
+
+        options
+    }
+
+    /// Inline `files` of all services, as paths relative to
+    /// [`crate::context::Context::files_dir`] and their contents
+    pub fn inline_files(&self, context: &Context) -> Vec<(String, String)> {
+        self.container_options()
+            .into_iter()
+            .flat_map(|(kind, options)| {
+                options.files.iter().flatten().map(move |file| {
+                    (
+                        context.file_name_of(&kind, &file.target),
+                        file.content.clone(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Picks new free host ports for the container, see [`crate::context::HostPortBinding::rebind`].
+    /// Returns the old and the new ports
+    pub fn rebind_ports_of(
+        &self,
+        context: &Context,
+        container_name: &str,
+    ) -> Result<Vec<(u16, u16)>> {
+        let mut bindings = vec![];
+
+        if let Some(postgres) = &self.postgres {
+            if context.container_name_of(&ServiceKind::Postgres) == container_name {
+                bindings.push(postgres.binding());
+            }
+        }
+
+        for service in &self.custom {
+            if context.container_name_of(service.kind()) == container_name {
+                bindings.extend(service.bindings());
+            }
+        }
+
+        bindings
+            .into_iter()
+            .filter_map(|binding| binding.rebind().transpose())
+            .collect()
+    }
+
     /// These actions run after all services have been created
     pub async fn post_up(&self, docker: &bollard::Docker) -> Result<()> {
+        if let Some(postgres) = &self.postgres {
+            postgres.post_up(docker).await?;
+        }
+
         self.proxy.post_up(docker).await?;
 
         Ok(())
@@ -167,6 +461,10 @@ impl Services {
             env_vars.extend(postgres.env_vars());
         }
 
+        for service in &self.custom {
+            env_vars.extend(service.env_vars());
+        }
+
         if let Some(expose_namespace_to_env) = context
             .app_config()
             .expose_namespace_to_env(context.override_context())
@@ -177,10 +475,12 @@ impl Services {
             ));
         }
 
+        env_vars.extend(git_sha_env_var(context, current_git_sha().as_deref()));
+
         env_vars
     }
 
-    pub fn connection_info(&self) -> Vec<(ServiceKind, String)> {
+    pub fn connection_info(&self) -> Vec<(ServiceKind, ConnectionInfoItem)> {
         let mut infos = vec![];
 
         if let Some(postgres) = &self.postgres {
@@ -192,6 +492,15 @@ impl Services {
             );
         }
 
+        for service in &self.custom {
+            infos.extend(
+                service
+                    .connection_info()
+                    .into_iter()
+                    .map(|s| (service.kind().clone(), s)),
+            );
+        }
+
         if let Some(app) = &self.app {
             infos.extend(
                 app.connection_info()
@@ -203,3 +512,53 @@ impl Services {
         infos
     }
 }
+
+/// The commit set by `set_git_sha_env`, unset outside of git
+fn current_git_sha() -> Option<String> {
+    std::env::var(constants::GIT_SHA_ENV).ok()
+}
+
+/// The commit of the app under the name from `expose_git_sha_to_env`.
+/// Outside of git the variable is left out
+fn git_sha_env_var(context: &Context, git_sha: Option<&str>) -> Option<(String, String)> {
+    let name = context
+        .app_config()
+        .expose_git_sha_to_env(context.override_context())?;
+
+    Some((name.to_owned(), git_sha?.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+    use crate::{cli::Args, config};
+
+    #[test]
+    fn test_app_env_contains_git_sha() {
+        let args = Args::parse_from(["dploy", "run"]);
+        let override_context = config::OverrideContext {
+            namespace: args.namespace().to_owned(),
+            command: args.command().into(),
+        };
+        let app_config =
+            config::AppConfig::parse("name = \"app\"\nexpose_git_sha_to_env = \"GIT_SHA\"\n")
+                .unwrap();
+        let context = Context::new(args, app_config, override_context);
+
+        let services = Services::from_context_with_git_sha(&context, Some("0123abcd")).unwrap();
+        let container_config = services
+            .app()
+            .unwrap()
+            .to_container_config(&context)
+            .unwrap();
+
+        assert!(container_config
+            .config()
+            .env
+            .iter()
+            .flatten()
+            .any(|env| env == "GIT_SHA=0123abcd"));
+    }
+}
@@ -0,0 +1,948 @@
+use std::{collections::HashMap, net::IpAddr, path::Path, time::Duration};
+
+use anyhow::{bail, Context as _, Result};
+use bollard::{container, models};
+
+use crate::{
+    config::{ContainerOptions, DurationConfig, GpusConfig, HealthcheckConfig},
+    constants,
+    context::Context,
+    network::DPLOY_NETWORK,
+    presentation,
+    utils::{network::Cidr, string::interpolate_env},
+};
+
+use super::ServiceKind;
+
+/// Labels set by dploy on every container it manages
+pub const LABEL_SERVICE: &str = "dploy.service";
+pub const LABEL_APP: &str = "dploy.app";
+pub const LABEL_NAMESPACE: &str = "dploy.namespace";
+pub const LABEL_GIT_SHA: &str = "dploy.git_sha";
+
+/// IPC namespace parameters, the only `kernel.*` sysctls docker allows
+const NAMESPACED_KERNEL_SYSCTLS: &[&str] = &[
+    "kernel.msgmax",
+    "kernel.msgmnb",
+    "kernel.msgmni",
+    "kernel.sem",
+    "kernel.shmall",
+    "kernel.shmmax",
+    "kernel.shmmni",
+    "kernel.shm_rmid_forced",
+];
+
+const PID_MODES: &[&str] = &["host"];
+const IPC_MODES: &[&str] = &["none", "private", "shareable", "host"];
+
+/// Capabilities known to Docker, without the `CAP_` prefix
+const KNOWN_CAPABILITIES: &[&str] = &[
+    "ALL",
+    "AUDIT_CONTROL",
+    "AUDIT_READ",
+    "AUDIT_WRITE",
+    "BLOCK_SUSPEND",
+    "BPF",
+    "CHECKPOINT_RESTORE",
+    "CHOWN",
+    "DAC_OVERRIDE",
+    "DAC_READ_SEARCH",
+    "FOWNER",
+    "FSETID",
+    "IPC_LOCK",
+    "IPC_OWNER",
+    "KILL",
+    "LEASE",
+    "LINUX_IMMUTABLE",
+    "MAC_ADMIN",
+    "MAC_OVERRIDE",
+    "MKNOD",
+    "NET_ADMIN",
+    "NET_BIND_SERVICE",
+    "NET_BROADCAST",
+    "NET_RAW",
+    "PERFMON",
+    "SETFCAP",
+    "SETGID",
+    "SETPCAP",
+    "SETUID",
+    "SYSLOG",
+    "SYS_ADMIN",
+    "SYS_BOOT",
+    "SYS_CHROOT",
+    "SYS_MODULE",
+    "SYS_NICE",
+    "SYS_PACCT",
+    "SYS_PTRACE",
+    "SYS_RAWIO",
+    "SYS_RESOURCE",
+    "SYS_TIME",
+    "SYS_TTY_CONFIG",
+    "WAKE_ALARM",
+];
+
+/// Limit names supported by docker, see `ulimit -a`
+const KNOWN_ULIMITS: &[&str] = &[
+    "core",
+    "cpu",
+    "data",
+    "fsize",
+    "locks",
+    "memlock",
+    "msgqueue",
+    "nice",
+    "nofile",
+    "nproc",
+    "rss",
+    "rtprio",
+    "rttime",
+    "sigpending",
+    "stack",
+];
+
+/// Signals docker accepts by name, without the `SIG` prefix
+const KNOWN_SIGNALS: &[&str] = &[
+    "ABRT", "ALRM", "BUS", "CHLD", "CONT", "FPE", "HUP", "ILL", "INT", "IO", "KILL", "PIPE",
+    "PROF", "PWR", "QUIT", "SEGV", "STKFLT", "STOP", "SYS", "TERM", "TRAP", "TSTP", "TTIN", "TTOU",
+    "URG", "USR1", "USR2", "VTALRM", "WINCH", "XCPU", "XFSZ",
+];
+
+const NO_NEW_PRIVILEGES_SECURITY_OPT: &str = "no-new-privileges:true";
+
+/// Applies user-defined container options on top of the config built by the service.
+/// Options which are valid but risky are reported by [`warn_about_options`]
+pub fn apply_container_options(
+    context: &Context,
+    service_kind: &ServiceKind,
+    options: &ContainerOptions,
+    config: &mut container::Config<String>,
+) -> Result<()> {
+    config.labels = Some(container_labels(context, service_kind, options)?);
+
+    if let Some(signal) = &options.stop_signal {
+        if !is_known_signal(signal) {
+            bail!("Invalid stop signal `{signal}`. Expected a name like SIGINT or a number");
+        }
+
+        config.stop_signal = Some(signal.clone());
+    }
+
+    if let Some(stop_timeout) = options.stop_timeout()? {
+        config.stop_timeout = Some(stop_timeout.as_secs() as i64);
+    }
+
+    if let Some(healthcheck) = &options.healthcheck {
+        config.healthcheck = Some(to_health_config(healthcheck)?);
+    }
+
+    if let Some(readiness) = &options.readiness {
+        if readiness.exec.is_empty() {
+            bail!("readiness.exec must contain a command, e.g. `[\"pg_isready\"]`");
+        }
+
+        if let Some(timeout) = &readiness.timeout {
+            timeout.to_duration()?;
+        }
+    }
+
+    if let Some(platform) = &options.platform {
+        if !is_valid_platform(platform) {
+            bail!("Invalid platform `{platform}`. Expected `os/arch`, e.g. linux/amd64");
+        }
+    }
+
+    if let Some(ip) = &options.ip {
+        let address = static_ip_address(context, ip)?;
+
+        dploy_endpoint(config).ipam_config = Some(match address {
+            IpAddr::V4(address) => models::EndpointIpamConfig {
+                ipv4_address: Some(address.to_string()),
+                ..Default::default()
+            },
+            IpAddr::V6(address) => models::EndpointIpamConfig {
+                ipv6_address: Some(address.to_string()),
+                ..Default::default()
+            },
+        });
+    }
+
+    if let Some(mac_address) = &options.mac_address {
+        validate_mac_address(mac_address)?;
+        dploy_endpoint(config).mac_address = Some(mac_address.to_lowercase());
+    }
+
+    let host_config = config.host_config.get_or_insert_with(Default::default);
+
+    if let Some(shm_size) = &options.shm_size {
+        host_config.shm_size = Some(parse_size(shm_size)?);
+    }
+
+    if let Some(cpuset_cpus) = &options.cpuset_cpus {
+        validate_cpuset("cpuset_cpus", cpuset_cpus)?;
+        host_config.cpuset_cpus = Some(cpuset_cpus.clone());
+    }
+
+    if let Some(cpuset_mems) = &options.cpuset_mems {
+        validate_cpuset("cpuset_mems", cpuset_mems)?;
+        host_config.cpuset_mems = Some(cpuset_mems.clone());
+    }
+
+    if let Some(oom_score_adj) = options.oom_score_adj {
+        if !(-1000..=1000).contains(&oom_score_adj) {
+            bail!("Invalid oom_score_adj {oom_score_adj}. Expected a value from -1000 to 1000");
+        }
+
+        host_config.oom_score_adj = Some(oom_score_adj);
+    }
+
+    host_config.oom_kill_disable = options.oom_kill_disable;
+
+    if let Some(gpus) = &options.gpus {
+        host_config.device_requests = Some(vec![to_device_request(gpus)?]);
+    }
+
+    if let Some(sysctls) = &options.sysctls {
+        host_config.sysctls = Some(sysctls.clone().into_iter().collect());
+    }
+
+    host_config.cap_add = options.cap_add.clone();
+    host_config.cap_drop = options.cap_drop.clone();
+
+    let mut security_opt = options.security_opt.clone().unwrap_or_default();
+
+    if options.no_new_privileges == Some(true)
+        && !security_opt
+            .iter()
+            .any(|opt| opt.starts_with("no-new-privileges"))
+    {
+        security_opt.push(NO_NEW_PRIVILEGES_SECURITY_OPT.to_owned());
+    }
+
+    if !security_opt.is_empty() {
+        host_config.security_opt = Some(security_opt);
+    }
+
+    if let Some(pid_mode) = &options.pid_mode {
+        host_config.pid_mode = Some(resolve_namespace_mode(
+            context, "pid_mode", pid_mode, PID_MODES,
+        )?);
+    }
+
+    if let Some(ipc_mode) = &options.ipc_mode {
+        host_config.ipc_mode = Some(resolve_namespace_mode(
+            context, "ipc_mode", ipc_mode, IPC_MODES,
+        )?);
+    }
+
+    host_config.privileged = options.privileged;
+    host_config.readonly_rootfs = options.read_only;
+    host_config.init = options.init;
+
+    for socket in options.sockets.iter().flatten() {
+        let mount = socket_mount(context, socket)?;
+        host_config.mounts.get_or_insert_with(Vec::new).push(mount);
+    }
+
+    for device in options.devices.iter().flatten() {
+        let mapping = device_mapping(context, device)?;
+        host_config
+            .devices
+            .get_or_insert_with(Vec::new)
+            .push(mapping);
+    }
+
+    for file in options.files.iter().flatten() {
+        if !file.target.starts_with('/') {
+            bail!(
+                "Invalid file target `{}`. The path must be absolute",
+                file.target
+            );
+        }
+
+        host_config
+            .mounts
+            .get_or_insert_with(Vec::new)
+            .push(models::Mount {
+                source: Some(
+                    context
+                        .files_dir()
+                        .join(context.file_name_of(service_kind, &file.target))
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+                target: Some(file.target.clone()),
+                typ: Some(models::MountTypeEnum::BIND),
+                read_only: Some(true),
+                ..Default::default()
+            });
+    }
+
+    for tmpfs in options.tmpfs.iter().flatten() {
+        let (path, tmpfs_options) = parse_tmpfs(tmpfs)?;
+        host_config
+            .tmpfs
+            .get_or_insert_with(Default::default)
+            .insert(path.to_owned(), tmpfs_options.to_owned());
+    }
+
+    for server in options.dns.iter().flatten() {
+        if server.parse::<IpAddr>().is_err() {
+            bail!("Invalid DNS server `{server}`. Expected an IP address");
+        }
+    }
+
+    if options.dns.is_some() {
+        host_config.dns = options.dns.clone();
+    }
+
+    if options.dns_search.is_some() {
+        host_config.dns_search = options.dns_search.clone();
+    }
+
+    for (name, ulimit) in options.ulimits.iter().flatten() {
+        if !KNOWN_ULIMITS.contains(&name.as_str()) {
+            bail!(
+                "Unknown ulimit `{name}`. Expected one of: {}",
+                KNOWN_ULIMITS.join(", ")
+            );
+        }
+
+        if ulimit.soft > ulimit.hard {
+            bail!(
+                "Invalid ulimit `{name}`. Soft limit {} is greater than hard limit {}",
+                ulimit.soft,
+                ulimit.hard
+            );
+        }
+
+        host_config
+            .ulimits
+            .get_or_insert_with(Vec::new)
+            .push(models::ResourcesUlimits {
+                name: Some(name.clone()),
+                soft: Some(ulimit.soft),
+                hard: Some(ulimit.hard),
+            });
+    }
+
+    Ok(())
+}
+
+fn dploy_endpoint(config: &mut container::Config<String>) -> &mut models::EndpointSettings {
+    config
+        .networking_config
+        .get_or_insert_with(|| container::NetworkingConfig {
+            endpoints_config: HashMap::new(),
+        })
+        .endpoints_config
+        .entry(DPLOY_NETWORK.to_owned())
+        .or_default()
+}
+
+/// A list of numbers and ranges, e.g. `0-3,6`, like docker's `--cpuset-cpus`
+fn validate_cpuset(name: &str, cpuset: &str) -> Result<()> {
+    // parse also accepts a sign
+    let parse = |number: &str| {
+        number
+            .chars()
+            .all(|char| char.is_ascii_digit())
+            .then(|| number.parse::<u32>().ok())
+            .flatten()
+    };
+
+    let is_valid = cpuset.split(',').all(|part| {
+        let (start, end) = part.split_once('-').unwrap_or((part, part));
+
+        match (parse(start), parse(end)) {
+            (Some(start), Some(end)) => start <= end,
+            _ => false,
+        }
+    });
+
+    if !is_valid {
+        bail!("Invalid {name} `{cpuset}`. Expected numbers and ranges, e.g. 0-3 or 0,2");
+    }
+
+    Ok(())
+}
+
+/// Six hex pairs separated by `:`. Multicast addresses can't be assigned to an interface
+fn validate_mac_address(mac_address: &str) -> Result<()> {
+    let octets = mac_address
+        .split(':')
+        .map(|octet| {
+            // from_str_radix also accepts a sign
+            (octet.len() == 2 && octet.chars().all(|char| char.is_ascii_hexdigit()))
+                .then(|| u8::from_str_radix(octet, 16).ok())
+                .flatten()
+        })
+        .collect::<Option<Vec<_>>>();
+
+    let Some(octets) = octets.filter(|octets| octets.len() == 6) else {
+        bail!(
+            "Invalid mac_address `{mac_address}`. Expected six hex pairs, e.g. 02:42:ac:11:00:02"
+        );
+    };
+
+    if octets[0] & 1 == 1 {
+        bail!("Invalid mac_address `{mac_address}`. It is a multicast address");
+    }
+
+    Ok(())
+}
+
+/// Docker only assigns static addresses on networks with a user-defined subnet
+fn static_ip_address(context: &Context, ip: &str) -> Result<IpAddr> {
+    let network = context.app_config().network(context.override_context());
+
+    check_static_ip(
+        ip,
+        network.and_then(|network| network.subnet()),
+        network.and_then(|network| network.gateway()),
+    )
+}
+
+fn check_static_ip(ip: &str, subnet: Option<&str>, gateway: Option<&str>) -> Result<IpAddr> {
+    let address = ip
+        .parse::<IpAddr>()
+        .with_context(|| format!("Invalid ip `{ip}`. Expected an IP address"))?;
+
+    let Some(subnet) = subnet else {
+        bail!("A static ip requires a [network] subnet");
+    };
+
+    let subnet = Cidr::parse(subnet).context("Invalid [network] subnet")?;
+
+    if subnet.is_ipv4() != address.is_ipv4() {
+        bail!("The ip {ip} is not of the same address family as the [network] subnet {subnet}");
+    }
+
+    if !subnet.contains(&address) {
+        bail!("The ip {ip} is outside of the [network] subnet {subnet}");
+    }
+
+    if subnet.is_network_or_broadcast(&address) {
+        bail!("The ip {ip} is the network or broadcast address of the [network] subnet {subnet}");
+    }
+
+    if gateway == Some(ip) {
+        bail!("The ip {ip} is the gateway of the dploy network");
+    }
+
+    Ok(address)
+}
+
+/// Splits `path:options` into the container path and the mount options,
+/// which are passed to docker as is (e.g. `size=64m,mode=1777`).
+/// The values of `size` and `mode` are checked, the other options are left to docker
+fn parse_tmpfs(tmpfs: &str) -> Result<(&str, &str)> {
+    let (path, options) = tmpfs.split_once(':').unwrap_or((tmpfs, ""));
+
+    if !path.starts_with('/') {
+        bail!("Invalid tmpfs mount `{tmpfs}`. The path must be absolute");
+    }
+
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        match option.split_once('=') {
+            Some((_, "")) => {
+                bail!("Invalid tmpfs mount `{tmpfs}`. Option `{option}` has no value")
+            }
+            // a percentage of the host's memory, like mount's size option
+            Some(("size", size)) if size.strip_suffix('%').is_some_and(is_positive_number) => {}
+            Some(("size", size)) => {
+                parse_size(size).with_context(|| format!("Invalid tmpfs mount `{tmpfs}`"))?;
+            }
+            Some(("mode", mode))
+                if mode.len() > 4 || !mode.chars().all(|char| ('0'..='7').contains(&char)) =>
+            {
+                bail!(
+                    "Invalid tmpfs mount `{tmpfs}`. Mode `{mode}` is not an octal mode, e.g. 1777"
+                )
+            }
+            _ => {}
+        }
+    }
+
+    Ok((path, options))
+}
+
+fn is_positive_number(value: &str) -> bool {
+    value.chars().all(|char| char.is_ascii_digit())
+        && value.parse::<u32>().is_ok_and(|number| number > 0)
+}
+
+fn socket_mount(context: &Context, socket: &str) -> Result<models::Mount> {
+    let Some((host_path, container_path)) = socket.split_once(':') else {
+        bail!("Invalid socket mapping `{socket}`. Expected `host_path:container_path`");
+    };
+
+    // sockets of remote hosts can't be checked from here
+    if context.ssh_credentials().is_none() {
+        check_socket_exists(Path::new(host_path))?;
+    }
+
+    Ok(models::Mount {
+        source: Some(host_path.to_owned()),
+        target: Some(container_path.to_owned()),
+        typ: Some(models::MountTypeEnum::BIND),
+        ..Default::default()
+    })
+}
+
+fn device_mapping(context: &Context, device: &str) -> Result<models::DeviceMapping> {
+    let mapping = parse_device(device)?;
+    let host_path = mapping.path_on_host.as_deref().unwrap_or_default();
+
+    // devices of remote hosts can't be checked from here
+    if context.ssh_credentials().is_none() && !Path::new(host_path).exists() {
+        bail!("Device {host_path} does not exist on the host");
+    }
+
+    Ok(mapping)
+}
+
+/// Parses `host_path[:container_path[:permissions]]`, like `docker run --device`.
+/// The container path defaults to the host one and permissions to `rwm`
+fn parse_device(device: &str) -> Result<models::DeviceMapping> {
+    let mut parts = device.split(':');
+    let host_path = parts.next().unwrap_or_default();
+    let container_path = parts.next().unwrap_or(host_path);
+    let permissions = parts.next().unwrap_or("rwm");
+
+    if parts.next().is_some() || !host_path.starts_with('/') || !container_path.starts_with('/') {
+        bail!(
+            "Invalid device mapping `{device}`. \
+             Expected `host_path[:container_path[:permissions]]` with absolute paths"
+        );
+    }
+
+    if permissions.is_empty()
+        || !permissions
+            .chars()
+            .all(|char| matches!(char, 'r' | 'w' | 'm'))
+    {
+        bail!("Invalid device permissions `{permissions}`. Expected a combination of r, w and m");
+    }
+
+    Ok(models::DeviceMapping {
+        path_on_host: Some(host_path.to_owned()),
+        path_in_container: Some(container_path.to_owned()),
+        cgroup_permissions: Some(permissions.to_owned()),
+    })
+}
+
+#[cfg(unix)]
+fn check_socket_exists(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let Ok(metadata) = path.metadata() else {
+        bail!("Socket {} does not exist on the host", path.display());
+    };
+
+    if !metadata.file_type().is_socket() {
+        bail!("{} is not a unix socket", path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_socket_exists(path: &Path) -> Result<()> {
+    if !path.exists() {
+        bail!("Socket {} does not exist on the host", path.display());
+    }
+
+    Ok(())
+}
+
+fn is_known_signal(signal: &str) -> bool {
+    if signal
+        .parse::<u8>()
+        .is_ok_and(|number| (1..=64).contains(&number))
+    {
+        return true;
+    }
+
+    let signal = signal.to_uppercase();
+    let signal = signal.strip_prefix("SIG").unwrap_or(&signal);
+
+    KNOWN_SIGNALS.contains(&signal)
+}
+
+fn to_health_config(healthcheck: &HealthcheckConfig) -> Result<models::HealthConfig> {
+    match healthcheck.test.first().map(String::as_str) {
+        Some("NONE") if healthcheck.test.len() == 1 => {}
+        Some("CMD" | "CMD-SHELL") if healthcheck.test.len() > 1 => {}
+        _ => bail!(
+            "Invalid healthcheck test {:?}. Expected [\"CMD\", ...], [\"CMD-SHELL\", \"command\"] or [\"NONE\"]",
+            healthcheck.test
+        ),
+    }
+
+    // docker treats 0 as "inherit from the image", so durations must be at least 1ms
+    let nanos = |name: &str, duration: &Option<DurationConfig>| -> Result<Option<i64>> {
+        let Some(duration) = duration else {
+            return Ok(None);
+        };
+
+        let duration = duration
+            .to_duration()
+            .with_context(|| format!("Invalid healthcheck {name}"))?;
+
+        if duration < Duration::from_millis(1) {
+            bail!("Healthcheck {name} must be at least 1ms");
+        }
+
+        Ok(Some(duration.as_nanos() as i64))
+    };
+
+    if healthcheck.retries.is_some_and(|retries| retries < 0) {
+        bail!("Healthcheck retries can't be negative");
+    }
+
+    Ok(models::HealthConfig {
+        test: Some(healthcheck.test.clone()),
+        interval: nanos("interval", &healthcheck.interval)?,
+        timeout: nanos("timeout", &healthcheck.timeout)?,
+        retries: healthcheck.retries,
+        start_period: nanos("start_period", &healthcheck.start_period)?,
+        ..Default::default()
+    })
+}
+
+/// Warns about options which docker accepts but which are likely mistakes or risky.
+/// Called once when the services are validated, `label` prefixes the warnings
+pub fn warn_about_options(label: &str, options: &ContainerOptions) {
+    if options.oom_kill_disable == Some(true) {
+        presentation::print_container_warning(
+            label,
+            "The OOM killer is disabled, the host may hang when it runs out of memory",
+        );
+    }
+
+    for name in options.sysctls.iter().flat_map(|sysctls| sysctls.keys()) {
+        if !is_namespaced_sysctl(name) {
+            presentation::print_container_warning(
+                label,
+                &format!("Sysctl {name} is not namespaced, docker will refuse to set it"),
+            );
+        }
+    }
+
+    for capability in options
+        .cap_add
+        .iter()
+        .chain(options.cap_drop.iter())
+        .flatten()
+    {
+        if !is_known_capability(capability) {
+            presentation::print_container_warning(
+                label,
+                &format!("Unknown capability {capability}"),
+            );
+        }
+    }
+
+    if options.privileged == Some(true) {
+        presentation::print_container_warning(
+            label,
+            "Running privileged, the container has full access to the host",
+        );
+
+        if options.read_only == Some(true) {
+            presentation::print_container_warning(
+                label,
+                "read_only has no effect on a privileged container, it can remount its filesystem",
+            );
+        }
+    }
+}
+
+/// User labels with `${ENV}` references replaced, followed by dploy's own labels,
+/// which take precedence
+fn container_labels(
+    context: &Context,
+    service_kind: &ServiceKind,
+    options: &ContainerOptions,
+) -> Result<HashMap<String, String>> {
+    let mut labels = HashMap::new();
+
+    for (key, value) in options.labels.iter().flatten() {
+        let value = interpolate_env(value)
+            .with_context(|| format!("Failed to interpolate the value of label `{key}`"))?;
+
+        labels.insert(key.clone(), value);
+    }
+
+    labels.insert(LABEL_SERVICE.to_owned(), service_kind.to_string());
+
+    // singletons are shared by all applications and namespaces
+    if service_kind.is_local() {
+        labels.insert(
+            LABEL_APP.to_owned(),
+            context
+                .app_config()
+                .name(context.override_context())
+                .to_owned(),
+        );
+        labels.insert(LABEL_NAMESPACE.to_owned(), context.namespace().to_owned());
+    }
+
+    // the commit the app image was built from
+    if *service_kind == ServiceKind::App {
+        if let Ok(git_sha) = std::env::var(constants::GIT_SHA_ENV) {
+            labels.insert(LABEL_GIT_SHA.to_owned(), git_sha);
+        }
+    }
+
+    Ok(labels)
+}
+
+/// Parses sizes like `512k`, `256m` or `1g` into bytes. Plain numbers are bytes
+pub fn parse_size(value: &str) -> Result<i64> {
+    let lowercase = value.trim().to_lowercase();
+    let size = lowercase.strip_suffix('b').unwrap_or(&lowercase);
+
+    let (number, multiplier) = match size.chars().last() {
+        Some('k') => (&size[..size.len() - 1], 1024),
+        Some('m') => (&size[..size.len() - 1], 1024 * 1024),
+        Some('g') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+
+    let Some(number) = number.parse::<i64>().ok().filter(|number| *number > 0) else {
+        bail!(
+            "Invalid size `{value}`. Expected a positive number with an optional k, m or g suffix"
+        );
+    };
+
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("Size `{value}` is too large"))
+}
+
+/// Same as `docker run --gpus`, using the NVIDIA runtime
+fn to_device_request(gpus: &GpusConfig) -> Result<models::DeviceRequest> {
+    let (count, device_ids) = match gpus {
+        GpusConfig::All(value) if value == "all" => (Some(-1), None),
+        GpusConfig::All(value) => {
+            bail!("Invalid gpus `{value}`. Expected \"all\", a count or a list of device ids")
+        }
+        GpusConfig::Count(count) if *count > 0 => (Some(*count), None),
+        GpusConfig::Count(count) => bail!("GPU count must be positive, got {count}"),
+        GpusConfig::DeviceIds(ids) if !ids.is_empty() => (None, Some(ids.clone())),
+        GpusConfig::DeviceIds(_) => bail!("The list of GPU device ids can't be empty"),
+    };
+
+    Ok(models::DeviceRequest {
+        driver: Some("nvidia".to_owned()),
+        count,
+        device_ids,
+        capabilities: Some(vec![vec!["gpu".to_owned()]]),
+        ..Default::default()
+    })
+}
+
+/// `os/arch` with an optional variant, e.g. `linux/arm64/v8`
+fn is_valid_platform(platform: &str) -> bool {
+    let parts = platform.split('/').collect::<Vec<_>>();
+
+    (2..=3).contains(&parts.len()) && parts.iter().all(|part| !part.is_empty())
+}
+
+/// Checks a namespace mode and replaces the service in `container:<service>`
+/// with the name of its container
+fn resolve_namespace_mode(
+    context: &Context,
+    option: &str,
+    mode: &str,
+    modes: &[&str],
+) -> Result<String> {
+    if modes.contains(&mode) {
+        return Ok(mode.to_owned());
+    }
+
+    match mode.strip_prefix("container:") {
+        Some(service) if !service.is_empty() => {
+            let container_name = context.container_name_of(&ServiceKind::from_name(service));
+
+            Ok(format!("container:{container_name}"))
+        }
+        _ => bail!(
+            "Invalid {option} `{mode}`. Expected one of {} or `container:<service>`",
+            modes.join(", ")
+        ),
+    }
+}
+
+/// Only sysctls scoped to the container's namespaces can be set, see `docker run --sysctl`
+fn is_namespaced_sysctl(name: &str) -> bool {
+    NAMESPACED_KERNEL_SYSCTLS.contains(&name)
+        || name.starts_with("fs.mqueue.")
+        || name.starts_with("net.")
+}
+
+fn is_known_capability(capability: &str) -> bool {
+    let capability = capability.to_uppercase();
+    let capability = capability.strip_prefix("CAP_").unwrap_or(&capability);
+
+    KNOWN_CAPABILITIES.contains(&capability)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(512, parse_size("512").unwrap());
+        assert_eq!(512 * 1024, parse_size("512k").unwrap());
+        assert_eq!(256 * 1024 * 1024, parse_size("256MB").unwrap());
+        assert_eq!(1024 * 1024 * 1024, parse_size(" 1g ").unwrap());
+
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("-1m").is_err());
+        assert!(parse_size("1t").is_err());
+        assert!(parse_size("9223372036854775807k").is_err());
+        assert!(parse_size("9000000000g").is_err());
+    }
+
+    #[test]
+    fn test_parse_device() {
+        let mapping = |host: &str, container: &str, permissions: &str| models::DeviceMapping {
+            path_on_host: Some(host.to_owned()),
+            path_in_container: Some(container.to_owned()),
+            cgroup_permissions: Some(permissions.to_owned()),
+        };
+
+        assert_eq!(
+            parse_device("/dev/fuse").unwrap(),
+            mapping("/dev/fuse", "/dev/fuse", "rwm")
+        );
+        assert_eq!(
+            parse_device("/dev/ttyUSB0:/dev/ttyACM0").unwrap(),
+            mapping("/dev/ttyUSB0", "/dev/ttyACM0", "rwm")
+        );
+        assert_eq!(
+            parse_device("/dev/sda:/dev/xvda:r").unwrap(),
+            mapping("/dev/sda", "/dev/xvda", "r")
+        );
+
+        for device in [
+            "",
+            "dev/fuse",
+            "/dev/fuse:dev/fuse",
+            "/dev/fuse:/dev/fuse:",
+            "/dev/fuse:/dev/fuse:rx",
+            "/dev/fuse:/dev/fuse:RW",
+            "/dev/fuse:/dev/fuse:rwm:extra",
+        ] {
+            assert!(parse_device(device).is_err(), "{device}");
+        }
+    }
+
+    #[test]
+    fn test_parse_tmpfs() {
+        assert_eq!(parse_tmpfs("/tmp").unwrap(), ("/tmp", ""));
+        assert_eq!(
+            parse_tmpfs("/run:size=64m,mode=1777").unwrap(),
+            ("/run", "size=64m,mode=1777")
+        );
+        assert_eq!(
+            parse_tmpfs("/run:size=50%,noexec").unwrap(),
+            ("/run", "size=50%,noexec")
+        );
+        assert_eq!(parse_tmpfs("/run:mode=700").unwrap(), ("/run", "mode=700"));
+
+        for tmpfs in [
+            "tmp",
+            "tmp:size=64m",
+            "/run:size=",
+            "/run:mode=",
+            "/run:size=64x",
+            "/run:size=0",
+            "/run:size=-1m",
+            "/run:size=%",
+            "/run:size=0%",
+            "/run:mode=800",
+            "/run:mode=u+rwx",
+            "/run:mode=17777",
+        ] {
+            assert!(parse_tmpfs(tmpfs).is_err(), "{tmpfs}");
+        }
+    }
+
+    #[test]
+    fn test_validate_cpuset() {
+        for cpuset in ["0", "0-3", "0,2", "0-3,5", "1-1", "0-3,6-7,9"] {
+            assert!(validate_cpuset("cpuset_cpus", cpuset).is_ok(), "{cpuset}");
+        }
+
+        for cpuset in [
+            "", ",", "0,", ",0", "0,,1", "3-0", "0-", "-3", "0-1-2", "0--3", "a", "0-x", " 0",
+            "+1", "0-+3",
+        ] {
+            assert!(validate_cpuset("cpuset_cpus", cpuset).is_err(), "{cpuset}");
+        }
+    }
+
+    #[test]
+    fn test_validate_mac_address() {
+        assert!(validate_mac_address("02:42:ac:11:00:02").is_ok());
+        assert!(validate_mac_address("02:42:AC:11:00:02").is_ok());
+
+        for mac_address in [
+            "",
+            "02:42:ac:11:00",
+            "02:42:ac:11:00:02:03",
+            "02:42:ac:11:00:",
+            "02-42-ac-11-00-02",
+            "2:42:ac:11:00:02",
+            "002:42:ac:11:00:02",
+            "02:42:ag:11:00:02",
+            "+2:42:ac:11:00:02",
+            // multicast
+            "01:00:5e:00:00:01",
+            "ff:ff:ff:ff:ff:ff",
+        ] {
+            assert!(validate_mac_address(mac_address).is_err(), "{mac_address}");
+        }
+    }
+
+    #[test]
+    fn test_check_static_ip() {
+        let subnet = Some("172.30.0.0/16");
+        let gateway = Some("172.30.0.1");
+
+        assert_eq!(
+            check_static_ip("172.30.0.10", subnet, gateway).unwrap(),
+            "172.30.0.10".parse::<IpAddr>().unwrap()
+        );
+        assert!(check_static_ip("fd00::10", Some("fd00::/64"), None).is_ok());
+
+        for (ip, subnet) in [
+            // not an address
+            ("172.30.0", subnet),
+            ("172.30.0.10/32", subnet),
+            // no subnet to assign it from
+            ("172.30.0.10", None),
+            // another address family
+            ("fd00::10", subnet),
+            ("172.30.0.10", Some("fd00::/64")),
+            // outside of the subnet
+            ("172.31.0.10", subnet),
+            ("10.0.0.1", subnet),
+            // network and broadcast address
+            ("172.30.0.0", subnet),
+            ("172.30.255.255", subnet),
+            ("fd00::", Some("fd00::/64")),
+            // the gateway
+            ("172.30.0.1", subnet),
+        ] {
+            assert!(
+                check_static_ip(ip, subnet, gateway).is_err(),
+                "{ip} in {subnet:?}"
+            );
+        }
+    }
+}
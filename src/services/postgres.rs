@@ -1,51 +1,101 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fs, path::PathBuf, time::Duration};
 
-use anyhow::Result;
+use anyhow::{bail, Context as _, Result};
 use bollard::{container, models};
 
 use crate::{
+    config::ContainerOptions,
     context::{Context, HostPortBinding},
+    docker,
     network::DPLOY_NETWORK,
+    presentation,
 };
 
-use super::{ConnectionInfo, ContainerConfig, EnvVars, ServiceKind, ToContainerConfig};
+use super::{
+    options, prefixed_env_name, ConnectionInfo, ConnectionInfoItem, ContainerConfig, EnvVars,
+    ServiceKind, ToContainerConfig,
+};
 
 const DEFAULT_PORT: u16 = 5432;
 const DEFAULT_USER: &str = "admin";
 const DEFAULT_PASSWORD: &str = "admin";
 
 const IMAGE_NAME: &str = "postgres";
+
+/// Docker's 64m is too small for parallel queries, which allocate shared memory in `/dev/shm`
+const DEFAULT_SHM_SIZE: &str = "256m";
 const DATA_PATH: &str = "/var/lib/postgresql/data";
 
+/// Lives inside the data volume so that it disappears together with the data
+const INIT_MARKER_PATH: &str = "/var/lib/postgresql/data/.dploy_initialized";
+const INIT_SCRIPTS_INNER_DIR: &str = "/tmp/dploy-init";
+
+const READINESS_TIMEOUT: Duration = Duration::from_secs(60);
+const READINESS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 const SERVICE_KIND: ServiceKind = ServiceKind::Postgres;
 
 pub struct PostgresService {
+    name: String,
+
     expose_url_to_env: Option<String>,
+    env_prefix: Option<String>,
 
     database_name: String,
     database_user: String,
     database_password: String,
 
     binding: HostPortBinding,
+
+    init_scripts: Vec<PathBuf>,
+
+    image: String,
+    digest: Option<String>,
+
+    container_options: ContainerOptions,
 }
 
 impl PostgresService {
-    pub fn from_context(context: &Context) -> Option<Self> {
-        context
-            .app_config()
-            .postgres(context.override_context())
-            .map(|config| Self {
-                expose_url_to_env: config.expose_url_to_env().map(ToOwned::to_owned),
-
-                database_name: config
-                    .database_name()
-                    .unwrap_or(context.app_config().name(context.override_context()))
-                    .to_owned(),
-                database_user: DEFAULT_USER.to_owned(),
-                database_password: DEFAULT_PASSWORD.to_owned(),
-
-                binding: context.host_port_binding_of(SERVICE_KIND, DEFAULT_PORT),
-            })
+    pub fn from_context(context: &Context) -> Result<Option<Self>> {
+        let Some(config) = context.app_config().postgres(context.override_context()) else {
+            return Ok(None);
+        };
+
+        let (image, digest) = docker::pinned_image(IMAGE_NAME, config.digest())?;
+
+        Ok(Some(Self {
+            name: context.container_name_of(&SERVICE_KIND),
+
+            expose_url_to_env: config.expose_url_to_env().map(ToOwned::to_owned),
+            env_prefix: config.env_prefix().map(ToOwned::to_owned),
+
+            database_name: config
+                .database_name()
+                .unwrap_or(context.app_config().name(context.override_context()))
+                .to_owned(),
+            database_user: DEFAULT_USER.to_owned(),
+            database_password: DEFAULT_PASSWORD.to_owned(),
+
+            binding: context.host_port_binding_of(
+                &SERVICE_KIND,
+                DEFAULT_PORT,
+                config.connection_mode(),
+            )?,
+
+            init_scripts: config.init_scripts().iter().map(PathBuf::from).collect(),
+
+            image,
+            digest,
+
+            container_options: ContainerOptions {
+                shm_size: Some(DEFAULT_SHM_SIZE.to_owned()),
+                ..context
+                    .app_config()
+                    .container_options(context.override_context())
+                    .inherited()
+            }
+            .merge(config.container_options()),
+        }))
     }
 
     pub fn construct_url(&self, host: &str, port: u16) -> String {
@@ -55,6 +105,18 @@ impl PostgresService {
         )
     }
 
+    pub fn binding(&self) -> &HostPortBinding {
+        &self.binding
+    }
+
+    pub fn container_options(&self) -> &ContainerOptions {
+        &self.container_options
+    }
+
+    pub fn env_prefix(&self) -> Option<&str> {
+        self.env_prefix.as_deref()
+    }
+
     pub fn inner_url(&self) -> String {
         let inner_port = self.binding.inner_port();
         let inner_host = self.binding.inner_host();
@@ -68,6 +130,207 @@ impl PostgresService {
 
         host_port.map(|port| self.construct_url(host_host, port))
     }
+
+    /// Runs the init scripts once for a freshly created database.
+    /// Databases which already contain tables are only marked as initialized
+    pub async fn post_up(&self, docker: &bollard::Docker) -> Result<()> {
+        if self.init_scripts.is_empty() {
+            return Ok(());
+        }
+
+        let is_running = docker::check_container_running(docker, &self.name).await?;
+        if !is_running {
+            return Ok(());
+        }
+
+        self.wait_until_ready(docker).await?;
+
+        let (exit_code, _) = self.exec(docker, &["test", "-f", INIT_MARKER_PATH]).await?;
+        if exit_code == 0 {
+            return Ok(());
+        }
+
+        if self.has_user_tables(docker).await? {
+            presentation::print_dependency_init_skipped(&self.name);
+        } else {
+            presentation::print_dependency_initializing(&self.name);
+
+            self.upload_init_scripts(docker).await?;
+            self.run_init_scripts(docker).await?;
+        }
+
+        let (exit_code, output) = self.exec(docker, &["touch", INIT_MARKER_PATH]).await?;
+        if exit_code != 0 {
+            bail!(
+                "Failed to mark the database as initialized: {}",
+                output.trim()
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn wait_until_ready(&self, docker: &bollard::Docker) -> Result<()> {
+        let started_at = std::time::Instant::now();
+
+        // connect over tcp, the entrypoint's temporary init server only listens on the socket
+        let command = [
+            "pg_isready",
+            "-h",
+            "127.0.0.1",
+            "-U",
+            self.database_user.as_str(),
+            "-d",
+            self.database_name.as_str(),
+        ];
+
+        while started_at.elapsed() < READINESS_TIMEOUT {
+            let (exit_code, _) = self.exec(docker, &command).await?;
+            if exit_code == 0 {
+                return Ok(());
+            }
+
+            tokio::time::sleep(READINESS_POLL_INTERVAL).await;
+        }
+
+        bail!(
+            "Database {} did not become ready in {} seconds",
+            self.name,
+            READINESS_TIMEOUT.as_secs()
+        );
+    }
+
+    async fn has_user_tables(&self, docker: &bollard::Docker) -> Result<bool> {
+        let (exit_code, output) = self
+            .exec(
+                docker,
+                &[
+                    "psql",
+                    "-tA",
+                    "-U",
+                    self.database_user.as_str(),
+                    "-d",
+                    self.database_name.as_str(),
+                    "-c",
+                    "select count(*) from information_schema.tables \
+                    where table_schema not in ('pg_catalog', 'information_schema')",
+                ],
+            )
+            .await?;
+
+        if exit_code != 0 {
+            bail!("Failed to inspect the database: {}", output.trim());
+        }
+
+        Ok(output.trim().parse::<u64>().unwrap_or_default() > 0)
+    }
+
+    async fn upload_init_scripts(&self, docker: &bollard::Docker) -> Result<()> {
+        let mut bytes = Vec::<u8>::new();
+        let mut archive = tar::Builder::new(&mut bytes);
+
+        for (index, script) in self.init_scripts.iter().enumerate() {
+            let contents = fs::read(script)
+                .with_context(|| format!("Init script {} does not exist", script.display()))?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+
+            archive.append_data(
+                &mut header,
+                init_script_file_name(index, script)?,
+                &*contents,
+            )?;
+        }
+
+        archive.into_inner()?;
+
+        let (exit_code, output) = self
+            .exec(docker, &["mkdir", "-p", INIT_SCRIPTS_INNER_DIR])
+            .await?;
+        if exit_code != 0 {
+            bail!("Failed to prepare init scripts: {}", output.trim());
+        }
+
+        docker::logged(
+            "upload_to_container",
+            docker
+                .upload_to_container(
+                    &self.name,
+                    Some(container::UploadToContainerOptions {
+                        path: INIT_SCRIPTS_INNER_DIR,
+                        ..Default::default()
+                    }),
+                    bytes.into(),
+                )
+                .await,
+        )?;
+
+        Ok(())
+    }
+
+    async fn run_init_scripts(&self, docker: &bollard::Docker) -> Result<()> {
+        for (index, script) in self.init_scripts.iter().enumerate() {
+            let path = format!(
+                "{INIT_SCRIPTS_INNER_DIR}/{}",
+                init_script_file_name(index, script)?
+            );
+
+            let command = if path.ends_with(".sh") {
+                vec!["sh", path.as_str()]
+            } else {
+                vec![
+                    "psql",
+                    "-v",
+                    "ON_ERROR_STOP=1",
+                    "-U",
+                    self.database_user.as_str(),
+                    "-d",
+                    self.database_name.as_str(),
+                    "-f",
+                    path.as_str(),
+                ]
+            };
+
+            let (exit_code, output) = self.exec(docker, &command).await?;
+            if exit_code != 0 {
+                bail!(
+                    "Init script {} failed:\n{}",
+                    script.display(),
+                    output.trim()
+                );
+            }
+        }
+
+        self.exec(docker, &["rm", "-rf", INIT_SCRIPTS_INNER_DIR])
+            .await?;
+
+        Ok(())
+    }
+
+    async fn exec(&self, docker: &bollard::Docker, command: &[&str]) -> Result<(i64, String)> {
+        docker::exec_command_captured(docker, &self.name, command).await
+    }
+}
+
+/// Prefixed with the index to keep the configured order and avoid name clashes
+fn init_script_file_name(index: usize, script: &std::path::Path) -> Result<String> {
+    let Some(file_name) = script.file_name() else {
+        bail!("Invalid init script path {}", script.display());
+    };
+
+    let file_name = file_name.to_string_lossy();
+
+    if !file_name.ends_with(".sql") && !file_name.ends_with(".sh") {
+        bail!(
+            "Init script {} must be a .sql or .sh file",
+            script.display()
+        );
+    }
+
+    Ok(format!("{index:02}_{file_name}"))
 }
 
 impl EnvVars for PostgresService {
@@ -75,7 +338,10 @@ impl EnvVars for PostgresService {
         let mut vars = Vec::new();
 
         if let Some(expose_url_to_env) = &self.expose_url_to_env {
-            vars.push((expose_url_to_env.clone(), self.inner_url()))
+            vars.push((
+                prefixed_env_name(self.env_prefix.as_deref(), expose_url_to_env),
+                self.inner_url(),
+            ))
         }
 
         vars
@@ -83,17 +349,29 @@ impl EnvVars for PostgresService {
 }
 
 impl ConnectionInfo for PostgresService {
-    fn connection_info(&self) -> Vec<String> {
-        vec![self.host_url()].into_iter().flatten().collect()
+    fn connection_info(&self) -> Vec<ConnectionInfoItem> {
+        let address = self
+            .binding
+            .host_port()
+            .map(|port| (self.binding.host_host().to_owned(), port));
+
+        self.host_url()
+            .map(|url| match &self.env_prefix {
+                Some(prefix) => format!("{url} ({prefix})"),
+                None => url,
+            })
+            .map(|url| ConnectionInfoItem::new(url, address))
+            .into_iter()
+            .collect()
     }
 }
 
 impl ToContainerConfig for PostgresService {
     fn to_container_config(&self, context: &Context) -> Result<ContainerConfig> {
-        let name = context.container_name_of(SERVICE_KIND);
+        let name = context.container_name_of(&SERVICE_KIND);
 
         let mut config = container::Config {
-            image: Some(IMAGE_NAME.to_owned()),
+            image: Some(self.image.clone()),
             hostname: Some(name.clone()),
             domainname: Some(name.clone()),
 
@@ -113,18 +391,25 @@ impl ToContainerConfig for PostgresService {
             ..Default::default()
         };
 
-        let mut host_config = models::HostConfig::default();
-
-        host_config.mounts = Some(vec![context.mount(SERVICE_KIND, DATA_PATH)]);
-        host_config.port_bindings = Some(self.binding.to_port_binding());
-
-        host_config.restart_policy = Some(models::RestartPolicy {
-            name: Some(models::RestartPolicyNameEnum::ALWAYS),
+        config.host_config = Some(models::HostConfig {
+            mounts: Some(vec![context.mount(&SERVICE_KIND, DATA_PATH)]),
+            port_bindings: Some(self.binding.to_port_binding()),
+            restart_policy: Some(models::RestartPolicy {
+                name: Some(models::RestartPolicyNameEnum::ALWAYS),
+                ..Default::default()
+            }),
             ..Default::default()
         });
 
-        config.host_config = Some(host_config);
+        options::apply_container_options(
+            context,
+            &SERVICE_KIND,
+            &self.container_options,
+            &mut config,
+        )?;
 
-        Ok(ContainerConfig::new(name, IMAGE_NAME.to_owned(), config))
+        Ok(ContainerConfig::new(name, self.image.clone(), config)
+            .with_platform(self.container_options.platform.clone())
+            .with_digest(self.digest.clone()))
     }
 }
@@ -1,8 +1,13 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 use itertools::Itertools;
 
-use crate::{context, docker, network, prelude::*, services};
+use crate::{
+    config::{self, ContainerOptions},
+    context, docker, network,
+    prelude::*,
+    services,
+};
 
 const IMAGE_NAME: &str = "caddy";
 
@@ -27,19 +32,76 @@ pub struct ProxyService {
     name: String,
     should_run: bool,
     app_service_container_name: String,
+    /// The app containers, several with `deploy --scale`
+    app_upstreams: Vec<String>,
     bindings: Vec<context::HostPortBinding>,
     configs: Vec<ProxyServiceConfig>,
+    container_options: ContainerOptions,
 }
 
 struct ProxyServiceConfig {
     domain: String,
     port: u16,
+    max_body_size: Option<i64>,
+    read_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    send_timeout: Option<Duration>,
+}
+
+/// Domain routed by the proxy to a container port
+#[derive(Debug, serde::Serialize)]
+pub struct ProxyRoute {
+    pub domain: String,
+    pub upstreams: Vec<String>,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_body_size_bytes: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_timeout_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub send_timeout_ms: Option<u128>,
+}
+
+impl ProxyServiceConfig {
+    fn from_config(config: &config::ProxyConfig) -> Result<Self> {
+        let timeout = |name: &str, value: &Option<config::DurationConfig>| {
+            value
+                .as_ref()
+                .map(|value| value.to_duration())
+                .transpose()
+                .with_context(|| format!("Invalid {name} of the proxy for {}", config.domain))
+        };
+
+        let max_body_size = config
+            .client_max_body_size
+            .as_deref()
+            .map(services::options::parse_size)
+            .transpose()
+            .with_context(|| {
+                format!(
+                    "Invalid client_max_body_size of the proxy for {}",
+                    config.domain
+                )
+            })?;
+
+        Ok(Self {
+            domain: config.domain.clone(),
+            port: config.port,
+            max_body_size,
+            read_timeout: timeout("proxy_read_timeout", &config.proxy_read_timeout)?,
+            connect_timeout: timeout("proxy_connect_timeout", &config.proxy_connect_timeout)?,
+            send_timeout: timeout("proxy_send_timeout", &config.proxy_send_timeout)?,
+        })
+    }
 }
 
 impl ProxyService {
-    pub fn from_context(context: &context::Context) -> Self {
-        let app_service_container_name = context.container_name_of(services::ServiceKind::App);
-        let name = context.container_name_of(SERVICE_KIND);
+    pub fn from_context(context: &context::Context) -> Result<Self> {
+        let app_service_container_name = context.container_name_of(&services::ServiceKind::App);
+        let app_upstreams = context.app_container_names();
+        let name = context.container_name_of(&SERVICE_KIND);
 
         let configs = context.app_config().proxy(context.override_context());
 
@@ -50,21 +112,25 @@ impl ProxyService {
 
         let configs = configs
             .iter()
-            .map(|config| ProxyServiceConfig {
-                domain: config.domain.clone(),
-                port: config.port,
-            })
-            .collect();
+            .map(ProxyServiceConfig::from_config)
+            .collect::<Result<_>>()?;
 
         let should_run = context.should_create_proxy_service();
 
-        Self {
+        let container_options = context
+            .app_config()
+            .container_options(context.override_context())
+            .inherited();
+
+        Ok(Self {
             name,
             app_service_container_name,
+            app_upstreams,
             bindings,
             configs,
             should_run,
-        }
+            container_options,
+        })
     }
 
     pub async fn post_up(&self, docker: &bollard::Docker) -> Result<()> {
@@ -111,6 +177,21 @@ impl ProxyService {
         &self.name
     }
 
+    pub fn routes(&self) -> Vec<ProxyRoute> {
+        self.configs
+            .iter()
+            .map(|config| ProxyRoute {
+                domain: config.domain.clone(),
+                upstreams: self.app_upstreams.clone(),
+                port: config.port,
+                max_body_size_bytes: config.max_body_size,
+                read_timeout_ms: config.read_timeout.map(|timeout| timeout.as_millis()),
+                connect_timeout_ms: config.connect_timeout.map(|timeout| timeout.as_millis()),
+                send_timeout_ms: config.send_timeout.map(|timeout| timeout.as_millis()),
+            })
+            .collect()
+    }
+
     async fn delete_configs(&self, docker: &bollard::Docker) -> Result<()> {
         let config_path = self.service_config_path();
         let command = format!("rm {config_path}");
@@ -119,8 +200,30 @@ impl ProxyService {
         Ok(())
     }
 
+    /// Points the proxy to other upstream containers,
+    /// e.g. to switch traffic to a new app container during a blue-green deploy
+    pub async fn route_to(&self, docker: &bollard::Docker, upstreams: &[&str]) -> Result<()> {
+        if self.configs.is_empty() {
+            return Ok(());
+        }
+
+        let is_running = docker::check_container_running(docker, &self.name).await?;
+        if !is_running {
+            return Ok(());
+        }
+
+        self.write_configs(docker, upstreams).await?;
+        self.reload_caddy(docker).await?;
+
+        Ok(())
+    }
+
     async fn put_configs(&self, docker: &bollard::Docker) -> Result<()> {
-        let config_contents = self.service_config_contents();
+        self.write_configs(docker, &self.app_upstream_names()).await
+    }
+
+    async fn write_configs(&self, docker: &bollard::Docker, upstreams: &[&str]) -> Result<()> {
+        let config_contents = self.upstream_config_contents(upstreams);
         let config_path = self.service_config_path();
         let command = format!(r#"echo "{config_contents}" > {config_path}"#,);
         docker::exec_command_detached(docker, &self.name, &command).await?;
@@ -136,16 +239,24 @@ impl ProxyService {
         Ok(())
     }
 
-    fn service_config_contents(&self) -> String {
+    pub fn service_config_contents(&self) -> String {
+        self.upstream_config_contents(&self.app_upstream_names())
+    }
+
+    fn app_upstream_names(&self) -> Vec<&str> {
+        self.app_upstreams.iter().map(String::as_str).collect()
+    }
+
+    fn upstream_config_contents(&self, upstreams: &[&str]) -> String {
         self.configs
             .iter()
-            .map(|config| self.https_config(config))
+            .map(|config| self.https_config(config, upstreams))
             .join("\n")
             .trim()
             .to_owned()
     }
 
-    fn service_config_path(&self) -> String {
+    pub fn service_config_path(&self) -> String {
         let Self {
             app_service_container_name,
             ..
@@ -157,16 +268,38 @@ impl ProxyService {
             .to_string()
     }
 
-    fn https_config(&self, config: &ProxyServiceConfig) -> String {
-        let ProxyServiceConfig { domain, port } = config;
-        let Self {
-            app_service_container_name,
-            ..
-        } = self;
+    /// Caddy balances the requests between several upstreams.
+    /// The proxy timeouts map to the timeouts of Caddy's http transport
+    fn https_config(&self, config: &ProxyServiceConfig, upstreams: &[&str]) -> String {
+        let ProxyServiceConfig { domain, port, .. } = config;
+        let upstreams = upstreams
+            .iter()
+            .map(|upstream| format!("{upstream}:{port}"))
+            .join(" ");
+
+        let request_body = config
+            .max_body_size
+            .map(|size| format!("request_body {{\nmax_size {size}\n}}\n"))
+            .unwrap_or_default();
+
+        let timeouts = [
+            ("dial_timeout", config.connect_timeout),
+            ("read_timeout", config.read_timeout),
+            ("write_timeout", config.send_timeout),
+        ]
+        .into_iter()
+        .filter_map(|(name, timeout)| {
+            timeout.map(|timeout| format!("{name} {}ms\n", timeout.as_millis()))
+        })
+        .join("");
+
+        let reverse_proxy = if timeouts.is_empty() {
+            format!("reverse_proxy {upstreams}")
+        } else {
+            format!("reverse_proxy {upstreams} {{\ntransport http {{\n{timeouts}}}\n}}")
+        };
 
-        format!(
-            "{domain}:443, {domain}:80 {{ \nreverse_proxy {app_service_container_name}:{port}\n }}",
-        )
+        format!("{domain}:443, {domain}:80 {{ \n{request_body}{reverse_proxy}\n }}",)
     }
 
     fn caddy_config_inner_file(&self) -> String {
@@ -175,17 +308,19 @@ impl ProxyService {
 }
 
 impl services::ConnectionInfo for ProxyService {
-    fn connection_info(&self) -> Vec<String> {
+    fn connection_info(&self) -> Vec<services::ConnectionInfoItem> {
         self.configs
             .iter()
-            .map(|ProxyServiceConfig { domain, port }| format!("{domain} -> {port}"))
+            .map(|ProxyServiceConfig { domain, port, .. }| {
+                services::ConnectionInfoItem::new(format!("{domain} -> {port}"), None)
+            })
             .collect()
     }
 }
 
 impl services::ToContainerConfig for ProxyService {
     fn to_container_config(&self, context: &context::Context) -> Result<services::ContainerConfig> {
-        let name = context.container_name_of(SERVICE_KIND);
+        let name = context.container_name_of(&SERVICE_KIND);
         let config_file = self.caddy_config_inner_file();
 
         let mut config = bollard::container::Config {
@@ -216,29 +351,33 @@ impl services::ToContainerConfig for ProxyService {
             ..Default::default()
         };
 
-        let mut host_config = bollard::models::HostConfig::default();
-
-        host_config.mounts = Some(
-            VOLUMES_MAPPINGS
-                .iter()
-                .map(|(host, inner)| context.manual_mount(host, inner))
-                .collect(),
-        );
-        host_config.port_bindings = Some(context::HostPortBinding::to_port_bindings(
-            &self.bindings.iter().collect::<Vec<_>>(),
-        ));
-
-        host_config.restart_policy = Some(bollard::models::RestartPolicy {
-            name: Some(bollard::models::RestartPolicyNameEnum::ALWAYS),
+        config.host_config = Some(bollard::models::HostConfig {
+            mounts: Some(
+                VOLUMES_MAPPINGS
+                    .iter()
+                    .map(|(host, inner)| context.manual_mount(host, inner))
+                    .collect(),
+            ),
+            port_bindings: Some(context::HostPortBinding::to_port_bindings(
+                &self.bindings.iter().collect::<Vec<_>>(),
+            )),
+            restart_policy: Some(bollard::models::RestartPolicy {
+                name: Some(bollard::models::RestartPolicyNameEnum::ALWAYS),
+                ..Default::default()
+            }),
             ..Default::default()
         });
 
-        config.host_config = Some(host_config);
+        services::options::apply_container_options(
+            context,
+            &SERVICE_KIND,
+            &self.container_options,
+            &mut config,
+        )?;
 
-        Ok(services::ContainerConfig::new(
-            name,
-            IMAGE_NAME.to_owned(),
-            config,
-        ))
+        Ok(
+            services::ContainerConfig::new(name, IMAGE_NAME.to_owned(), config)
+                .with_platform(self.container_options.platform.clone()),
+        )
     }
 }
@@ -1,18 +1,48 @@
-use std::{net::TcpListener, path::Path, time::Duration};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, IsTerminal},
+    net::{SocketAddr, TcpListener},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::{Command, Stdio},
+    sync::Mutex,
+    time::Duration,
+};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bollard::{Docker, API_DEFAULT_VERSION};
 use openssh::{ForwardType, KnownHosts, Session, SessionBuilder};
+use termion::input::TermRead;
 
-use crate::{context, presentation};
+use crate::{context, docker, presentation};
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
 
+const PASSPHRASE_ENV: &str = "DPLOY_SSH_PASSPHRASE";
+const AGENT_SOCKET_ENV: &str = "SSH_AUTH_SOCK";
+
+/// Keys added to ssh-agent by dploy are removed again after an hour
+const AGENT_KEY_LIFETIME_SECS: u32 = 3600;
+
+/// Prints the passphrase for ssh-add once. A second call means it was wrong,
+/// failing then stops ssh-add from asking again forever
+const ASKPASS_SCRIPT: &str = r#"#!/bin/sh
+[ -e "$0.used" ] && exit 1
+touch "$0.used"
+printf '%s\n' "$DPLOY_SSH_PASSPHRASE"
+"#;
+
+/// Local ends of the forwarded docker sockets by host, for requests bollard can't make
+static DOCKER_FORWARDS: Mutex<BTreeMap<String, SocketAddr>> = Mutex::new(BTreeMap::new());
+
 pub async fn get_remote_docker_client(context: &context::Context) -> Result<(Docker, Session)> {
     let Some(credentials) = context.ssh_credentials() else {
         bail!("No SSH credentials provided")
     };
 
+    prepare_agent(&credentials)?;
+
     let mut builder = SessionBuilder::default();
 
     builder
@@ -35,7 +65,7 @@ pub async fn get_remote_docker_client(context: &context::Context) -> Result<(Doc
     let temp_dir = builder
         .launch_master(credentials.host())
         .await
-        .context("Could not launch ssh")?;
+        .map_err(|error| connect_error(&credentials, error))?;
 
     let session = Session::new_process_mux(temp_dir);
     presentation::print_remote_host_success(credentials.host());
@@ -46,13 +76,201 @@ pub async fn get_remote_docker_client(context: &context::Context) -> Result<(Doc
         .await
         .context("Could not request port forward")?;
 
+    DOCKER_FORWARDS
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+        .insert(credentials.host().to_owned(), local_addr);
+
     let docker = Docker::connect_with_http(&local_addr.to_string(), 120, API_DEFAULT_VERSION)
         .context("Could not connect to docker")?;
 
-    docker
-        .version()
-        .await
-        .context("Could not get docker version")?;
+    docker::wait_for_docker(&docker, context.args().wait_for_docker()).await?;
 
     Ok((docker, session))
 }
+
+/// Address of the docker API of the host, forwarded by [`get_remote_docker_client`]
+pub fn forwarded_docker_address(host: &str) -> Option<SocketAddr> {
+    DOCKER_FORWARDS
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+        .get(host)
+        .copied()
+}
+
+/// ssh runs in batch mode and can't ask for a passphrase,
+/// so an encrypted key is added to ssh-agent beforehand
+fn prepare_agent(credentials: &context::SshCredentials) -> Result<()> {
+    let is_agent_running = env::var_os(AGENT_SOCKET_ENV).is_some();
+
+    match credentials.agent() {
+        context::SshAgent::Required if !is_agent_running => {
+            bail!(
+                "--ssh-agent is set, but ssh-agent is not running ({AGENT_SOCKET_ENV} is not set)"
+            )
+        }
+        context::SshAgent::Disabled => {
+            // ssh is the only user of the variable in this process
+            env::remove_var(AGENT_SOCKET_ENV);
+        }
+        _ => {}
+    }
+
+    let Some(keyfile) = credentials.keyfile() else {
+        return Ok(());
+    };
+
+    if !is_key_encrypted(keyfile)? {
+        return Ok(());
+    }
+
+    if credentials.agent() == context::SshAgent::Disabled {
+        bail!(
+            "{} is encrypted and has to be added to ssh-agent, which --no-agent turns off",
+            keyfile.display()
+        );
+    }
+
+    if !is_agent_running {
+        bail!(
+            "{} is encrypted. Start ssh-agent, dploy then adds the key to it",
+            keyfile.display()
+        );
+    }
+
+    if is_key_in_agent(keyfile)? {
+        return Ok(());
+    }
+
+    let passphrase = read_passphrase(keyfile)?;
+    add_key_to_agent(keyfile, &passphrase)
+}
+
+/// Loading the key with an empty passphrase only fails if it's encrypted
+fn is_key_encrypted(keyfile: &Path) -> Result<bool> {
+    let output = Command::new("ssh-keygen")
+        .args(["-y", "-P", "", "-f"])
+        .arg(keyfile)
+        .stdin(Stdio::null())
+        .output()
+        .context("Could not run ssh-keygen")?;
+
+    if output.status.success() {
+        return Ok(false);
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    if stderr.contains("passphrase") {
+        return Ok(true);
+    }
+
+    bail!(
+        "Could not read the key file {}: {}",
+        keyfile.display(),
+        stderr.trim()
+    );
+}
+
+/// Compares the public key next to the key file with the keys of the agent
+fn is_key_in_agent(keyfile: &Path) -> Result<bool> {
+    let mut public_keyfile = keyfile.as_os_str().to_owned();
+    public_keyfile.push(".pub");
+
+    let Ok(public_key) = fs::read_to_string(&public_keyfile) else {
+        return Ok(false);
+    };
+
+    let Some(key) = public_key.split_whitespace().nth(1) else {
+        return Ok(false);
+    };
+
+    let output = Command::new("ssh-add")
+        .arg("-L")
+        .stdin(Stdio::null())
+        .output()
+        .context("Could not run ssh-add")?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some(key)))
+}
+
+fn read_passphrase(keyfile: &Path) -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+
+    if !io::stdin().is_terminal() {
+        bail!(
+            "{} is encrypted. Set {PASSPHRASE_ENV} to its passphrase",
+            keyfile.display()
+        );
+    }
+
+    eprint!("Passphrase for {}: ", keyfile.display());
+    let passphrase = io::stdin().read_passwd(&mut io::stderr())?;
+    eprintln!();
+
+    passphrase.ok_or_else(|| anyhow!("No passphrase entered"))
+}
+
+fn add_key_to_agent(keyfile: &Path, passphrase: &str) -> Result<()> {
+    let askpass_dir = tempfile::tempdir()?;
+    let askpass = askpass_dir.path().join("askpass.sh");
+
+    fs::write(&askpass, ASKPASS_SCRIPT)?;
+    fs::set_permissions(&askpass, fs::Permissions::from_mode(0o700))?;
+
+    let output = Command::new("ssh-add")
+        .arg("-t")
+        .arg(AGENT_KEY_LIFETIME_SECS.to_string())
+        .arg(keyfile)
+        .env("SSH_ASKPASS", &askpass)
+        .env("SSH_ASKPASS_REQUIRE", "force")
+        .env(
+            "DISPLAY",
+            env::var("DISPLAY").unwrap_or_else(|_| ":0".to_owned()),
+        )
+        .env(PASSPHRASE_ENV, passphrase)
+        .stdin(Stdio::null())
+        .output()
+        .context("Could not run ssh-add")?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    if stderr.contains("Bad passphrase") {
+        bail!("Wrong passphrase for {}", keyfile.display());
+    }
+
+    bail!(
+        "Could not add {} to ssh-agent: {}",
+        keyfile.display(),
+        stderr.trim()
+    );
+}
+
+/// ssh reports a rejected key as "Permission denied", which is told apart from
+/// connection problems
+fn connect_error(credentials: &context::SshCredentials, error: openssh::Error) -> anyhow::Error {
+    let error = anyhow::Error::new(error);
+
+    if !format!("{error:#}").contains("Permission denied") {
+        return error.context("Could not launch ssh");
+    }
+
+    let key = match credentials.keyfile() {
+        Some(keyfile) => format!("the key {}", keyfile.display()),
+        None => "the keys of ssh-agent and the default keys".to_owned(),
+    };
+
+    anyhow!(
+        "{}@{} rejected {key}. Check that the public key is in ~/.ssh/authorized_keys on the server",
+        credentials.username(),
+        credentials.host()
+    )
+}
@@ -0,0 +1,19 @@
+use std::fs;
+
+use crate::{context, prelude::*};
+
+/// Reads a value persisted by a previous run. Missing or unreadable
+/// state is treated as absent
+pub fn read(context: &context::Context, key: &str) -> Option<String> {
+    fs::read_to_string(context.state_dir().join(key)).ok()
+}
+
+pub fn write(context: &context::Context, key: &str, value: &str) -> Result<()> {
+    let state_dir = context.state_dir();
+
+    fs::create_dir_all(&state_dir)
+        .with_context(|| format!("Could not create state directory {}", state_dir.display()))?;
+    fs::write(state_dir.join(key), value)?;
+
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use std::{path::Path, process};
+
+/// Short hash of the current commit, if the directory is inside a git repository
+pub fn git_short_hash(dir: &Path) -> Option<String> {
+    rev_parse_head(dir, &["--short"])
+}
+
+/// Full hash of the current commit, if the directory is inside a git repository
+pub fn git_hash(dir: &Path) -> Option<String> {
+    rev_parse_head(dir, &[])
+}
+
+/// Name of the checked out branch, none for a detached HEAD or outside of git
+pub fn current_branch(dir: &Path) -> Option<String> {
+    rev_parse_head(dir, &["--abbrev-ref"]).filter(|branch| branch != "HEAD")
+}
+
+fn rev_parse_head(dir: &Path, flags: &[&str]) -> Option<String> {
+    let output = process::Command::new("git")
+        .arg("rev-parse")
+        .args(flags)
+        .arg("HEAD")
+        .current_dir(dir)
+        .stderr(process::Stdio::null())
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let value = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+
+    (!value.is_empty()).then_some(value)
+}
@@ -1,3 +1,4 @@
 pub mod file;
+pub mod git;
 pub mod network;
 pub mod string;
@@ -1,11 +1,187 @@
-use std::net::TcpListener;
+use std::{
+    fmt,
+    net::{IpAddr, TcpListener, ToSocketAddrs},
+    time::Duration,
+};
 
-const ERROR_TEXT: &str = "Failed to acquire a free port";
+use anyhow::{bail, Context, Result};
 
-pub fn free_port() -> u16 {
-    TcpListener::bind("127.0.0.1:0")
-        .expect(ERROR_TEXT)
-        .local_addr()
-        .expect(ERROR_TEXT)
-        .port()
+const FREE_PORT_ADDRESS: &str = "127.0.0.1:0";
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+const PROBE_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Asks the OS for a free port on the loopback interface
+pub fn free_port() -> Result<u16> {
+    free_port_on(FREE_PORT_ADDRESS)
+}
+
+fn free_port_on(address: impl ToSocketAddrs) -> Result<u16> {
+    let port = TcpListener::bind(address)
+        .and_then(|listener| listener.local_addr())
+        .context("No free port is available")?
+        .port();
+
+    Ok(port)
+}
+
+/// Block of IP addresses in CIDR notation, e.g. `172.30.0.0/16`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Self> {
+        let Some((address, prefix_length)) = value.split_once('/') else {
+            bail!("Invalid CIDR `{value}`. Expected an address and a prefix length, e.g. 172.30.0.0/16");
+        };
+
+        let address = address
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid address in CIDR `{value}`"))?;
+
+        let max_prefix_length = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_length = prefix_length
+            .parse::<u8>()
+            .ok()
+            .filter(|length| *length <= max_prefix_length)
+            .with_context(|| {
+                format!(
+                    "Invalid prefix length in CIDR `{value}`, expected 0 to {max_prefix_length}"
+                )
+            })?;
+
+        Ok(Self {
+            address,
+            prefix_length,
+        })
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+
+                u32::from(network) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+
+                u128::from(network) & mask == u128::from(*address) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the address is the first one of the block, or for IPv4 the last one,
+    /// which can't be assigned to a host. Blocks of /31 and /32 have neither
+    pub fn is_network_or_broadcast(&self, address: &IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(_), IpAddr::V4(address)) if self.prefix_length < 31 => {
+                let host_mask = u32::MAX >> self.prefix_length;
+                let host_bits = u32::from(*address) & host_mask;
+
+                host_bits == 0 || host_bits == host_mask
+            }
+            (IpAddr::V6(_), IpAddr::V6(address)) if self.prefix_length < 127 => {
+                u128::from(*address) & (u128::MAX >> self.prefix_length) == 0
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.address.is_ipv4()
+    }
+
+    /// Whether every address of `other` is in this block
+    pub fn contains_cidr(&self, other: &Cidr) -> bool {
+        other.prefix_length >= self.prefix_length && self.contains(&other.address)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+/// Checks once whether something accepts TCP connections on the given address
+pub async fn probe_tcp(host: &str, port: u16) -> bool {
+    let connect = tokio::net::TcpStream::connect((host, port));
+
+    matches!(
+        tokio::time::timeout(PROBE_CONNECT_TIMEOUT, connect).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Polls the address until it accepts TCP connections or the timeout elapses.
+/// Returns whether the address became connectable
+pub async fn wait_for_tcp(host: &str, port: u16, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        if probe_tcp(host, port).await {
+            return true;
+        }
+
+        if tokio::time::Instant::now() + PROBE_INTERVAL > deadline {
+            return false;
+        }
+
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_free_port_fails_without_free_ports() {
+        // binding an address which doesn't belong to this host fails
+        // the same way as binding when all ports are taken
+        let result = free_port_on("192.0.2.1:0");
+
+        assert!(result.is_err());
+        assert!(free_port().is_ok_and(|port| port != 0));
+    }
+
+    #[test]
+    fn test_cidr() {
+        let subnet = Cidr::parse("172.30.0.0/16").unwrap();
+
+        assert!(subnet.contains(&"172.30.5.1".parse().unwrap()));
+        assert!(!subnet.contains(&"172.31.0.1".parse().unwrap()));
+        assert!(subnet.contains_cidr(&Cidr::parse("172.30.5.0/24").unwrap()));
+        assert!(!subnet.contains_cidr(&Cidr::parse("172.0.0.0/8").unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(&"10.0.0.1".parse().unwrap()));
+        assert!(Cidr::parse("fd00::/8")
+            .unwrap()
+            .contains(&"fd12::1".parse().unwrap()));
+
+        assert!(subnet.is_network_or_broadcast(&"172.30.0.0".parse().unwrap()));
+        assert!(subnet.is_network_or_broadcast(&"172.30.255.255".parse().unwrap()));
+        assert!(!subnet.is_network_or_broadcast(&"172.30.0.255".parse().unwrap()));
+        assert!(!Cidr::parse("172.30.0.0/31")
+            .unwrap()
+            .is_network_or_broadcast(&"172.30.0.0".parse().unwrap()));
+
+        assert!(Cidr::parse("172.30.0.0").is_err());
+        assert!(Cidr::parse("172.30.0.0/33").is_err());
+        assert!(Cidr::parse("172.30.0/16").is_err());
+    }
 }
@@ -0,0 +1,83 @@
+//! Commands printing a document keep the progress out of stdout, so it can be piped
+
+use std::{fs, path::Path, process::Command};
+
+fn create_app_dir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+
+    fs::write(
+        dir.path().join("dploy.toml"),
+        "name = \"document-output\"\nports = [3000]\n\n[postgres]\n",
+    )
+    .unwrap();
+    fs::write(dir.path().join("Dockerfile"), "FROM scratch\n").unwrap();
+
+    dir
+}
+
+fn run_dploy(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_dploy"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "dploy {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_export_compose_prints_only_yaml() {
+    let dir = create_app_dir();
+
+    for args in [
+        &["run", "export", "compose"][..],
+        &[
+            "--namespace",
+            "staging",
+            "--json-events",
+            "run",
+            "export",
+            "compose",
+        ],
+    ] {
+        let stdout = run_dploy(dir.path(), args);
+        let compose = serde_yaml::from_str::<serde_yaml::Value>(&stdout).unwrap();
+
+        assert!(compose.get("services").is_some(), "{stdout}");
+    }
+}
+
+#[test]
+fn test_config_diff_prints_only_json() {
+    let dir = create_app_dir();
+
+    for args in [
+        &[
+            "run", "config", "diff", "--from", "default", "--to", "staging", "-o", "json",
+        ][..],
+        &[
+            "--namespace",
+            "staging",
+            "--json-events",
+            "run",
+            "config",
+            "diff",
+            "--from",
+            "default",
+            "--to",
+            "staging",
+            "-o",
+            "json",
+        ],
+    ] {
+        let stdout = run_dploy(dir.path(), args);
+
+        serde_json::from_str::<serde_json::Value>(&stdout).unwrap();
+    }
+}
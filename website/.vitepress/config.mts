@@ -20,6 +20,10 @@ export default defineConfig({
         text: "Getting started",
         link: "/getting-started",
       },
+      {
+        text: "Configuration",
+        link: "/configuration",
+      },
       {
         text: "Difference between modes",
         link: "/difference-between-modes",